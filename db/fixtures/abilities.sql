@@ -0,0 +1,8 @@
+-- Copyright 2024 StarfleetAI
+-- SPDX-License-Identifier: Apache-2.0
+
+INSERT INTO abilities (id, company_id, name, created_at, updated_at)
+VALUES
+    ('00000000-0000-0000-0001-000000000001', '00000000-0000-0000-0000-000000000001', 'search_web', NOW(), NOW()),
+    ('00000000-0000-0000-0001-000000000002', '00000000-0000-0000-0000-000000000001', 'read_file', NOW(), NOW()),
+    ('00000000-0000-0000-0001-000000000003', '00000000-0000-0000-0000-000000000001', 'write_file', NOW(), NOW());
@@ -0,0 +1,9 @@
+-- Copyright 2024 StarfleetAI
+-- SPDX-License-Identifier: Apache-2.0
+
+-- `id_int` values are kept out of the sequence range to avoid clashes with agents created in tests.
+INSERT INTO agents (id, id_int, company_id, name, description, is_enabled, created_at, updated_at)
+VALUES
+    ('00000000-0000-0000-0003-000000000001', 1001, '00000000-0000-0000-0000-000000000001', 'Researcher', 'Searches the web', true, NOW(), NOW()),
+    ('00000000-0000-0000-0003-000000000002', 1002, '00000000-0000-0000-0000-000000000001', 'Archivist', 'Retired agent', false, NOW(), NOW()),
+    ('00000000-0000-0000-0003-000000000003', 1003, '00000000-0000-0000-0000-000000000001', 'Coder', 'Writes code', true, NOW(), NOW());
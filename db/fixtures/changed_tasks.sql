@@ -0,0 +1,12 @@
+-- Copyright 2024 StarfleetAI
+-- SPDX-License-Identifier: Apache-2.0
+
+INSERT INTO companies (id, name, slug, created_at, updated_at)
+VALUES ('00000000-0000-0000-0000-000000000002', 'Romulan Star Empire', 'romulans', NOW(), NOW());
+
+INSERT INTO tasks (company_id, user_id, agent_id, title, status, created_at, updated_at)
+VALUES
+    ('00000000-0000-0000-0000-000000000001', '00000000-0000-0000-0002-000000000001', '00000000-0000-0000-0003-000000000001', 'Updated today', 'Draft', '2024-04-19 10:00:00+00', '2024-04-22 10:00:00+00'),
+    ('00000000-0000-0000-0000-000000000001', '00000000-0000-0000-0002-000000000001', '00000000-0000-0000-0003-000000000001', 'Updated yesterday', 'Draft', '2024-04-19 11:00:00+00', '2024-04-21 10:00:00+00'),
+    ('00000000-0000-0000-0000-000000000001', '00000000-0000-0000-0002-000000000001', '00000000-0000-0000-0003-000000000001', 'Not updated', 'Draft', '2024-04-20 10:00:00+00', '2024-04-20 10:00:00+00'),
+    ('00000000-0000-0000-0000-000000000002', '00000000-0000-0000-0002-000000000001', '00000000-0000-0000-0003-000000000001', 'Other company', 'Draft', '2024-04-20 10:00:00+00', '2024-04-22 11:00:00+00');
@@ -0,0 +1,12 @@
+-- Copyright 2024 StarfleetAI
+-- SPDX-License-Identifier: Apache-2.0
+
+INSERT INTO chats (id, company_id, model_id, title, created_at, updated_at)
+VALUES (
+    '00000000-0000-0000-0006-000000000001',
+    '00000000-0000-0000-0000-000000000001',
+    '00000000-0000-0000-0004-000000000001',
+    'Weekend plans',
+    NOW(),
+    NOW()
+);
@@ -0,0 +1,5 @@
+-- Copyright 2024 StarfleetAI
+-- SPDX-License-Identifier: Apache-2.0
+
+INSERT INTO companies (id, name, slug, created_at, updated_at)
+VALUES ('00000000-0000-0000-0000-000000000001', 'Starfleet', 'starfleet', NOW(), NOW());
@@ -0,0 +1,5 @@
+-- Copyright 2024 StarfleetAI
+-- SPDX-License-Identifier: Apache-2.0
+
+INSERT INTO models (id, company_id, provider, name, context_length, max_tokens, text_in, text_out, function_calling, created_at, updated_at)
+VALUES ('00000000-0000-0000-0004-000000000001', '00000000-0000-0000-0000-000000000001', 'OpenAI', 'gpt-4-turbo', 128000, 4096, true, true, true, NOW(), NOW());
@@ -0,0 +1,14 @@
+-- Copyright 2024 StarfleetAI
+-- SPDX-License-Identifier: Apache-2.0
+
+INSERT INTO tasks (id, company_id, user_id, agent_id, title, status, created_at, updated_at)
+VALUES (
+    '00000000-0000-0000-0005-000000000001',
+    '00000000-0000-0000-0000-000000000001',
+    '00000000-0000-0000-0002-000000000001',
+    '00000000-0000-0000-0003-000000000001',
+    'Write a script to fetch the weather',
+    'Draft',
+    NOW(),
+    NOW()
+);
@@ -0,0 +1,5 @@
+-- Copyright 2024 StarfleetAI
+-- SPDX-License-Identifier: Apache-2.0
+
+INSERT INTO users (id, company_id, first_name, last_name, created_at, updated_at)
+VALUES ('00000000-0000-0000-0002-000000000001', '00000000-0000-0000-0000-000000000001', 'Jean-Luc', 'Picard', NOW(), NOW());
@@ -0,0 +1,13 @@
+-- Copyright 2024 StarfleetAI
+-- SPDX-License-Identifier: Apache-2.0
+
+CREATE TABLE task_plans (
+    id uuid PRIMARY KEY DEFAULT uuid_generate_v4(),
+    company_id uuid NOT NULL REFERENCES companies(id),
+    task_id uuid NOT NULL REFERENCES tasks(id) ON DELETE CASCADE,
+    plan JSON NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+CREATE INDEX task_plans_task_id_idx ON task_plans (company_id, task_id, created_at DESC);
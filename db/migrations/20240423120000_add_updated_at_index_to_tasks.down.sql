@@ -0,0 +1,4 @@
+-- Copyright 2024 StarfleetAI
+-- SPDX-License-Identifier: Apache-2.0
+
+DROP INDEX tasks_updated_at_idx;
@@ -0,0 +1,4 @@
+-- Copyright 2024 StarfleetAI
+-- SPDX-License-Identifier: Apache-2.0
+
+CREATE INDEX tasks_updated_at_idx ON tasks (company_id, updated_at);
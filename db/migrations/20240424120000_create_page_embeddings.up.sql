@@ -0,0 +1,16 @@
+-- Copyright 2024 StarfleetAI
+-- SPDX-License-Identifier: Apache-2.0
+
+CREATE TABLE page_embeddings (
+    id uuid PRIMARY KEY DEFAULT uuid_generate_v4(),
+    company_id uuid NOT NULL REFERENCES companies(id),
+    page_id uuid NOT NULL REFERENCES pages(id) ON DELETE CASCADE,
+    model TEXT NOT NULL,
+    chunk TEXT NOT NULL,
+    embedding REAL[] NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+CREATE INDEX page_embeddings_model_idx ON page_embeddings (company_id, model);
+CREATE INDEX page_embeddings_page_id_idx ON page_embeddings (company_id, page_id);
@@ -0,0 +1,12 @@
+-- Copyright 2024 StarfleetAI
+-- SPDX-License-Identifier: Apache-2.0
+
+CREATE TABLE message_tags (
+    company_id uuid NOT NULL REFERENCES companies(id),
+    message_id uuid NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+    tag TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    PRIMARY KEY (message_id, tag)
+);
+
+CREATE INDEX message_tags_company_id_tag_idx ON message_tags (company_id, tag);
@@ -0,0 +1,12 @@
+-- Copyright 2024 StarfleetAI
+-- SPDX-License-Identifier: Apache-2.0
+
+CREATE TABLE task_dependencies (
+    company_id uuid NOT NULL REFERENCES companies(id),
+    task_id uuid NOT NULL REFERENCES tasks(id) ON DELETE CASCADE,
+    depends_on_id uuid NOT NULL REFERENCES tasks(id) ON DELETE CASCADE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    PRIMARY KEY (task_id, depends_on_id)
+);
+
+CREATE INDEX task_dependencies_depends_on_id_idx ON task_dependencies (depends_on_id);
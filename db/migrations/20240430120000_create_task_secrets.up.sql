@@ -0,0 +1,20 @@
+-- Copyright 2024 StarfleetAI
+-- SPDX-License-Identifier: Apache-2.0
+
+CREATE TABLE task_secrets (
+    id uuid PRIMARY KEY DEFAULT uuid_generate_v4(),
+    company_id uuid NOT NULL REFERENCES companies(id),
+    -- Secrets without an agent are available to all the company's agents.
+    agent_id uuid REFERENCES agents(id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    -- Nonce followed by the AES-256-GCM sealed value.
+    encrypted_value BYTEA NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+CREATE UNIQUE INDEX task_secrets_agent_id_name_idx ON task_secrets (
+    company_id,
+    COALESCE(agent_id, '00000000-0000-0000-0000-000000000000'),
+    name
+);
@@ -0,0 +1,5 @@
+-- Copyright 2024 StarfleetAI
+-- SPDX-License-Identifier: Apache-2.0
+
+ALTER TABLE models DROP COLUMN api_version;
+ALTER TABLE models DROP COLUMN deployment;
@@ -0,0 +1,5 @@
+-- Copyright 2024 StarfleetAI
+-- SPDX-License-Identifier: Apache-2.0
+
+ALTER TABLE models ADD COLUMN deployment TEXT;
+ALTER TABLE models ADD COLUMN api_version TEXT;
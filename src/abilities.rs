@@ -1,13 +1,17 @@
 // Copyright 2024 StarfleetAI
 // SPDX-License-Identifier: Apache-2.0
 
-use std::path::PathBuf;
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
 
 use anyhow::{anyhow, Context};
 use askama::Template;
+use serde::Deserialize;
 use sqlx::{Pool, Postgres};
 use tokio::{fs, spawn};
-use tracing::{debug, trace};
+use tracing::{debug, instrument, trace};
 use uuid::Uuid;
 
 use crate::{
@@ -15,6 +19,8 @@ use crate::{
     clients::openai::{Function, Tool, ToolCall},
     docker,
     repo::{self, messages::CreateParams},
+    secrets::{self, Secrets, SecretsCipher},
+    settings::Sandbox,
     types::{
         abilities::Ability,
         messages::{Message, Role, Status},
@@ -28,6 +34,43 @@ pub enum Error {
     TokioJoin(#[from] tokio::task::JoinError),
     #[error("ability is used by agents")]
     IsUsedByAgents,
+    #[error("`{0}` is not a valid function name")]
+    InvalidName(String),
+    #[error("language `{0}` is not supported, only `python` is")]
+    UnsupportedLanguage(String),
+    #[error("code doesn't define function `{0}`")]
+    FunctionNotDefined(String),
+    #[error("ability `{0}` already exists")]
+    DuplicateName(String),
+}
+
+/// Ability definition used for batch import.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AbilityManifest {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub code: String,
+    /// Language of the code. Only `python` is supported.
+    pub language: String,
+    /// Function definition. Derived from the code, if not given.
+    #[serde(default)]
+    pub parameters_json: Option<Function>,
+}
+
+/// Manifest entry which failed validation.
+#[derive(Debug)]
+pub struct ManifestError {
+    /// Position of the entry in the manifest.
+    pub index: usize,
+    pub name: String,
+    pub error: crate::errors::Error,
+}
+
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub created: Vec<Ability>,
+    pub errors: Vec<ManifestError>,
 }
 
 #[derive(Template)]
@@ -43,13 +86,13 @@ struct CallToolsTemplate<'a> {
     tool_call: &'a str,
 }
 
-/// Get function definition by its code.
+/// Get function definition by its code, which is run in a container limited by `sandbox`.
 ///
 /// # Errors
 ///
 /// Returns error if there was a problem when determining function parameters.
 // TODO: work correctly if there are imports in the code
-pub async fn get_function_definition(code: &str) -> Result<Function> {
+pub async fn get_function_definition(code: &str, sandbox: &Sandbox) -> Result<Function> {
     let template = GetFunctionDefinitionTemplate { code };
 
     // TODO: seems a little bit inefficient to run a container only to get a function definition.
@@ -59,8 +102,14 @@ pub async fn get_function_definition(code: &str) -> Result<Function> {
             .render()
             .context("Failed to render `get_function_definition` script")?,
         None,
+        &Secrets::default(),
+        None,
+        sandbox,
+        sandbox.timeout(),
+        None,
     )
-    .await?;
+    .await?
+    .combined();
 
     debug!("Function definition script output: {:?}", output);
 
@@ -84,17 +133,163 @@ pub fn preprocess_code(code: &str) -> String {
     result.trim().to_string()
 }
 
-/// Executes tool calls for the message.
+/// Checks the manifest entry without storing anything. Returns the ability's function
+/// definition, which is derived from the code if the entry doesn't provide one.
+///
+/// # Errors
+///
+/// Returns error if the name is not a valid function name or the language is not supported.
+/// Returns error if the code doesn't define the function.
+/// Returns error if there was a problem when determining function parameters.
+pub async fn validate(manifest: &AbilityManifest, sandbox: &Sandbox) -> Result<Function> {
+    let name = manifest.name.as_str();
+    let is_valid_name = name
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !is_valid_name {
+        return Err(Error::InvalidName(name.to_string()).into());
+    }
+
+    if !manifest.language.eq_ignore_ascii_case("python") {
+        return Err(Error::UnsupportedLanguage(manifest.language.clone()).into());
+    }
+
+    if !manifest.code.contains(&format!("def {name}(")) {
+        return Err(Error::FunctionNotDefined(name.to_string()).into());
+    }
+
+    let function = match &manifest.parameters_json {
+        Some(function) => function.clone(),
+        None => get_function_definition(&manifest.code, sandbox).await?,
+    };
+    if function.name != name {
+        return Err(Error::FunctionNotDefined(name.to_string()).into());
+    }
+
+    Ok(function)
+}
+
+/// Reads ability manifests from the `*.json` files in the directory, in the file name order.
+///
+/// # Errors
+///
+/// Returns error if the directory or any of the files can't be read or parsed.
+pub async fn read_manifest_dir(dir: &Path) -> Result<Vec<AbilityManifest>> {
+    let mut entries = fs::read_dir(dir)
+        .await
+        .with_context(|| format!("Failed to read manifest directory {}", dir.display()))?;
+
+    let mut paths = Vec::new();
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .context("Failed to read manifest directory entry")?
+    {
+        let path = entry.path();
+        if path.extension().is_some_and(|ext| ext == "json") {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    let mut manifests = Vec::with_capacity(paths.len());
+    for path in paths {
+        let content = fs::read_to_string(&path)
+            .await
+            .with_context(|| format!("Failed to read manifest {}", path.display()))?;
+        let manifest = serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse manifest {}", path.display()))?;
+
+        manifests.push(manifest);
+    }
+
+    Ok(manifests)
+}
+
+/// Validates the manifest entries and creates abilities for the valid ones in a single query.
+///
+/// Invalid entries, including the ones named after existing abilities, are reported in the
+/// result. With `abort_on_error`, nothing is created if any of the entries is invalid.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while accessing database.
+#[instrument(skip(pool, entries, sandbox))]
+pub async fn import_from_manifest(
+    pool: &Pool<Postgres>,
+    cid: Uuid,
+    entries: Vec<AbilityManifest>,
+    abort_on_error: bool,
+    sandbox: &Sandbox,
+) -> Result<ImportReport> {
+    debug!("Importing {} abilities", entries.len());
+
+    let mut names = repo::abilities::list(pool, cid)
+        .await?
+        .into_iter()
+        .map(|ability| ability.name)
+        .collect::<HashSet<_>>();
+
+    let mut report = ImportReport::default();
+    let mut params = Vec::with_capacity(entries.len());
+
+    for (index, manifest) in entries.into_iter().enumerate() {
+        let result = match names.contains(&manifest.name) {
+            true => Err(Error::DuplicateName(manifest.name.clone()).into()),
+            false => validate(&manifest, sandbox).await,
+        };
+
+        match result {
+            Ok(parameters_json) => {
+                names.insert(manifest.name.clone());
+                params.push(repo::abilities::CreateParams {
+                    name: manifest.name,
+                    description: manifest.description,
+                    code: preprocess_code(&manifest.code),
+                    parameters_json,
+                });
+            }
+            Err(error) => report.errors.push(ManifestError {
+                index,
+                name: manifest.name,
+                error,
+            }),
+        }
+    }
+
+    if abort_on_error && !report.errors.is_empty() {
+        debug!(
+            "Import is aborted, {} entries are invalid",
+            report.errors.len()
+        );
+
+        return Ok(report);
+    }
+
+    if !params.is_empty() {
+        report.created = repo::abilities::create_many(pool, cid, params).await?;
+    }
+
+    Ok(report)
+}
+
+/// Executes tool calls for the message. If the `cipher` is given, the agent's secrets are
+/// available to the code as environment variables.
 ///
 /// # Errors
 ///
 /// Will return an error if there was a problem while executing tool calls.
+#[allow(clippy::too_many_arguments)]
 pub async fn execute_for_message(
     pool: &Pool<Postgres>,
     channel: &Channel,
+    cipher: Option<&SecretsCipher>,
     cid: Uuid,
     uid: Uuid,
-    workdir_root: &PathBuf,
+    workdir_root: &Path,
+    sandbox: &Sandbox,
     message: &Message,
 ) -> Result<()> {
     // Load agent abilities
@@ -108,6 +303,11 @@ pub async fn execute_for_message(
         return Err(anyhow!("Tool calls are not set for the message").into());
     };
 
+    let secrets = match cipher {
+        Some(cipher) => secrets::for_agent(pool, cipher, cid, message.agent_id).await?,
+        None => Secrets::default(),
+    };
+
     let mut handles = Vec::with_capacity(tool_calls.len());
     for tool_call in tool_calls.iter() {
         // Skip internal tool calls
@@ -116,12 +316,14 @@ pub async fn execute_for_message(
         }
 
         let abilities = abilities.clone();
-        let workdir_root = workdir_root.clone();
+        let workdir_root = workdir_root.to_path_buf();
         let msg = message.clone();
         let tc = tool_call.clone();
+        let secrets = secrets.clone();
+        let sandbox = sandbox.clone();
 
         let handle = spawn(async move {
-            let output = execute(&abilities, &workdir_root, &msg, &tc).await?;
+            let output = execute(&abilities, &workdir_root, &msg, &tc, &secrets, &sandbox).await?;
             // Wrap output in a code block
             //
             // TODO: This is a temporary solution. It's better to wrap it on before markdown-2-html
@@ -160,7 +362,8 @@ pub async fn execute_for_message(
     Ok(())
 }
 
-/// Execute abilities code.
+/// Execute abilities code in a container limited by `sandbox`, with the secrets as environment
+/// variables.
 ///
 /// # Errors
 ///
@@ -170,6 +373,8 @@ pub async fn execute(
     workdir_root: &PathBuf,
     message: &Message,
     tool_call: &ToolCall,
+    secrets: &Secrets,
+    sandbox: &Sandbox,
 ) -> Result<String> {
     debug!(
         "Executing tool call `{}` for message `{}`",
@@ -222,8 +427,23 @@ pub async fn execute(
         .await
         .with_context(|| "Failed to write script to workdir")?;
 
+    // Abilities are the agent's tools, e.g. API clients, so they are given the network
+    let sandbox = Sandbox {
+        network_disabled: false,
+        ..sandbox.clone()
+    };
+
     // Run script
-    let output = docker::run_python_script(&workdir, &script_name).await;
+    let output = docker::run_python_script(
+        &workdir,
+        &script_name,
+        secrets,
+        None,
+        &sandbox,
+        sandbox.timeout(),
+    )
+    .await
+    .map(|output| output.combined());
 
     // Delete script
     fs::remove_file(&script_path)
@@ -232,3 +452,252 @@ pub async fn execute(
 
     output
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::errors;
+
+    use super::*;
+
+    const COMPANY_ID: Uuid = Uuid::from_u128(1);
+
+    fn manifest(name: &str, language: &str, code: &str) -> serde_json::Value {
+        json!({
+            "name": name,
+            "description": format!("Ability `{name}`"),
+            "code": code,
+            "language": language,
+            "parameters_json": { "name": name, "parameters": { "type": "object", "properties": {} } }
+        })
+    }
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(path = "../db/fixtures", scripts("companies", "abilities"))
+    )]
+    async fn test_import_from_manifest_stores_valid_entries(pool: Pool<Postgres>) {
+        let dir = std::env::temp_dir().join(format!("bridge-abilities-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).await.unwrap();
+
+        let files = [
+            (
+                "01_greet.json",
+                manifest("greet", "python", "def greet():\n    return 'Hi'  \n"),
+            ),
+            (
+                "02_shout.json",
+                manifest("shout", "javascript", "function shout() {}"),
+            ),
+            (
+                "03_search_web.json",
+                manifest("search_web", "python", "def search_web():\n    pass"),
+            ),
+            (
+                "04_count.json",
+                manifest("count", "python", "def counter():\n    return 1"),
+            ),
+            (
+                "05_sum.json",
+                manifest("sum", "Python", "def sum():\n    return 2"),
+            ),
+            ("README.md", json!("Not a manifest")),
+        ];
+        for (name, content) in files {
+            fs::write(dir.join(name), content.to_string())
+                .await
+                .unwrap();
+        }
+
+        let manifests = read_manifest_dir(&dir).await.unwrap();
+        fs::remove_dir_all(&dir).await.unwrap();
+        assert_eq!(manifests.len(), 5);
+
+        let report = import_from_manifest(
+            &pool,
+            COMPANY_ID,
+            manifests.clone(),
+            true,
+            &Sandbox::default(),
+        )
+        .await
+        .unwrap();
+        assert!(report.created.is_empty());
+        assert_eq!(report.errors.len(), 3);
+        assert_eq!(
+            repo::abilities::list(&pool, COMPANY_ID)
+                .await
+                .unwrap()
+                .len(),
+            3
+        );
+
+        let report = import_from_manifest(&pool, COMPANY_ID, manifests, false, &Sandbox::default())
+            .await
+            .unwrap();
+
+        let created = report
+            .created
+            .iter()
+            .map(|ability| ability.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(created, ["greet", "sum"]);
+        assert_eq!(report.created[0].code, "def greet():\n    return 'Hi'");
+        assert_eq!(report.created[1].function().name, "sum");
+
+        let errors = report
+            .errors
+            .iter()
+            .map(|error| (error.index, error.name.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(errors, [(1, "shout"), (2, "search_web"), (3, "count")]);
+        assert!(matches!(
+            report.errors[0].error,
+            errors::Error::Abilities(Error::UnsupportedLanguage(_))
+        ));
+        assert!(matches!(
+            report.errors[1].error,
+            errors::Error::Abilities(Error::DuplicateName(_))
+        ));
+        assert!(matches!(
+            report.errors[2].error,
+            errors::Error::Abilities(Error::FunctionNotDefined(_))
+        ));
+
+        assert_eq!(
+            repo::abilities::list(&pool, COMPANY_ID)
+                .await
+                .unwrap()
+                .len(),
+            5
+        );
+    }
+
+    /// Collects the formatted log lines.
+    #[derive(Clone, Default)]
+    struct LogBuffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(
+            path = "../db/fixtures",
+            scripts("companies", "agents", "models", "chats")
+        )
+    )]
+    #[ignore = "requires Docker to run the abilities code"]
+    async fn test_secrets_are_passed_to_code_but_not_exposed(pool: Pool<Postgres>) {
+        const CHAT_ID: Uuid = Uuid::from_u128(0x0006_0000_0000_0001);
+        const CODER_ID: Uuid = Uuid::from_u128(0x0003_0000_0000_0003);
+        const TOKEN: &str = "sk-very-secret-token";
+
+        let logs = LogBuffer::default();
+        let writer = logs.clone();
+        let _guard = tracing::subscriber::set_default(
+            tracing_subscriber::fmt()
+                .with_max_level(tracing::Level::TRACE)
+                .with_writer(move || writer.clone())
+                .finish(),
+        );
+
+        let cipher = SecretsCipher::new(&[7; 32]).unwrap();
+        secrets::set(
+            &pool,
+            &cipher,
+            COMPANY_ID,
+            Some(CODER_ID),
+            "API_TOKEN",
+            TOKEN,
+        )
+        .await
+        .unwrap();
+
+        let ability = repo::abilities::create(
+            &pool,
+            COMPANY_ID,
+            repo::abilities::CreateParams {
+                name: "read_token".to_string(),
+                description: "Reads the API token".to_string(),
+                code: "def read_token():\n    import os\n    return 'token: ' + os.environ['API_TOKEN']"
+                    .to_string(),
+                parameters_json: serde_json::from_value(
+                    json!({ "name": "read_token", "parameters": { "type": "object", "properties": {} } }),
+                )
+                .unwrap(),
+            },
+        )
+        .await
+        .unwrap();
+        repo::agent_abilities::create(&pool, COMPANY_ID, CODER_ID, ability.id)
+            .await
+            .unwrap();
+
+        let message = repo::messages::create(
+            &pool,
+            COMPANY_ID,
+            CreateParams {
+                chat_id: CHAT_ID,
+                agent_id: Some(CODER_ID),
+                status: Status::WaitingForToolCall,
+                role: Role::Assistant,
+                tool_calls: Some(json!([{
+                    "id": "call_1",
+                    "type": "function",
+                    "function": { "name": "read_token", "arguments": "{}" }
+                }])),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        let workdir_root = std::env::temp_dir().join(format!("bridge-secrets-{}", Uuid::new_v4()));
+        let channel: Channel = Box::new(crate::channel::NoopEmitter);
+        execute_for_message(
+            &pool,
+            &channel,
+            Some(&cipher),
+            COMPANY_ID,
+            Uuid::new_v4(),
+            &workdir_root,
+            &Sandbox::default(),
+            &message,
+        )
+        .await
+        .unwrap();
+
+        let messages = repo::messages::list(
+            &pool,
+            COMPANY_ID,
+            repo::messages::ListParams { chat_id: CHAT_ID },
+        )
+        .await
+        .unwrap();
+        let output = messages
+            .iter()
+            .find(|message| message.role == Role::Tool)
+            .and_then(|message| message.content.clone())
+            .unwrap();
+        assert!(output.contains("token: [REDACTED]"));
+        assert!(messages.iter().all(|message| !message
+            .content
+            .as_deref()
+            .unwrap_or_default()
+            .contains(TOKEN)));
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("Script output"));
+        assert!(!logs.contains(TOKEN));
+    }
+}
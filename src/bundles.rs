@@ -0,0 +1,388 @@
+// Copyright 2024 StarfleetAI
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::HashMap;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use tracing::{debug, instrument};
+use uuid::Uuid;
+
+use crate::{
+    agents,
+    clients::openai::Function,
+    repo,
+    settings::Settings,
+    types::{agents::Example, models::Provider, Result},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("agent `{0}` is not found in the bundle")]
+    AgentNotFound(Uuid),
+    #[error("ability `{0}` is not found in the bundle")]
+    AbilityNotFound(Uuid),
+}
+
+/// Company setup, portable between environments.
+///
+/// Ids are only used to link records within the bundle and are remapped on import.
+/// API keys are never exported.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Bundle {
+    pub abilities: Vec<BundleAbility>,
+    pub agents: Vec<BundleAgent>,
+    pub agent_abilities: Vec<BundleAgentAbility>,
+    pub models: Vec<BundleModel>,
+    pub settings: Settings,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BundleAbility {
+    pub id: Uuid,
+    pub name: String,
+    pub description: String,
+    pub code: String,
+    pub parameters_json: Function,
+}
+
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BundleAgent {
+    pub id: Uuid,
+    pub name: String,
+    pub description: String,
+    pub system_message: String,
+    pub is_enabled: bool,
+    pub is_code_interpreter_enabled: bool,
+    pub is_web_browser_enabled: bool,
+    pub execution_steps_limit: Option<i32>,
+    #[serde(default)]
+    pub forbidden_tools: Vec<String>,
+    #[serde(default)]
+    pub examples: Vec<Example>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BundleAgentAbility {
+    pub agent_id: Uuid,
+    pub ability_id: Uuid,
+}
+
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BundleModel {
+    pub provider: Provider,
+    pub name: String,
+    pub context_length: i32,
+    pub max_tokens: i64,
+    pub text_in: bool,
+    pub text_out: bool,
+    pub image_in: bool,
+    pub image_out: bool,
+    pub audio_in: bool,
+    pub audio_out: bool,
+    pub function_calling: bool,
+    pub api_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deployment: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_version: Option<String>,
+    /// Not exported, but can be added to the bundle by hand.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<String>,
+}
+
+/// Exports company's agents, abilities, models and settings, without API keys.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while accessing database.
+#[instrument(skip(pool))]
+pub async fn export_bundle(pool: &Pool<Postgres>, cid: Uuid) -> Result<Bundle> {
+    let abilities = repo::abilities::list(pool, cid)
+        .await?
+        .into_iter()
+        .map(|ability| BundleAbility {
+            parameters_json: ability.function(),
+            id: ability.id,
+            name: ability.name,
+            description: ability.description,
+            code: ability.code,
+        })
+        .collect();
+
+    let agents = repo::agents::list(pool, cid)
+        .await?
+        .into_iter()
+        .map(|agent| BundleAgent {
+            examples: agent.examples(),
+            id: agent.id,
+            name: agent.name,
+            description: agent.description,
+            system_message: agent.system_message,
+            is_enabled: agent.is_enabled,
+            is_code_interpreter_enabled: agent.is_code_interpreter_enabled,
+            is_web_browser_enabled: agent.is_web_browser_enabled,
+            execution_steps_limit: agent.execution_steps_limit,
+            forbidden_tools: agent.forbidden_tools,
+        })
+        .collect();
+
+    let agent_abilities = repo::agent_abilities::list(pool, cid)
+        .await?
+        .into_iter()
+        .map(|link| BundleAgentAbility {
+            agent_id: link.agent_id,
+            ability_id: link.ability_id,
+        })
+        .collect();
+
+    let models = repo::models::list(pool, cid)
+        .await?
+        .into_iter()
+        .map(|model| BundleModel {
+            provider: model.provider,
+            name: model.name,
+            context_length: model.context_length,
+            max_tokens: model.max_tokens,
+            text_in: model.text_in,
+            text_out: model.text_out,
+            image_in: model.image_in,
+            image_out: model.image_out,
+            audio_in: model.audio_in,
+            audio_out: model.audio_out,
+            function_calling: model.function_calling,
+            api_url: model.api_url,
+            deployment: model.deployment,
+            api_version: model.api_version,
+            api_key: None,
+        })
+        .collect();
+
+    let mut settings = repo::settings::get(pool, cid).await?;
+    settings.api_keys.clear();
+
+    Ok(Bundle {
+        abilities,
+        agents,
+        agent_abilities,
+        models,
+        settings,
+    })
+}
+
+/// Recreates the bundle records for the company in a single transaction.
+///
+/// Abilities and agents are always created anew, while models with the same provider and name
+/// are updated. Company's API keys are kept, unless the bundle overrides them.
+///
+/// # Errors
+///
+/// Returns error if the bundle links unknown agents or abilities.
+/// Returns error if there was a problem while accessing database.
+#[instrument(skip(pool, bundle))]
+pub async fn import_bundle(pool: &Pool<Postgres>, cid: Uuid, bundle: Bundle) -> Result<()> {
+    debug!(
+        "Importing {} agents and {} abilities",
+        bundle.agents.len(),
+        bundle.abilities.len()
+    );
+
+    let mut settings = bundle.settings;
+    let mut api_keys = repo::settings::get(pool, cid).await?.api_keys;
+    api_keys.append(&mut settings.api_keys);
+    settings.api_keys = api_keys;
+
+    let mut tx = pool.begin().await.context("Failed to begin transaction")?;
+
+    let mut ability_ids = HashMap::with_capacity(bundle.abilities.len());
+    for ability in bundle.abilities {
+        let created = repo::abilities::create(
+            &mut *tx,
+            cid,
+            repo::abilities::CreateParams {
+                name: ability.name,
+                description: ability.description,
+                code: ability.code,
+                parameters_json: ability.parameters_json,
+            },
+        )
+        .await?;
+
+        ability_ids.insert(ability.id, created.id);
+    }
+
+    // Agents are created one by one, so that they are matched to the bundle's IDs by key rather
+    // than by the order of the returned rows.
+    let mut agent_ids = HashMap::with_capacity(bundle.agents.len());
+    for agent in bundle.agents {
+        let created = agents::create(
+            &mut *tx,
+            cid,
+            repo::agents::CreateParams {
+                name: agent.name,
+                description: agent.description,
+                system_message: agent.system_message,
+                is_enabled: agent.is_enabled,
+                is_code_interpreter_enabled: agent.is_code_interpreter_enabled,
+                is_web_browser_enabled: agent.is_web_browser_enabled,
+                execution_steps_limit: agent.execution_steps_limit,
+                forbidden_tools: agent.forbidden_tools,
+                examples: agent.examples,
+            },
+        )
+        .await?;
+
+        agent_ids.insert(agent.id, created.id);
+    }
+
+    for link in bundle.agent_abilities {
+        let agent_id = agent_ids
+            .get(&link.agent_id)
+            .ok_or(Error::AgentNotFound(link.agent_id))?;
+        let ability_id = ability_ids
+            .get(&link.ability_id)
+            .ok_or(Error::AbilityNotFound(link.ability_id))?;
+
+        repo::agent_abilities::create(&mut *tx, cid, *agent_id, *ability_id).await?;
+    }
+
+    for model in bundle.models {
+        repo::models::create(
+            &mut *tx,
+            cid,
+            repo::models::CreateParams {
+                provider: model.provider,
+                name: model.name,
+                context_length: model.context_length,
+                max_tokens: model.max_tokens,
+                text_in: model.text_in,
+                text_out: model.text_out,
+                image_in: model.image_in,
+                image_out: model.image_out,
+                audio_in: model.audio_in,
+                audio_out: model.audio_out,
+                function_calling: model.function_calling,
+                api_url: model.api_url,
+                api_key: model.api_key,
+                deployment: model.deployment,
+                api_version: model.api_version,
+            },
+        )
+        .await?;
+    }
+
+    repo::settings::upsert(&mut *tx, cid, &settings).await?;
+
+    tx.commit().await.context("Failed to commit transaction")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    const COMPANY_ID: Uuid = Uuid::from_u128(1);
+    const OTHER_COMPANY_ID: Uuid = Uuid::from_u128(2);
+
+    /// Agent names along with their ability names.
+    async fn agent_abilities(pool: &Pool<Postgres>, cid: Uuid) -> BTreeSet<(String, String)> {
+        let agents = repo::agents::list(pool, cid)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|agent| (agent.id, agent.name))
+            .collect::<HashMap<_, _>>();
+        let abilities = repo::abilities::list(pool, cid)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|ability| (ability.id, ability.name))
+            .collect::<HashMap<_, _>>();
+
+        repo::agent_abilities::list(pool, cid)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|link| {
+                (
+                    agents[&link.agent_id].clone(),
+                    abilities[&link.ability_id].clone(),
+                )
+            })
+            .collect()
+    }
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(
+            path = "../db/fixtures",
+            scripts("companies", "abilities", "agents", "models")
+        )
+    )]
+    async fn test_export_and_import_round_trip(pool: Pool<Postgres>) {
+        for (agent, ability) in [(1, 1), (3, 2), (3, 3)] {
+            repo::agent_abilities::create(
+                &pool,
+                COMPANY_ID,
+                Uuid::from_u128(0x0003_0000_0000_0000 + agent),
+                Uuid::from_u128(0x0001_0000_0000_0000 + ability),
+            )
+            .await
+            .unwrap();
+        }
+        sqlx::query("UPDATE models SET api_key = 'sk-model'")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let mut settings = repo::settings::get(&pool, COMPANY_ID).await.unwrap();
+        settings
+            .api_keys
+            .insert(Provider::OpenAI, "sk-settings".to_string());
+        repo::settings::update(&pool, COMPANY_ID, &settings)
+            .await
+            .unwrap();
+
+        sqlx::query(
+            "INSERT INTO companies (id, name, slug, created_at, updated_at) \
+             VALUES ($1, 'Enterprise', 'enterprise', NOW(), NOW())",
+        )
+        .bind(OTHER_COMPANY_ID)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let bundle = export_bundle(&pool, COMPANY_ID).await.unwrap();
+
+        let json = serde_json::to_string(&bundle).unwrap();
+        assert!(!json.contains("sk-"));
+
+        import_bundle(
+            &pool,
+            OTHER_COMPANY_ID,
+            serde_json::from_str(&json).unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let expected = agent_abilities(&pool, COMPANY_ID).await;
+        assert_eq!(expected.len(), 3);
+        assert_eq!(agent_abilities(&pool, OTHER_COMPANY_ID).await, expected);
+
+        let agents = repo::agents::list(&pool, OTHER_COMPANY_ID).await.unwrap();
+        assert_eq!(agents.len(), 3);
+        assert!(agents
+            .iter()
+            .any(|agent| agent.name == "Archivist" && !agent.is_enabled));
+
+        let models = repo::models::list(&pool, OTHER_COMPANY_ID).await.unwrap();
+        assert_eq!(models.len(), 1);
+        assert_eq!(models[0].name, "gpt-4-turbo");
+        assert_eq!(models[0].api_key, None);
+    }
+}
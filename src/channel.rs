@@ -11,10 +11,27 @@ use crate::types::{chats::Chat, messages::Message, task_results::TaskResult, tas
 #[serde(tag = "event", content = "data")]
 pub enum Event<'a> {
     ChatUpdated(&'a Chat),
+    ChatDeleted(&'a Chat),
     MessageCreated(&'a Message),
     MessageUpdated(&'a Message),
+    /// Heartbeat of a message which is still being written, sent when no chunks arrive for a
+    /// while, e.g. when the model is thinking.
+    MessageStreaming {
+        id: Uuid,
+    },
     TaskCreated(&'a Task),
     TaskUpdated(&'a Task),
+    /// Task execution failed with a transient error and is attempted again.
+    TaskRetrying {
+        id: Uuid,
+        attempt: u8,
+        max_retries: u8,
+    },
+    /// Snapshot of the whole task subtree, root first, after a status transition within it.
+    TaskTreeUpdated {
+        root_id: Uuid,
+        tasks: &'a [Task],
+    },
     TaskResultCreated(&'a TaskResult),
 }
 
@@ -22,6 +39,24 @@ pub enum Event<'a> {
 pub trait Emitter {
     // TODO: maybe use Option<Uuid> instead of Uuid
     async fn emit(&self, user_id: Uuid, event: &Event) -> Result<()>;
+
+    /// Whether the user still receives the events. Completions streamed to the user with
+    /// `cancel_on_disconnect` set are cancelled once they are gone, to save tokens.
+    async fn is_connected(&self, _user_id: Uuid) -> bool {
+        true
+    }
 }
 
 pub type Channel = Box<dyn Emitter + Send + Sync>;
+
+/// Emitter which discards all events.
+#[cfg(test)]
+pub(crate) struct NoopEmitter;
+
+#[cfg(test)]
+#[async_trait]
+impl Emitter for NoopEmitter {
+    async fn emit(&self, _user_id: Uuid, _event: &Event) -> Result<()> {
+        Ok(())
+    }
+}
@@ -1,35 +1,56 @@
 // Copyright 2024 StarfleetAI
 // SPDX-License-Identifier: Apache-2.0
 
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
 use anyhow::{anyhow, Context};
+use reqwest::{Response, StatusCode};
+use serde::Deserialize;
 use serde_json::Value;
 use sqlx::{Pool, Postgres};
-use tracing::{debug, error, instrument, trace, warn};
+use tokio::time::{Interval, MissedTickBehavior};
+use tracing::{debug, instrument, trace, warn};
 use uuid::Uuid;
 
 use crate::{
     channel::{Channel, Event},
     clients::{
-        self,
+        self, anthropic,
+        circuit_breaker::CircuitBreaker,
         openai::{
-            Client, CreateChatCompletionRequest, FunctionCall, Tool, ToolCall, ToolCalls, ToolType,
+            self, CreateChatCompletionRequest, FinishReason, FunctionCall, ResponseFormat,
+            StreamOptions, Tool, ToolCall, ToolCalls, ToolChoice, ToolType,
         },
+        usage::{self, OpenAIUsageExtractor, StreamUsage, StreamUsageExtractor},
+        ChatClient, StreamEvent, StreamTranslator,
     },
+    embeddings::{cosine_similarity, Embedder},
     errors, messages,
     repo::{
         self,
         messages::{ListParams, UpdateWithCompletionResultParams},
     },
+    tokens::TokenCounter,
+    transcript::TranscriptSink,
     types::{
         abilities::Ability,
+        agents::Example,
+        chats::{Chat, Kind},
         messages::{Message, Role, Status},
-        models::Model,
+        models::{Model, Provider},
         Result,
     },
 };
 
-const CHUNK_SEPARATOR: &str = "\n\n";
-const DONE_CHUNK: &str = "data: [DONE]";
+/// Tokens taken by the message role and formatting, on top of the content.
+const MESSAGE_OVERHEAD_TOKENS: usize = 4;
+/// Data of the event which ends the OpenAI stream.
+const DONE_DATA: &str = "[DONE]";
+/// Appended to the content of the messages stopped by the provider's content filter.
+pub const CONTENT_FILTERED_NOTE: &str = "_The response was stopped by the content filter._";
 
 #[derive(Debug, Default)]
 pub struct CreateCompletionParams {
@@ -37,18 +58,67 @@ pub struct CreateCompletionParams {
     pub messages_post: Option<Vec<Message>>,
     pub abilities: Option<Vec<Ability>>,
     pub is_self_reflection: bool,
+    /// Extra request metadata (e.g. `task_id`), sent only to providers that support it.
+    pub metadata: Option<HashMap<String, String>>,
+    /// Pre-built tools, offered to the LLM along with the abilities.
+    pub tools: Option<Vec<Tool>>,
+    /// Position of the injected `tools` and `abilities` relative to the agent's abilities.
+    pub tools_order: ToolsOrder,
+    /// Maximum time to wait for the whole completion. The request is dropped on expiry.
+    pub timeout: Option<Duration>,
+    /// Sampling temperature, provider's default if not set.
+    pub temperature: Option<f32>,
+    /// Nucleus sampling probability mass, provider's default if not set.
+    pub top_p: Option<f32>,
+    /// Maximum number of tokens to generate, provider's default if not set.
+    pub max_tokens: Option<i64>,
+    /// Sequences where the generation stops.
+    pub stop: Option<Vec<String>>,
+    /// Format of the generated content, plain text if not set.
+    pub response_format: Option<ResponseFormat>,
+    /// Whether and which tools the LLM must call. Rejected if no tools are offered.
+    pub tool_choice: Option<ToolChoice>,
+    /// Audit log for the created message.
+    pub transcript: Option<TranscriptSink>,
+    /// Whether to cancel the completion once the user stops receiving the events. Only makes
+    /// sense for the completions streamed directly to the user.
+    pub cancel_on_disconnect: bool,
+}
+
+/// Order in which the tools are offered to the LLM, which affects its tool selection.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ToolsOrder {
+    /// Injected tools and abilities go before the agent's abilities.
+    #[default]
+    InjectedFirst,
+    /// Agent's abilities go before the injected tools and abilities.
+    AgentFirst,
 }
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("Failed to get completion")]
     FailedToGetCompletion,
+    #[error("completion timed out after {0:?}")]
+    CompletionTimedOut(Duration),
+    #[error("provider `{0}` is unavailable after repeated failures, try again later")]
+    CircuitOpen(Provider),
+    #[error("completion is cancelled, since the receiver is gone")]
+    ReceiverGone,
+    #[error("chat can't be merged into itself")]
+    MergeIntoItself,
+    #[error("only direct chats can be merged, got `{0}` chat")]
+    MergeNonDirect(Kind),
+    #[error("no agent associated with chat `{0}`, add an agent to the chat to continue")]
+    NoAgent(Uuid),
+    #[error("arguments of tool call `{name}` exceed {max_len} bytes, completion is aborted")]
+    ToolCallArgumentsTooLong { name: String, max_len: usize },
 }
 
 /// Does the whole chat completion routine.
 // TODO: refactor this function.
-#[instrument(skip(pool, channel, params, model, api_key, user_agent))]
-#[allow(clippy::too_many_lines)]
+#[instrument(skip_all, fields(company_id = %cid, user_id = %uid, chat_id = %chat_id))]
+#[allow(clippy::too_many_lines, clippy::too_many_arguments)]
 pub async fn create_completion(
     pool: &Pool<Postgres>,
     channel: &Channel,
@@ -62,6 +132,8 @@ pub async fn create_completion(
 ) -> Result<()> {
     debug!("Getting chat completion");
 
+    let settings = repo::settings::get(pool, cid).await?;
+
     let mut tx = pool.begin().await.context("Failed to begin transaction")?;
 
     let mut messages = repo::messages::list(&mut *tx, cid, ListParams { chat_id }).await?;
@@ -74,15 +146,26 @@ pub async fn create_completion(
         messages = messages.into_iter().chain(messages_post).collect();
     }
 
-    trace!("Messages so far: {:?}", messages);
-
     // Get current agent.
-    let agent = repo::agents::get_for_chat(&mut *tx, cid, chat_id).await?;
+    let agent = repo::agents::get_for_chat(&mut *tx, cid, chat_id)
+        .await?
+        .ok_or(Error::NoAgent(chat_id))?;
     let agent_abilities = repo::abilities::list_for_agent(&mut *tx, cid, agent.id).await?;
-    let abilities = match params.abilities {
-        Some(abilities) => abilities.into_iter().chain(agent_abilities).collect(),
-        None => agent_abilities,
-    };
+    // Examples take up the context window as well.
+    let messages = with_examples(chat_id, messages, &agent.examples());
+
+    trace!("Messages so far: {:?}", messages);
+
+    // Leave room for the completion itself.
+    let reserved_tokens = params.max_tokens.unwrap_or(model.max_tokens);
+    let context_tokens = i64::from(model.context_length).saturating_sub(reserved_tokens);
+    let messages = fit_context_window(
+        messages,
+        TokenCounter::shared(),
+        &model.name,
+        usize::try_from(context_tokens).unwrap_or_default(),
+    )
+    .await;
 
     let req_messages = messages
         .into_iter()
@@ -109,10 +192,28 @@ pub async fn create_completion(
 
     channel.emit(uid, &Event::MessageCreated(&message)).await?;
 
-    let tools = match construct_tools(abilities).await {
+    let transcript = params.transcript;
+    if let Some(transcript) = &transcript {
+        transcript.message_created(&message);
+    }
+    let record_final_state = |message: &Message| {
+        if let Some(transcript) = &transcript {
+            transcript.message_updated(message);
+        }
+    };
+
+    let tools = match completion_tools(
+        params.tools.unwrap_or_default(),
+        params.abilities.unwrap_or_default(),
+        agent_abilities,
+        params.tools_order,
+    )
+    .await
+    {
         Ok(tools) => tools,
         Err(err) => {
             fail_message(pool, channel, uid, &mut message).await?;
+            record_final_state(&message);
 
             return Err(err);
         }
@@ -120,50 +221,130 @@ pub async fn create_completion(
 
     debug!("Tools: {:?}", tools);
 
-    // Send request to LLM
-    let client = Client::new(api_key, model.api_url_or_default(), user_agent);
+    let metadata = if model.provider.supports_request_metadata() {
+        let mut metadata = params.metadata.unwrap_or_default();
+        metadata.insert("chat_id".to_string(), chat_id.to_string());
+
+        Some(metadata)
+    } else {
+        None
+    };
+
+    // Don't hammer the provider while it's down.
+    let breaker = CircuitBreaker::for_api_url(model.api_url_or_default());
+    let Some(permit) = breaker.try_acquire() else {
+        fail_message(pool, channel, uid, &mut message).await?;
+        record_final_state(&message);
+
+        return Err(Error::CircuitOpen(model.provider.clone()).into());
+    };
 
-    create_completion_stream(
+    // Send request to LLM
+    let client = clients::chat_client(model, api_key, user_agent);
+    let stream_options = model
+        .provider
+        .requires_stream_usage_option()
+        .then_some(StreamOptions {
+            include_usage: true,
+        });
+
+    let completion = create_completion_stream(
         pool,
         channel,
         cid,
         uid,
-        req_messages,
+        CreateChatCompletionRequest {
+            model: &model.name,
+            messages: req_messages,
+            tools,
+            temperature: params.temperature,
+            top_p: params.top_p,
+            max_tokens: params.max_tokens,
+            stop: params.stop,
+            response_format: params.response_format,
+            tool_choice: params.tool_choice,
+            stream_options,
+            metadata,
+            ..Default::default()
+        },
         &mut message,
-        tools,
-        model,
         client,
-    )
-    .await?;
+        usage::extractor_for(&model.provider),
+        settings.chats.max_tool_call_arguments_len,
+        Duration::from_millis(settings.chats.streaming_heartbeat_interval_ms),
+        params.cancel_on_disconnect,
+    );
+
+    let result = match params.timeout {
+        // On expiry the completion future is dropped along with the in-flight response stream.
+        Some(timeout) => match tokio::time::timeout(timeout, completion).await {
+            Ok(result) => result,
+            Err(_) => {
+                warn!("Completion timed out after {:?}", timeout);
+
+                Err(Error::CompletionTimedOut(timeout).into())
+            }
+        },
+        None => completion.await,
+    };
 
-    if message.status == Status::Writing {
-        fail_message(pool, channel, uid, &mut message).await?;
+    let result = match result {
+        Ok(()) if message.status != Status::Writing => {
+            permit.record_success();
 
-        return Err(anyhow!("Failed to get completion").into());
-    }
+            Ok(())
+        }
+        Ok(()) => {
+            permit.record_failure();
+            fail_message(pool, channel, uid, &mut message).await?;
 
-    Ok(())
+            Err(anyhow!("Failed to get completion").into())
+        }
+        Err(err) => {
+            if is_provider_failure(&err) {
+                permit.record_failure();
+            }
+            if matches!(err, errors::Error::Chats(Error::CompletionTimedOut(_))) {
+                fail_message_with_error(pool, channel, uid, &mut message, &err).await?;
+            }
+
+            Err(err)
+        }
+    };
+
+    record_final_state(&message);
+
+    result
+}
+
+/// Whether the error means the provider is down or overloaded. Rejected requests, as well as
+/// completions aborted on our side, don't count against the provider.
+fn is_provider_failure(err: &errors::Error) -> bool {
+    match err {
+        errors::Error::Chats(Error::CompletionTimedOut(_))
+        | errors::Error::OpenAI(openai::Error::Timeout(_))
+        // Error events are what 5xx responses are once the stream has started.
+        | errors::Error::Anthropic(anthropic::Error::Stream(_)) => true,
+        errors::Error::OpenAI(openai::Error::Api(err)) => {
+            err.status == StatusCode::TOO_MANY_REQUESTS || err.status.is_server_error()
+        }
+        errors::Error::Application(err) => err.chain().any(|cause| cause.is::<reqwest::Error>()),
+        _ => false,
+    }
 }
 
 #[allow(dead_code)]
-async fn create_completion_sync<'a>(
+async fn create_completion_sync(
     pool: &Pool<Postgres>,
     channel: &Channel,
     cid: Uuid,
     uid: Uuid,
-    messages: Vec<clients::openai::Message>,
-    message: &'a mut Message,
-    tools: Option<Vec<Tool>>,
-    model: &'a Model,
-    client: Client,
+    request: CreateChatCompletionRequest<'_>,
+    message: &mut Message,
+    client: Box<dyn ChatClient>,
 ) -> Result<()> {
     let response = match client
-        .create_chat_completion(CreateChatCompletionRequest {
-            model: &model.name,
-            messages,
-            tools,
-            ..Default::default()
-        })
+        .create_chat_completion(request)
         .await
         .context("Failed to create chat completion")
     {
@@ -175,7 +356,7 @@ async fn create_completion_sync<'a>(
         }
     };
 
-    let choice = response.choices.first().context("Failed to get choice")?;
+    let choice = response.first_choice()?;
 
     if let clients::openai::Message::Assistant {
         content,
@@ -186,10 +367,7 @@ async fn create_completion_sync<'a>(
         message.content = content.clone();
         message.tool_calls = tool_calls.clone();
 
-        message.status = match message.tool_calls().is_empty() {
-            false => Status::WaitingForToolCall,
-            true => Status::Completed,
-        };
+        finalize_completion(message, Some(choice.finish_reason));
 
         if let Err(err) = repo::messages::update_with_completion_result(
             pool,
@@ -210,7 +388,7 @@ async fn create_completion_sync<'a>(
             return Err(err.into());
         };
 
-        if let Err(err) = channel.emit(uid, &Event::MessageUpdated(&message)).await {
+        if let Err(err) = channel.emit(uid, &Event::MessageUpdated(message)).await {
             warn!("Failed to emit `MessageUpdate` event: {}", err);
         }
     } else {
@@ -222,288 +400,1014 @@ async fn create_completion_sync<'a>(
     Ok(())
 }
 
-async fn create_completion_stream<'a>(
+#[allow(clippy::too_many_arguments)]
+async fn create_completion_stream(
     pool: &Pool<Postgres>,
     channel: &Channel,
     cid: Uuid,
     uid: Uuid,
-    messages: Vec<clients::openai::Message>,
-    message: &'a mut Message,
-    tools: Option<Vec<Tool>>,
-    model: &'a Model,
-    client: Client,
+    request: CreateChatCompletionRequest<'_>,
+    message: &mut Message,
+    client: Box<dyn ChatClient>,
+    usage_extractor: Box<dyn StreamUsageExtractor>,
+    max_tool_call_arguments_len: usize,
+    heartbeat_interval: Duration,
+    cancel_on_disconnect: bool,
 ) -> Result<()> {
-    let mut response = match client
-        .create_chat_completion_stream(CreateChatCompletionRequest {
-            model: &model.name,
-            messages,
-            stream: true,
-            tools,
-        })
-        .await
-        .context("Failed to create chat completion")
-    {
+    // Errors are passed as is, so that timeouts can be told apart.
+    let response = match client.create_chat_completion_stream(request).await {
         Ok(response) => response,
         Err(err) => {
-            fail_message(pool, channel, uid, message).await?;
+            warn!("Failed to create chat completion stream: {}", err);
+            fail_message_with_error(pool, channel, uid, message, &err).await?;
 
-            return Err(err.into());
+            return Err(err);
         }
     };
 
-    let mut chunk_remainder = String::new();
-
-    while let Some(chunk) = match response.chunk().await.context("Failed to get chunk") {
-        Ok(chunk) => chunk,
-        Err(err) => {
-            fail_message(pool, channel, uid, message).await?;
-
-            return Err(err.into());
-        }
-    } {
-        // TODO: come up with a more efficient way to split chunks.
-        chunk_remainder.push_str(&String::from_utf8_lossy(&chunk));
-        let chunk = chunk_remainder.clone();
-        chunk_remainder = String::new();
-        debug!("RAW chunk: {:?}", chunk);
-
-        let chunks = chunk
-            .split(CHUNK_SEPARATOR)
-            .map(str::trim)
-            .filter(|chunk| !chunk.is_empty())
-            .collect::<Vec<&str>>();
-
-        for chunk in chunks {
-            if chunk == DONE_CHUNK {
-                let mut tool_calls = message.tool_calls();
-
-                message.status = match tool_calls.is_empty() {
-                    false => Status::WaitingForToolCall,
-                    true => Status::Completed,
-                };
+    let mut stream = CompletionStream::new(response)
+        .with_usage_extractor(usage_extractor)
+        .with_read_timeout(client.timeout())
+        .with_max_tool_call_arguments_len(max_tool_call_arguments_len);
+    if let Some(translator) = client.stream_translator() {
+        stream = stream.with_translator(translator);
+    }
 
-                // Cleanup tool calls arguments due to newlines in JSON values causing issues.
-                if !tool_calls.is_empty() {
-                    for tool_call in &mut tool_calls.0 {
-                        tool_call.function.arguments =
-                            cleanup_json_string_newlines(&tool_call.function.arguments);
+    let message_id = message.id;
+    let mut heartbeat = (!heartbeat_interval.is_zero()).then(|| {
+        let mut heartbeat = tokio::time::interval_at(
+            tokio::time::Instant::now() + heartbeat_interval,
+            heartbeat_interval,
+        );
+        heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        heartbeat
+    });
+
+    loop {
+        // The pending read is kept across the heartbeats, so the read timeout is not reset.
+        let result = {
+            let next = stream.next(message);
+            tokio::pin!(next);
+
+            loop {
+                tokio::select! {
+                    result = &mut next => break result,
+                    () = tick(heartbeat.as_mut()) => {
+                        if let Err(err) = channel
+                            .emit(uid, &Event::MessageStreaming { id: message_id })
+                            .await
+                        {
+                            warn!("Failed to emit `MessageStreaming` event: {}", err);
+                        }
                     }
-
-                    message.tool_calls = Some(serde_json::json!(tool_calls));
                 }
+            }
+        };
+        if let Some(heartbeat) = heartbeat.as_mut() {
+            heartbeat.reset();
+        }
 
-                if let Err(err) = repo::messages::update_with_completion_result(
-                    pool,
-                    cid,
-                    UpdateWithCompletionResultParams {
-                        id: message.id,
-                        status: message.status,
-                        content: message.content.clone(),
-                        prompt_tokens: None,
-                        completion_tokens: None,
-                        tool_calls: message.tool_calls.clone(),
-                    },
-                )
-                .await
-                .context("Failed to update assistant message")
-                {
-                    fail_message(pool, channel, uid, message).await?;
-
-                    return Err(err.into());
-                };
-            } else {
-                match apply_completion_chunk(message, chunk) {
-                    Err(errors::Error::Messages(
-                        messages::Error::ChunkDeserialization(_)
-                        | messages::Error::NoValidChunkPrefix,
-                    )) => {
-                        // TODO: might be incomplete chunk, but might, as well, be an error. Handle this properly.
-                        debug!("Error parsing chunk, might be incomplete, pushing to remainder");
-                        chunk_remainder = chunk.to_string();
-                    }
-                    Err(err) => {
-                        fail_message(pool, channel, uid, message).await?;
+        let update = match result {
+            Ok(Some(update)) => update,
+            Ok(None) => break,
+            Err(err) => {
+                fail_message_with_error(pool, channel, uid, message, &err).await?;
 
-                        return Err(err);
-                    }
-                    _ => {}
-                };
+                return Err(err);
             }
+        };
+
+        if cancel_on_disconnect && update == StreamUpdate::Chunk && !channel.is_connected(uid).await
+        {
+            debug!("Receiver is gone, cancelling completion");
+
+            // The response is dropped on return, which stops the generation. The partial
+            // content is kept, but there is nobody to notify about it.
+            message.status = Status::Failed;
+            repo::messages::update_with_completion_result(
+                pool,
+                cid,
+                UpdateWithCompletionResultParams {
+                    id: message.id,
+                    status: message.status,
+                    content: message.content.clone(),
+                    tool_calls: message.tool_calls.clone(),
+                    ..Default::default()
+                },
+            )
+            .await
+            .context("Failed to update cancelled assistant message")?;
+
+            return Err(Error::ReceiverGone.into());
+        }
 
-            if let Err(err) = channel.emit(uid, &Event::MessageUpdated(&message)).await {
-                warn!("Failed to emit `MessageUpdate` event: {}", err);
+        if update == StreamUpdate::Done {
+            if let Err(err) = repo::messages::update_with_completion_result(
+                pool,
+                cid,
+                UpdateWithCompletionResultParams {
+                    id: message.id,
+                    status: message.status,
+                    content: message.content.clone(),
+                    prompt_tokens: message.prompt_tokens,
+                    completion_tokens: message.completion_tokens,
+                    tool_calls: message.tool_calls.clone(),
+                },
+            )
+            .await
+            .context("Failed to update assistant message")
+            {
+                fail_message(pool, channel, uid, message).await?;
+
+                return Err(err.into());
             };
         }
+
+        if let Err(err) = channel.emit(uid, &Event::MessageUpdated(message)).await {
+            warn!("Failed to emit `MessageUpdate` event: {}", err);
+        };
+
+        // The persisted final state is emitted once, anything sent after the end is ignored.
+        if update == StreamUpdate::Done {
+            break;
+        }
     }
 
     Ok(())
 }
 
-/// Constructs tools from abilities.
-///
-/// # Errors
-///
-/// Returns error if there was a problem while constructing tools.
-pub async fn construct_tools(abilities: Vec<Ability>) -> Result<Option<Vec<Tool>>> {
-    let mut tools = None;
-
-    if !abilities.is_empty() {
-        tools = Some(
-            abilities
-                .into_iter()
-                .map(|ability| {
-                    let mut function = ability.function();
-                    function.description = Some(ability.description);
-
-                    Ok(Tool {
-                        type_: "function".to_string(),
-                        function,
-                    })
-                })
-                .collect::<Result<Vec<Tool>>>()
-                .with_context(|| "Failed to construct tools")?,
-        );
+/// Waits for the next heartbeat, forever if they are disabled.
+async fn tick(heartbeat: Option<&mut Interval>) {
+    match heartbeat {
+        Some(heartbeat) => {
+            heartbeat.tick().await;
+        }
+        None => std::future::pending().await,
     }
-
-    Ok(tools)
 }
 
-async fn fail_message(
-    pool: &Pool<Postgres>,
-    channel: &Channel,
-    uid: Uuid,
-    message: &mut Message,
-) -> Result<()> {
-    repo::messages::update_status(pool, message.company_id, message.id, Status::Failed).await?;
-    message.status = Status::Failed;
-
-    channel.emit(uid, &Event::MessageUpdated(&message)).await?;
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum StreamUpdate {
+    /// A chunk was applied to the message.
+    Chunk,
+    /// The stream is complete, message status and tool calls are finalized.
+    Done,
+}
 
-    Ok(())
+/// Incrementally applies a streamed chat completion to a message.
+pub(crate) struct CompletionStream {
+    response: Response,
+    parser: EventParser,
+    events: VecDeque<String>,
+    usage_extractor: Box<dyn StreamUsageExtractor>,
+    usage: StreamUsage,
+    translator: Option<Box<dyn StreamTranslator>>,
+    read_timeout: Option<Duration>,
+    max_tool_call_arguments_len: Option<usize>,
+    finish_reason: Option<FinishReason>,
+    done: bool,
 }
 
-#[allow(clippy::too_many_lines)]
-#[instrument(skip(message))]
-fn apply_completion_chunk(message: &mut Message, chunk: &str) -> Result<()> {
-    debug!("Applying completion chunk");
-    let mut current_tool_call = None;
+impl CompletionStream {
+    pub(crate) fn new(response: Response) -> Self {
+        Self {
+            response,
+            parser: EventParser::default(),
+            events: VecDeque::new(),
+            usage_extractor: Box::new(OpenAIUsageExtractor),
+            usage: StreamUsage::default(),
+            translator: None,
+            read_timeout: None,
+            max_tool_call_arguments_len: None,
+            finish_reason: None,
+            done: false,
+        }
+    }
 
-    let tool_calls = message.tool_calls();
+    /// Fails the stream once arguments of a tool call grow over `max_len` bytes.
+    pub(crate) fn with_max_tool_call_arguments_len(mut self, max_len: usize) -> Self {
+        self.max_tool_call_arguments_len = Some(max_len);
+        self
+    }
 
-    if !tool_calls.is_empty() {
-        current_tool_call = tool_calls.0.into_iter().last();
-        trace!("Last tool call: {:?}", current_tool_call);
+    /// Fails the stream if no data is received for `timeout`.
+    pub(crate) fn with_read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
     }
 
-    let completion: Value = serde_json::from_str(
-        chunk
-            .trim()
-            .strip_prefix("data: ")
-            .ok_or(messages::Error::NoValidChunkPrefix)?,
-    )
-    .map_err(messages::Error::ChunkDeserialization)?;
+    /// Extracts token usage in the provider's format instead of the OpenAI one.
+    pub(crate) fn with_usage_extractor(mut self, extractor: Box<dyn StreamUsageExtractor>) -> Self {
+        self.usage_extractor = extractor;
+        self
+    }
 
-    if let Some(choices) = completion.get("choices") {
-        trace!("Choices: {:?}", choices);
+    /// Translates the provider's events to the OpenAI chunks before applying them.
+    pub(crate) fn with_translator(mut self, translator: Box<dyn StreamTranslator>) -> Self {
+        self.translator = Some(translator);
+        self
+    }
 
-        if let Some(delta) = choices[0].get("delta") {
-            trace!("Delta: {:?}", delta);
+    /// Applies the next completion chunk to the `message`. Returns `None` when the stream ends.
+    ///
+    /// # Errors
+    ///
+    /// Returns error if there was a problem while reading the stream or applying a chunk.
+    pub(crate) async fn next(&mut self, message: &mut Message) -> Result<Option<StreamUpdate>> {
+        loop {
+            if let Some(data) = self.events.pop_front() {
+                let Some(data) = self.translate_event(&data)? else {
+                    continue;
+                };
 
-            match delta.get("content") {
-                Some(content) if content.is_string() => {
-                    trace!("Content: {:?}", content);
+                if data == DONE_DATA {
+                    self.done = true;
+                    finalize_completion(message, self.finish_reason);
+                    message.prompt_tokens = self.usage.prompt_tokens;
+                    message.completion_tokens = self.usage.completion_tokens;
 
-                    message.content = Some(match &message.content {
-                        Some(existed) => {
-                            existed.to_owned()
-                                + content.as_str().context("Failed to get content as str")?
-                        }
-                        None => content
-                            .as_str()
-                            .context("Failed to get content as str")?
-                            .to_string(),
-                    });
+                    return Ok(Some(StreamUpdate::Done));
                 }
-                _ => {}
+
+                let completion = apply_completion_chunk(message, &data)?;
+                if let Some(usage) = self.usage_extractor.extract(&completion) {
+                    self.usage.merge(usage);
+                }
+                if let Some(finish_reason) = completion
+                    .pointer("/choices/0/finish_reason")
+                    .and_then(|reason| FinishReason::deserialize(reason).ok())
+                {
+                    self.finish_reason = Some(finish_reason);
+                }
+                self.check_tool_call_arguments_len(message)?;
+
+                return Ok(Some(StreamUpdate::Chunk));
             }
 
-            match delta.get("tool_calls") {
-                Some(tool_calls) if tool_calls.is_array() => {
-                    trace!("Tool call: {:?}", tool_calls[0]);
+            let chunk = match self.read_timeout {
+                Some(timeout) => tokio::time::timeout(timeout, self.response.chunk())
+                    .await
+                    .map_err(|_| openai::Error::Timeout(timeout))?,
+                None => self.response.chunk().await,
+            };
+            let Some(chunk) = chunk.context("Failed to get chunk")? else {
+                // The last event may be missing its terminating blank line.
+                if let Some(data) = self.parser.finish() {
+                    self.events.push_back(data);
 
-                    if current_tool_call.is_none() || tool_calls[0].get("id").is_some() {
-                        trace!("Current tool call: {:?}", current_tool_call);
-                        trace!("Get id: {:?}", tool_calls[0].get("id"));
-                        current_tool_call = Some(ToolCall {
-                            id: String::new(),
-                            type_: ToolType::Function,
-                            function: FunctionCall {
-                                name: String::new(),
-                                arguments: String::new(),
-                            },
-                        });
-                    }
+                    continue;
+                }
 
-                    if let Some(id) = tool_calls[0].get("id") {
-                        trace!("ID: {:?}", id);
+                // Some providers, e.g. Ollama, may close the stream without `[DONE]`. The
+                // completion is complete anyway, once the finish reason is received.
+                if !self.done && self.finish_reason.is_some() {
+                    debug!("Stream ended without `[DONE]`, finishing the completion");
+                    self.events.push_back(DONE_DATA.to_string());
 
-                        current_tool_call
-                            .as_mut()
-                            .context("Failed to get tool call")?
-                            .id
-                            .push_str(id.as_str().context("Failed to get id as str")?);
-                    }
+                    continue;
+                }
 
-                    if let Some(function) = tool_calls[0].get("function") {
-                        trace!("Function: {:?}", function);
+                return Ok(None);
+            };
 
-                        if let Some(name) = function.get("name") {
-                            trace!("Name: {:?}", name);
+            trace!("RAW chunk: {:?}", String::from_utf8_lossy(&chunk));
+            self.events.extend(self.parser.feed(&chunk));
+        }
+    }
 
-                            current_tool_call
-                                .as_mut()
-                                .context("Failed to get tool call")?
-                                .function
-                                .name
-                                .push_str(name.as_str().context("Failed to get name as str")?);
-                        }
+    /// Returns the OpenAI chunk data for the provider's event data, or `None` if it carries no
+    /// completion data.
+    fn translate_event(&mut self, data: &str) -> Result<Option<String>> {
+        let Some(translator) = self.translator.as_mut() else {
+            return Ok(Some(data.to_string()));
+        };
 
-                        if let Some(arguments) = function.get("arguments") {
-                            trace!("Arguments: {:?}", arguments);
+        let event: Value =
+            serde_json::from_str(data).map_err(messages::Error::ChunkDeserialization)?;
 
-                            current_tool_call
-                                .as_mut()
-                                .context("Failed to get tool call")?
-                                .function
-                                .arguments
-                                .push_str(
-                                    arguments
-                                        .as_str()
-                                        .context("Failed to get arguments as str")?,
-                                );
-                        }
-                    }
-                }
-                _ => {}
-            }
+        if let Some(usage) = self.usage_extractor.extract(&event) {
+            self.usage.merge(usage);
         }
+
+        Ok(match translator.translate(&event)? {
+            StreamEvent::Chunk(completion) => Some(completion.to_string()),
+            StreamEvent::Done => Some(DONE_DATA.to_string()),
+            StreamEvent::Skip => None,
+        })
     }
 
-    if let Some(tool_call) = current_tool_call {
-        let tool_calls = message.tool_calls();
-        let tool_calls = match tool_calls.is_empty() {
-            true => vec![tool_call],
-            false => {
-                let mut tc = tool_calls.0.into_iter().collect::<Vec<ToolCall>>();
-                if tool_call.id == tc.last().context("Last tool call is somehow None")?.id {
-                    tc.pop();
-                }
+    /// Only the last tool call is checked, as the chunks only ever extend it.
+    fn check_tool_call_arguments_len(&self, message: &Message) -> Result<()> {
+        let Some(max_len) = self.max_tool_call_arguments_len else {
+            return Ok(());
+        };
 
-                tc.push(tool_call);
+        match message.tool_calls().0.pop() {
+            Some(tool_call) if tool_call.function.arguments.len() > max_len => {
+                warn!("Tool call arguments exceed {} bytes, aborting", max_len);
 
-                tc
+                Err(Error::ToolCallArgumentsTooLong {
+                    name: tool_call.function.name,
+                    max_len,
+                }
+                .into())
             }
-        };
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Sets the final status of the completed message and cleans up its tool calls.
+///
+/// Filtered messages are flagged with a note, so that neither the user nor the agent mistakes
+/// them for complete ones.
+fn finalize_completion(message: &mut Message, finish_reason: Option<FinishReason>) {
+    let mut tool_calls = message.tool_calls();
+
+    message.status = match (finish_reason, tool_calls.is_empty()) {
+        (Some(FinishReason::ContentFilter), _) => {
+            warn!("Completion was stopped by the content filter");
+
+            message.content = Some(with_note(message.content.take(), CONTENT_FILTERED_NOTE));
+
+            Status::ContentFiltered
+        }
+        (_, false) => Status::WaitingForToolCall,
+        (_, true) => Status::Completed,
+    };
+
+    // Cleanup tool calls arguments due to newlines in JSON values causing issues.
+    if !tool_calls.is_empty() {
+        for tool_call in &mut tool_calls.0 {
+            tool_call.function.arguments =
+                cleanup_json_string_newlines(&tool_call.function.arguments);
+        }
+
+        message.tool_calls = Some(serde_json::json!(tool_calls));
+    }
+}
+
+/// Appends the note to the content, separated by a blank line.
+fn with_note(content: Option<String>, note: &str) -> String {
+    match content {
+        Some(content) if !content.trim().is_empty() => format!("{content}\n\n{note}"),
+        _ => note.to_string(),
+    }
+}
+
+/// Merges the source chat into the target one.
+///
+/// Source messages are appended after the target's last message, tasks referencing the source
+/// chat are re-pointed to the target and the source chat is deleted. The target chat keeps its
+/// model (falling back to the source's one, if it has none) and gets all the source's agents.
+///
+/// # Errors
+///
+/// Returns error if the chats are the same or any of them is not a direct chat.
+/// Returns error if there was a problem while accessing database.
+#[instrument(skip(pool, channel))]
+pub async fn merge(
+    pool: &Pool<Postgres>,
+    channel: &Channel,
+    cid: Uuid,
+    uid: Uuid,
+    source_chat_id: Uuid,
+    target_chat_id: Uuid,
+) -> Result<Chat> {
+    if source_chat_id == target_chat_id {
+        return Err(Error::MergeIntoItself.into());
+    }
+
+    let mut tx = pool.begin().await.context("Failed to begin transaction")?;
+
+    let source = repo::chats::get(&mut *tx, cid, source_chat_id).await?;
+    let target = repo::chats::get(&mut *tx, cid, target_chat_id).await?;
+
+    for chat in [&source, &target] {
+        if chat.kind != Kind::Direct {
+            return Err(Error::MergeNonDirect(chat.kind.clone()).into());
+        }
+    }
+
+    let source_messages = repo::messages::list(
+        &mut *tx,
+        cid,
+        ListParams {
+            chat_id: source_chat_id,
+        },
+    )
+    .await?;
+
+    // Messages are re-created, so they are timestamped after the target's last message.
+    let mut messages = repo::messages::create_multiple(
+        &mut *tx,
+        cid,
+        source_messages
+            .iter()
+            .map(|message| repo::messages::CreateParams {
+                chat_id: target_chat_id,
+                agent_id: message.agent_id,
+                status: message.status,
+                role: message.role,
+                content: message.content.clone(),
+                prompt_tokens: message.prompt_tokens,
+                completion_tokens: message.completion_tokens,
+                tool_calls: message.tool_calls.clone(),
+                tool_call_id: message.tool_call_id.clone(),
+                is_self_reflection: message.is_self_reflection,
+                is_internal_tool_output: message.is_internal_tool_output,
+            })
+            .collect(),
+    )
+    .await?;
+    // The returned rows may come in any order, while the timestamps follow the source messages.
+    messages.sort_by_key(|message| message.created_at);
+
+    let mut merged_messages = Vec::with_capacity(messages.len());
+    for (source_message, mut message) in source_messages.iter().zip(messages) {
+        for tag in &source_message.tags {
+            repo::messages::add_tag(&mut *tx, cid, message.id, tag).await?;
+        }
+        message.tags.clone_from(&source_message.tags);
+
+        // Messages are created unpinned.
+        if source_message.is_pinned {
+            message = repo::messages::toggle_pin(&mut *tx, cid, message.id).await?;
+        }
+
+        merged_messages.push(message);
+    }
+
+    let tasks = repo::tasks::reassign_chat(&mut *tx, cid, source_chat_id, target_chat_id).await?;
+
+    let target_agents = repo::agents_chats::list_for_chat(&mut *tx, cid, target_chat_id).await?;
+    for agent_id in repo::agents_chats::list_for_chat(&mut *tx, cid, source_chat_id).await? {
+        if !target_agents.contains(&agent_id) {
+            repo::agents_chats::create(&mut *tx, cid, agent_id, target_chat_id).await?;
+        }
+    }
+
+    if target.model_id.is_none() && source.model_id.is_some() {
+        repo::chats::update_model_id(&mut *tx, cid, target_chat_id, source.model_id).await?;
+    }
+
+    repo::agents_chats::delete_for_chat(&mut *tx, cid, source_chat_id).await?;
+    repo::messages::delete_for_chat(&mut *tx, cid, source_chat_id).await?;
+    repo::chats::delete(&mut *tx, cid, source_chat_id).await?;
+
+    let target = repo::chats::get(&mut *tx, cid, target_chat_id).await?;
+
+    tx.commit().await.context("Failed to commit transaction")?;
+
+    for message in &merged_messages {
+        channel.emit(uid, &Event::MessageCreated(message)).await?;
+    }
+    for task in &tasks {
+        channel.emit(uid, &Event::TaskUpdated(task)).await?;
+    }
+    channel.emit(uid, &Event::ChatUpdated(&target)).await?;
+    channel.emit(uid, &Event::ChatDeleted(&source)).await?;
+
+    Ok(target)
+}
+
+/// Pins or unpins the message, so that it's kept in the context regardless of its age.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while accessing database.
+#[instrument(skip(pool, channel))]
+pub async fn toggle_message_pin(
+    pool: &Pool<Postgres>,
+    channel: &Channel,
+    cid: Uuid,
+    uid: Uuid,
+    message_id: Uuid,
+) -> Result<Message> {
+    let message = repo::messages::toggle_pin(pool, cid, message_id).await?;
+
+    channel.emit(uid, &Event::MessageUpdated(&message)).await?;
+
+    Ok(message)
+}
+
+/// Drops the oldest messages which don't fit into `max_tokens`. System and pinned messages are
+/// always kept, the rest are kept from the newest one until the first that doesn't fit.
+///
+/// Tool results go along with the assistant message that called the tools, since the API
+/// rejects them apart.
+async fn fit_context_window(
+    messages: Vec<Message>,
+    counter: &TokenCounter,
+    model_name: &str,
+    max_tokens: usize,
+) -> Vec<Message> {
+    let text_len = |message: &Message| {
+        message.content.as_deref().map_or(0, str::len)
+            + message
+                .tool_calls
+                .as_ref()
+                .map_or(0, |tc| tc.to_string().len())
+    };
+
+    // Every token covers at least one byte, so short histories are not tokenized at all.
+    let upper_bound = messages
+        .iter()
+        .map(|message| text_len(message) + MESSAGE_OVERHEAD_TOKENS)
+        .sum::<usize>();
+    if upper_bound <= max_tokens {
+        return messages;
+    }
+
+    let mut groups: Vec<Vec<Message>> = Vec::new();
+    for message in messages {
+        match groups.last_mut() {
+            Some(group) if message.role == Role::Tool => group.push(message),
+            _ => groups.push(vec![message]),
+        }
+    }
+
+    let mut tokens = Vec::with_capacity(groups.len());
+    for group in &groups {
+        let mut group_tokens = 0;
+        for message in group {
+            let tool_calls = message.tool_calls.as_ref().map(Value::to_string);
+            group_tokens += MESSAGE_OVERHEAD_TOKENS
+                + counter
+                    .count(message.content.as_deref().unwrap_or_default(), model_name)
+                    .await
+                + counter
+                    .count(tool_calls.as_deref().unwrap_or_default(), model_name)
+                    .await;
+        }
+        tokens.push(group_tokens);
+    }
+
+    let is_kept_always = |group: &[Message]| {
+        group
+            .iter()
+            .any(|message| message.role == Role::System || message.is_pinned)
+    };
+
+    let mut keep = groups
+        .iter()
+        .map(|group| is_kept_always(group))
+        .collect::<Vec<_>>();
+    let mut available = max_tokens.saturating_sub(
+        tokens
+            .iter()
+            .zip(&keep)
+            .filter(|(_, keep)| **keep)
+            .map(|(tokens, _)| tokens)
+            .sum(),
+    );
+
+    for (i, group_tokens) in tokens.iter().enumerate().rev() {
+        if keep[i] {
+            continue;
+        }
+        if *group_tokens > available {
+            break;
+        }
+
+        available -= group_tokens;
+        keep[i] = true;
+    }
+
+    debug!(
+        "Dropping {} of {} message groups to fit the context",
+        keep.iter().filter(|keep| !**keep).count(),
+        groups.len()
+    );
+
+    groups
+        .into_iter()
+        .zip(keep)
+        .filter(|(_, keep)| *keep)
+        .flat_map(|(group, _)| group)
+        .collect()
+}
+
+/// Constructs tools from abilities and appends them to the pre-built `tools`.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while constructing tools.
+pub async fn construct_tools(
+    abilities: Vec<Ability>,
+    mut tools: Vec<Tool>,
+) -> Result<Option<Vec<Tool>>> {
+    if !abilities.is_empty() {
+        tools.extend(
+            abilities
+                .into_iter()
+                .map(|ability| {
+                    let mut function = ability.function();
+                    function.description = Some(ability.description);
+
+                    Ok(Tool {
+                        type_: "function".to_string(),
+                        function,
+                    })
+                })
+                .collect::<Result<Vec<Tool>>>()
+                .with_context(|| "Failed to construct tools")?,
+        );
+    }
+
+    if tools.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(tools))
+}
+
+/// Inserts few-shot examples as user/assistant message pairs right after the leading system
+/// messages, so they go before the task and the chat history.
+fn with_examples(chat_id: Uuid, mut messages: Vec<Message>, examples: &[Example]) -> Vec<Message> {
+    let position = messages
+        .iter()
+        .take_while(|message| message.role == Role::System)
+        .count();
+
+    let pairs = examples.iter().flat_map(|example| {
+        [
+            (Role::User, &example.user),
+            (Role::Assistant, &example.assistant),
+        ]
+        .map(|(role, content)| Message {
+            chat_id,
+            role,
+            content: Some(content.clone()),
+            status: Status::Completed,
+            ..Default::default()
+        })
+    });
+    messages.splice(position..position, pairs);
+
+    messages
+}
+
+/// Constructs completion tools, placing the injected tools and abilities according to `order`.
+async fn completion_tools(
+    injected_tools: Vec<Tool>,
+    injected_abilities: Vec<Ability>,
+    agent_abilities: Vec<Ability>,
+    order: ToolsOrder,
+) -> Result<Option<Vec<Tool>>> {
+    let injected = construct_tools(injected_abilities, injected_tools).await?;
+    let agent = construct_tools(agent_abilities, Vec::new()).await?;
+
+    let (first, second) = match order {
+        ToolsOrder::InjectedFirst => (injected, agent),
+        ToolsOrder::AgentFirst => (agent, injected),
+    };
+
+    construct_tools(
+        Vec::new(),
+        first
+            .into_iter()
+            .chain(second)
+            .flatten()
+            .collect::<Vec<_>>(),
+    )
+    .await
+}
+
+/// Returns up to `k` chat messages most relevant to the `query`, ordered by relevance.
+///
+/// Message embeddings are cached in the database, so only messages without a cached embedding
+/// are embedded.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while accessing database.
+/// Returns error if messages or query cannot be embedded.
+#[instrument(skip(pool, embedder, query))]
+pub async fn relevant_history<T>(
+    pool: &Pool<Postgres>,
+    embedder: &T,
+    cid: Uuid,
+    chat_id: Uuid,
+    query: &str,
+    k: usize,
+) -> Result<Vec<Message>>
+where
+    T: Embedder + ?Sized,
+{
+    let messages = repo::messages::list(pool, cid, ListParams { chat_id })
+        .await?
+        .into_iter()
+        .filter(|message| {
+            message.status == Status::Completed
+                && message
+                    .content
+                    .as_deref()
+                    .is_some_and(|content| !content.trim().is_empty())
+        })
+        .collect::<Vec<_>>();
+
+    if messages.is_empty() || k == 0 {
+        return Ok(Vec::new());
+    }
+
+    let mut embeddings =
+        repo::message_embeddings::list_for_chat(pool, cid, chat_id, embedder.model_name())
+            .await?
+            .into_iter()
+            .map(|embedding| (embedding.message_id, embedding.embedding))
+            .collect::<HashMap<_, _>>();
+
+    let missing = messages
+        .iter()
+        .filter(|message| !embeddings.contains_key(&message.id))
+        .collect::<Vec<_>>();
+
+    if !missing.is_empty() {
+        debug!("Embedding {} chat messages", missing.len());
+
+        let computed = embedder.embed_sentences(
+            missing
+                .iter()
+                .filter_map(|message| message.content.as_deref())
+                .collect(),
+        )?;
+
+        for message in missing {
+            let Some(embedding) = message
+                .content
+                .as_deref()
+                .and_then(|content| computed.get(content))
+            else {
+                continue;
+            };
+
+            repo::message_embeddings::create(
+                pool,
+                cid,
+                message.id,
+                embedder.model_name(),
+                embedding,
+            )
+            .await?;
+            embeddings.insert(message.id, embedding.clone());
+        }
+    }
+
+    let query_embedding = embedder
+        .embed_sentences(vec![query])?
+        .remove(query)
+        .context("Failed to embed query")?;
+
+    let mut scored = messages
+        .into_iter()
+        .filter_map(|message| {
+            embeddings
+                .get(&message.id)
+                .map(|embedding| (cosine_similarity(embedding, &query_embedding), message))
+        })
+        .collect::<Vec<_>>();
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    Ok(scored
+        .into_iter()
+        .take(k)
+        .map(|(_, message)| message)
+        .collect())
+}
+
+async fn fail_message(
+    pool: &Pool<Postgres>,
+    channel: &Channel,
+    uid: Uuid,
+    message: &mut Message,
+) -> Result<()> {
+    repo::messages::update_status(pool, message.company_id, message.id, Status::Failed).await?;
+    message.status = Status::Failed;
+
+    channel.emit(uid, &Event::MessageUpdated(message)).await?;
+
+    Ok(())
+}
+
+/// Fails the message. Timed out messages are flagged with a note, so that neither the user nor
+/// the agent mistakes them for complete ones.
+async fn fail_message_with_error(
+    pool: &Pool<Postgres>,
+    channel: &Channel,
+    uid: Uuid,
+    message: &mut Message,
+    err: &errors::Error,
+) -> Result<()> {
+    let timeout = match err {
+        errors::Error::Chats(Error::CompletionTimedOut(timeout))
+        | errors::Error::OpenAI(openai::Error::Timeout(timeout)) => *timeout,
+        _ => return fail_message(pool, channel, uid, message).await,
+    };
+
+    let note = format!("_The response timed out after {timeout:?}._");
+    message.content = Some(with_note(message.content.take(), &note));
+    message.status = Status::Failed;
+
+    repo::messages::update_with_completion_result(
+        pool,
+        message.company_id,
+        UpdateWithCompletionResultParams {
+            id: message.id,
+            status: message.status,
+            content: message.content.clone(),
+            tool_calls: message.tool_calls.clone(),
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    channel.emit(uid, &Event::MessageUpdated(message)).await?;
+
+    Ok(())
+}
+
+/// Incremental parser of a server-sent events stream. Bytes are buffered until a line is
+/// complete, so the stream may be split anywhere, even in the middle of a UTF-8 character.
+#[derive(Debug, Default)]
+struct EventParser {
+    buffer: Vec<u8>,
+    /// Data of the event being received.
+    data: Option<String>,
+}
+
+impl EventParser {
+    /// Feeds the received bytes, returns the data of the events completed by them.
+    fn feed(&mut self, bytes: &[u8]) -> Vec<String> {
+        self.buffer.extend_from_slice(bytes);
+
+        let mut events = Vec::new();
+        let mut consumed = 0;
+        while let Some(len) = self.buffer[consumed..].iter().position(|&b| b == b'\n') {
+            // A line feed is never a part of a multibyte character, so the line is complete.
+            let line = String::from_utf8_lossy(&self.buffer[consumed..consumed + len]);
+            if let Some(data) = Self::process_line(&mut self.data, &line) {
+                events.push(data);
+            }
+
+            consumed += len + 1;
+        }
+        self.buffer.drain(..consumed);
+
+        events
+    }
+
+    /// Returns the data of the last event, if the stream ended before its terminating blank line.
+    fn finish(&mut self) -> Option<String> {
+        let line = std::mem::take(&mut self.buffer);
+        Self::process_line(&mut self.data, &String::from_utf8_lossy(&line));
+
+        self.data.take().filter(|data| !data.is_empty())
+    }
+
+    /// Applies the line to the event being received, returns its data once a blank line ends it.
+    /// Fields other than `data` are not used, e.g. `event` repeats the `type` of the data.
+    fn process_line(data: &mut Option<String>, line: &str) -> Option<String> {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        if line.is_empty() {
+            return data.take().filter(|data| !data.is_empty());
+        }
+
+        let (field, value) = line.split_once(':').unwrap_or((line, ""));
+        if field == "data" {
+            let value = value.strip_prefix(' ').unwrap_or(value);
+            match data {
+                Some(data) => {
+                    data.push('\n');
+                    data.push_str(value);
+                }
+                None => *data = Some(value.to_string()),
+            }
+        }
+
+        None
+    }
+}
+
+#[allow(clippy::too_many_lines)]
+#[instrument(skip(message))]
+/// Applies the chunk's event data to the message, returns the parsed chunk.
+fn apply_completion_chunk(message: &mut Message, data: &str) -> Result<Value> {
+    debug!("Applying completion chunk");
+    let mut current_tool_call = None;
+
+    let tool_calls = message.tool_calls();
+
+    if !tool_calls.is_empty() {
+        current_tool_call = tool_calls.0.into_iter().last();
+        trace!("Last tool call: {:?}", current_tool_call);
+    }
+
+    let completion: Value =
+        serde_json::from_str(data).map_err(messages::Error::ChunkDeserialization)?;
+
+    if let Some(choices) = completion.get("choices") {
+        trace!("Choices: {:?}", choices);
+
+        if let Some(delta) = choices[0].get("delta") {
+            trace!("Delta: {:?}", delta);
+
+            match delta.get("content") {
+                Some(content) if content.is_string() => {
+                    trace!("Content: {:?}", content);
+
+                    message.content = Some(match &message.content {
+                        Some(existed) => {
+                            existed.to_owned()
+                                + content.as_str().context("Failed to get content as str")?
+                        }
+                        None => content
+                            .as_str()
+                            .context("Failed to get content as str")?
+                            .to_string(),
+                    });
+                }
+                _ => {}
+            }
+
+            match delta.get("tool_calls") {
+                Some(tool_calls) if tool_calls.is_array() => {
+                    trace!("Tool call: {:?}", tool_calls[0]);
+
+                    if current_tool_call.is_none() || tool_calls[0].get("id").is_some() {
+                        trace!("Current tool call: {:?}", current_tool_call);
+                        trace!("Get id: {:?}", tool_calls[0].get("id"));
+                        current_tool_call = Some(ToolCall {
+                            id: String::new(),
+                            type_: ToolType::Function,
+                            function: FunctionCall {
+                                name: String::new(),
+                                arguments: String::new(),
+                            },
+                        });
+                    }
+
+                    if let Some(id) = tool_calls[0].get("id") {
+                        trace!("ID: {:?}", id);
+
+                        current_tool_call
+                            .as_mut()
+                            .context("Failed to get tool call")?
+                            .id
+                            .push_str(id.as_str().context("Failed to get id as str")?);
+                    }
+
+                    if let Some(function) = tool_calls[0].get("function") {
+                        trace!("Function: {:?}", function);
+
+                        if let Some(name) = function.get("name") {
+                            trace!("Name: {:?}", name);
+
+                            current_tool_call
+                                .as_mut()
+                                .context("Failed to get tool call")?
+                                .function
+                                .name
+                                .push_str(name.as_str().context("Failed to get name as str")?);
+                        }
+
+                        if let Some(arguments) = function.get("arguments") {
+                            trace!("Arguments: {:?}", arguments);
+
+                            current_tool_call
+                                .as_mut()
+                                .context("Failed to get tool call")?
+                                .function
+                                .arguments
+                                .push_str(
+                                    arguments
+                                        .as_str()
+                                        .context("Failed to get arguments as str")?,
+                                );
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    if let Some(tool_call) = current_tool_call {
+        let tool_calls = message.tool_calls();
+        let tool_calls = match tool_calls.is_empty() {
+            true => vec![tool_call],
+            false => {
+                let mut tc = tool_calls.0.into_iter().collect::<Vec<ToolCall>>();
+                if tool_call.id == tc.last().context("Last tool call is somehow None")?.id {
+                    tc.pop();
+                }
+
+                tc.push(tool_call);
+
+                tc
+            }
+        };
 
         trace!("Resulting tool calls: {:?}", tool_calls);
 
@@ -515,7 +1419,7 @@ fn apply_completion_chunk(message: &mut Message, chunk: &str) -> Result<()> {
         );
     }
 
-    Ok(())
+    Ok(completion)
 }
 
 // This function is used to remove newlines from the JSON struct. It should not alter the keys or the values, only the newlines between them.
@@ -547,8 +1451,62 @@ fn cleanup_json_string_newlines(json_str: &str) -> String {
 
 #[cfg(test)]
 mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use chrono::Utc;
+
+    use crate::{channel::Emitter, embeddings::KeywordEmbedder};
+
     use super::*;
 
+    const COMPANY_ID: Uuid = Uuid::from_u128(1);
+    const CHAT_ID: Uuid = Uuid::from_u128(0x0006_0000_0000_0001);
+    const CHUNK_SEPARATOR: &str = "\n\n";
+    const DONE_CHUNK: &str = "data: [DONE]";
+
+    #[test]
+    fn test_examples_go_between_system_message_and_task() {
+        let message = |role, content: &str| Message {
+            chat_id: CHAT_ID,
+            role,
+            content: Some(content.to_string()),
+            ..Default::default()
+        };
+        let examples = [("2 + 2?", "4"), ("3 + 3?", "6")].map(|(user, assistant)| Example {
+            user: user.to_string(),
+            assistant: assistant.to_string(),
+        });
+
+        let messages = with_examples(
+            CHAT_ID,
+            vec![
+                message(Role::System, "You are a calculator"),
+                message(Role::User, "5 + 5?"),
+                message(Role::Assistant, "10"),
+            ],
+            &examples,
+        );
+
+        assert_eq!(
+            messages
+                .iter()
+                .map(|message| (message.role, message.content.as_deref().unwrap()))
+                .collect::<Vec<_>>(),
+            vec![
+                (Role::System, "You are a calculator"),
+                (Role::User, "2 + 2?"),
+                (Role::Assistant, "4"),
+                (Role::User, "3 + 3?"),
+                (Role::Assistant, "6"),
+                (Role::User, "5 + 5?"),
+                (Role::Assistant, "10"),
+            ]
+        );
+    }
+
     #[test]
     fn test_cleanup_json_string_newlines() {
         let json_str = r#"[{"id":"call_qSoLU7GYixJU7OLXKJxGdBGz","type":"function","function":{"name":"sfai_provide_text_result","arguments":"{\n\"text\": \"In Vue 3, the 'ref' keyword is used in the composition API to create \\\"reac\ntive\\\" references. While regular JavaScript variables won't be reactive inside Vue's templating system, `ref` creates a reactive and mutable object that can be used to keep track of changes in your Vue component. \n\nA ref is defined as follows:\n```javascript\nimport { ref } from 'vue'\n\nconst myVar = ref('initial value')\n```\nYou would access a ref value with `.value`:\n```javascript\nconsole.log(myVar.value)\n```\n\nOne practical example is if we wanted a button click to increment a counter:\n```javascript\nimport { ref } from 'vue'\n\nconst counter = ref(0)\n\n// In your method\nconst increment = () => {\n  counter.value += 1\n}\n\nexport default {\n  setup() {\n    return { counter , increment }\n  }\n}\n```\nIn this scenario, anytime `counter.value` is updated, Vue.js would be aware of the changes and re-render as needed. 'ref' is useful to track stateful values throughout your Vue application.\",\n\"is_done\": true\n} \n"}}]"#;
@@ -562,4 +1520,1009 @@ mod tests {
             expected
         );
     }
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(path = "../db/fixtures", scripts("companies", "models", "chats"))
+    )]
+    async fn test_relevant_history_prefers_relevant_messages(pool: Pool<Postgres>) {
+        for (role, content) in [
+            (Role::User, "What is the weather forecast for Saturday?"),
+            (Role::Assistant, "The forecast says rain in the afternoon."),
+            (Role::User, "Now write a python script to rename my photos."),
+            (Role::Assistant, "Here is the python script you asked for."),
+            (Role::User, "Thanks, what should I have for lunch?"),
+        ] {
+            repo::messages::create(
+                &pool,
+                COMPANY_ID,
+                repo::messages::CreateParams {
+                    chat_id: CHAT_ID,
+                    status: Status::Completed,
+                    role,
+                    content: Some(content.to_string()),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        }
+
+        let embedder = KeywordEmbedder::default();
+
+        let messages = relevant_history(
+            &pool,
+            &embedder,
+            COMPANY_ID,
+            CHAT_ID,
+            "Should I take an umbrella, is rain in the forecast?",
+            2,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            messages
+                .iter()
+                .map(|message| message.content.as_deref().unwrap())
+                .collect::<Vec<_>>(),
+            vec![
+                "The forecast says rain in the afternoon.",
+                "What is the weather forecast for Saturday?"
+            ]
+        );
+        // Five messages and the query.
+        assert_eq!(embedder.embedded.load(Ordering::SeqCst), 6);
+
+        relevant_history(&pool, &embedder, COMPANY_ID, CHAT_ID, "python", 1)
+            .await
+            .unwrap();
+
+        // Message embeddings are cached, only the query is embedded.
+        assert_eq!(embedder.embedded.load(Ordering::SeqCst), 7);
+    }
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(path = "../db/fixtures", scripts("companies", "models", "chats"))
+    )]
+    async fn test_fit_context_window_keeps_pinned_messages(pool: Pool<Postgres>) {
+        let channel: Channel = Box::new(crate::channel::NoopEmitter);
+        let created_at = Utc::now() - chrono::Duration::hours(1);
+        // Both instructions are of the same age, but only the first one is pinned.
+        for (age, role, content, is_pinned) in [
+            (3, Role::System, "You are a helpful assistant.", false),
+            (2, Role::User, "Always answer in French.", true),
+            (2, Role::User, "My cat is called Felix.", false),
+            (1, Role::Assistant, "Noted.", false),
+            (0, Role::User, "What is the capital of Italy?", false),
+        ] {
+            let message = repo::messages::create(
+                &pool,
+                COMPANY_ID,
+                repo::messages::CreateParams {
+                    chat_id: CHAT_ID,
+                    status: Status::Completed,
+                    role,
+                    content: Some(content.to_string()),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+            sqlx::query("UPDATE messages SET created_at = $1 WHERE id = $2")
+                .bind(created_at - chrono::Duration::minutes(age))
+                .bind(message.id)
+                .execute(&pool)
+                .await
+                .unwrap();
+
+            if is_pinned {
+                let message =
+                    toggle_message_pin(&pool, &channel, COMPANY_ID, Uuid::nil(), message.id)
+                        .await
+                        .unwrap();
+                assert!(message.is_pinned);
+            }
+        }
+
+        let messages = repo::messages::list(&pool, COMPANY_ID, ListParams { chat_id: CHAT_ID })
+            .await
+            .unwrap();
+        let contents = |messages: &[Message]| {
+            messages
+                .iter()
+                .map(|message| message.content.as_deref().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        // Fits everything but the oldest unpinned message.
+        let fitted =
+            fit_context_window(messages, &TokenCounter::default(), "llama3-70b-8192", 40).await;
+
+        assert_eq!(
+            contents(&fitted),
+            vec![
+                "You are a helpful assistant.",
+                "Always answer in French.",
+                "Noted.",
+                "What is the capital of Italy?",
+            ]
+        );
+    }
+
+    /// Applies the stream to a message, read by the given sizes.
+    fn apply_stream(stream: &[u8], read_len: usize) -> Message {
+        let mut message = Message::default();
+        let mut parser = EventParser::default();
+
+        let events = stream
+            .chunks(read_len)
+            .flat_map(|read| parser.feed(read))
+            .collect::<Vec<_>>();
+        for data in events.into_iter().chain(parser.finish()) {
+            if data != DONE_DATA {
+                apply_completion_chunk(&mut message, &data).unwrap();
+            }
+        }
+        finalize_completion(&mut message, Some(FinishReason::ToolCalls));
+
+        message
+    }
+
+    #[test]
+    fn test_stream_keeps_multibyte_chars_split_across_chunks() {
+        let stream = ["Hi 👋", " there"]
+            .iter()
+            .map(|content| {
+                format!(
+                    "data: {}{CHUNK_SEPARATOR}",
+                    serde_json::json!({ "choices": [{ "delta": { "content": content } }] })
+                )
+            })
+            .collect::<String>()
+            .into_bytes();
+
+        // Split in the middle of the 4-byte emoji.
+        let split_at = stream
+            .windows(4)
+            .position(|w| w == "👋".as_bytes())
+            .unwrap()
+            + 2;
+        let (first, second) = stream.split_at(split_at);
+
+        let mut message = Message::default();
+        let mut parser = EventParser::default();
+
+        for read in [first, second] {
+            for data in parser.feed(read) {
+                apply_completion_chunk(&mut message, &data).unwrap();
+            }
+        }
+
+        assert!(parser.buffer.is_empty());
+        assert_eq!(message.content.as_deref(), Some("Hi 👋 there"));
+    }
+
+    #[test]
+    fn test_stream_read_byte_by_byte_equals_whole_buffer() {
+        let tool_call = |tool_call: Value| serde_json::json!({ "choices": [{ "index": 0, "delta": { "tool_calls": [tool_call] } }] });
+        // CRLF line endings, a comment, an `event` field and the event without a trailing blank
+        // line, as sent by different providers.
+        let stream = [
+            ": keep-alive\r\n\r\n".to_string(),
+            format!(
+                "event: message\r\ndata: {}\r\n\r\n",
+                serde_json::json!({ "choices": [{ "index": 0, "delta": { "content": "Météo: ☀️ " } }] })
+            ),
+            format!(
+                "data: {}{CHUNK_SEPARATOR}",
+                serde_json::json!({ "choices": [{ "index": 0, "delta": { "content": "à Zürich, 東京" } }] })
+            ),
+            format!(
+                "data: {}{CHUNK_SEPARATOR}",
+                tool_call(serde_json::json!({
+                    "index": 0,
+                    "id": "call_1",
+                    "type": "function",
+                    "function": { "name": "write_file", "arguments": "{\"path\": \"" }
+                }))
+            ),
+            format!(
+                "data: {}{CHUNK_SEPARATOR}",
+                tool_call(serde_json::json!({
+                    "index": 0,
+                    "function": { "arguments": "prévisions.md\"}" }
+                }))
+            ),
+            format!("{DONE_CHUNK}\n"),
+        ]
+        .concat()
+        .into_bytes();
+
+        let whole = apply_stream(&stream, stream.len());
+        assert_eq!(whole.content.as_deref(), Some("Météo: ☀️ à Zürich, 東京"));
+        assert_eq!(
+            whole.tool_calls().0[0].function.arguments,
+            "{\"path\": \"prévisions.md\"}"
+        );
+
+        for read_len in [1, 2, 3, 7] {
+            let message = apply_stream(&stream, read_len);
+
+            assert_eq!(message.content, whole.content, "read by {read_len} bytes");
+            assert_eq!(
+                message.tool_calls, whole.tool_calls,
+                "read by {read_len} bytes"
+            );
+        }
+    }
+
+    #[test]
+    fn test_stream_joins_multiline_event_data() {
+        let mut parser = EventParser::default();
+
+        let mut events = parser.feed(b"data: {\"choices\":\ndata: []}\n");
+        assert!(events.is_empty());
+        events.extend(parser.feed(b"\ndata:[DONE]\n\n"));
+
+        assert_eq!(events, vec!["{\"choices\":\n[]}", DONE_DATA]);
+        assert_eq!(parser.finish(), None);
+    }
+
+    #[tokio::test]
+    async fn test_stalled_stream_times_out() {
+        use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        // Sends the first chunk and then hangs without closing the connection.
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let chunk = format!(
+                "data: {}{CHUNK_SEPARATOR}",
+                serde_json::json!({ "choices": [{ "index": 0, "delta": { "content": "Hi" } }] })
+            );
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\
+                 Transfer-Encoding: chunked\r\n\r\n{:x}\r\n{chunk}\r\n",
+                chunk.len()
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        });
+
+        let timeout = Duration::from_millis(100);
+        let response = reqwest::get(url).await.unwrap();
+        let mut stream = CompletionStream::new(response).with_read_timeout(timeout);
+        let mut message = Message::default();
+
+        assert_eq!(
+            stream.next(&mut message).await.unwrap(),
+            Some(StreamUpdate::Chunk)
+        );
+        assert!(matches!(
+            stream.next(&mut message).await,
+            Err(errors::Error::OpenAI(openai::Error::Timeout(t))) if t == timeout
+        ));
+        assert_eq!(message.content.as_deref(), Some("Hi"));
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_stream_aborts_on_overlong_tool_call_arguments() {
+        use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        // Streams the arguments of a single tool call endlessly, until the client hangs up.
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let chunk = |function: Value| {
+                let tool_call = serde_json::json!({ "index": 0, "function": function });
+                let chunk = format!(
+                    "data: {}{CHUNK_SEPARATOR}",
+                    serde_json::json!({ "choices": [{ "index": 0, "delta": { "tool_calls": [tool_call] } }] })
+                );
+
+                format!("{:x}\r\n{chunk}\r\n", chunk.len())
+            };
+
+            let mut response = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\
+                                Transfer-Encoding: chunked\r\n\r\n"
+                .to_string();
+            response.push_str(&chunk(
+                serde_json::json!({ "name": "write_file", "arguments": "{\"content\": \"" }),
+            ));
+            let mut data = response.into_bytes();
+            loop {
+                if socket.write_all(&data).await.is_err() {
+                    break;
+                }
+                data =
+                    chunk(serde_json::json!({ "arguments": "all work and no play " })).into_bytes();
+            }
+        });
+
+        let max_len = 1000;
+        let response = reqwest::get(url).await.unwrap();
+        let mut stream = CompletionStream::new(response).with_max_tool_call_arguments_len(max_len);
+        let mut message = Message::default();
+
+        let result = loop {
+            match stream.next(&mut message).await {
+                Ok(Some(StreamUpdate::Chunk)) => {}
+                result => break result,
+            }
+        };
+
+        assert!(matches!(
+            result,
+            Err(errors::Error::Chats(Error::ToolCallArgumentsTooLong { name, max_len: 1000 }))
+                if name == "write_file"
+        ));
+        let arguments_len = message.tool_calls().0[0].function.arguments.len();
+        assert!(arguments_len > max_len && arguments_len < max_len + 100);
+
+        drop(stream);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stream_sets_provider_usage_on_done() {
+        use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        let body = [
+            serde_json::json!({ "choices": [{ "index": 0, "delta": { "content": "Hello" } }] }),
+            serde_json::json!({
+                "choices": [{ "index": 0, "delta": {}, "finish_reason": "stop" }],
+                "x_groq": { "usage": { "prompt_tokens": 18, "completion_tokens": 3 } }
+            }),
+        ]
+        .iter()
+        .map(|chunk| format!("data: {chunk}{CHUNK_SEPARATOR}"))
+        .chain([format!("{DONE_CHUNK}{CHUNK_SEPARATOR}")])
+        .collect::<String>();
+
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(body, "text/event-stream"))
+            .mount(&server)
+            .await;
+
+        let response = reqwest::get(server.uri()).await.unwrap();
+        let mut stream = CompletionStream::new(response)
+            .with_usage_extractor(usage::extractor_for(&Provider::Groq));
+        let mut message = Message::default();
+
+        while stream.next(&mut message).await.unwrap() == Some(StreamUpdate::Chunk) {
+            assert_eq!(message.prompt_tokens, None);
+        }
+
+        assert_eq!(message.status, Status::Completed);
+        assert_eq!(message.content.as_deref(), Some("Hello"));
+        assert_eq!(message.prompt_tokens, Some(18));
+        assert_eq!(message.completion_tokens, Some(3));
+    }
+
+    #[tokio::test]
+    async fn test_stream_sets_openai_usage_from_final_chunk() {
+        use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
+
+        async fn streamed_message(chunks: &[Value]) -> Message {
+            let server = MockServer::start().await;
+            let body = chunks
+                .iter()
+                .map(|chunk| format!("data: {chunk}{CHUNK_SEPARATOR}"))
+                .chain([format!("{DONE_CHUNK}{CHUNK_SEPARATOR}")])
+                .collect::<String>();
+
+            Mock::given(method("GET"))
+                .respond_with(ResponseTemplate::new(200).set_body_raw(body, "text/event-stream"))
+                .mount(&server)
+                .await;
+
+            let response = reqwest::get(server.uri()).await.unwrap();
+            let mut stream = CompletionStream::new(response);
+            let mut message = Message::default();
+            while stream.next(&mut message).await.unwrap() == Some(StreamUpdate::Chunk) {}
+
+            message
+        }
+
+        let content = serde_json::json!({
+            "choices": [{ "index": 0, "delta": { "content": "Hello" } }],
+            "usage": null
+        });
+        let stop = serde_json::json!({
+            "choices": [{ "index": 0, "delta": {}, "finish_reason": "stop" }],
+            "usage": null
+        });
+        let usage = serde_json::json!({
+            "choices": [],
+            "usage": { "prompt_tokens": 23, "completion_tokens": 2, "total_tokens": 25 }
+        });
+
+        let message = streamed_message(&[content.clone(), stop.clone(), usage]).await;
+        assert_eq!(message.status, Status::Completed);
+        assert_eq!(message.content.as_deref(), Some("Hello"));
+        assert_eq!(message.prompt_tokens, Some(23));
+        assert_eq!(message.completion_tokens, Some(2));
+
+        // Providers ignoring `stream_options` send no usage.
+        let message = streamed_message(&[content, stop]).await;
+        assert_eq!(message.status, Status::Completed);
+        assert_eq!(message.prompt_tokens, None);
+        assert_eq!(message.completion_tokens, None);
+    }
+
+    #[tokio::test]
+    async fn test_completion_tools_order() {
+        let tool = |name: &str| Tool {
+            type_: "function".to_string(),
+            function: crate::clients::openai::Function {
+                name: name.to_string(),
+                description: None,
+                parameters: None,
+            },
+        };
+        let ability = |name: &str| Ability::for_fn(name, &serde_json::json!({ "name": name }));
+
+        let names = |order| async move {
+            completion_tools(
+                vec![tool("sfai_done")],
+                vec![ability("provide_text_result")],
+                vec![ability("search_web"), ability("read_file")],
+                order,
+            )
+            .await
+            .unwrap()
+            .unwrap()
+            .into_iter()
+            .map(|tool| tool.function.name)
+            .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            names(ToolsOrder::InjectedFirst).await,
+            vec![
+                "sfai_done",
+                "provide_text_result",
+                "search_web",
+                "read_file"
+            ]
+        );
+        assert_eq!(
+            names(ToolsOrder::AgentFirst).await,
+            vec![
+                "search_web",
+                "read_file",
+                "sfai_done",
+                "provide_text_result"
+            ]
+        );
+    }
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(
+            path = "../db/fixtures",
+            scripts("companies", "users", "agents", "models", "chats", "tasks")
+        )
+    )]
+    async fn test_merge_appends_messages_and_repoints_tasks(pool: Pool<Postgres>) {
+        const SOURCE_CHAT_ID: Uuid = Uuid::from_u128(0x0006_0000_0000_0002);
+        const TASK_ID: Uuid = Uuid::from_u128(0x0005_0000_0000_0001);
+        const RESEARCHER_ID: Uuid = Uuid::from_u128(0x0003_0000_0000_0001);
+
+        sqlx::query(
+            "INSERT INTO chats (id, company_id, model_id, title, created_at, updated_at) \
+             SELECT $1, company_id, model_id, 'Weekend plans, continued', NOW(), NOW() \
+             FROM chats WHERE id = $2",
+        )
+        .bind(SOURCE_CHAT_ID)
+        .bind(CHAT_ID)
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query("UPDATE tasks SET origin_chat_id = $1 WHERE id = $2")
+            .bind(SOURCE_CHAT_ID)
+            .bind(TASK_ID)
+            .execute(&pool)
+            .await
+            .unwrap();
+        repo::agents_chats::create(&pool, COMPANY_ID, RESEARCHER_ID, SOURCE_CHAT_ID)
+            .await
+            .unwrap();
+
+        for (chat_id, content, is_self_reflection, is_internal_tool_output) in [
+            (CHAT_ID, "Hiking on Saturday?", false, false),
+            (SOURCE_CHAT_ID, "Check the weather", true, false),
+            (CHAT_ID, "Sure!", false, false),
+            (SOURCE_CHAT_ID, "Rain is in the forecast", false, true),
+            (SOURCE_CHAT_ID, "Let's go on Sunday then", false, false),
+        ] {
+            repo::messages::create(
+                &pool,
+                COMPANY_ID,
+                repo::messages::CreateParams {
+                    chat_id,
+                    role: Role::User,
+                    status: Status::Completed,
+                    content: Some(content.to_string()),
+                    is_self_reflection,
+                    is_internal_tool_output,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        }
+
+        let channel: Channel = Box::new(crate::channel::NoopEmitter);
+        merge(
+            &pool,
+            &channel,
+            COMPANY_ID,
+            Uuid::from_u128(0x0002_0000_0000_0001),
+            SOURCE_CHAT_ID,
+            CHAT_ID,
+        )
+        .await
+        .unwrap();
+
+        let messages = repo::messages::list(&pool, COMPANY_ID, ListParams { chat_id: CHAT_ID })
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|message| {
+                (
+                    message.content.unwrap(),
+                    message.is_self_reflection,
+                    message.is_internal_tool_output,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            vec![
+                ("Hiking on Saturday?".to_string(), false, false),
+                ("Sure!".to_string(), false, false),
+                ("Check the weather".to_string(), true, false),
+                ("Rain is in the forecast".to_string(), false, true),
+                ("Let's go on Sunday then".to_string(), false, false),
+            ]
+        );
+
+        let task = repo::tasks::get(&pool, COMPANY_ID, TASK_ID).await.unwrap();
+        assert_eq!(task.origin_chat_id, Some(CHAT_ID));
+
+        assert_eq!(
+            repo::agents_chats::list_for_chat(&pool, COMPANY_ID, CHAT_ID)
+                .await
+                .unwrap(),
+            vec![RESEARCHER_ID]
+        );
+        assert!(repo::chats::get(&pool, COMPANY_ID, SOURCE_CHAT_ID)
+            .await
+            .is_err());
+    }
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(path = "../db/fixtures", scripts("companies", "models", "chats"))
+    )]
+    async fn test_merge_into_itself_fails(pool: Pool<Postgres>) {
+        let channel: Channel = Box::new(crate::channel::NoopEmitter);
+
+        let result = merge(&pool, &channel, COMPANY_ID, Uuid::nil(), CHAT_ID, CHAT_ID).await;
+
+        assert!(matches!(
+            result,
+            Err(errors::Error::Chats(Error::MergeIntoItself))
+        ));
+    }
+
+    /// Ollama-style stream which is closed without `[DONE]` and reports usage in the last chunk.
+    const OLLAMA_TEXT_STREAM: &str = "data: {\"id\":\"chatcmpl-402\",\"object\":\"chat.completion.chunk\",\"created\":1714473421,\"model\":\"llama3\",\"system_fingerprint\":\"fp_ollama\",\"choices\":[{\"index\":0,\"delta\":{\"role\":\"assistant\",\"content\":\"Hello\"},\"finish_reason\":null}]}\n\n\
+data: {\"id\":\"chatcmpl-402\",\"object\":\"chat.completion.chunk\",\"created\":1714473421,\"model\":\"llama3\",\"system_fingerprint\":\"fp_ollama\",\"choices\":[{\"index\":0,\"delta\":{\"role\":\"assistant\",\"content\":\" there!\"},\"finish_reason\":null}]}\n\n\
+data: {\"id\":\"chatcmpl-402\",\"object\":\"chat.completion.chunk\",\"created\":1714473421,\"model\":\"llama3\",\"system_fingerprint\":\"fp_ollama\",\"choices\":[{\"index\":0,\"delta\":{\"role\":\"assistant\",\"content\":\"\"},\"finish_reason\":\"stop\"}],\"usage\":{\"prompt_tokens\":26,\"completion_tokens\":3,\"total_tokens\":29}}\n\n";
+
+    /// Ollama-style stream with a tool call, which arrives in a single chunk and is followed by
+    /// `[DONE]` with no blank line.
+    const OLLAMA_TOOL_CALL_STREAM: &str = "data: {\"id\":\"chatcmpl-817\",\"object\":\"chat.completion.chunk\",\"created\":1714473502,\"model\":\"llama3.1\",\"system_fingerprint\":\"fp_ollama\",\"choices\":[{\"index\":0,\"delta\":{\"role\":\"assistant\",\"content\":\"\",\"tool_calls\":[{\"id\":\"call_x1mbp8\",\"index\":0,\"type\":\"function\",\"function\":{\"name\":\"search_web\",\"arguments\":\"{\\\"query\\\":\\\"rust\\\"}\"}}]},\"finish_reason\":\"tool_calls\"}]}\n\n\
+data: {\"id\":\"chatcmpl-817\",\"object\":\"chat.completion.chunk\",\"created\":1714473502,\"model\":\"llama3.1\",\"system_fingerprint\":\"fp_ollama\",\"choices\":[],\"usage\":{\"prompt_tokens\":88,\"completion_tokens\":17,\"total_tokens\":105}}\n\n\
+data: [DONE]\n";
+
+    #[tokio::test]
+    async fn test_stream_handles_ollama_quirks() {
+        use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
+
+        async fn streamed_message(body: &str) -> (Message, Vec<StreamUpdate>) {
+            let server = MockServer::start().await;
+            Mock::given(method("GET"))
+                .respond_with(ResponseTemplate::new(200).set_body_raw(body, "text/event-stream"))
+                .mount(&server)
+                .await;
+
+            let response = reqwest::get(server.uri()).await.unwrap();
+            let mut stream = CompletionStream::new(response)
+                .with_usage_extractor(usage::extractor_for(&Provider::Ollama));
+            let mut message = Message::default();
+            let mut updates = Vec::new();
+            while let Some(update) = stream.next(&mut message).await.unwrap() {
+                updates.push(update);
+            }
+
+            (message, updates)
+        }
+
+        let (message, updates) = streamed_message(OLLAMA_TEXT_STREAM).await;
+        assert_eq!(message.status, Status::Completed);
+        assert_eq!(message.content.as_deref(), Some("Hello there!"));
+        assert_eq!(message.prompt_tokens, Some(26));
+        assert_eq!(message.completion_tokens, Some(3));
+        assert_eq!(
+            updates
+                .iter()
+                .filter(|update| **update == StreamUpdate::Done)
+                .count(),
+            1
+        );
+
+        let (message, updates) = streamed_message(OLLAMA_TOOL_CALL_STREAM).await;
+        assert_eq!(message.status, Status::WaitingForToolCall);
+        let tool_calls = message.tool_calls().0;
+        assert_eq!(tool_calls.len(), 1);
+        assert_eq!(tool_calls[0].id, "call_x1mbp8");
+        assert_eq!(tool_calls[0].function.name, "search_web");
+        assert_eq!(tool_calls[0].function.arguments, r#"{"query":"rust"}"#);
+        assert_eq!(message.prompt_tokens, Some(88));
+        assert_eq!(message.completion_tokens, Some(17));
+        assert_eq!(updates.last(), Some(&StreamUpdate::Done));
+    }
+
+    /// Emitter which is gone after the given number of message updates.
+    struct DisconnectingEmitter {
+        updates: AtomicUsize,
+        updates_before_disconnect: usize,
+    }
+
+    #[async_trait::async_trait]
+    impl Emitter for DisconnectingEmitter {
+        async fn emit(&self, _user_id: Uuid, event: &Event) -> Result<()> {
+            if matches!(event, Event::MessageUpdated(_)) {
+                self.updates.fetch_add(1, Ordering::SeqCst);
+            }
+
+            Ok(())
+        }
+
+        async fn is_connected(&self, _user_id: Uuid) -> bool {
+            self.updates.load(Ordering::SeqCst) < self.updates_before_disconnect
+        }
+    }
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(
+            path = "../db/fixtures",
+            scripts("companies", "users", "agents", "models", "chats")
+        )
+    )]
+    async fn test_completion_is_cancelled_when_receiver_is_gone(pool: Pool<Postgres>) {
+        use wiremock::{
+            matchers::{method, path},
+            Mock, MockServer, ResponseTemplate,
+        };
+
+        let server = MockServer::start().await;
+        let body = ["a", "b", "c", "d", "e"]
+            .iter()
+            .map(|content| {
+                format!(
+                    "data: {}{CHUNK_SEPARATOR}",
+                    serde_json::json!({ "choices": [{ "index": 0, "delta": { "content": content } }] })
+                )
+            })
+            .chain([format!("{DONE_CHUNK}{CHUNK_SEPARATOR}")])
+            .collect::<String>();
+
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(body, "text/event-stream"))
+            .mount(&server)
+            .await;
+
+        repo::agents_chats::create(
+            &pool,
+            COMPANY_ID,
+            Uuid::from_u128(0x0003_0000_0000_0001),
+            CHAT_ID,
+        )
+        .await
+        .unwrap();
+        let model = repo::models::get(&pool, COMPANY_ID, Uuid::from_u128(0x0004_0000_0000_0001))
+            .await
+            .unwrap();
+        let model = Model {
+            api_url: Some(format!("{}/", server.uri())),
+            ..model
+        };
+        let complete = |cancel_on_disconnect: bool| {
+            let pool = pool.clone();
+            let model = model.clone();
+
+            async move {
+                let channel: Channel = Box::new(DisconnectingEmitter {
+                    updates: AtomicUsize::new(0),
+                    updates_before_disconnect: 2,
+                });
+
+                create_completion(
+                    &pool,
+                    &channel,
+                    COMPANY_ID,
+                    Uuid::from_u128(0x0002_0000_0000_0001),
+                    CHAT_ID,
+                    CreateCompletionParams {
+                        cancel_on_disconnect,
+                        ..Default::default()
+                    },
+                    &model,
+                    "sk-test",
+                    "bridge-test",
+                )
+                .await
+            }
+        };
+
+        let result = complete(true).await;
+
+        assert!(matches!(
+            result,
+            Err(errors::Error::Chats(Error::ReceiverGone))
+        ));
+
+        let messages = repo::messages::list(&pool, COMPANY_ID, ListParams { chat_id: CHAT_ID })
+            .await
+            .unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].status, Status::Failed);
+        // Streaming stopped right after the first chunk nobody received.
+        assert_eq!(messages[0].content.as_deref(), Some("abc"));
+
+        // Completions not streamed to the user, e.g. the task executor's ones, run to the end.
+        complete(false).await.unwrap();
+
+        let messages = repo::messages::list(&pool, COMPANY_ID, ListParams { chat_id: CHAT_ID })
+            .await
+            .unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1].status, Status::Completed);
+        assert_eq!(messages[1].content.as_deref(), Some("abcde"));
+    }
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(
+            path = "../db/fixtures",
+            scripts("companies", "users", "agents", "models", "chats")
+        )
+    )]
+    async fn test_circuit_opens_on_provider_failures_only(pool: Pool<Postgres>) {
+        use wiremock::{
+            matchers::{method, path},
+            Mock, MockServer, ResponseTemplate,
+        };
+
+        use crate::clients::circuit_breaker::FAILURE_THRESHOLD;
+
+        let server = MockServer::start().await;
+        // Rejected requests come first, then the provider goes down.
+        Mock::given(method("POST"))
+            .and(path("/circuit/chat/completions"))
+            .respond_with(ResponseTemplate::new(400))
+            .up_to_n_times(u64::from(FAILURE_THRESHOLD))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/circuit/chat/completions"))
+            .respond_with(ResponseTemplate::new(503).insert_header("Retry-After", "0"))
+            .mount(&server)
+            .await;
+
+        repo::agents_chats::create(
+            &pool,
+            COMPANY_ID,
+            Uuid::from_u128(0x0003_0000_0000_0001),
+            CHAT_ID,
+        )
+        .await
+        .unwrap();
+        let model = repo::models::get(&pool, COMPANY_ID, Uuid::from_u128(0x0004_0000_0000_0001))
+            .await
+            .unwrap();
+        let model = Model {
+            // Mock servers are reused by the tests, so the breaker gets a URL of its own.
+            api_url: Some(format!("{}/circuit/", server.uri())),
+            ..model
+        };
+        let channel: Channel = Box::<RecordingEmitter>::default();
+        let complete = || {
+            create_completion(
+                &pool,
+                &channel,
+                COMPANY_ID,
+                Uuid::from_u128(0x0002_0000_0000_0001),
+                CHAT_ID,
+                CreateCompletionParams::default(),
+                &model,
+                "sk-test",
+                "bridge-test",
+            )
+        };
+
+        for status in [StatusCode::BAD_REQUEST, StatusCode::SERVICE_UNAVAILABLE] {
+            for _ in 0..FAILURE_THRESHOLD {
+                match complete().await {
+                    Err(errors::Error::OpenAI(openai::Error::Api(err))) => {
+                        assert_eq!(err.status, status);
+                    }
+                    result => panic!("unexpected result: {result:?}"),
+                }
+            }
+        }
+
+        let requests = server.received_requests().await.unwrap().len();
+        assert!(matches!(
+            complete().await,
+            Err(errors::Error::Chats(Error::CircuitOpen(_)))
+        ));
+        // Failed fast, without reaching the provider.
+        assert_eq!(server.received_requests().await.unwrap().len(), requests);
+    }
+
+    /// Emitter which records the streaming events as `streaming` and `updated:<status>`.
+    #[derive(Default)]
+    struct RecordingEmitter {
+        events: Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl Emitter for RecordingEmitter {
+        async fn emit(&self, _user_id: Uuid, event: &Event) -> Result<()> {
+            let event = match event {
+                Event::MessageStreaming { .. } => "streaming".to_string(),
+                Event::MessageUpdated(message) => format!("updated:{:?}", message.status),
+                _ => return Ok(()),
+            };
+            self.events.lock().unwrap().push(event);
+
+            Ok(())
+        }
+    }
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(
+            path = "../db/fixtures",
+            scripts("companies", "users", "agents", "models", "chats")
+        )
+    )]
+    async fn test_heartbeats_fire_during_stream_gap(pool: Pool<Postgres>) {
+        use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let api_url = format!("http://{}/", listener.local_addr().unwrap());
+
+        // Streams the first chunk, pauses, and then finishes the completion.
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let chunk = |data: String| {
+                let chunk = format!("{data}{CHUNK_SEPARATOR}");
+                format!("{:x}\r\n{chunk}\r\n", chunk.len())
+            };
+
+            socket
+                .write_all(
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\
+                         Transfer-Encoding: chunked\r\n\r\n{}",
+                        chunk(format!(
+                            "data: {}",
+                            serde_json::json!({ "choices": [{ "index": 0, "delta": { "content": "Hel" } }] })
+                        ))
+                    )
+                    .as_bytes(),
+                )
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            let rest = [
+                chunk(format!(
+                    "data: {}",
+                    serde_json::json!({ "choices": [{ "index": 0, "delta": { "content": "lo" }, "finish_reason": "stop" }] })
+                )),
+                chunk(format!(
+                    "data: {}",
+                    serde_json::json!({ "choices": [], "usage": { "prompt_tokens": 9, "completion_tokens": 2 } })
+                )),
+                chunk(DONE_CHUNK.to_string()),
+                "0\r\n\r\n".to_string(),
+            ]
+            .concat();
+            socket.write_all(rest.as_bytes()).await.unwrap();
+        });
+
+        repo::settings::insert(
+            &pool,
+            COMPANY_ID,
+            &crate::settings::Settings {
+                chats: crate::settings::Chats {
+                    streaming_heartbeat_interval_ms: 50,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        repo::agents_chats::create(
+            &pool,
+            COMPANY_ID,
+            Uuid::from_u128(0x0003_0000_0000_0001),
+            CHAT_ID,
+        )
+        .await
+        .unwrap();
+        let model = repo::models::get(&pool, COMPANY_ID, Uuid::from_u128(0x0004_0000_0000_0001))
+            .await
+            .unwrap();
+        let model = Model {
+            api_url: Some(api_url),
+            ..model
+        };
+        let emitter = RecordingEmitter::default();
+        let events = emitter.events.clone();
+        let channel: Channel = Box::new(emitter);
+
+        create_completion(
+            &pool,
+            &channel,
+            COMPANY_ID,
+            Uuid::from_u128(0x0002_0000_0000_0001),
+            CHAT_ID,
+            CreateCompletionParams::default(),
+            &model,
+            "sk-test",
+            "bridge-test",
+        )
+        .await
+        .unwrap();
+        server.await.unwrap();
+
+        let events = events.lock().unwrap().clone();
+        let heartbeats = events.iter().filter(|event| *event == "streaming").count();
+        assert!(heartbeats >= 3, "expected heartbeats, got {events:?}");
+        assert_eq!(events.first().map(String::as_str), Some("updated:Writing"));
+        assert_eq!(
+            events
+                .iter()
+                .filter(|event| *event == "updated:Completed")
+                .count(),
+            1
+        );
+        assert_eq!(events.last().map(String::as_str), Some("updated:Completed"));
+
+        let messages = repo::messages::list(&pool, COMPANY_ID, ListParams { chat_id: CHAT_ID })
+            .await
+            .unwrap();
+        assert_eq!(messages[0].status, Status::Completed);
+        assert_eq!(messages[0].content.as_deref(), Some("Hello"));
+        assert_eq!(messages[0].prompt_tokens, Some(9));
+        assert_eq!(messages[0].completion_tokens, Some(2));
+    }
 }
@@ -0,0 +1,721 @@
+// Copyright 2024 StarfleetAI
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{collections::HashMap, time::Duration};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use reqwest::Response;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    clients::{
+        openai::{
+            self, ChatCompletion, Choice, Content, ContentPart, CreateChatCompletionRequest,
+            FinishReason, FunctionCall, Message, ToolCall, ToolCalls, ToolChoice, ToolType, Usage,
+        },
+        ChatClient, StreamEvent, StreamTranslator,
+    },
+    types::Result,
+};
+
+const API_VERSION: &str = "2023-06-01";
+/// The limit is required by the API, unlike the OpenAI one.
+const DEFAULT_MAX_TOKENS: i64 = 4096;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("image URL `{0}` is not supported, only base64 data URIs are")]
+    UnsupportedImageUrl(String),
+    #[error("arguments of the tool call `{0}` are not a valid JSON: {1}")]
+    InvalidToolCallArguments(String, serde_json::Error),
+    #[error("messages API stream failed: {0}")]
+    Stream(String),
+}
+
+/// Client of the Anthropic messages API, taking and returning the OpenAI chat completions.
+pub struct Client {
+    /// Shares the transport, retries and timeouts with the OpenAI client.
+    http: openai::Client,
+}
+
+impl Client {
+    #[must_use]
+    pub fn new(api_key: &str, api_url: &str, user_agent: &str) -> Self {
+        Self {
+            http: openai::Client::new(api_key, api_url, user_agent)
+                .with_api_key_header("x-api-key")
+                .with_header("anthropic-version", API_VERSION),
+        }
+    }
+
+    /// Sets the request timeout, see [`openai::Client::with_timeout`].
+    #[must_use]
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.http = self.http.with_timeout(timeout);
+        self
+    }
+
+    /// Creates a chat completion.
+    ///
+    /// # Errors
+    ///
+    /// Returns error if the request can't be translated to the messages API one.
+    /// Returns error if there was a problem while making the API call.
+    pub async fn create_chat_completion(
+        &self,
+        request: CreateChatCompletionRequest<'_>,
+    ) -> Result<ChatCompletion> {
+        request.validate()?;
+
+        let response: MessagesResponse = self
+            .http
+            .post("messages", MessagesRequest::new(&request)?)
+            .await?;
+
+        Ok(response.into())
+    }
+
+    /// Creates a streaming chat completion. Events are translated by [`MessagesStreamTranslator`].
+    ///
+    /// # Errors
+    ///
+    /// Returns error if the request can't be translated to the messages API one.
+    /// Returns error if there was a problem while making the API call.
+    pub async fn create_chat_completion_stream(
+        &self,
+        request: CreateChatCompletionRequest<'_>,
+    ) -> Result<Response> {
+        request.validate()?;
+
+        let mut body = MessagesRequest::new(&request)?;
+        body.stream = true;
+
+        self.http.post_stream("messages", body).await
+    }
+}
+
+#[async_trait]
+impl ChatClient for Client {
+    async fn create_chat_completion(
+        &self,
+        request: CreateChatCompletionRequest<'_>,
+    ) -> Result<ChatCompletion> {
+        Client::create_chat_completion(self, request).await
+    }
+
+    async fn create_chat_completion_stream(
+        &self,
+        request: CreateChatCompletionRequest<'_>,
+    ) -> Result<Response> {
+        Client::create_chat_completion_stream(self, request).await
+    }
+
+    fn timeout(&self) -> Duration {
+        self.http.timeout()
+    }
+
+    fn stream_translator(&self) -> Option<Box<dyn StreamTranslator>> {
+        Some(Box::<MessagesStreamTranslator>::default())
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct MessagesRequest<'a> {
+    model: &'a str,
+    max_tokens: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<String>,
+    messages: Vec<MessageParam>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<ToolParam<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_choice: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop_sequences: Option<&'a [String]>,
+    stream: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct MessageParam {
+    role: &'static str,
+    content: Vec<ContentBlock>,
+}
+
+#[derive(Debug, Serialize)]
+struct ToolParam<'a> {
+    name: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<&'a str>,
+    input_schema: Value,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentBlock {
+    Text {
+        text: String,
+    },
+    Image {
+        source: ImageSource,
+    },
+    ToolUse {
+        id: String,
+        name: String,
+        input: Value,
+    },
+    ToolResult {
+        tool_use_id: String,
+        content: String,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+struct ImageSource {
+    #[serde(rename = "type")]
+    type_: String,
+    media_type: String,
+    data: String,
+}
+
+impl<'a> MessagesRequest<'a> {
+    /// System messages are moved to the top-level `system` prompt, tool outputs are sent as
+    /// `tool_result` blocks of the user messages.
+    fn new(request: &'a CreateChatCompletionRequest<'a>) -> Result<Self> {
+        let mut system = Vec::new();
+        let mut messages: Vec<MessageParam> = Vec::new();
+
+        for message in &request.messages {
+            let (role, blocks) = match message {
+                Message::System { content, .. } => {
+                    system.push(content.as_str());
+                    continue;
+                }
+                Message::User { content, .. } => ("user", user_blocks(content)?),
+                Message::Assistant { content, .. } => (
+                    "assistant",
+                    assistant_blocks(content.as_deref(), &message.tool_calls())?,
+                ),
+                Message::Tool {
+                    content,
+                    tool_call_id,
+                } => (
+                    "user",
+                    vec![ContentBlock::ToolResult {
+                        tool_use_id: tool_call_id.clone(),
+                        content: content.clone(),
+                    }],
+                ),
+            };
+
+            if blocks.is_empty() {
+                continue;
+            }
+
+            // Roles must alternate, so the consecutive messages of the same role are merged,
+            // e.g. the outputs of the parallel tool calls.
+            match messages.last_mut() {
+                Some(last) if last.role == role => last.content.extend(blocks),
+                _ => messages.push(MessageParam {
+                    role,
+                    content: blocks,
+                }),
+            }
+        }
+
+        let mut tools = request
+            .tools
+            .iter()
+            .flatten()
+            .map(|tool| ToolParam {
+                name: &tool.function.name,
+                description: tool.function.description.as_deref(),
+                input_schema: tool.function.parameters.as_ref().map_or_else(
+                    || json!({ "type": "object", "properties": {} }),
+                    |parameters| json!(parameters),
+                ),
+            })
+            .collect::<Vec<_>>();
+
+        let tool_choice = match &request.tool_choice {
+            None => None,
+            Some(ToolChoice::Auto) => Some(json!({ "type": "auto" })),
+            Some(ToolChoice::Required) => Some(json!({ "type": "any" })),
+            Some(ToolChoice::Function { name }) => Some(json!({ "type": "tool", "name": name })),
+            // Not supported by the API, so the tools are not offered instead.
+            Some(ToolChoice::None) => {
+                tools.clear();
+                None
+            }
+        };
+
+        Ok(Self {
+            model: request.model,
+            max_tokens: request.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+            system: (!system.is_empty()).then(|| system.join("\n\n")),
+            messages,
+            tools,
+            tool_choice,
+            temperature: request.temperature,
+            top_p: request.top_p,
+            stop_sequences: request.stop.as_deref(),
+            stream: false,
+        })
+    }
+}
+
+fn user_blocks(content: &Content) -> Result<Vec<ContentBlock>> {
+    match content {
+        Content::Text(text) => Ok(vec![ContentBlock::Text { text: text.clone() }]),
+        Content::Parts(parts) => parts
+            .iter()
+            .map(|part| match part {
+                ContentPart::Text { text } => Ok(ContentBlock::Text { text: text.clone() }),
+                ContentPart::ImageUrl { image_url } => image_block(&image_url.url),
+            })
+            .collect(),
+    }
+}
+
+fn image_block(url: &str) -> Result<ContentBlock> {
+    let (media_type, data) = url
+        .strip_prefix("data:")
+        .and_then(|data_uri| data_uri.split_once(";base64,"))
+        .ok_or_else(|| Error::UnsupportedImageUrl(url.to_string()))?;
+
+    Ok(ContentBlock::Image {
+        source: ImageSource {
+            type_: "base64".to_string(),
+            media_type: media_type.to_string(),
+            data: data.to_string(),
+        },
+    })
+}
+
+fn assistant_blocks(content: Option<&str>, tool_calls: &ToolCalls) -> Result<Vec<ContentBlock>> {
+    let text = content
+        .filter(|content| !content.trim().is_empty())
+        .map(|content| ContentBlock::Text {
+            text: content.to_string(),
+        });
+
+    let tool_uses = tool_calls.iter().map(|tool_call| {
+        let arguments = tool_call.function.arguments.trim();
+        let input = if arguments.is_empty() {
+            json!({})
+        } else {
+            serde_json::from_str(arguments).map_err(|err| {
+                Error::InvalidToolCallArguments(tool_call.function.name.clone(), err)
+            })?
+        };
+
+        Ok(ContentBlock::ToolUse {
+            id: tool_call.id.clone(),
+            name: tool_call.function.name.clone(),
+            input,
+        })
+    });
+
+    text.map(Ok).into_iter().chain(tool_uses).collect()
+}
+
+fn finish_reason(stop_reason: Option<&str>) -> FinishReason {
+    match stop_reason {
+        Some("end_turn" | "stop_sequence") => FinishReason::Stop,
+        Some("max_tokens") => FinishReason::Length,
+        Some("tool_use") => FinishReason::ToolCalls,
+        _ => FinishReason::Other,
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct MessagesResponse {
+    id: String,
+    model: String,
+    content: Vec<ContentBlock>,
+    stop_reason: Option<String>,
+    usage: MessagesUsage,
+}
+
+#[derive(Debug, Deserialize)]
+struct MessagesUsage {
+    input_tokens: u32,
+    output_tokens: u32,
+}
+
+impl From<MessagesResponse> for ChatCompletion {
+    fn from(response: MessagesResponse) -> Self {
+        let mut text = Vec::new();
+        let mut tool_calls = Vec::new();
+
+        for block in response.content {
+            match block {
+                ContentBlock::Text { text: block_text } => text.push(block_text),
+                ContentBlock::ToolUse { id, name, input } => tool_calls.push(ToolCall {
+                    id,
+                    type_: ToolType::Function,
+                    function: FunctionCall {
+                        name,
+                        arguments: input.to_string(),
+                    },
+                }),
+                ContentBlock::Image { .. } | ContentBlock::ToolResult { .. } => {}
+            }
+        }
+
+        Self {
+            created: u32::try_from(Utc::now().timestamp()).unwrap_or_default(),
+            id: response.id,
+            model: response.model,
+            object: "chat.completion".to_string(),
+            choices: vec![Choice {
+                finish_reason: finish_reason(response.stop_reason.as_deref()),
+                index: 0,
+                message: Message::Assistant {
+                    content: (!text.is_empty()).then(|| text.concat()),
+                    name: None,
+                    tool_calls: (!tool_calls.is_empty()).then(|| json!(tool_calls)),
+                },
+                logprobs: None,
+            }],
+            usage: Usage {
+                completion_tokens: response.usage.output_tokens,
+                prompt_tokens: response.usage.input_tokens,
+                total_tokens: response.usage.input_tokens + response.usage.output_tokens,
+            },
+        }
+    }
+}
+
+/// Translates the messages API stream events. Usage is reported by
+/// [`crate::clients::usage::AnthropicUsageExtractor`].
+#[derive(Debug, Default)]
+pub struct MessagesStreamTranslator {
+    /// Tool call indexes by the content block indexes, which count the text blocks as well.
+    tool_call_indexes: HashMap<u64, usize>,
+}
+
+impl StreamTranslator for MessagesStreamTranslator {
+    fn translate(&mut self, event: &Value) -> Result<StreamEvent> {
+        let str_at = |pointer| event.pointer(pointer).and_then(Value::as_str);
+        let block_index = event
+            .get("index")
+            .and_then(Value::as_u64)
+            .unwrap_or_default();
+        let delta = |delta: Value| {
+            StreamEvent::Chunk(json!({ "choices": [{ "index": 0, "delta": delta }] }))
+        };
+
+        Ok(match str_at("/type") {
+            Some("content_block_start") => match str_at("/content_block/type") {
+                Some("tool_use") => {
+                    let index = self.tool_call_indexes.len();
+                    self.tool_call_indexes.insert(block_index, index);
+
+                    delta(json!({
+                        "tool_calls": [{
+                            "index": index,
+                            "id": str_at("/content_block/id"),
+                            "type": "function",
+                            "function": { "name": str_at("/content_block/name"), "arguments": "" }
+                        }]
+                    }))
+                }
+                Some("text") => match str_at("/content_block/text") {
+                    Some(text) if !text.is_empty() => delta(json!({ "content": text })),
+                    _ => StreamEvent::Skip,
+                },
+                _ => StreamEvent::Skip,
+            },
+            Some("content_block_delta") => match str_at("/delta/type") {
+                Some("text_delta") => delta(json!({ "content": str_at("/delta/text") })),
+                Some("input_json_delta") => delta(json!({
+                    "tool_calls": [{
+                        "index": self.tool_call_indexes.get(&block_index),
+                        "function": { "arguments": str_at("/delta/partial_json") }
+                    }]
+                })),
+                _ => StreamEvent::Skip,
+            },
+            Some("message_delta") => match str_at("/delta/stop_reason") {
+                Some(stop_reason) => StreamEvent::Chunk(json!({
+                    "choices": [{
+                        "index": 0,
+                        "delta": {},
+                        "finish_reason": finish_reason(Some(stop_reason))
+                    }]
+                })),
+                None => StreamEvent::Skip,
+            },
+            Some("message_stop") => StreamEvent::Done,
+            Some("error") => {
+                return Err(Error::Stream(
+                    str_at("/error/message")
+                        .unwrap_or("unknown error")
+                        .to_string(),
+                )
+                .into())
+            }
+            _ => StreamEvent::Skip,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::{
+        matchers::{body_partial_json, header, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use crate::{
+        chats::{CompletionStream, StreamUpdate},
+        clients::{openai::Function, usage},
+        types::{
+            messages::{self, Status},
+            models::Provider,
+        },
+    };
+
+    use super::*;
+
+    fn tool_call(id: &str, name: &str, arguments: &str) -> ToolCall {
+        ToolCall {
+            id: id.to_string(),
+            type_: ToolType::Function,
+            function: FunctionCall {
+                name: name.to_string(),
+                arguments: arguments.to_string(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_request_translation() {
+        let request = CreateChatCompletionRequest {
+            model: "claude-3-opus-20240229",
+            messages: vec![
+                Message::System {
+                    content: "You are a researcher.".to_string(),
+                    name: None,
+                },
+                Message::User {
+                    content: "Read both files.".into(),
+                    name: None,
+                },
+                Message::Assistant {
+                    content: Some("Reading them.".to_string()),
+                    name: None,
+                    tool_calls: Some(json!([
+                        tool_call("toolu_01", "read_file", r#"{"path":"a.txt"}"#),
+                        tool_call("toolu_02", "read_file", r#"{"path":"b.txt"}"#),
+                    ])),
+                },
+                Message::Tool {
+                    content: "A".to_string(),
+                    tool_call_id: "toolu_01".to_string(),
+                },
+                Message::Tool {
+                    content: "B".to_string(),
+                    tool_call_id: "toolu_02".to_string(),
+                },
+                Message::User {
+                    content: "Now summarize.".into(),
+                    name: None,
+                },
+            ],
+            tools: Some(vec![openai::Tool {
+                type_: "function".to_string(),
+                function: Function {
+                    name: "read_file".to_string(),
+                    description: Some("Reads a file".to_string()),
+                    parameters: None,
+                },
+            }]),
+            tool_choice: Some(ToolChoice::Required),
+            ..Default::default()
+        };
+
+        let json = serde_json::to_value(MessagesRequest::new(&request).unwrap()).unwrap();
+
+        assert_eq!(
+            json,
+            json!({
+                "model": "claude-3-opus-20240229",
+                "max_tokens": DEFAULT_MAX_TOKENS,
+                "system": "You are a researcher.",
+                "messages": [
+                    { "role": "user", "content": [{ "type": "text", "text": "Read both files." }] },
+                    {
+                        "role": "assistant",
+                        "content": [
+                            { "type": "text", "text": "Reading them." },
+                            { "type": "tool_use", "id": "toolu_01", "name": "read_file", "input": { "path": "a.txt" } },
+                            { "type": "tool_use", "id": "toolu_02", "name": "read_file", "input": { "path": "b.txt" } }
+                        ]
+                    },
+                    {
+                        "role": "user",
+                        "content": [
+                            { "type": "tool_result", "tool_use_id": "toolu_01", "content": "A" },
+                            { "type": "tool_result", "tool_use_id": "toolu_02", "content": "B" },
+                            { "type": "text", "text": "Now summarize." }
+                        ]
+                    }
+                ],
+                "tools": [{
+                    "name": "read_file",
+                    "description": "Reads a file",
+                    "input_schema": { "type": "object", "properties": {} }
+                }],
+                "tool_choice": { "type": "any" },
+                "stream": false
+            })
+        );
+    }
+
+    #[test]
+    fn test_request_translation_rejects_remote_images() {
+        let request = CreateChatCompletionRequest {
+            model: "claude-3-opus-20240229",
+            messages: vec![Message::User {
+                content: Content::Parts(vec![ContentPart::ImageUrl {
+                    image_url: openai::ImageUrl {
+                        url: "https://example.com/cat.png".to_string(),
+                        detail: None,
+                    },
+                }]),
+                name: None,
+            }],
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            MessagesRequest::new(&request),
+            Err(crate::errors::Error::Anthropic(Error::UnsupportedImageUrl(
+                _
+            )))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_create_chat_completion() {
+        let server = MockServer::start().await;
+
+        Mock::given(method("POST"))
+            .and(path("/messages"))
+            .and(header("x-api-key", "test-key"))
+            .and(header("anthropic-version", API_VERSION))
+            .and(body_partial_json(json!({ "system": "Be brief." })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "id": "msg_01",
+                "type": "message",
+                "role": "assistant",
+                "model": "claude-3-haiku-20240307",
+                "content": [
+                    { "type": "text", "text": "Searching." },
+                    { "type": "tool_use", "id": "toolu_01", "name": "search_web", "input": { "query": "rust" } }
+                ],
+                "stop_reason": "tool_use",
+                "usage": { "input_tokens": 12, "output_tokens": 7 }
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = Client::new("test-key", &format!("{}/", server.uri()), "bridge-test");
+        let completion = client
+            .create_chat_completion(CreateChatCompletionRequest {
+                model: "claude-3-haiku-20240307",
+                messages: vec![
+                    Message::System {
+                        content: "Be brief.".to_string(),
+                        name: None,
+                    },
+                    Message::User {
+                        content: "Search for rust.".into(),
+                        name: None,
+                    },
+                ],
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        let choice = completion.first_choice().unwrap();
+        assert_eq!(choice.finish_reason, FinishReason::ToolCalls);
+        assert_eq!(
+            json!(choice.message.tool_calls().0),
+            json!([tool_call("toolu_01", "search_web", r#"{"query":"rust"}"#)])
+        );
+        assert_eq!(completion.usage.total_tokens, 19);
+    }
+
+    #[tokio::test]
+    async fn test_stream_translation() {
+        let server = MockServer::start().await;
+        let events = [
+            json!({ "type": "message_start", "message": { "usage": { "input_tokens": 25, "output_tokens": 1 } } }),
+            json!({ "type": "content_block_start", "index": 0, "content_block": { "type": "text", "text": "" } }),
+            json!({ "type": "ping" }),
+            json!({ "type": "content_block_delta", "index": 0, "delta": { "type": "text_delta", "text": "Let me " } }),
+            json!({ "type": "content_block_delta", "index": 0, "delta": { "type": "text_delta", "text": "search." } }),
+            json!({ "type": "content_block_stop", "index": 0 }),
+            json!({ "type": "content_block_start", "index": 1, "content_block": { "type": "tool_use", "id": "toolu_01", "name": "search_web", "input": {} } }),
+            json!({ "type": "content_block_delta", "index": 1, "delta": { "type": "input_json_delta", "partial_json": "{\"query\": " } }),
+            json!({ "type": "content_block_delta", "index": 1, "delta": { "type": "input_json_delta", "partial_json": "\"rust\"}" } }),
+            json!({ "type": "content_block_stop", "index": 1 }),
+            json!({ "type": "message_delta", "delta": { "stop_reason": "tool_use" }, "usage": { "output_tokens": 15 } }),
+            json!({ "type": "message_stop" }),
+        ];
+        let body = events
+            .iter()
+            .map(|event| {
+                format!(
+                    "event: {}\ndata: {event}\n\n",
+                    event["type"].as_str().unwrap()
+                )
+            })
+            .collect::<String>();
+
+        Mock::given(method("POST"))
+            .and(path("/messages"))
+            .and(body_partial_json(json!({ "stream": true })))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(body, "text/event-stream"))
+            .mount(&server)
+            .await;
+
+        let client = Client::new("test-key", &format!("{}/", server.uri()), "bridge-test");
+        let response = client
+            .create_chat_completion_stream(CreateChatCompletionRequest {
+                model: "claude-3-haiku-20240307",
+                messages: vec![Message::User {
+                    content: "Search for rust.".into(),
+                    name: None,
+                }],
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let mut stream = CompletionStream::new(response)
+            .with_usage_extractor(usage::extractor_for(&Provider::Anthropic))
+            .with_translator(ChatClient::stream_translator(&client).unwrap());
+        let mut message = messages::Message::default();
+
+        while stream.next(&mut message).await.unwrap() == Some(StreamUpdate::Chunk) {}
+
+        assert_eq!(message.status, Status::WaitingForToolCall);
+        assert_eq!(message.content.as_deref(), Some("Let me search."));
+        assert_eq!(
+            json!(message.tool_calls().0),
+            json!([tool_call("toolu_01", "search_web", r#"{"query": "rust"}"#)])
+        );
+        assert_eq!(message.prompt_tokens, Some(25));
+        assert_eq!(message.completion_tokens, Some(15));
+    }
+}
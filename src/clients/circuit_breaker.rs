@@ -0,0 +1,202 @@
+// Copyright 2024 StarfleetAI
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use tracing::warn;
+
+pub(crate) const FAILURE_THRESHOLD: u32 = 5;
+const COOLDOWN: Duration = Duration::from_secs(30);
+
+static BREAKERS: OnceLock<Mutex<HashMap<String, Arc<CircuitBreaker>>>> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Requests go through, consecutive failures are counted.
+    Closed { failures: u32 },
+    /// Requests fail fast until the cooldown is over.
+    Open { until: Instant },
+    /// A single probe request is in flight, others fail fast.
+    HalfOpen,
+}
+
+/// Stops sending requests to the provider after repeated failures, so that tasks fail fast
+/// during the outage instead of waiting for every request to fail.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    cooldown: Duration,
+    state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+    #[must_use]
+    pub fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            failure_threshold,
+            cooldown,
+            state: Mutex::new(State::Closed { failures: 0 }),
+        }
+    }
+
+    /// Returns the shared breaker for the provider API URL.
+    #[must_use]
+    pub fn for_api_url(api_url: &str) -> Arc<Self> {
+        let mut breakers = BREAKERS
+            .get_or_init(Mutex::default)
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+
+        breakers
+            .entry(api_url.to_string())
+            .or_insert_with(|| Arc::new(Self::new(FAILURE_THRESHOLD, COOLDOWN)))
+            .clone()
+    }
+
+    /// Returns the permit to send the request, or `None` if the circuit is open. Once the
+    /// cooldown is over, lets a single probe request through.
+    #[must_use]
+    pub fn try_acquire(&self) -> Option<Permit<'_>> {
+        let mut state = self.state();
+
+        let is_probe = match *state {
+            State::Closed { .. } => false,
+            State::Open { until } if Instant::now() >= until => {
+                *state = State::HalfOpen;
+                true
+            }
+            State::Open { .. } | State::HalfOpen => return None,
+        };
+
+        Some(Permit {
+            breaker: self,
+            is_probe,
+        })
+    }
+
+    fn record_success(&self) {
+        *self.state() = State::Closed { failures: 0 };
+    }
+
+    fn record_failure(&self) {
+        let mut state = self.state();
+
+        let failures = match *state {
+            State::Closed { failures } => failures + 1,
+            State::Open { .. } | State::HalfOpen => self.failure_threshold,
+        };
+
+        *state = if failures >= self.failure_threshold {
+            warn!(
+                "Circuit is open for {:?} after {} failures",
+                self.cooldown, failures
+            );
+
+            State::Open {
+                until: Instant::now() + self.cooldown,
+            }
+        } else {
+            State::Closed { failures }
+        };
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+/// Permit to send a single request. Dropping it without recording the outcome, e.g. when the
+/// request is cancelled or fails for reasons unrelated to the provider, doesn't count as either,
+/// but lets the next request probe the provider if this one was the probe.
+#[derive(Debug)]
+pub struct Permit<'a> {
+    breaker: &'a CircuitBreaker,
+    is_probe: bool,
+}
+
+impl Permit<'_> {
+    pub fn record_success(mut self) {
+        self.breaker.record_success();
+        // The outcome is recorded, so there is no probe to release on drop.
+        self.is_probe = false;
+    }
+
+    pub fn record_failure(mut self) {
+        self.breaker.record_failure();
+        self.is_probe = false;
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if !self.is_probe {
+            return;
+        }
+
+        let mut state = self.breaker.state();
+        if *state == State::HalfOpen {
+            *state = State::Open {
+                until: Instant::now(),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_opens_after_failures_and_closes_after_probe() {
+        let breaker = CircuitBreaker::new(3, Duration::from_millis(50));
+
+        for _ in 0..3 {
+            breaker.try_acquire().unwrap().record_failure();
+        }
+
+        assert!(breaker.try_acquire().is_none());
+
+        std::thread::sleep(Duration::from_millis(60));
+
+        // Only one probe goes through while half-open.
+        let probe = breaker.try_acquire().unwrap();
+        assert!(breaker.try_acquire().is_none());
+
+        probe.record_success();
+
+        assert!(breaker.try_acquire().is_some());
+        assert!(breaker.try_acquire().is_some());
+    }
+
+    #[test]
+    fn test_failed_probe_reopens_circuit() {
+        let breaker = CircuitBreaker::new(2, Duration::from_millis(50));
+        breaker.try_acquire().unwrap().record_failure();
+        breaker.try_acquire().unwrap().record_failure();
+
+        std::thread::sleep(Duration::from_millis(60));
+        breaker.try_acquire().unwrap().record_failure();
+
+        assert!(breaker.try_acquire().is_none());
+    }
+
+    #[test]
+    fn test_dropped_probe_lets_next_request_probe() {
+        let breaker = CircuitBreaker::new(1, Duration::from_millis(50));
+        breaker.try_acquire().unwrap().record_failure();
+
+        std::thread::sleep(Duration::from_millis(60));
+        let probe = breaker.try_acquire().unwrap();
+        assert!(breaker.try_acquire().is_none());
+        drop(probe);
+
+        // The cancelled probe doesn't keep the circuit half-open.
+        assert!(breaker.try_acquire().is_some());
+    }
+}
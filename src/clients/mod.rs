@@ -1,4 +1,91 @@
 // Copyright 2024 StarfleetAI
 // SPDX-License-Identifier: Apache-2.0
 
+use std::time::Duration;
+
+use async_trait::async_trait;
+use reqwest::Response;
+use serde_json::Value;
+
+use crate::types::{
+    models::{Model, Provider},
+    Result,
+};
+
+use self::openai::{ChatCompletion, CreateChatCompletionRequest};
+
+pub mod anthropic;
+pub mod circuit_breaker;
 pub mod openai;
+pub mod usage;
+
+/// Chat completion API of a provider. Requests and responses are in the OpenAI format and are
+/// translated by the clients of other APIs.
+#[async_trait]
+pub trait ChatClient: Send + Sync {
+    /// Creates a chat completion.
+    ///
+    /// # Errors
+    ///
+    /// Returns error if there was a problem while making the API call.
+    async fn create_chat_completion(
+        &self,
+        request: CreateChatCompletionRequest<'_>,
+    ) -> Result<ChatCompletion>;
+
+    /// Creates a streaming chat completion, returns the response with the provider's events.
+    ///
+    /// # Errors
+    ///
+    /// Returns error if there was a problem while making the API call.
+    async fn create_chat_completion_stream(
+        &self,
+        request: CreateChatCompletionRequest<'_>,
+    ) -> Result<Response>;
+
+    /// Timeout of a request, and of every read of the stream.
+    fn timeout(&self) -> Duration;
+
+    /// Translator of the streamed events to the OpenAI chunks, `None` if no translation is
+    /// needed.
+    fn stream_translator(&self) -> Option<Box<dyn StreamTranslator>> {
+        None
+    }
+}
+
+/// Translated streamed event.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamEvent {
+    /// OpenAI chat completion chunk.
+    Chunk(Value),
+    /// Completion is finished.
+    Done,
+    /// Event carries no completion data.
+    Skip,
+}
+
+/// Translates the provider's streamed events to the OpenAI chunks. Events of a stream are
+/// passed in order, so the translator may keep the stream state.
+pub trait StreamTranslator: Send {
+    /// Translates the event's `data`.
+    ///
+    /// # Errors
+    ///
+    /// Returns error if the event reports an error.
+    fn translate(&mut self, event: &Value) -> Result<StreamEvent>;
+}
+
+/// Creates the client for the model's provider.
+#[must_use]
+pub fn chat_client(model: &Model, api_key: &str, user_agent: &str) -> Box<dyn ChatClient> {
+    match model.provider {
+        Provider::OpenAI | Provider::Groq | Provider::Ollama | Provider::Azure => {
+            Box::new(openai::Client::for_model(model, api_key, user_agent))
+        }
+        Provider::Anthropic => Box::new(anthropic::Client::new(
+            api_key,
+            model.api_url_or_default(),
+            user_agent,
+        )),
+    }
+}
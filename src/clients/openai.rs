@@ -1,20 +1,115 @@
 // Copyright 2024 StarfleetAI
 // SPDX-License-Identifier: Apache-2.0
 
-use std::{collections::HashMap, ops::Deref};
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter},
+    future::Future,
+    ops::Deref,
+    sync::OnceLock,
+    time::Duration,
+};
 
 use anyhow::Context;
-use reqwest::Response;
+use async_trait::async_trait;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use reqwest::{header::RETRY_AFTER, RequestBuilder, Response, StatusCode};
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use tracing::debug;
+use serde_json::{json, Value};
+use tracing::{debug, warn};
 
-use crate::types::Result;
+use crate::{
+    clients::ChatClient,
+    types::{
+        models::{Model, Provider},
+        Result,
+    },
+};
+
+/// HTTP client shared by all the API clients, so that connections and TLS sessions are reused.
+static HTTP_CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(120);
+const DEFAULT_MAX_RETRIES: u32 = 3;
+const DEFAULT_RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+/// Upper bound for a single retry delay, including the one requested by `Retry-After`.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("inference API request timed out after {0:?}")]
+    Timeout(Duration),
+    #[error("inference API responded with {}: {}", .0.status, .0.message)]
+    Api(ApiError),
+    #[error("inference API returned no choices")]
+    EmptyChoices,
+    #[error("tool choice `{0}` is set, but no tools are offered")]
+    ToolChoiceWithoutTools(String),
+    #[error("tool `{0}` is forced, but not offered")]
+    ForcedToolNotOffered(String),
+}
+
+/// Error reported by the inference API in a non-2xx response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub message: String,
+    /// Error type, e.g. `invalid_request_error`.
+    pub error_type: Option<String>,
+    /// Machine-readable error code, e.g. `invalid_api_key`.
+    pub code: Option<String>,
+}
+
+impl ApiError {
+    /// Parses the OpenAI-style `{"error": {...}}` body, falling back to the raw body as the
+    /// message.
+    fn from_body(status: StatusCode, body: &str) -> Self {
+        #[derive(Deserialize)]
+        struct ErrorBody {
+            error: ErrorDetails,
+        }
+
+        #[derive(Deserialize)]
+        struct ErrorDetails {
+            message: String,
+            #[serde(rename = "type")]
+            error_type: Option<String>,
+            code: Option<Value>,
+        }
+
+        match serde_json::from_str::<ErrorBody>(body) {
+            Ok(ErrorBody { error }) => Self {
+                status,
+                message: error.message,
+                error_type: error.error_type,
+                code: error.code.and_then(|code| match code {
+                    Value::String(code) => Some(code),
+                    Value::Null => None,
+                    code => Some(code.to_string()),
+                }),
+            },
+            Err(_) => Self {
+                status,
+                message: body.trim().to_string(),
+                error_type: None,
+                code: None,
+            },
+        }
+    }
+}
 
 pub struct Client {
     pub api_key: String,
     pub api_url: String,
     pub user_agent: String,
+    timeout: Duration,
+    max_retries: u32,
+    retry_base_delay: Duration,
+    /// Header carrying the API key as is. `Authorization: Bearer` is used if not set.
+    api_key_header: Option<&'static str>,
+    headers: Vec<(&'static str, String)>,
+    query: Vec<(&'static str, String)>,
+    http: reqwest::Client,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -28,7 +123,7 @@ pub enum Message {
     },
     #[serde(rename = "user")]
     User {
-        content: String,
+        content: Content,
         #[serde(skip_serializing_if = "Option::is_none")]
         name: Option<String>,
     },
@@ -52,18 +147,106 @@ impl Message {
     #[must_use]
     pub fn tool_calls(&self) -> ToolCalls {
         match self {
-            Message::Assistant { tool_calls, .. } => match tool_calls {
-                Some(tool_calls) => match serde_json::from_value(tool_calls.clone()) {
-                    Ok(tool_calls) => tool_calls,
-                    Err(_) => ToolCalls::default(),
-                },
-                None => ToolCalls::default(),
-            },
+            Message::Assistant {
+                tool_calls: Some(tool_calls),
+                ..
+            } => serde_json::from_value(tool_calls.clone()).unwrap_or_default(),
             _ => ToolCalls::default(),
         }
     }
 }
 
+/// User message content: plain text, or text and images for the models accepting image input.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Content {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+impl Content {
+    /// Text of the content, with the text parts separated by newlines.
+    #[must_use]
+    pub fn text(&self) -> String {
+        match self {
+            Self::Text(text) => text.clone(),
+            Self::Parts(parts) => parts
+                .iter()
+                .filter_map(|part| match part {
+                    ContentPart::Text { text } => Some(text.as_str()),
+                    ContentPart::ImageUrl { .. } => None,
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+}
+
+impl From<String> for Content {
+    fn from(text: String) -> Self {
+        Self::Text(text)
+    }
+}
+
+impl From<&str> for Content {
+    fn from(text: &str) -> Self {
+        Self::Text(text.to_string())
+    }
+}
+
+impl Serialize for Content {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        match self {
+            // Sent as a plain string, which is understood by the providers without vision support.
+            Self::Text(text) => serializer.serialize_str(text),
+            Self::Parts(parts) => match parts.as_slice() {
+                [ContentPart::Text { text }] => serializer.serialize_str(text),
+                parts => parts.serialize(serializer),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+impl ContentPart {
+    /// Image part with the PNG embedded as a base64 data URI.
+    #[must_use]
+    pub fn png(bytes: &[u8]) -> Self {
+        Self::ImageUrl {
+            image_url: ImageUrl {
+                url: format!("data:image/png;base64,{}", BASE64_STANDARD.encode(bytes)),
+                detail: None,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ImageUrl {
+    /// Image URL, or the image itself as a base64 data URI.
+    pub url: String,
+    /// Resolution the model processes the image at, `auto` if not set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<ImageDetail>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageDetail {
+    Auto,
+    Low,
+    High,
+}
+
 impl TryFrom<crate::types::messages::Message> for Message {
     type Error = anyhow::Error;
 
@@ -80,13 +263,15 @@ impl TryFrom<crate::types::messages::Message> for Message {
             crate::types::messages::Role::User => Message::User {
                 content: message
                     .content
-                    .with_context(|| "Failed to get message content")?,
+                    .with_context(|| "Failed to get message content")?
+                    .into(),
                 name: None,
             },
             crate::types::messages::Role::CodeInterpreter => Message::User {
                 content: message
                     .content
-                    .with_context(|| "Failed to get message content")?,
+                    .with_context(|| "Failed to get message content")?
+                    .into(),
                 name: Some("Code-Interpreter".to_string()),
             },
             crate::types::messages::Role::Assistant => Message::Assistant {
@@ -158,6 +343,9 @@ pub struct FunctionParameters {
     #[serde(rename = "type")]
     pub type_: String,
     pub properties: HashMap<String, FunctionPropertyValue>,
+    /// Names of the properties the model must always provide.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub required: Option<Vec<String>>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -165,8 +353,18 @@ pub struct FunctionPropertyValue {
     #[serde(rename = "type")]
     pub type_: String,
     pub description: String,
+    /// Schema of the array items, for `array` properties.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub items: Option<FunctionParameters>,
+    /// Nested properties, for `object` properties.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub properties: Option<HashMap<String, FunctionPropertyValue>>,
+    /// Required nested properties, for `object` properties.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub required: Option<Vec<String>>,
+    /// Allowed values of the property.
+    #[serde(rename = "enum", skip_serializing_if = "Option::is_none")]
+    pub enum_: Option<Vec<Value>>,
 }
 
 #[derive(Debug, Serialize, Default)]
@@ -175,7 +373,117 @@ pub struct CreateChatCompletionRequest<'a> {
     pub messages: Vec<Message>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tools: Option<Vec<Tool>>,
+    /// Sampling temperature, lower values make the output more deterministic.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    /// Nucleus sampling probability mass.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    /// Maximum number of tokens to generate.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<i64>,
+    /// Sequences where the generation stops.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+    /// Format of the generated content.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<ResponseFormat>,
+    /// Whether and which tools the model must call. Only valid along with the `tools`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<ToolChoice>,
     pub stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_options: Option<StreamOptions>,
+    /// Whether the provider should store the completion on its side.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub store: Option<bool>,
+    /// Arbitrary key-value pairs used to correlate provider-side logs with our entities.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<HashMap<String, String>>,
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    Text,
+    /// Content is guaranteed to be a valid JSON object. The messages must ask for JSON
+    /// explicitly, otherwise the API rejects the request.
+    JsonObject,
+}
+
+impl CreateChatCompletionRequest<'_> {
+    /// Checks that the tool choice refers to the offered tools, as the API rejects it otherwise.
+    ///
+    /// # Errors
+    ///
+    /// Returns error if the tool choice is set without tools, or forces a tool which is not
+    /// offered.
+    pub fn validate(&self) -> Result<()> {
+        let Some(tool_choice) = &self.tool_choice else {
+            return Ok(());
+        };
+
+        let tools = match &self.tools {
+            Some(tools) if !tools.is_empty() => tools,
+            _ => return Err(Error::ToolChoiceWithoutTools(tool_choice.to_string()).into()),
+        };
+
+        if let ToolChoice::Function { name } = tool_choice {
+            if !tools.iter().any(|tool| tool.function.name == *name) {
+                return Err(Error::ForcedToolNotOffered(name.clone()).into());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Controls which tools the model calls.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ToolChoice {
+    /// Model decides whether to call tools.
+    Auto,
+    /// Model doesn't call tools, even if offered.
+    None,
+    /// Model calls one or more tools.
+    Required,
+    /// Model calls the given tool.
+    Function { name: String },
+}
+
+impl Display for ToolChoice {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Auto => write!(f, "auto"),
+            Self::None => write!(f, "none"),
+            Self::Required => write!(f, "required"),
+            Self::Function { name } => write!(f, "function `{name}`"),
+        }
+    }
+}
+
+impl Serialize for ToolChoice {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        match self {
+            Self::Auto => serializer.serialize_str("auto"),
+            Self::None => serializer.serialize_str("none"),
+            Self::Required => serializer.serialize_str("required"),
+            Self::Function { name } => json!({
+                "type": "function",
+                "function": { "name": name }
+            })
+            .serialize(serializer),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Default, Clone, Copy)]
+pub struct StreamOptions {
+    /// Whether to send token usage in the final chunk.
+    pub include_usage: bool,
 }
 
 #[derive(Debug, Deserialize)]
@@ -192,7 +500,7 @@ pub struct ChatCompletionChunk {
 pub struct ChunkChoice {
     pub index: u32,
     pub delta: Message,
-    pub finish_reason: Option<String>,
+    pub finish_reason: Option<FinishReason>,
     pub logprobs: Option<f32>,
 }
 
@@ -206,14 +514,40 @@ pub struct ChatCompletion {
     pub usage: Usage,
 }
 
+impl ChatCompletion {
+    /// Returns the first choice, which is the only one unless more are requested.
+    ///
+    /// # Errors
+    ///
+    /// Returns error if the response has no choices.
+    pub fn first_choice(&self) -> Result<&Choice> {
+        Ok(self.choices.first().ok_or(Error::EmptyChoices)?)
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Choice {
-    pub finish_reason: String,
+    pub finish_reason: FinishReason,
     pub index: u32,
     pub message: Message,
     pub logprobs: Option<f32>,
 }
 
+/// Why the model stopped generating.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    Stop,
+    Length,
+    ToolCalls,
+    /// Content was omitted or cut by the provider's content filter.
+    ContentFilter,
+    FunctionCall,
+    /// Reason not known to us, e.g. a provider-specific one.
+    #[serde(other)]
+    Other,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Usage {
     pub completion_tokens: u32,
@@ -228,18 +562,108 @@ impl<'a> Client {
             api_key: api_key.to_string(),
             api_url: api_url.to_string(),
             user_agent: user_agent.to_string(),
+            timeout: DEFAULT_TIMEOUT,
+            max_retries: DEFAULT_MAX_RETRIES,
+            retry_base_delay: DEFAULT_RETRY_BASE_DELAY,
+            api_key_header: None,
+            headers: Vec::new(),
+            query: Vec::new(),
+            // Cloning shares the connection pool.
+            http: HTTP_CLIENT.get_or_init(reqwest::Client::new).clone(),
+        }
+    }
+
+    /// Sets the request timeout. For streaming requests it limits the wait for the response
+    /// headers and, separately, for every chunk of the stream.
+    #[must_use]
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    #[must_use]
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Sets how many times a request is retried on `429` and `5xx` responses, and the delay
+    /// before the first retry, which doubles on every next one. `Retry-After` header takes
+    /// precedence over the delay.
+    #[must_use]
+    pub fn with_retries(mut self, max_retries: u32, base_delay: Duration) -> Self {
+        self.max_retries = max_retries;
+        self.retry_base_delay = base_delay;
+        self
+    }
+
+    /// Passes the API key in the `name` header instead of `Authorization: Bearer`, for the APIs
+    /// which are not OpenAI-compatible.
+    #[must_use]
+    pub fn with_api_key_header(mut self, name: &'static str) -> Self {
+        self.api_key_header = Some(name);
+        self
+    }
+
+    /// Adds the header to every request.
+    #[must_use]
+    pub fn with_header(mut self, name: &'static str, value: &str) -> Self {
+        self.headers.push((name, value.to_string()));
+        self
+    }
+
+    /// Creates the client for the Azure OpenAI deployment, which is addressed by its URL and
+    /// takes the API key in the `api-key` header.
+    #[must_use]
+    pub fn azure(
+        api_key: &'a str,
+        endpoint: &'a str,
+        deployment: &'a str,
+        api_version: &'a str,
+        user_agent: &'a str,
+    ) -> Self {
+        let api_url = format!(
+            "{}/openai/deployments/{deployment}/",
+            endpoint.trim_end_matches('/')
+        );
+
+        Self::new(api_key, &api_url, user_agent)
+            .with_api_key_header("api-key")
+            .with_query("api-version", api_version)
+    }
+
+    /// Creates the client for the model, addressing the Azure deployments by their URLs.
+    #[must_use]
+    pub fn for_model(model: &'a Model, api_key: &'a str, user_agent: &'a str) -> Self {
+        match model.provider {
+            Provider::Azure => Self::azure(
+                api_key,
+                model.api_url_or_default(),
+                model.deployment_or_default(),
+                model.api_version_or_default(),
+                user_agent,
+            ),
+            _ => Self::new(api_key, model.api_url_or_default(), user_agent),
         }
     }
 
+    /// Adds the query parameter to every request.
+    #[must_use]
+    pub fn with_query(mut self, name: &'static str, value: &str) -> Self {
+        self.query.push((name, value.to_string()));
+        self
+    }
+
     /// Creates a streaming chat completion.
     ///
     /// # Errors
     ///
+    /// Returns error if the request's tool choice doesn't match its tools.
     /// Returns error if there was a problem while making the API call.
     pub async fn create_chat_completion_stream(
         &self,
         mut request: CreateChatCompletionRequest<'_>,
     ) -> Result<Response> {
+        request.validate()?;
         request.stream = true;
 
         self.post_stream("chat/completions", &request).await
@@ -249,18 +673,24 @@ impl<'a> Client {
     ///
     /// # Errors
     ///
+    /// Returns error if the request's tool choice doesn't match its tools.
     /// Returns error if there was a problem while making the API call.
     pub async fn create_chat_completion(
         &self,
         request: CreateChatCompletionRequest<'_>,
     ) -> Result<ChatCompletion> {
+        request.validate()?;
+
         self.post("chat/completions", &request).await
     }
 
     /// Sends a stream POST request, returns the response for further processing.
+    /// Rate limited and failed requests are retried before the stream starts.
     ///
     /// # Errors
     ///
+    /// Returns error if the response headers are not received within the timeout.
+    /// Returns error if the API responds with an error status.
     /// Returns error if there was a problem while sending the request or
     /// deserializing the response.
     pub async fn post_stream<B>(&self, endpoint: &str, body: B) -> Result<Response>
@@ -268,28 +698,45 @@ impl<'a> Client {
         B: serde::Serialize,
     {
         let url = format!("{}{endpoint}", self.api_url);
-        let client = reqwest::Client::new();
 
         let body =
             serde_json::to_value(body).with_context(|| "Failed to serialize request body")?;
 
         debug!("Inference API request: {:?}", body.to_string());
 
-        Ok(client
-            .post(&url)
-            .header("Authorization", format!("Bearer {}", self.api_key))
-            .header("Content-Type", "application/json")
-            .header("User-Agent", self.user_agent.clone())
-            .json(&body)
-            .send()
-            .await
-            .with_context(|| "Failed to send request")?)
+        // Request timeout would limit the whole stream, so only the headers are waited for here.
+        // Retries happen before any chunk is read, so they are safe for streams too.
+        let response = self
+            .send_with_retries(|| async {
+                let response = tokio::time::timeout(self.timeout, self.request(&url, &body).send())
+                    .await
+                    .map_err(|_| Error::Timeout(self.timeout))?
+                    .with_context(|| "Failed to send request")?;
+
+                Ok(response)
+            })
+            .await?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response
+                .text()
+                .await
+                .map_err(|err| self.request_error(err, "Failed to get response text"))?;
+
+            return Err(Error::Api(ApiError::from_body(status, &body)).into());
+        }
+
+        Ok(response)
     }
 
     /// Sends a POST request, deserializes the response to the given type.
+    /// Rate limited and failed requests are retried.
     ///
     /// # Errors
     ///
+    /// Returns error if the response is not received within the timeout.
+    /// Returns error if the API responds with an error status.
     /// Returns error if there was a problem while sending the request or
     /// deserializing the response.
     pub async fn post<T, B>(&self, endpoint: &str, body: B) -> Result<T>
@@ -298,27 +745,595 @@ impl<'a> Client {
         B: serde::Serialize,
     {
         let url = format!("{}{endpoint}", self.api_url);
-        let client = reqwest::Client::new();
 
         let body =
             serde_json::to_value(body).with_context(|| "Failed to serialize request body")?;
         debug!("Inference API request: {:?}", body.to_string());
 
-        let response = client
-            .post(&url)
-            .header("Authorization", format!("Bearer {}", self.api_key))
-            .header("Content-Type", "application/json")
-            .header("User-Agent", self.user_agent.clone())
-            .json(&body)
-            .send()
-            .await
-            .with_context(|| "Failed to send request")?
+        let response = self
+            .send_with_retries(|| async {
+                self.request(&url, &body)
+                    .timeout(self.timeout)
+                    .send()
+                    .await
+                    .map_err(|err| self.request_error(err, "Failed to send request"))
+            })
+            .await?;
+        let status = response.status();
+        let response = response
             .text()
             .await
-            .with_context(|| "Failed to get response text")?;
+            .map_err(|err| self.request_error(err, "Failed to get response text"))?;
 
         debug!("Inference API response: {:?}", response);
 
+        if !status.is_success() {
+            return Err(Error::Api(ApiError::from_body(status, &response)).into());
+        }
+
         Ok(serde_json::from_str(&response)?)
     }
+
+    fn request(&self, url: &str, body: &Value) -> RequestBuilder {
+        let request = match self.api_key_header {
+            Some(name) => self.http.post(url).header(name, self.api_key.clone()),
+            None => self
+                .http
+                .post(url)
+                .header("Authorization", format!("Bearer {}", self.api_key)),
+        };
+
+        self.headers
+            .iter()
+            .fold(request, |request, (name, value)| {
+                request.header(*name, value.clone())
+            })
+            .header("Content-Type", "application/json")
+            .header("User-Agent", self.user_agent.clone())
+            .query(&self.query)
+            .json(body)
+    }
+
+    /// Repeats the request while the API is rate limited or fails, returning the last response.
+    async fn send_with_retries<F, Fut>(&self, send: F) -> Result<Response>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<Response>>,
+    {
+        let mut attempt = 0;
+
+        loop {
+            let response = send().await?;
+            let status = response.status();
+
+            if attempt >= self.max_retries
+                || !(status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error())
+            {
+                return Ok(response);
+            }
+
+            let delay = retry_after(&response)
+                .unwrap_or_else(|| {
+                    self.retry_base_delay
+                        .saturating_mul(2_u32.saturating_pow(attempt))
+                })
+                .min(MAX_RETRY_DELAY);
+            attempt += 1;
+
+            warn!(
+                "Inference API responded with {status}, retry {attempt}/{} in {delay:?}",
+                self.max_retries
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    fn request_error(&self, err: reqwest::Error, context: &'static str) -> crate::errors::Error {
+        if err.is_timeout() {
+            Error::Timeout(self.timeout).into()
+        } else {
+            anyhow::Error::new(err).context(context).into()
+        }
+    }
+}
+
+#[async_trait]
+impl ChatClient for Client {
+    async fn create_chat_completion(
+        &self,
+        request: CreateChatCompletionRequest<'_>,
+    ) -> Result<ChatCompletion> {
+        Client::create_chat_completion(self, request).await
+    }
+
+    async fn create_chat_completion_stream(
+        &self,
+        request: CreateChatCompletionRequest<'_>,
+    ) -> Result<Response> {
+        Client::create_chat_completion_stream(self, request).await
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+}
+
+/// Delay requested by the `Retry-After` header. Only the delay in seconds is supported.
+fn retry_after(response: &Response) -> Option<Duration> {
+    let seconds = response.headers().get(RETRY_AFTER)?.to_str().ok()?;
+
+    seconds.trim().parse().ok().map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::types::abilities::Ability;
+
+    use super::*;
+
+    #[test]
+    fn test_request_serializes_metadata() {
+        let request = CreateChatCompletionRequest {
+            model: "gpt-4-turbo",
+            metadata: Some(HashMap::from([(
+                "task_id".to_string(),
+                "7d6c3a52-3bc4-4f5e-8a7e-4f2b1c0e9d11".to_string(),
+            )])),
+            store: Some(true),
+            ..Default::default()
+        };
+
+        let json = serde_json::to_value(&request).unwrap();
+
+        assert_eq!(
+            json["metadata"]["task_id"],
+            "7d6c3a52-3bc4-4f5e-8a7e-4f2b1c0e9d11"
+        );
+        assert_eq!(json["store"], true);
+    }
+
+    #[test]
+    fn test_function_parameters_serialize_required() {
+        let json = json!({
+            "name": "goto",
+            "parameters": {
+                "type": "object",
+                "properties": {
+                    "url": { "type": "string", "description": "URL to navigate to" }
+                },
+                "required": ["url"]
+            }
+        });
+
+        let function = Ability::for_fn("Go to URL", &json).function();
+        let parameters = function.parameters.as_ref().unwrap();
+
+        assert_eq!(parameters.required, Some(vec!["url".to_string()]));
+        assert_eq!(serde_json::to_value(&function).unwrap(), json);
+    }
+
+    #[test]
+    fn test_function_property_value_nested_object_and_enum() {
+        let json = json!({
+            "type": "object",
+            "description": "Viewport size",
+            "properties": {
+                "unit": {
+                    "type": "string",
+                    "description": "Size unit",
+                    "enum": ["px", "em"]
+                },
+                "width": { "type": "integer", "description": "Width" }
+            },
+            "required": ["width"]
+        });
+
+        let value: FunctionPropertyValue = serde_json::from_value(json.clone()).unwrap();
+
+        let properties = value.properties.as_ref().unwrap();
+        assert_eq!(
+            properties["unit"].enum_,
+            Some(vec![json!("px"), json!("em")])
+        );
+        assert_eq!(serde_json::to_value(&value).unwrap(), json);
+    }
+
+    #[test]
+    fn test_function_parameters_skip_empty_required() {
+        let parameters = FunctionParameters {
+            type_: "object".to_string(),
+            properties: HashMap::new(),
+            required: None,
+        };
+
+        let json = serde_json::to_value(&parameters).unwrap();
+
+        assert!(json.get("required").is_none());
+    }
+
+    #[test]
+    fn test_request_skips_empty_metadata() {
+        let request = CreateChatCompletionRequest {
+            model: "gpt-4-turbo",
+            ..Default::default()
+        };
+
+        let json = serde_json::to_value(&request).unwrap();
+
+        assert!(json.get("metadata").is_none());
+        assert!(json.get("store").is_none());
+        for field in [
+            "temperature",
+            "top_p",
+            "max_tokens",
+            "stop",
+            "stream_options",
+        ] {
+            assert!(json.get(field).is_none());
+        }
+    }
+
+    #[test]
+    fn test_request_serializes_stream_options() {
+        let request = CreateChatCompletionRequest {
+            model: "gpt-4-turbo",
+            stream: true,
+            stream_options: Some(StreamOptions {
+                include_usage: true,
+            }),
+            ..Default::default()
+        };
+
+        let json = serde_json::to_value(&request).unwrap();
+
+        assert_eq!(json["stream_options"], json!({ "include_usage": true }));
+    }
+
+    #[test]
+    fn test_request_serializes_response_format() {
+        let request = CreateChatCompletionRequest {
+            model: "gpt-4-turbo",
+            response_format: Some(ResponseFormat::JsonObject),
+            ..Default::default()
+        };
+
+        let json = serde_json::to_value(&request).unwrap();
+
+        assert_eq!(json["response_format"], json!({ "type": "json_object" }));
+        assert_eq!(
+            serde_json::to_value(ResponseFormat::Text).unwrap(),
+            json!({ "type": "text" })
+        );
+    }
+
+    #[test]
+    fn test_user_content_serialization() {
+        let user = |content: Content| {
+            serde_json::to_value(Message::User {
+                content,
+                name: None,
+            })
+            .unwrap()
+        };
+
+        let row = crate::types::messages::Message {
+            role: crate::types::messages::Role::User,
+            content: Some("Describe the page".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            serde_json::to_value(Message::try_from(row).unwrap()).unwrap(),
+            json!({ "role": "user", "content": "Describe the page" })
+        );
+        assert_eq!(
+            user(Content::Parts(vec![ContentPart::Text {
+                text: "Describe the page".to_string()
+            }])),
+            json!({ "role": "user", "content": "Describe the page" })
+        );
+
+        let content = Content::Parts(vec![
+            ContentPart::Text {
+                text: "Describe the page".to_string(),
+            },
+            ContentPart::png(b"PNG"),
+        ]);
+        assert_eq!(
+            user(content.clone()),
+            json!({
+                "role": "user",
+                "content": [
+                    { "type": "text", "text": "Describe the page" },
+                    { "type": "image_url", "image_url": { "url": "data:image/png;base64,UE5H" } }
+                ]
+            })
+        );
+        assert_eq!(
+            serde_json::from_value::<Content>(serde_json::to_value(&content).unwrap()).unwrap(),
+            content
+        );
+        assert_eq!(content.text(), "Describe the page");
+    }
+
+    #[test]
+    fn test_request_serializes_tool_choice() {
+        let request = CreateChatCompletionRequest {
+            model: "gpt-4-turbo",
+            tool_choice: Some(ToolChoice::Function {
+                name: "sfai_done".to_string(),
+            }),
+            ..Default::default()
+        };
+
+        let json = serde_json::to_value(&request).unwrap();
+
+        assert_eq!(
+            json["tool_choice"],
+            json!({ "type": "function", "function": { "name": "sfai_done" } })
+        );
+        for (tool_choice, expected) in [
+            (ToolChoice::Auto, "auto"),
+            (ToolChoice::None, "none"),
+            (ToolChoice::Required, "required"),
+        ] {
+            assert_eq!(serde_json::to_value(tool_choice).unwrap(), json!(expected));
+        }
+    }
+
+    #[test]
+    fn test_tool_choice_requires_offered_tools() {
+        let tool = |name: &str| Tool {
+            type_: "function".to_string(),
+            function: Function {
+                name: name.to_string(),
+                ..Default::default()
+            },
+        };
+        let request = |tools: Option<Vec<Tool>>, tool_choice: ToolChoice| {
+            CreateChatCompletionRequest {
+                model: "gpt-4-turbo",
+                tools,
+                tool_choice: Some(tool_choice),
+                ..Default::default()
+            }
+            .validate()
+        };
+
+        assert!(matches!(
+            request(None, ToolChoice::Required),
+            Err(crate::errors::Error::OpenAI(Error::ToolChoiceWithoutTools(
+                _
+            )))
+        ));
+        assert!(matches!(
+            request(Some(Vec::new()), ToolChoice::None),
+            Err(crate::errors::Error::OpenAI(Error::ToolChoiceWithoutTools(
+                _
+            )))
+        ));
+        assert!(matches!(
+            request(
+                Some(vec![tool("sfai_done")]),
+                ToolChoice::Function {
+                    name: "sfai_fail".to_string()
+                }
+            ),
+            Err(crate::errors::Error::OpenAI(Error::ForcedToolNotOffered(name))) if name == "sfai_fail"
+        ));
+        assert!(request(
+            Some(vec![tool("sfai_done")]),
+            ToolChoice::Function {
+                name: "sfai_done".to_string()
+            }
+        )
+        .is_ok());
+        assert!(CreateChatCompletionRequest::default().validate().is_ok());
+    }
+
+    #[test]
+    fn test_request_serializes_sampling_parameters() {
+        let request = CreateChatCompletionRequest {
+            model: "gpt-4-turbo",
+            messages: vec![Message::User {
+                content: "Plan the trip".into(),
+                name: None,
+            }],
+            temperature: Some(0.0),
+            top_p: Some(0.5),
+            max_tokens: Some(512),
+            stop: Some(vec!["\n\n".to_string()]),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            serde_json::to_value(&request).unwrap(),
+            json!({
+                "model": "gpt-4-turbo",
+                "messages": [{ "role": "user", "content": "Plan the trip" }],
+                "temperature": 0.0,
+                "top_p": 0.5,
+                "max_tokens": 512,
+                "stop": ["\n\n"],
+                "stream": false
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hanging_provider_times_out() {
+        use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(json!({}))
+                    .set_delay(Duration::from_secs(5)),
+            )
+            .mount(&server)
+            .await;
+
+        let timeout = Duration::from_millis(100);
+        let client = Client::new("sk-test", &format!("{}/", server.uri()), "bridge-test")
+            .with_timeout(timeout);
+        let request = || CreateChatCompletionRequest {
+            model: "gpt-4-turbo",
+            ..Default::default()
+        };
+
+        let result = client.create_chat_completion(request()).await;
+        assert!(matches!(
+            result,
+            Err(crate::errors::Error::OpenAI(Error::Timeout(t))) if t == timeout
+        ));
+
+        let result = client.create_chat_completion_stream(request()).await;
+        assert!(matches!(
+            result,
+            Err(crate::errors::Error::OpenAI(Error::Timeout(t))) if t == timeout
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_request_is_retried() {
+        use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "0"))
+            .up_to_n_times(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "id": "chatcmpl-1",
+                "object": "chat.completion",
+                "created": 1_714_000_000,
+                "model": "gpt-4-turbo",
+                "choices": [{
+                    "index": 0,
+                    "message": { "role": "assistant", "content": "Hello" },
+                    "finish_reason": "stop",
+                    "logprobs": null
+                }],
+                "usage": { "prompt_tokens": 5, "completion_tokens": 1, "total_tokens": 6 }
+            })))
+            .mount(&server)
+            .await;
+
+        let client = Client::new("sk-test", &format!("{}/", server.uri()), "bridge-test")
+            .with_retries(3, Duration::from_millis(1));
+
+        let completion = client
+            .create_chat_completion(CreateChatCompletionRequest {
+                model: "gpt-4-turbo",
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            &completion.choices[0].message,
+            Message::Assistant { content: Some(content), .. } if content == "Hello"
+        ));
+        assert_eq!(server.received_requests().await.unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_azure_client_addresses_deployment() {
+        use wiremock::{
+            matchers::{header, header_exists, method, path, query_param},
+            Mock, MockServer, ResponseTemplate,
+        };
+
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/openai/deployments/gpt4-prod/chat/completions"))
+            .and(query_param("api-version", "2024-02-01"))
+            .and(header("api-key", "azure-key"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "id": "chatcmpl-1",
+                "object": "chat.completion",
+                "created": 1_714_000_000,
+                "model": "gpt-4",
+                "choices": [{
+                    "index": 0,
+                    "message": { "role": "assistant", "content": "Hello" },
+                    "finish_reason": "stop",
+                    "logprobs": null
+                }],
+                "usage": { "prompt_tokens": 5, "completion_tokens": 1, "total_tokens": 6 }
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(header_exists("authorization"))
+            .respond_with(ResponseTemplate::new(401))
+            .expect(0)
+            .mount(&server)
+            .await;
+
+        // Endpoints are copied from the Azure portal with the trailing slash.
+        let client = Client::azure(
+            "azure-key",
+            &format!("{}/", server.uri()),
+            "gpt4-prod",
+            "2024-02-01",
+            "bridge-test",
+        );
+
+        let completion = client
+            .create_chat_completion(CreateChatCompletionRequest {
+                model: "gpt-4",
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(completion.usage.total_tokens, 6);
+    }
+
+    #[tokio::test]
+    async fn test_error_response_is_parsed() {
+        use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(400).set_body_json(json!({
+                "error": {
+                    "message": "'messages' must contain at least one message",
+                    "type": "invalid_request_error",
+                    "param": "messages",
+                    "code": null
+                }
+            })))
+            .mount(&server)
+            .await;
+
+        let client = Client::new("sk-test", &format!("{}/", server.uri()), "bridge-test");
+        let request = || CreateChatCompletionRequest {
+            model: "gpt-4-turbo",
+            ..Default::default()
+        };
+        let expected = ApiError {
+            status: StatusCode::BAD_REQUEST,
+            message: "'messages' must contain at least one message".to_string(),
+            error_type: Some("invalid_request_error".to_string()),
+            code: None,
+        };
+
+        let result = client.create_chat_completion(request()).await;
+        assert!(matches!(
+            result,
+            Err(crate::errors::Error::OpenAI(Error::Api(err))) if err == expected
+        ));
+
+        let result = client.create_chat_completion_stream(request()).await;
+        assert!(matches!(
+            result,
+            Err(crate::errors::Error::OpenAI(Error::Api(err))) if err == expected
+        ));
+    }
 }
@@ -0,0 +1,203 @@
+// Copyright 2024 StarfleetAI
+// SPDX-License-Identifier: Apache-2.0
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::types::models::Provider;
+
+/// Token usage reported by the provider in a streamed completion.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StreamUsage {
+    pub prompt_tokens: Option<i32>,
+    pub completion_tokens: Option<i32>,
+}
+
+impl StreamUsage {
+    /// Overrides the counts with the ones reported later in the stream.
+    pub fn merge(&mut self, other: StreamUsage) {
+        self.prompt_tokens = other.prompt_tokens.or(self.prompt_tokens);
+        self.completion_tokens = other.completion_tokens.or(self.completion_tokens);
+    }
+}
+
+/// Pulls token usage out of the streamed completion events, which every provider places
+/// differently.
+pub trait StreamUsageExtractor: Send + Sync {
+    /// Returns usage reported in the event, if any.
+    fn extract(&self, event: &Value) -> Option<StreamUsage>;
+}
+
+/// Returns the extractor for the provider's streaming format.
+#[must_use]
+pub fn extractor_for(provider: &Provider) -> Box<dyn StreamUsageExtractor> {
+    match provider {
+        Provider::OpenAI | Provider::Ollama | Provider::Azure => Box::new(OpenAIUsageExtractor),
+        Provider::Groq => Box::new(GroqUsageExtractor),
+        Provider::Anthropic => Box::new(AnthropicUsageExtractor),
+    }
+}
+
+#[derive(Deserialize)]
+struct CompletionUsage {
+    prompt_tokens: i32,
+    completion_tokens: i32,
+}
+
+impl From<CompletionUsage> for StreamUsage {
+    fn from(usage: CompletionUsage) -> Self {
+        Self {
+            prompt_tokens: Some(usage.prompt_tokens),
+            completion_tokens: Some(usage.completion_tokens),
+        }
+    }
+}
+
+fn completion_usage(usage: Option<&Value>) -> Option<StreamUsage> {
+    CompletionUsage::deserialize(usage?)
+        .ok()
+        .map(StreamUsage::from)
+}
+
+/// OpenAI sends usage in the final chunk with no choices, if `stream_options.include_usage` is
+/// requested.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct OpenAIUsageExtractor;
+
+impl StreamUsageExtractor for OpenAIUsageExtractor {
+    fn extract(&self, event: &Value) -> Option<StreamUsage> {
+        completion_usage(event.get("usage"))
+    }
+}
+
+/// Groq sends usage in the `x_groq` field of the last chunk.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct GroqUsageExtractor;
+
+impl StreamUsageExtractor for GroqUsageExtractor {
+    fn extract(&self, event: &Value) -> Option<StreamUsage> {
+        completion_usage(event.pointer("/x_groq/usage"))
+            .or_else(|| OpenAIUsageExtractor.extract(event))
+    }
+}
+
+/// Anthropic sends prompt tokens in the `message_start` event and the cumulative completion
+/// tokens in the `message_delta` events.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AnthropicUsageExtractor;
+
+impl StreamUsageExtractor for AnthropicUsageExtractor {
+    fn extract(&self, event: &Value) -> Option<StreamUsage> {
+        let tokens = |pointer| {
+            event
+                .pointer(pointer)
+                .and_then(Value::as_i64)
+                .and_then(|tokens| i32::try_from(tokens).ok())
+        };
+
+        match event.get("type")?.as_str()? {
+            "message_start" => Some(StreamUsage {
+                prompt_tokens: tokens("/message/usage/input_tokens"),
+                completion_tokens: tokens("/message/usage/output_tokens"),
+            }),
+            "message_delta" => Some(StreamUsage {
+                prompt_tokens: None,
+                completion_tokens: tokens("/usage/output_tokens"),
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds the `data` lines of the recorded server-sent events to the extractor.
+    fn extract_from_stream(extractor: &dyn StreamUsageExtractor, stream: &str) -> StreamUsage {
+        let mut usage = StreamUsage::default();
+
+        for data in stream
+            .lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .filter(|data| *data != "[DONE]")
+        {
+            let event = serde_json::from_str(data).unwrap();
+            if let Some(extracted) = extractor.extract(&event) {
+                usage.merge(extracted);
+            }
+        }
+
+        usage
+    }
+
+    fn usage(prompt_tokens: i32, completion_tokens: i32) -> StreamUsage {
+        StreamUsage {
+            prompt_tokens: Some(prompt_tokens),
+            completion_tokens: Some(completion_tokens),
+        }
+    }
+
+    #[test]
+    fn test_openai_usage_from_final_chunk() {
+        let stream = r#"data: {"id":"chatcmpl-1","object":"chat.completion.chunk","created":1714000000,"model":"gpt-4-turbo","choices":[{"index":0,"delta":{"role":"assistant","content":""},"finish_reason":null}],"usage":null}
+
+data: {"id":"chatcmpl-1","object":"chat.completion.chunk","created":1714000000,"model":"gpt-4-turbo","choices":[{"index":0,"delta":{"content":"Hello"},"finish_reason":null}],"usage":null}
+
+data: {"id":"chatcmpl-1","object":"chat.completion.chunk","created":1714000000,"model":"gpt-4-turbo","choices":[{"index":0,"delta":{},"finish_reason":"stop"}],"usage":null}
+
+data: {"id":"chatcmpl-1","object":"chat.completion.chunk","created":1714000000,"model":"gpt-4-turbo","choices":[],"usage":{"prompt_tokens":23,"completion_tokens":2,"total_tokens":25}}
+
+data: [DONE]
+"#;
+
+        assert_eq!(
+            extract_from_stream(&OpenAIUsageExtractor, stream),
+            usage(23, 2)
+        );
+    }
+
+    #[test]
+    fn test_groq_usage_from_x_groq() {
+        let stream = r#"data: {"id":"chatcmpl-2","object":"chat.completion.chunk","created":1714000000,"model":"llama3-70b-8192","choices":[{"index":0,"delta":{"role":"assistant","content":""},"logprobs":null,"finish_reason":null}],"x_groq":{"id":"req_1"}}
+
+data: {"id":"chatcmpl-2","object":"chat.completion.chunk","created":1714000000,"model":"llama3-70b-8192","choices":[{"index":0,"delta":{"content":"Hello"},"logprobs":null,"finish_reason":null}]}
+
+data: {"id":"chatcmpl-2","object":"chat.completion.chunk","created":1714000000,"model":"llama3-70b-8192","choices":[{"index":0,"delta":{},"logprobs":null,"finish_reason":"stop"}],"x_groq":{"id":"req_1","usage":{"queue_time":0.01,"prompt_tokens":18,"prompt_time":0.004,"completion_tokens":3,"completion_time":0.006,"total_tokens":21,"total_time":0.01}}}
+
+data: [DONE]
+"#;
+
+        assert_eq!(
+            extract_from_stream(&GroqUsageExtractor, stream),
+            usage(18, 3)
+        );
+    }
+
+    #[test]
+    fn test_anthropic_usage_from_message_events() {
+        let stream = r#"event: message_start
+data: {"type":"message_start","message":{"id":"msg_1","type":"message","role":"assistant","content":[],"model":"claude-3-opus-20240229","stop_reason":null,"stop_sequence":null,"usage":{"input_tokens":25,"output_tokens":1}}}
+
+event: content_block_start
+data: {"type":"content_block_start","index":0,"content_block":{"type":"text","text":""}}
+
+event: content_block_delta
+data: {"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Hello"}}
+
+event: content_block_stop
+data: {"type":"content_block_stop","index":0}
+
+event: message_delta
+data: {"type":"message_delta","delta":{"stop_reason":"end_turn","stop_sequence":null},"usage":{"output_tokens":15}}
+
+event: message_stop
+data: {"type":"message_stop"}
+"#;
+
+        assert_eq!(
+            extract_from_stream(&AnthropicUsageExtractor, stream),
+            usage(25, 15)
+        );
+    }
+}
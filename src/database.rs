@@ -1,9 +1,12 @@
+use std::collections::HashSet;
+
 use anyhow::Result;
 use sqlx::postgres::PgPoolOptions;
 use sqlx::{Pool, Postgres};
 use tracing::{debug, info};
 
 use crate::repo::{messages, tasks};
+use crate::types::{messages::Status as MessageStatus, tasks::Status, tasks::Task};
 
 const DEFAULT_POOL_SIZE: u32 = 5;
 
@@ -49,22 +52,54 @@ pub async fn prepare(pool: &Pool<Postgres>) -> Result<()> {
     debug!("Cleaning up after possible previous termination");
 
     // TODO: continue writing the messages that were writing before the termination
-    messages::transition_all(
-        pool,
-        crate::types::messages::Status::Writing,
-        crate::types::messages::Status::Failed,
-    )
-    .await?;
-    tasks::transition_all(
-        pool,
-        crate::types::tasks::Status::InProgress,
-        crate::types::tasks::Status::ToDo,
-    )
-    .await?;
+    messages::transition_all(pool, MessageStatus::Writing, MessageStatus::Failed).await?;
+    reset_stuck_tasks(pool).await?;
+
+    Ok(())
+}
+
+/// Moves the tasks in progress which can't be resumed back to `ToDo`. The rest are left in
+/// progress, to be continued by `TaskExecutor::resume_root_tasks` from their execution chats.
+///
+/// Parent tasks stay in progress while any of their sub-tasks does.
+async fn reset_stuck_tasks(pool: &Pool<Postgres>) -> Result<()> {
+    let in_progress = tasks::list_all_by_status(pool, Status::InProgress).await?;
+
+    let mut resumable = HashSet::new();
+    for task in &in_progress {
+        if is_resumable(pool, task).await? {
+            resumable.insert(task.id);
+            resumable.extend(task.parent_ids()?.unwrap_or_default());
+        }
+    }
+
+    for task in in_progress
+        .iter()
+        .filter(|task| !resumable.contains(&task.id))
+    {
+        debug!("Task #{} can't be resumed, resetting it to `ToDo`", task.id);
+        tasks::update_status(pool, task.company_id, task.id, Status::ToDo).await?;
+    }
 
     Ok(())
 }
 
+/// Whether the leaf task's execution chat ends with a finished message, which the execution can
+/// be continued from. Interrupted completions are failed by then, so they are not resumed.
+async fn is_resumable(pool: &Pool<Postgres>, task: &Task) -> Result<bool> {
+    let Some(chat_id) = task.execution_chat_id else {
+        return Ok(false);
+    };
+    if tasks::get_all_children_count(pool, task.company_id, task).await? > 0 {
+        return Ok(false);
+    }
+
+    Ok(matches!(
+        messages::get_last_message(pool, task.company_id, chat_id).await?,
+        Some(message) if !matches!(message.status, MessageStatus::Writing | MessageStatus::Failed)
+    ))
+}
+
 fn get_pool_size() -> u32 {
     if let Ok(pool_size) = std::env::var("DATABASE_POOL_SIZE") {
         pool_size.parse().unwrap_or(DEFAULT_POOL_SIZE)
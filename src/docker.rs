@@ -1,78 +1,213 @@
 // Copyright 2024 StarfleetAI
 // SPDX-License-Identifier: Apache-2.0
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
+use std::time::Duration;
 
 use anyhow::Context;
 use bollard::models::{ContainerInspectResponse, PortBinding};
 use bollard::{
-    container::{Config, RemoveContainerOptions},
+    container::{Config, LogOutput, RemoveContainerOptions},
     exec::{CreateExecOptions, StartExecResults},
     image::CreateImageOptions,
     secret::HostConfig,
 };
 use futures_util::{StreamExt, TryStreamExt};
 use tokio::sync::OnceCell;
-use tracing::trace;
+use tokio_util::sync::CancellationToken;
+use tracing::{trace, warn};
 
-use crate::types::Result;
+use crate::{secrets::Secrets, settings::Sandbox, types::Result};
 
 const CONTAINER_WORKDIR: &str = "/bridge";
-const DEFAULT_PYTHON_IMAGE: &str = "python:slim";
-const DEFAULT_CHROMEDRIVER_IMAGE: &str = "zenika/alpine-chrome:with-chromedriver";
+/// Exit code of a process killed with `SIGKILL`, which is what the OOM killer sends.
+const KILLED_EXIT_CODE: i64 = 137;
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error(transparent)]
     Bollard(#[from] bollard::errors::Error),
+    #[error("code execution was cancelled")]
+    Cancelled,
+    #[error("code execution was killed, probably for exceeding the memory limit of {0} MB")]
+    Killed(u64),
+    #[error("code execution timed out after {0:?}")]
+    TimedOut(Duration),
 }
 
-/// Run a Python code in a container.
+/// Run a Python code in a container limited by `sandbox`, with the secrets and `env` as
+/// environment variables. The container is removed once `timeout` expires or `cancel` is
+/// cancelled.
 ///
 /// # Errors
 ///
 /// Will return an error if there was a problem while running the code.
+/// Will return an error if the execution was cancelled or timed out.
+/// Will return an error if the code was killed for exceeding the memory limit.
 /// TODO move to `ContainerManager`
-pub async fn run_python_code(script: &str, maybe_workdir: Option<&Path>) -> Result<String> {
+pub async fn run_python_code(
+    script: &str,
+    maybe_workdir: Option<&Path>,
+    secrets: &Secrets,
+    env: Option<&HashMap<String, String>>,
+    sandbox: &Sandbox,
+    timeout: Option<Duration>,
+    cancel: Option<&CancellationToken>,
+) -> Result<ExecOutput> {
     let binds = binds_for(maybe_workdir);
     let cmd = vec!["python", "-c", &script];
 
-    run_in_container(DEFAULT_PYTHON_IMAGE, binds, cmd).await
+    run_in_container(
+        &sandbox.python_image,
+        binds,
+        cmd,
+        secrets,
+        env,
+        sandbox,
+        timeout,
+        cancel,
+    )
+    .await
 }
 
-/// Run a Python script in a container.
+/// Run a JavaScript code with Node.js in a container limited by `sandbox`, with the secrets and
+/// `env` as environment variables. The container is removed once `timeout` expires or `cancel`
+/// is cancelled.
+///
+/// # Errors
+///
+/// Will return an error if there was a problem while running the code.
+/// Will return an error if the execution was cancelled or timed out.
+/// Will return an error if the code was killed for exceeding the memory limit.
+pub async fn run_node_code(
+    script: &str,
+    maybe_workdir: Option<&Path>,
+    secrets: &Secrets,
+    env: Option<&HashMap<String, String>>,
+    sandbox: &Sandbox,
+    timeout: Option<Duration>,
+    cancel: Option<&CancellationToken>,
+) -> Result<ExecOutput> {
+    let binds = binds_for(maybe_workdir);
+    let cmd = vec!["node", "-e", &script];
+
+    run_in_container(
+        &sandbox.node_image,
+        binds,
+        cmd,
+        secrets,
+        env,
+        sandbox,
+        timeout,
+        cancel,
+    )
+    .await
+}
+
+/// Run a Python script in a container limited by `sandbox`, with the secrets and `env` as
+/// environment variables. The container is removed once `timeout` expires.
 ///
 /// # Errors
 ///
 /// Will return an error if there was a problem while running the script.
+/// Will return an error if the script was killed for exceeding the memory limit.
+/// Will return an error if the execution timed out.
 /// TODO move to `ContainerManager`
-pub async fn run_python_script(workdir: &Path, script_name: &str) -> Result<String> {
+pub async fn run_python_script(
+    workdir: &Path,
+    script_name: &str,
+    secrets: &Secrets,
+    env: Option<&HashMap<String, String>>,
+    sandbox: &Sandbox,
+    timeout: Option<Duration>,
+) -> Result<ExecOutput> {
     let binds = binds_for(Some(workdir));
     let script_name = format!("{CONTAINER_WORKDIR}/{script_name}");
     let cmd = vec!["python", &script_name];
 
-    run_in_container(DEFAULT_PYTHON_IMAGE, binds, cmd).await
+    run_in_container(
+        &sandbox.python_image,
+        binds,
+        cmd,
+        secrets,
+        env,
+        sandbox,
+        timeout,
+        None,
+    )
+    .await
 }
 
-/// Run a shell command in a container.
+/// Run a shell command in a container limited by `sandbox`, with the secrets and `env` as
+/// environment variables. The container is removed once `timeout` expires or `cancel` is
+/// cancelled.
 ///
 /// # Errors
 ///
 /// Will return an error if there was a problem while running the command.
-pub async fn run_cmd(cmd: &str, maybe_workdir: Option<&Path>) -> Result<String> {
+/// Will return an error if the execution was cancelled or timed out.
+/// Will return an error if the code was killed for exceeding the memory limit.
+pub async fn run_cmd(
+    cmd: &str,
+    maybe_workdir: Option<&Path>,
+    secrets: &Secrets,
+    env: Option<&HashMap<String, String>>,
+    sandbox: &Sandbox,
+    timeout: Option<Duration>,
+    cancel: Option<&CancellationToken>,
+) -> Result<ExecOutput> {
     let binds = binds_for(maybe_workdir);
     let cmd = vec!["sh", "-c", cmd];
 
-    run_in_container(DEFAULT_PYTHON_IMAGE, binds, cmd).await
+    run_in_container(
+        &sandbox.python_image,
+        binds,
+        cmd,
+        secrets,
+        env,
+        sandbox,
+        timeout,
+        cancel,
+    )
+    .await
+}
+
+/// Output of a code execution.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExecOutput {
+    pub stdout: String,
+    pub stderr: String,
+    /// Exit code of the code, `-1` if Docker didn't report it.
+    pub exit_code: i64,
 }
 
+impl ExecOutput {
+    /// Both streams in one string, stdout first.
+    #[must_use]
+    pub fn combined(&self) -> String {
+        match (self.stdout.is_empty(), self.stderr.is_empty()) {
+            (_, true) => self.stdout.clone(),
+            (true, false) => self.stderr.clone(),
+            (false, false) => format!("{}\n{}", self.stdout, self.stderr),
+        }
+    }
+}
+
+/// Secrets and `env` are passed to the exec only, so they are not kept in the container config.
+/// Secret values are redacted from the output, the `env` ones are not.
 /// TODO move to `ContainerManager`
+#[allow(clippy::too_many_arguments)]
 async fn run_in_container(
     image: &str,
     binds: Option<Vec<String>>,
     cmd: Vec<&str>,
-) -> Result<String> {
+    secrets: &Secrets,
+    env: Option<&HashMap<String, String>>,
+    sandbox: &Sandbox,
+    timeout: Option<Duration>,
+    cancel: Option<&CancellationToken>,
+) -> Result<ExecOutput> {
     let docker = bollard::Docker::connect_with_local_defaults().map_err(Error::Bollard)?;
 
     docker
@@ -90,29 +225,25 @@ async fn run_in_container(
 
     let has_binds = binds.is_some();
 
-    let config = Config {
-        image: Some(image),
-        tty: Some(true),
-        host_config: Some(HostConfig {
-            binds,
-            auto_remove: Some(true),
-            ..Default::default()
-        }),
-        ..Default::default()
-    };
+    let config = container_config(image, binds, sandbox);
 
     let id = docker
         .create_container::<&str, &str>(None, config)
         .await
         .map_err(Error::Bollard)?
         .id;
+    let container = ContainerGuard {
+        docker: docker.clone(),
+        id: Some(id.clone()),
+    };
 
     docker
         .start_container::<String>(&id, None)
         .await
         .map_err(Error::Bollard)?;
 
-    let mut out = String::new();
+    let mut stdout = String::new();
+    let mut stderr = String::new();
 
     // If there were no binds, we should use the default workdir
     let working_dir = if has_binds {
@@ -121,34 +252,123 @@ async fn run_in_container(
         None
     };
 
-    let exec = docker
-        .create_exec(
-            &id,
-            CreateExecOptions {
-                attach_stdout: Some(true),
-                attach_stderr: Some(true),
-                cmd: Some(cmd),
-                working_dir,
-                ..Default::default()
-            },
-        )
-        .await
-        .map_err(Error::Bollard)?
-        .id;
+    let env = exec_env(secrets, env);
+    let env = if env.is_empty() {
+        None
+    } else {
+        Some(env.iter().map(String::as_str).collect())
+    };
 
-    if let StartExecResults::Attached { mut output, .. } = docker
-        .start_exec(&exec, None)
-        .await
-        .map_err(Error::Bollard)?
-    {
-        while let Some(Ok(msg)) = output.next().await {
-            out.push_str(&msg.to_string());
+    let exec = async {
+        let exec = docker
+            .create_exec(
+                &id,
+                CreateExecOptions {
+                    attach_stdout: Some(true),
+                    attach_stderr: Some(true),
+                    cmd: Some(cmd),
+                    working_dir,
+                    env,
+                    ..Default::default()
+                },
+            )
+            .await?
+            .id;
+
+        if let StartExecResults::Attached { mut output, .. } =
+            docker.start_exec(&exec, None).await?
+        {
+            while let Some(Ok(msg)) = output.next().await {
+                match msg {
+                    LogOutput::StdErr { message } => {
+                        stderr.push_str(&String::from_utf8_lossy(&message));
+                    }
+                    LogOutput::StdOut { message } | LogOutput::Console { message } => {
+                        stdout.push_str(&String::from_utf8_lossy(&message));
+                    }
+                    LogOutput::StdIn { .. } => {}
+                }
+            }
         }
+
+        Ok(docker.inspect_exec(&exec).await?.exit_code)
+    };
+
+    let exec = async {
+        match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, exec)
+                .await
+                .map_err(|_| Error::TimedOut(timeout))?
+                .map_err(Error::Bollard),
+            None => exec.await.map_err(Error::Bollard),
+        }
+    };
+
+    let result = match cancel {
+        Some(cancel) => tokio::select! {
+            result = exec => result,
+            () = cancel.cancelled() => Err(Error::Cancelled),
+        },
+        None => exec.await,
+    };
+
+    // The container is removed even if the execution was cancelled or timed out, which also
+    // stops the code.
+    container.remove().await?;
+    let exit_code = result?.unwrap_or(-1);
+    if exit_code == KILLED_EXIT_CODE && sandbox.memory_mb > 0 {
+        return Err(Error::Killed(sandbox.memory_mb).into());
     }
 
+    let output = ExecOutput {
+        stdout: secrets.redact(stdout.trim()),
+        stderr: secrets.redact(stderr.trim()),
+        exit_code,
+    };
+
+    trace!("Script output: {:?}", output);
+
+    Ok(output)
+}
+
+/// Removes the container once dropped, so that the code doesn't keep running when the execution
+/// future is dropped, e.g. on a timeout.
+struct ContainerGuard {
+    docker: bollard::Docker,
+    /// Taken once the container is removed.
+    id: Option<String>,
+}
+
+impl ContainerGuard {
+    async fn remove(mut self) -> Result<()> {
+        match self.id.take() {
+            Some(id) => remove_container(&self.docker, &id).await,
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for ContainerGuard {
+    fn drop(&mut self) {
+        let Some(id) = self.id.take() else {
+            return;
+        };
+        let docker = self.docker.clone();
+
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move {
+                if let Err(err) = remove_container(&docker, &id).await {
+                    warn!("Failed to remove container `{}`: {}", id, err);
+                }
+            });
+        }
+    }
+}
+
+async fn remove_container(docker: &bollard::Docker, id: &str) -> Result<()> {
     docker
         .remove_container(
-            &id,
+            id,
             Some(RemoveContainerOptions {
                 force: true,
                 ..Default::default()
@@ -157,11 +377,69 @@ async fn run_in_container(
         .await
         .map_err(Error::Bollard)?;
 
-    out = out.trim().to_string();
+    Ok(())
+}
+
+/// `NAME=value` pairs of the exec environment. Secrets take precedence over the `env` variables
+/// with the same names.
+fn exec_env(secrets: &Secrets, env: Option<&HashMap<String, String>>) -> Vec<String> {
+    let mut pairs = Vec::new();
+
+    if let Some(env) = env {
+        // Values may be secret, so only the names are logged
+        trace!("Exec environment: {:?}", env.keys());
+
+        let secret_names = secrets.names().collect::<HashSet<_>>();
+        pairs.extend(
+            env.iter()
+                .filter(|(name, _)| !secret_names.contains(name.as_str()))
+                .map(|(name, value)| format!("{name}={value}")),
+        );
+    }
+    pairs.extend(secrets.env());
+
+    pairs
+}
+
+/// Config of a code execution container.
+fn container_config<'a>(
+    image: &'a str,
+    binds: Option<Vec<String>>,
+    sandbox: &Sandbox,
+) -> Config<&'a str> {
+    Config {
+        image: Some(image),
+        tty: Some(true),
+        host_config: Some(HostConfig {
+            binds,
+            auto_remove: Some(true),
+            network_mode: sandbox.network_disabled.then(|| "none".to_string()),
+            ..limited_host_config(sandbox)
+        }),
+        ..Default::default()
+    }
+}
+
+/// Host config which constrains the container's resources to the sandbox limits.
+fn limited_host_config(sandbox: &Sandbox) -> HostConfig {
+    let memory = i64::try_from(sandbox.memory_mb.saturating_mul(1024 * 1024)).unwrap_or(i64::MAX);
+
+    HostConfig {
+        memory: Some(memory),
+        // The same value as the memory disables the swap, which would allow to exceed the limit
+        memory_swap: Some(memory),
+        nano_cpus: nano_cpus(sandbox.cpus),
+        pids_limit: Some(sandbox.pids_limit),
+        ..Default::default()
+    }
+}
 
-    trace!("Script output: {:?}", out);
+/// Converts the number of CPUs to billionths of a CPU, `None` for `0` or an invalid number.
+fn nano_cpus(cpus: f64) -> Option<i64> {
+    // Nanoseconds are billionths as well, and the conversion rejects negative and non-finite values.
+    let nanos = Duration::try_from_secs_f64(cpus).ok()?.as_nanos();
 
-    Ok(out.to_string())
+    i64::try_from(nanos).ok().filter(|nanos| *nanos > 0)
 }
 
 fn binds_for(maybe_workdir: Option<&Path>) -> Option<Vec<String>> {
@@ -193,21 +471,27 @@ impl ContainerManager {
             .await
     }
 
-    /// Function for starting chromedriver container.
+    /// Starts a `WebDriver` container from the image, binding the container port (e.g.
+    /// `9515/tcp`) to a random host port. The container's resources are limited by `sandbox`.
     ///
     /// # Errors
     ///
-    /// Will return an error if there was a problem while starting the chromedriver container.
-    pub async fn launch_chromedriver_container(&self) -> Result<String> {
+    /// Will return an error if there was a problem while starting the container.
+    pub async fn launch_webdriver_container(
+        &self,
+        image: &str,
+        port_key: &str,
+        sandbox: &Sandbox,
+    ) -> Result<String> {
         let container_config = Config {
-            image: Some(DEFAULT_CHROMEDRIVER_IMAGE),
+            image: Some(image),
             tty: Some(true),
             host_config: Some(HostConfig {
                 auto_remove: Some(true),
                 port_bindings: {
                     let mut map = HashMap::with_capacity(1);
                     map.insert(
-                        "9515/tcp".to_string(),
+                        port_key.to_string(),
                         Some(vec![PortBinding {
                             host_ip: None,
                             host_port: Some(String::new()),
@@ -215,7 +499,7 @@ impl ContainerManager {
                     );
                     Some(map)
                 },
-                ..Default::default()
+                ..limited_host_config(sandbox)
             }),
             ..Default::default()
         };
@@ -262,3 +546,138 @@ impl ContainerManager {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_container_config() {
+        let sandbox = Sandbox {
+            python_image: "registry.local/python:3.12-pandas".to_string(),
+            ..Default::default()
+        };
+
+        let config = container_config(&sandbox.python_image, None, &sandbox);
+
+        assert_eq!(config.image, Some("registry.local/python:3.12-pandas"));
+        // The environment is passed to the exec instead.
+        assert_eq!(config.env, None);
+        let env = HashMap::from([("A".to_string(), "1".to_string())]);
+        assert_eq!(exec_env(&Secrets::default(), Some(&env)), vec!["A=1"]);
+        let host_config = config.host_config.unwrap();
+        assert_eq!(host_config.network_mode.as_deref(), Some("none"));
+        assert_eq!(host_config.memory, Some(512 * 1024 * 1024));
+    }
+
+    #[test]
+    fn test_nano_cpus() {
+        assert_eq!(nano_cpus(1.5), Some(1_500_000_000));
+        assert_eq!(nano_cpus(0.0), None);
+        assert_eq!(nano_cpus(-1.0), None);
+        assert_eq!(nano_cpus(f64::NAN), None);
+        assert_eq!(nano_cpus(f64::INFINITY), None);
+    }
+
+    #[test]
+    fn test_combined_output() {
+        let output = |stdout: &str, stderr: &str| ExecOutput {
+            stdout: stdout.to_string(),
+            stderr: stderr.to_string(),
+            exit_code: 0,
+        };
+
+        assert_eq!(output("out", "").combined(), "out");
+        assert_eq!(output("", "err").combined(), "err");
+        assert_eq!(output("out", "err").combined(), "out\nerr");
+        assert_eq!(output("", "").combined(), "");
+    }
+
+    #[tokio::test]
+    #[ignore = "requires Docker to run the code"]
+    async fn test_code_exceeding_memory_limit_is_killed() {
+        let sandbox = Sandbox {
+            memory_mb: 64,
+            ..Default::default()
+        };
+
+        let result = run_python_code(
+            "data = bytearray(1024 * 1024 * 1024)",
+            None,
+            &Secrets::default(),
+            None,
+            &sandbox,
+            None,
+            None,
+        )
+        .await;
+
+        assert!(matches!(
+            result,
+            Err(crate::errors::Error::Docker(Error::Killed(64)))
+        ));
+    }
+
+    #[tokio::test]
+    #[ignore = "requires Docker to run the code"]
+    async fn test_sleeping_command_times_out() {
+        let timeout = Duration::from_secs(1);
+
+        let result = run_cmd(
+            "sleep 99999",
+            None,
+            &Secrets::default(),
+            None,
+            &Sandbox::default(),
+            Some(timeout),
+            None,
+        )
+        .await;
+
+        assert!(matches!(
+            result,
+            Err(crate::errors::Error::Docker(Error::TimedOut(t))) if t == timeout
+        ));
+    }
+
+    #[tokio::test]
+    #[ignore = "requires Docker to run the code"]
+    async fn test_network_is_disabled() {
+        let script = "import urllib.request; urllib.request.urlopen('https://example.com')";
+
+        let output = run_python_code(
+            script,
+            None,
+            &Secrets::default(),
+            None,
+            &Sandbox::default(),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_ne!(output.exit_code, 0);
+        assert!(output.stderr.contains("URLError"));
+    }
+
+    #[tokio::test]
+    #[ignore = "requires Docker to run the code"]
+    async fn test_env_is_visible_to_code() {
+        let env = HashMap::from([("API_URL".to_string(), "http://api.local".to_string())]);
+
+        let output = run_python_code(
+            "import os; print(os.environ['API_URL'])",
+            None,
+            &Secrets::default(),
+            Some(&env),
+            &Sandbox::default(),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(output.stdout, "http://api.local");
+    }
+}
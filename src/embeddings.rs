@@ -2,7 +2,10 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::collections::HashMap;
+use std::fmt;
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock};
 
 use anyhow::Context;
 use candle_core::{
@@ -12,8 +15,12 @@ use candle_core::{
 use candle_nn::VarBuilder;
 use candle_transformers::models::bert::{BertModel, Config, DTYPE};
 use hf_hub::{api::tokio::Api, Repo, RepoType};
+use lru::LruCache;
 use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use tokenizers::{PaddingParams, Tokenizer};
+use tokio::sync::OnceCell;
 use tracing::{debug, error, info, instrument};
 
 use crate::types::Result;
@@ -48,37 +55,426 @@ pub enum Error {
     RegexInit(#[from] regex::Error),
     #[error("error reading config file: {0}")]
     ConfigRead(std::io::Error),
+    #[error("embedding dimension mismatch: expected {expected}, got {got}")]
+    DimensionMismatch { expected: usize, got: usize },
+    #[error("{0} is not supported by this build, enable the corresponding feature")]
+    DeviceNotSupported(DevicePref),
+    #[error("{device} is not available: {source}")]
+    DeviceUnavailable {
+        device: DevicePref,
+        source: candle_core::Error,
+    },
 }
 
+/// Device to compute the embeddings on.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DevicePref {
+    /// The first CUDA GPU, then the first Metal GPU, then the CPU, whichever is available.
+    #[default]
+    Auto,
+    Cpu,
+    /// CUDA GPU with the given index.
+    Cuda(usize),
+    /// Metal GPU with the given index.
+    Metal(usize),
+}
+
+/// How the token embeddings are pooled into the sentence embedding.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Pooling {
+    /// Mean of all the tokens, including the padding ones.
+    #[default]
+    Mean,
+    /// Mean of the tokens, excluding the padding ones.
+    MeanNoPad,
+    /// Embedding of the first, `[CLS]`, token. For the models trained with CLS pooling.
+    Cls,
+}
+
+impl fmt::Display for DevicePref {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Auto => write!(f, "auto-detected device"),
+            Self::Cpu => write!(f, "CPU"),
+            Self::Cuda(index) => write!(f, "CUDA device {index}"),
+            Self::Metal(index) => write!(f, "Metal device {index}"),
+        }
+    }
+}
+
+/// Downloads the file from the Hugging Face Hub repo, or gets it from the local cache.
+///
+/// # Errors
+///
+/// Will return an error if the file can't be downloaded.
+pub(crate) async fn hub_file(repo: Repo, filename: &str) -> Result<PathBuf> {
+    let api = Api::new().map_err(Error::HfHubApi)?;
+
+    Ok(api
+        .repo(repo)
+        .get(filename)
+        .await
+        .map_err(Error::HfHubApi)?)
+}
+
+/// Loads the repo's tokenizer.
+///
+/// # Errors
+///
+/// Will return an error if the tokenizer can't be downloaded or parsed.
+pub(crate) async fn hub_tokenizer(repo_id: &str) -> Result<Tokenizer> {
+    let filename = hub_file(
+        Repo::new(repo_id.to_string(), RepoType::Model),
+        TOKENIZER_FILENAME,
+    )
+    .await?;
+
+    Ok(Tokenizer::from_file(filename).map_err(Error::Tokenizer)?)
+}
+
+/// Computes sentence embeddings.
+pub trait Embedder {
+    /// Name of the model used to compute embeddings. Used as a cache key.
+    fn model_name(&self) -> &str;
+
+    /// Revision of the model, the default one if `None`. Used as a cache key.
+    fn revision(&self) -> Option<&str> {
+        None
+    }
+
+    /// How the token embeddings are pooled. Used as a cache key.
+    fn pooling(&self) -> Pooling {
+        Pooling::default()
+    }
+
+    /// Number of dimensions of the computed embeddings.
+    fn dimension(&self) -> usize;
+
+    /// Embeds a list of sentences.
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if the embeddings can't be generated.
+    fn embed_sentences<'a>(&self, sentences: Vec<&'a str>) -> Result<HashMap<&'a str, Vec<f32>>>;
+
+    /// Splits a piece of text into chunks, which are embedded separately.
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if the text can't be split.
+    fn split<'a>(&'a self, text: &'a str) -> Result<Vec<&'a str>> {
+        Ok(vec![text])
+    }
+
+    /// Embeds a piece of text, possibly splitting it into multiple chunks.
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if the text can't be embedded.
+    fn embed<'a>(&'a self, text: &'a str) -> Result<HashMap<&'a str, Vec<f32>>> {
+        self.embed_sentences(self.split(text)?)
+    }
+
+    /// Similarity of two embeddings computed by this embedder, from `-1.0` to `1.0`.
+    fn similarity(&self, a: &[f32], b: &[f32]) -> f32 {
+        cosine_similarity(a, b)
+    }
+
+    /// Ranks the candidates by their similarity to the query, most similar first. Returns the
+    /// indices of the candidates along with their similarity scores.
+    ///
+    /// The query and the candidates are embedded whole, without splitting.
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if the texts can't be embedded.
+    fn rank_by_similarity(&self, query: &str, candidates: &[&str]) -> Result<Vec<(usize, f32)>> {
+        let mut sentences = Vec::with_capacity(candidates.len() + 1);
+        sentences.push(query);
+        sentences.extend_from_slice(candidates);
+
+        let embeddings = self.embed_sentences(sentences)?;
+        let query_embedding = embeddings
+            .get(query)
+            .context("Query embedding is missing")?;
+
+        let mut ranking = candidates
+            .iter()
+            .enumerate()
+            .map(|(index, candidate)| {
+                let embedding = embeddings
+                    .get(candidate)
+                    .with_context(|| format!("Embedding of candidate {index} is missing"))?;
+
+                Ok((index, self.similarity(query_embedding, embedding)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        ranking.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+
+        Ok(ranking)
+    }
+}
+
+impl<E: Embedder + ?Sized> Embedder for Arc<E> {
+    fn model_name(&self) -> &str {
+        (**self).model_name()
+    }
+
+    fn revision(&self) -> Option<&str> {
+        (**self).revision()
+    }
+
+    fn pooling(&self) -> Pooling {
+        (**self).pooling()
+    }
+
+    fn dimension(&self) -> usize {
+        (**self).dimension()
+    }
+
+    fn embed_sentences<'a>(&self, sentences: Vec<&'a str>) -> Result<HashMap<&'a str, Vec<f32>>> {
+        (**self).embed_sentences(sentences)
+    }
+
+    fn split<'a>(&'a self, text: &'a str) -> Result<Vec<&'a str>> {
+        (**self).split(text)
+    }
+
+    fn similarity(&self, a: &[f32], b: &[f32]) -> f32 {
+        (**self).similarity(a, b)
+    }
+}
+
+/// Cache key of an embedding. Only the hash of the text is kept.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct EmbeddingKey {
+    pub model_name: String,
+    pub revision: Option<String>,
+    pub pooling: Pooling,
+    pub text_hash: [u8; 32],
+}
+
+impl EmbeddingKey {
+    /// Key of the text embedded by the embedder.
+    #[must_use]
+    pub fn new<E: Embedder + ?Sized>(embedder: &E, text: &str) -> Self {
+        Self {
+            model_name: embedder.model_name().to_string(),
+            revision: embedder.revision().map(str::to_string),
+            pooling: embedder.pooling(),
+            text_hash: Sha256::digest(text.as_bytes()).into(),
+        }
+    }
+}
+
+/// Storage for the computed embeddings.
+pub trait EmbeddingsCache: Send + Sync {
+    fn get(&self, key: &EmbeddingKey) -> Option<Vec<f32>>;
+
+    fn put(&self, key: EmbeddingKey, embedding: Vec<f32>);
+}
+
+/// Keeps the most recently used embeddings in memory.
+pub struct LruEmbeddingsCache {
+    entries: Mutex<LruCache<EmbeddingKey, Vec<f32>>>,
+}
+
+impl LruEmbeddingsCache {
+    /// Creates a cache holding up to `capacity` embeddings, but at least one.
+    #[must_use]
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: Mutex::new(LruCache::new(
+                NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN),
+            )),
+        }
+    }
+
+    /// Creates a cache of the configured size.
+    #[must_use]
+    pub fn from_settings(settings: &crate::settings::Embeddings) -> Self {
+        Self::new(settings.cache_size)
+    }
+
+    /// Returns the process-wide cache, created from the settings on the first call.
+    pub fn shared(settings: &crate::settings::Embeddings) -> Arc<Self> {
+        SHARED_EMBEDDINGS_CACHE
+            .get_or_init(|| Arc::new(Self::from_settings(settings)))
+            .clone()
+    }
+}
+
+impl EmbeddingsCache for LruEmbeddingsCache {
+    fn get(&self, key: &EmbeddingKey) -> Option<Vec<f32>> {
+        self.entries
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .get(key)
+            .cloned()
+    }
+
+    fn put(&self, key: EmbeddingKey, embedding: Vec<f32>) {
+        self.entries
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .put(key, embedding);
+    }
+}
+
+/// Embedder looking up the embeddings in the cache before computing them.
+///
+/// The cache can be shared between embedders, as the keys include the model name, revision and
+/// pooling.
+pub struct CachedEmbedder<E> {
+    inner: E,
+    cache: Arc<dyn EmbeddingsCache>,
+}
+
+impl<E: Embedder> CachedEmbedder<E> {
+    pub fn new(inner: E, cache: Arc<dyn EmbeddingsCache>) -> Self {
+        Self { inner, cache }
+    }
+}
+
+impl<E: Embedder> Embedder for CachedEmbedder<E> {
+    fn model_name(&self) -> &str {
+        self.inner.model_name()
+    }
+
+    fn revision(&self) -> Option<&str> {
+        self.inner.revision()
+    }
+
+    fn pooling(&self) -> Pooling {
+        self.inner.pooling()
+    }
+
+    fn dimension(&self) -> usize {
+        self.inner.dimension()
+    }
+
+    fn similarity(&self, a: &[f32], b: &[f32]) -> f32 {
+        self.inner.similarity(a, b)
+    }
+
+    fn embed_sentences<'a>(&self, sentences: Vec<&'a str>) -> Result<HashMap<&'a str, Vec<f32>>> {
+        let mut results = HashMap::with_capacity(sentences.len());
+        let mut missing = Vec::new();
+
+        for sentence in sentences {
+            if results.contains_key(sentence) {
+                continue;
+            }
+
+            match self.cache.get(&EmbeddingKey::new(self, sentence)) {
+                Some(embedding) => {
+                    results.insert(sentence, embedding);
+                }
+                None => missing.push(sentence),
+            }
+        }
+
+        debug!(
+            "Embeddings cache hits: {}, misses: {}",
+            results.len(),
+            missing.len()
+        );
+
+        if !missing.is_empty() {
+            for (sentence, embedding) in self.inner.embed_sentences(missing)? {
+                self.cache
+                    .put(EmbeddingKey::new(self, sentence), embedding.clone());
+                results.insert(sentence, embedding);
+            }
+        }
+
+        Ok(results)
+    }
+
+    fn split<'a>(&'a self, text: &'a str) -> Result<Vec<&'a str>> {
+        self.inner.split(text)
+    }
+}
+
+/// Parameters the shared embeddings were initialized with.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct SharedEmbeddingsKey {
+    model_name: String,
+    revision: Option<String>,
+    max_length: usize,
+    batch_size: NonZeroUsize,
+    pooling: Pooling,
+    device: DevicePref,
+}
+
+/// Embeddings shared by [`Embeddings::shared`]. Every key gets its own cell, so that models are
+/// loaded once even when requested concurrently, without blocking the loading of other models.
+type SharedEmbeddings = Mutex<HashMap<SharedEmbeddingsKey, Arc<OnceCell<Arc<Embeddings>>>>>;
+
+static SHARED_EMBEDDINGS: OnceLock<SharedEmbeddings> = OnceLock::new();
+
+static SHARED_EMBEDDINGS_CACHE: OnceLock<Arc<LruEmbeddingsCache>> = OnceLock::new();
+
 pub struct Embeddings {
     pub model_name: String,
+    /// Branch, tag or commit SHA of the model repo, `main` if not set.
+    pub revision: Option<String>,
     pub max_length: usize,
+    pub dimension: usize,
+    /// Number of sentences embedded at once.
+    pub batch_size: NonZeroUsize,
+    pub pooling: Pooling,
     device: Device,
     model: BertModel,
     tokenizer: Tokenizer,
 }
 
 impl Embeddings {
-    /// Initializes the embeddings model.
+    /// Initializes the embeddings model on the given device, embedding up to `batch_size`
+    /// sentences at once and pooling their tokens with `pooling`.
+    ///
+    /// `revision` is a branch, tag or commit SHA of the model repo to download, `main` if not set.
+    /// Pin a commit to get the same model on every build.
     ///
     /// # Errors
     ///
+    /// Will return an error if the requested device is not available.
     /// Will return an error if the model can't be initialized.
-    pub async fn init(model_name: String, max_length: usize) -> Result<Self> {
-        let device = Self::device()?;
+    pub async fn init(
+        model_name: String,
+        revision: Option<String>,
+        max_length: usize,
+        batch_size: NonZeroUsize,
+        pooling: Pooling,
+        device: DevicePref,
+    ) -> Result<Self> {
+        let device = Self::device(device)?;
         info!(
-            "Initializing embeddings with model: `{}` on device: `{:?}`",
-            model_name, device
+            "Initializing embeddings with model: `{}` at revision: `{}` on device: `{:?}`",
+            model_name,
+            revision.as_deref().unwrap_or("main"),
+            device
         );
 
-        // TODO: support revisions via the `Repo::with_revision`
-        let repo = Repo::new(model_name.clone(), RepoType::Model);
+        let repo = match revision.clone() {
+            Some(revision) => Repo::with_revision(model_name.clone(), RepoType::Model, revision),
+            None => Repo::new(model_name.clone(), RepoType::Model),
+        };
 
         let (config_filename, tokenizer_filename, weights_filename) =
             Self::model_files(repo).await?;
 
         let config = std::fs::read_to_string(config_filename).map_err(Error::ConfigRead)?;
-        let config: Config = serde_json::from_str(&config).context("Failed to parse config")?;
+        let config_json: serde_json::Value =
+            serde_json::from_str(&config).context("Failed to parse config")?;
+        let dimension = config_json["hidden_size"]
+            .as_u64()
+            .and_then(|size| usize::try_from(size).ok())
+            .context("Failed to get `hidden_size` from config")?;
+        let config: Config =
+            serde_json::from_value(config_json).context("Failed to parse config")?;
 
         let mut tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(Error::Tokenizer)?;
 
@@ -105,13 +501,93 @@ impl Embeddings {
 
         Ok(Self {
             model_name,
+            revision,
             max_length,
+            dimension,
+            batch_size,
+            pooling,
             device,
             model,
             tokenizer,
         })
     }
 
+    /// Returns the embeddings initialized with the same parameters before, initializing them
+    /// with [`Embeddings::init`] on the first call. The model is loaded once per process, and
+    /// is safe to share between threads.
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if the model can't be initialized. The next call retries then.
+    pub async fn shared(
+        model_name: String,
+        revision: Option<String>,
+        max_length: usize,
+        batch_size: NonZeroUsize,
+        pooling: Pooling,
+        device: DevicePref,
+    ) -> Result<Arc<Self>> {
+        let key = SharedEmbeddingsKey {
+            model_name,
+            revision,
+            max_length,
+            batch_size,
+            pooling,
+            device,
+        };
+
+        let cell = SHARED_EMBEDDINGS
+            .get_or_init(Mutex::default)
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .entry(key.clone())
+            .or_default()
+            .clone();
+
+        let embeddings = cell
+            .get_or_try_init(|| async {
+                Self::init(
+                    key.model_name,
+                    key.revision,
+                    key.max_length,
+                    key.batch_size,
+                    key.pooling,
+                    key.device,
+                )
+                .await
+                .map(Arc::new)
+            })
+            .await?;
+
+        Ok(embeddings.clone())
+    }
+
+    /// Returns the shared embeddings configured by the settings, looking up the computed
+    /// embeddings in the process-wide [`LruEmbeddingsCache::shared`] cache.
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if the model can't be initialized.
+    pub async fn shared_cached(
+        settings: &crate::settings::Embeddings,
+        max_length: usize,
+    ) -> Result<CachedEmbedder<Arc<Self>>> {
+        let embeddings = Self::shared(
+            settings.model.clone(),
+            settings.revision.clone(),
+            max_length,
+            settings.batch_size,
+            settings.pooling,
+            settings.device,
+        )
+        .await?;
+
+        Ok(CachedEmbedder::new(
+            embeddings,
+            LruEmbeddingsCache::shared(settings),
+        ))
+    }
+
     /// Embeds a piece of text.
     ///
     /// # Errors
@@ -136,24 +612,18 @@ impl Embeddings {
 
         let mut results: HashMap<_, _> = HashMap::new();
 
-        // TODO: Configure `chunk_size` via [`Settings`]
-        for chunk in sentences.chunks(24) {
-            let token_ids = self.tokenize_batch(chunk)?;
+        for chunk in sentences.chunks(self.batch_size.get()) {
+            let (token_ids, attention_mask) = self.tokenize_batch(chunk)?;
             let token_type_ids = token_ids.zeros_like().map_err(Error::Candle)?;
 
+            // TODO: pass the attention mask to `forward` once `candle` supports it for BERT, so
+            //       that the padding doesn't affect the token embeddings either
             let embeddings = self
                 .model
                 .forward(&token_ids, &token_type_ids)
                 .map_err(Error::Candle)?;
 
-            // Apply some avg-pooling by taking the mean embedding value for all tokens (including padding)
-            let (_n_sentences, n_tokens, _hidden_size) =
-                embeddings.dims3().map_err(Error::Candle)?;
-
-            #[allow(clippy::cast_precision_loss)]
-            let embeddings = (embeddings.sum(1).map_err(Error::Candle)? / (n_tokens as f64))
-                .map_err(Error::Candle)?;
-
+            let embeddings = pool(&embeddings, &attention_mask, self.pooling)?;
             let embeddings = Self::normalize_l2(&embeddings)?;
 
             for (i, sentence) in chunk.iter().enumerate() {
@@ -232,20 +702,33 @@ impl Embeddings {
         Ok(token_ids)
     }
 
-    fn tokenize_batch(&self, sentences: &[&str]) -> Result<Tensor> {
-        let tokens = self
+    /// Tokenizes the sentences, returns the token IDs and the attention mask.
+    fn tokenize_batch(&self, sentences: &[&str]) -> Result<(Tensor, Tensor)> {
+        let encodings = self
             .tokenizer
             .encode_batch(sentences.to_vec(), true)
             .map_err(Error::Tokenizer)?;
-        let token_ids = tokens
-            .iter()
-            .map(|tokens| {
-                let tokens = tokens.get_ids().to_vec();
-                Ok(Tensor::new(tokens.as_slice(), &self.device).map_err(Error::Candle)?)
-            })
-            .collect::<Result<Vec<_>>>()?;
 
-        Ok(Tensor::stack(&token_ids, 0).map_err(Error::Candle)?)
+        let stack = |rows: Vec<&[u32]>| -> Result<Tensor> {
+            let rows = rows
+                .into_iter()
+                .map(|row| Ok(Tensor::new(row, &self.device).map_err(Error::Candle)?))
+                .collect::<Result<Vec<_>>>()?;
+
+            Ok(Tensor::stack(&rows, 0).map_err(Error::Candle)?)
+        };
+
+        let token_ids = stack(encodings.iter().map(|e| e.get_ids()).collect())?;
+        let attention_mask = stack(encodings.iter().map(|e| e.get_attention_mask()).collect())?;
+
+        Ok((token_ids, attention_mask))
+    }
+
+    /// Dot product of two embeddings computed by this model. The embeddings are L2-normalized,
+    /// so it equals their [`cosine_similarity`], without computing the norms.
+    #[must_use]
+    pub fn dot(a: &[f32], b: &[f32]) -> f32 {
+        a.iter().zip(b).map(|(a, b)| a * b).sum()
     }
 
     fn normalize_l2(v: &Tensor) -> Result<Tensor> {
@@ -261,22 +744,354 @@ impl Embeddings {
     }
 
     async fn model_files(repo: Repo) -> Result<(PathBuf, PathBuf, PathBuf)> {
-        let api = Api::new().map_err(Error::HfHubApi)?;
-        let api = api.repo(repo);
-        let config = api.get(CONFIG_FILENAME).await.map_err(Error::HfHubApi)?;
-        let tokenizer = api.get(TOKENIZER_FILENAME).await.map_err(Error::HfHubApi)?;
-        let weights = api.get(WEIGHTS_FILENAME).await.map_err(Error::HfHubApi)?;
+        let config = hub_file(repo.clone(), CONFIG_FILENAME).await?;
+        let tokenizer = hub_file(repo.clone(), TOKENIZER_FILENAME).await?;
+        let weights = hub_file(repo, WEIGHTS_FILENAME).await?;
 
         Ok((config, tokenizer, weights))
     }
 
-    fn device() -> Result<Device> {
-        if cuda_is_available() {
-            Ok(Device::new_cuda(0).map_err(Error::Candle)?)
-        } else if metal_is_available() {
-            Ok(Device::new_metal(0).map_err(Error::Candle)?)
-        } else {
-            Ok(Device::Cpu)
+    /// Creates the preferred device. Devices requested explicitly are never substituted.
+    fn device(pref: DevicePref) -> std::result::Result<Device, Error> {
+        match pref {
+            DevicePref::Auto => {
+                if cuda_is_available() {
+                    Device::new_cuda(0).map_err(Error::Candle)
+                } else if metal_is_available() {
+                    Device::new_metal(0).map_err(Error::Candle)
+                } else {
+                    Ok(Device::Cpu)
+                }
+            }
+            DevicePref::Cpu => Ok(Device::Cpu),
+            DevicePref::Cuda(index) => {
+                if !cuda_is_available() {
+                    return Err(Error::DeviceNotSupported(pref));
+                }
+
+                Device::new_cuda(index).map_err(|source| Error::DeviceUnavailable {
+                    device: pref,
+                    source,
+                })
+            }
+            DevicePref::Metal(index) => {
+                if !metal_is_available() {
+                    return Err(Error::DeviceNotSupported(pref));
+                }
+
+                Device::new_metal(index).map_err(|source| Error::DeviceUnavailable {
+                    device: pref,
+                    source,
+                })
+            }
+        }
+    }
+}
+
+impl Embedder for Embeddings {
+    fn model_name(&self) -> &str {
+        &self.model_name
+    }
+
+    fn revision(&self) -> Option<&str> {
+        self.revision.as_deref()
+    }
+
+    fn pooling(&self) -> Pooling {
+        self.pooling
+    }
+
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn similarity(&self, a: &[f32], b: &[f32]) -> f32 {
+        Self::dot(a, b)
+    }
+
+    fn embed_sentences<'a>(&self, sentences: Vec<&'a str>) -> Result<HashMap<&'a str, Vec<f32>>> {
+        Embeddings::embed_sentences(self, sentences)
+    }
+
+    fn split<'a>(&'a self, text: &'a str) -> Result<Vec<&'a str>> {
+        self.split_text(text, 0)
+    }
+}
+
+/// Pools the token embeddings of shape `(sentences, tokens, hidden)` into the sentence
+/// embeddings of shape `(sentences, hidden)`. The attention mask of shape `(sentences, tokens)`
+/// has zeros for the padding tokens.
+fn pool(
+    embeddings: &Tensor,
+    attention_mask: &Tensor,
+    pooling: Pooling,
+) -> std::result::Result<Tensor, Error> {
+    match pooling {
+        Pooling::Mean => {
+            let (_n_sentences, n_tokens, _hidden_size) = embeddings.dims3()?;
+
+            #[allow(clippy::cast_precision_loss)]
+            Ok((embeddings.sum(1)? / (n_tokens as f64))?)
         }
+        Pooling::MeanNoPad => {
+            let mask = attention_mask.to_dtype(embeddings.dtype())?;
+            let sum = embeddings.broadcast_mul(&mask.unsqueeze(2)?)?.sum(1)?;
+
+            Ok(sum.broadcast_div(&mask.sum_keepdim(1)?)?)
+        }
+        Pooling::Cls => Ok(embeddings.narrow(1, 0, 1)?.squeeze(1)?),
+    }
+}
+
+/// Checks that the embedding has the expected number of dimensions.
+///
+/// # Errors
+///
+/// Returns error if the embedding dimension differs from the expected one.
+pub fn check_dimension(embedding: &[f32], expected: usize) -> std::result::Result<(), Error> {
+    if embedding.len() != expected {
+        return Err(Error::DimensionMismatch {
+            expected,
+            got: embedding.len(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Computes cosine similarity between two embeddings.
+///
+/// Returns `0.0` if any of the embeddings has zero length.
+#[must_use]
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(a, b)| a * b).sum();
+    let norm_a = a.iter().map(|a| a * a).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|b| b * b).sum::<f32>().sqrt();
+
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+
+    dot / (norm_a * norm_b)
+}
+
+/// Embeds sentences as bag-of-words vectors over a tiny vocabulary.
+#[cfg(test)]
+pub(crate) struct KeywordEmbedder {
+    pub model_name: String,
+    pub pooling: Pooling,
+    /// Number of sentences embedded so far.
+    pub embedded: std::sync::atomic::AtomicUsize,
+}
+
+#[cfg(test)]
+impl KeywordEmbedder {
+    const VOCABULARY: [&'static str; 6] =
+        ["weather", "rain", "forecast", "python", "script", "lunch"];
+
+    pub fn new(model_name: &str) -> Self {
+        Self {
+            model_name: model_name.to_string(),
+            pooling: Pooling::default(),
+            embedded: std::sync::atomic::AtomicUsize::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Default for KeywordEmbedder {
+    fn default() -> Self {
+        Self::new("keywords")
+    }
+}
+
+#[cfg(test)]
+impl Embedder for KeywordEmbedder {
+    fn model_name(&self) -> &str {
+        &self.model_name
+    }
+
+    fn pooling(&self) -> Pooling {
+        self.pooling
+    }
+
+    fn dimension(&self) -> usize {
+        Self::VOCABULARY.len()
+    }
+
+    fn embed_sentences<'a>(&self, sentences: Vec<&'a str>) -> Result<HashMap<&'a str, Vec<f32>>> {
+        self.embedded
+            .fetch_add(sentences.len(), std::sync::atomic::Ordering::SeqCst);
+
+        Ok(sentences
+            .into_iter()
+            .map(|sentence| {
+                let lowercase = sentence.to_lowercase();
+                let embedding = Self::VOCABULARY
+                    .iter()
+                    .map(|word| if lowercase.contains(word) { 1.0 } else { 0.0 })
+                    .collect();
+
+                (sentence, embedding)
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use super::*;
+
+    #[test]
+    fn test_cached_embedder_skips_computing_cached_texts() {
+        let cache: Arc<dyn EmbeddingsCache> = Arc::new(LruEmbeddingsCache::new(16));
+        let embedder = CachedEmbedder::new(KeywordEmbedder::default(), cache.clone());
+
+        let first = embedder.embed("Rain is in the forecast").unwrap();
+        let second = embedder.embed("Rain is in the forecast").unwrap();
+
+        assert_eq!(second, first);
+        assert_eq!(embedder.inner.embedded.load(Ordering::SeqCst), 1);
+
+        embedder.embed("Write a python script").unwrap();
+        assert_eq!(embedder.inner.embedded.load(Ordering::SeqCst), 2);
+
+        // Same text embedded by another model is not a hit.
+        let other = CachedEmbedder::new(KeywordEmbedder::new("other-keywords"), cache.clone());
+        other.embed("Rain is in the forecast").unwrap();
+        assert_eq!(other.inner.embedded.load(Ordering::SeqCst), 1);
+
+        // Neither is the one pooled differently by the same model.
+        let pooled = KeywordEmbedder {
+            pooling: Pooling::Cls,
+            ..KeywordEmbedder::default()
+        };
+        let other = CachedEmbedder::new(pooled, cache);
+        other.embed("Rain is in the forecast").unwrap();
+        assert_eq!(other.inner.embedded.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_shared_cache_is_reused_by_shared_embedders() {
+        let settings = crate::settings::Embeddings::default();
+        let inner = Arc::new(KeywordEmbedder::new("shared-cache-keywords"));
+
+        let first = CachedEmbedder::new(inner.clone(), LruEmbeddingsCache::shared(&settings));
+        first.embed("Rain is in the forecast").unwrap();
+        let second = CachedEmbedder::new(inner.clone(), LruEmbeddingsCache::shared(&settings));
+        second.embed("Rain is in the forecast").unwrap();
+
+        assert_eq!(inner.embedded.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_rank_by_similarity() {
+        let embedder = KeywordEmbedder::default();
+        let candidates = [
+            "Let's grab lunch",
+            "Heavy rain is in the weather forecast",
+            "The python script failed",
+        ];
+
+        let ranking = embedder
+            .rank_by_similarity("Will it rain? Check the forecast", &candidates)
+            .unwrap();
+
+        let indices = ranking.iter().map(|(index, _)| *index).collect::<Vec<_>>();
+        assert_eq!(indices[0], 1);
+        assert_eq!(indices.len(), candidates.len());
+        assert!(ranking.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+        assert!(ranking[0].1 > 0.8);
+
+        let ranking = embedder
+            .rank_by_similarity("Rerun the python script", &candidates)
+            .unwrap();
+        assert_eq!(ranking[0].0, 2);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires downloading the model from the Hugging Face Hub"]
+    async fn test_shared_embeddings_are_loaded_once() {
+        let shared = || {
+            Embeddings::shared(
+                "sentence-transformers/all-MiniLM-L6-v2".to_string(),
+                None,
+                256,
+                NonZeroUsize::MIN,
+                Pooling::default(),
+                DevicePref::Cpu,
+            )
+        };
+
+        let (first, second) = tokio::join!(shared(), shared());
+        let (first, second) = (first.unwrap(), second.unwrap());
+        assert!(Arc::ptr_eq(&first, &second));
+
+        let other = Embeddings::shared(
+            "sentence-transformers/all-MiniLM-L6-v2".to_string(),
+            None,
+            128,
+            NonZeroUsize::MIN,
+            Pooling::default(),
+            DevicePref::Cpu,
+        )
+        .await
+        .unwrap();
+        assert!(!Arc::ptr_eq(&first, &other));
+    }
+
+    #[test]
+    fn test_pool() {
+        // Two sentences of two-dimensional tokens, the first one has a padding token.
+        let embeddings = Tensor::new(
+            &[
+                [[1f32, 2.], [3., 4.], [8., 9.]],
+                [[2., 0.], [4., 2.], [6., 4.]],
+            ],
+            &Device::Cpu,
+        )
+        .unwrap();
+        let attention_mask = Tensor::new(&[[1u32, 1, 0], [1, 1, 1]], &Device::Cpu).unwrap();
+
+        let pooled = |pooling| {
+            pool(&embeddings, &attention_mask, pooling)
+                .unwrap()
+                .to_vec2::<f32>()
+                .unwrap()
+        };
+
+        assert_eq!(pooled(Pooling::Mean), [[4., 5.], [4., 2.]]);
+        assert_eq!(pooled(Pooling::MeanNoPad), [[2., 3.], [4., 2.]]);
+        assert_eq!(pooled(Pooling::Cls), [[1., 2.], [2., 0.]]);
+    }
+
+    #[test]
+    fn test_explicit_device_is_not_substituted() {
+        assert!(matches!(
+            Embeddings::device(DevicePref::Cpu),
+            Ok(Device::Cpu)
+        ));
+
+        #[cfg(not(feature = "cuda"))]
+        assert!(matches!(
+            Embeddings::device(DevicePref::Cuda(1)),
+            Err(Error::DeviceNotSupported(DevicePref::Cuda(1)))
+        ));
+        #[cfg(not(feature = "metal"))]
+        assert!(matches!(
+            Embeddings::device(DevicePref::Metal(0)),
+            Err(Error::DeviceNotSupported(DevicePref::Metal(0)))
+        ));
+    }
+
+    #[test]
+    fn test_dot_of_normalized_embeddings() {
+        let a = [0.6, 0.8, 0.0];
+        let b = [0.0, 0.8, 0.6];
+
+        assert!((Embeddings::dot(&a, &b) - cosine_similarity(&a, &b)).abs() < 1e-6);
+        assert!((Embeddings::dot(&a, &a) - 1.0).abs() < 1e-6);
     }
 }
@@ -12,14 +12,22 @@ pub enum Error {
     #[error(transparent)]
     Abilities(#[from] crate::abilities::Error),
     #[error(transparent)]
+    Agents(#[from] crate::agents::Error),
+    #[error(transparent)]
     Database(#[from] crate::database::Error),
     #[error("feedback channel error: {0}")]
     Channel(anyhow::Error),
     #[error(transparent)]
     SerdeJson(#[from] serde_json::Error),
     #[error(transparent)]
+    Anthropic(#[from] crate::clients::anthropic::Error),
+    #[error(transparent)]
     Browser(#[from] crate::browser::Error),
     #[error(transparent)]
+    Bundles(#[from] crate::bundles::Error),
+    #[error(transparent)]
+    Chats(#[from] crate::chats::Error),
+    #[error(transparent)]
     Docker(#[from] crate::docker::Error),
     #[error("embeddings error: {0}")]
     Embeddings(#[from] crate::embeddings::Error),
@@ -30,12 +38,22 @@ pub enum Error {
     #[error(transparent)]
     Models(#[from] crate::models::Error),
     #[error(transparent)]
+    OpenAI(#[from] crate::clients::openai::Error),
+    #[error(transparent)]
     Pages(#[from] crate::pages::Error),
     #[error(transparent)]
+    Pagination(#[from] crate::types::pagination::Error),
+    #[error(transparent)]
     Planner(#[from] crate::task_planner::Error),
     #[error(transparent)]
+    Secrets(#[from] crate::secrets::Error),
+    #[error(transparent)]
     Settings(#[from] crate::settings::Error),
     #[error(transparent)]
+    Tasks(#[from] crate::tasks::Error),
+    #[error(transparent)]
+    Transcript(#[from] crate::transcript::Error),
+    #[error(transparent)]
     WebBrowsing(#[from] crate::tools::web_browsing::Error),
 }
 
@@ -2,7 +2,9 @@
 // SPDX-License-Identifier: Apache-2.0
 
 pub mod abilities;
+pub mod agents;
 pub mod browser;
+pub mod bundles;
 pub mod channel;
 pub mod chats;
 pub mod clients;
@@ -14,8 +16,12 @@ pub mod messages;
 pub mod models;
 pub mod pages;
 pub mod repo;
+pub mod secrets;
 pub mod settings;
 pub mod task_executor;
 pub mod task_planner;
+pub mod tasks;
+pub mod tokens;
 pub mod tools;
+pub mod transcript;
 pub mod types;
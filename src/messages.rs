@@ -32,60 +32,41 @@ pub enum Error {
     OpenAIConversionError(#[from] anyhow::Error),
     #[error("chunk deserialization error: {0}")]
     ChunkDeserialization(#[from] serde_json::Error),
-    #[error("no valid chunk prefix found")]
-    NoValidChunkPrefix,
     #[error("no tool calls found in message")]
     NoToolCallsFound,
     #[error("tool call has no `id`")]
     NoToolCallId,
 }
 
+/// Generates a short chat title with the LLM.
+///
+/// Tool calls and internal tool outputs are never sent. Only the first system message is sent,
+/// unless `keep_extra_system_messages` is set, since the rest (e.g. execution preludes) only
+/// inflate the request.
+///
+/// # Errors
+///
+/// Returns error if there are not enough suitable messages to generate the title from.
+/// Returns error if there was a problem while getting the completion.
 #[instrument(skip(messages, model, api_key, user_agent))]
 pub async fn generate_chat_title(
     messages: Vec<Message>,
     model: &Model,
     api_key: &str,
     user_agent: &str,
+    keep_extra_system_messages: bool,
 ) -> Result<String> {
-    if messages.len() < 3 {
-        return Err(Error::TooFewMessages(messages.len()).into());
-    }
-
-    let user_message = messages.iter().find(|message| message.role == Role::User);
-    let assistant_message_without_tools = messages
-        .iter()
-        .find(|message| message.role == Role::Assistant && message.tool_calls().is_empty());
-
-    if user_message.is_none() || assistant_message_without_tools.is_none() {
-        return Err(Error::NoSuitableMessages.into());
-    }
-
-    let last_message = messages.last().unwrap();
-
-    if last_message.role != Role::Assistant {
-        return Err(Error::LastMessageNotFromAssistant.into());
-    }
-
-    let mut req_messages = messages
-        .into_iter()
-        .filter(|message| {
-            message.role == Role::User
-                || message.role == Role::System
-                || (message.role == Role::Assistant && message.tool_calls().is_empty())
-        })
-        .map(clients::openai::Message::try_from)
-        .collect::<std::result::Result<Vec<_>, _>>()
-        .map_err(Error::OpenAIConversionError)?;
+    let mut req_messages = title_request_messages(messages, keep_extra_system_messages)?;
 
     trace!("Messages so far: {:?}", req_messages);
 
     req_messages.push(clients::openai::Message::User {
-        content: "Provide a short title for the current conversation (4-6 words). Your response must only contain the chat title and nothing else.".to_string(),
+        content: "Provide a short title for the current conversation (4-6 words). Your response must only contain the chat title and nothing else.".into(),
         name: None,
     });
 
     // Send request to LLM
-    let client = Client::new(api_key, model.api_url_or_default(), user_agent);
+    let client = Client::for_model(model, api_key, user_agent);
     let response = client
         .create_chat_completion(CreateChatCompletionRequest {
             model: &model.name,
@@ -95,7 +76,7 @@ pub async fn generate_chat_title(
         .await
         .map_err(|e| Error::FailedToCreateChatCompletion(e.to_string()))?;
 
-    let mut title = match &response.choices[0].message {
+    let mut title = match &response.first_choice()?.message {
         crate::clients::openai::Message::Assistant { content, .. } => match content {
             Some(title) => title,
             _ => return Err(Error::EmptyLLMResponseReceived.into()),
@@ -114,3 +95,120 @@ pub async fn generate_chat_title(
 
     Ok(title)
 }
+
+fn title_request_messages(
+    messages: Vec<Message>,
+    keep_extra_system_messages: bool,
+) -> Result<Vec<clients::openai::Message>> {
+    if messages.len() < 3 {
+        return Err(Error::TooFewMessages(messages.len()).into());
+    }
+
+    if messages.last().map(|message| &message.role) != Some(&Role::Assistant) {
+        return Err(Error::LastMessageNotFromAssistant.into());
+    }
+
+    let mut has_system_message = false;
+    let messages = messages
+        .into_iter()
+        .filter(|message| !message.is_internal_tool_output)
+        .filter(|message| match message.role {
+            Role::User => true,
+            Role::Assistant => message.tool_calls().is_empty(),
+            Role::System => {
+                let is_extra = has_system_message;
+                has_system_message = true;
+
+                keep_extra_system_messages || !is_extra
+            }
+            Role::CodeInterpreter | Role::Tool => false,
+        })
+        .collect::<Vec<_>>();
+
+    if !messages.iter().any(|message| message.role == Role::User)
+        || !messages
+            .iter()
+            .any(|message| message.role == Role::Assistant)
+    {
+        return Err(Error::NoSuitableMessages.into());
+    }
+
+    Ok(messages
+        .into_iter()
+        .map(clients::openai::Message::try_from)
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(Error::OpenAIConversionError)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::errors;
+
+    use super::*;
+
+    fn message(role: Role, content: &str) -> Message {
+        Message {
+            role,
+            content: Some(content.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_title_request_skips_internal_outputs_and_extra_system_messages() {
+        let messages = vec![
+            message(Role::System, "You are a helpful assistant"),
+            message(Role::System, "Execution prelude"),
+            message(Role::User, "What's the weather?"),
+            Message {
+                is_internal_tool_output: true,
+                ..message(Role::Assistant, "Internal output")
+            },
+            message(Role::Assistant, "Sunny, 24°C"),
+        ];
+
+        let contents = |messages: Vec<clients::openai::Message>| {
+            messages
+                .into_iter()
+                .map(|message| match message {
+                    clients::openai::Message::System { content, .. } => content,
+                    clients::openai::Message::User { content, .. } => content.text(),
+                    clients::openai::Message::Assistant { content, .. } => {
+                        content.unwrap_or_default()
+                    }
+                    clients::openai::Message::Tool { content, .. } => content,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            contents(title_request_messages(messages.clone(), false).unwrap()),
+            [
+                "You are a helpful assistant",
+                "What's the weather?",
+                "Sunny, 24°C"
+            ]
+        );
+        assert_eq!(
+            contents(title_request_messages(messages, true).unwrap()).len(),
+            4
+        );
+    }
+
+    #[test]
+    fn test_title_request_requires_non_internal_assistant_message() {
+        let messages = vec![
+            message(Role::System, "You are a helpful assistant"),
+            message(Role::User, "What's the weather?"),
+            Message {
+                is_internal_tool_output: true,
+                ..message(Role::Assistant, "Internal output")
+            },
+        ];
+
+        assert!(matches!(
+            title_request_messages(messages, false),
+            Err(errors::Error::Messages(Error::NoSuitableMessages))
+        ));
+    }
+}
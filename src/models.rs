@@ -8,13 +8,19 @@ use uuid::Uuid;
 use crate::{
     repo,
     settings::Settings,
-    types::{chats::Chat, models::Model, Result},
+    types::{
+        chats::Chat,
+        models::{Model, Provider},
+        Result,
+    },
 };
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("default model `{1}` (cid: {0}) is not found in the database")]
     DefaultModelNotFound(Uuid, String),
+    #[error("API key for provider `{0:?}` is not set")]
+    ApiKeyNotFound(Provider),
 }
 
 /// Get model for a given chat.
@@ -54,3 +60,23 @@ pub async fn get_default(pool: &Pool<Postgres>, cid: Uuid, settings: &Settings)
         None => Err(Error::DefaultModelNotFound(cid, settings.default_model.clone()).into()),
     }
 }
+
+/// Get API key for a given model.
+///
+/// Model's own API key takes precedence over the one set for its provider. Ollama runs locally, so
+/// it's allowed to have no API key.
+///
+/// # Errors
+///
+/// Returns error if API key for the model's provider is not set.
+pub fn api_key<'a>(settings: &'a Settings, model: &'a Model) -> Result<&'a str> {
+    if let Some(api_key) = model.api_key.as_deref().filter(|key| !key.is_empty()) {
+        return Ok(api_key);
+    }
+
+    match settings.api_keys.get(&model.provider) {
+        Some(api_key) => Ok(api_key),
+        None if model.provider == Provider::Ollama => Ok(""),
+        None => Err(Error::ApiKeyNotFound(model.provider.clone()).into()),
+    }
+}
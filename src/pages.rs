@@ -1,8 +1,192 @@
 // Copyright 2024 StarfleetAI
 // SPDX-License-Identifier: Apache-2.0
 
+use std::collections::HashMap;
+
+use anyhow::Context;
+use sqlx::{Pool, Postgres};
+use tracing::{debug, instrument};
+use uuid::Uuid;
+
+use crate::{
+    embeddings::{cosine_similarity, Embedder},
+    repo,
+    types::{pages::Page, Result},
+};
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("validation error: {0}")]
     ValidationError(String),
 }
+
+/// Re-embeds all pages with the given model and replaces the stored embeddings.
+///
+/// Pages are embedded before touching the stored embeddings, and the old ones are replaced
+/// in a single transaction, so search never sees embeddings of different models at once.
+///
+/// # Errors
+///
+/// Returns error if pages cannot be embedded.
+/// Returns error if there was a problem while accessing database.
+#[instrument(skip(pool, embedder))]
+pub async fn reindex_with_model<T>(pool: &Pool<Postgres>, embedder: &T, cid: Uuid) -> Result<()>
+where
+    T: Embedder + ?Sized,
+{
+    let pages = repo::pages::list_full(pool, cid).await?;
+    debug!(
+        "Re-indexing {} pages with model `{}`",
+        pages.len(),
+        embedder.model_name()
+    );
+
+    let mut chunks = Vec::new();
+
+    for page in &pages {
+        if page.text.trim().is_empty() {
+            continue;
+        }
+
+        for (chunk, embedding) in embedder.embed(&page.text)? {
+            chunks.push((page.id, chunk, embedding));
+        }
+    }
+
+    let mut tx = pool.begin().await.context("Failed to begin transaction")?;
+
+    repo::page_embeddings::delete_all(&mut *tx, cid).await?;
+
+    repo::page_embeddings::create_many(
+        &mut *tx,
+        cid,
+        embedder.model_name(),
+        embedder.dimension(),
+        chunks
+            .iter()
+            .map(
+                |(page_id, chunk, embedding)| repo::page_embeddings::CreateParams {
+                    page_id: *page_id,
+                    chunk,
+                    embedding,
+                },
+            )
+            .collect(),
+    )
+    .await?;
+
+    tx.commit().await.context("Failed to commit transaction")?;
+
+    Ok(())
+}
+
+/// Returns up to `k` pages most relevant to the `query`, ordered by relevance.
+///
+/// Only embeddings computed with the embedder's model are considered.
+///
+/// # Errors
+///
+/// Returns error if the query cannot be embedded.
+/// Returns error if there was a problem while accessing database.
+#[instrument(skip(pool, embedder, query))]
+pub async fn search<T>(
+    pool: &Pool<Postgres>,
+    embedder: &T,
+    cid: Uuid,
+    query: &str,
+    k: usize,
+) -> Result<Vec<Page>>
+where
+    T: Embedder + ?Sized,
+{
+    let embeddings =
+        repo::page_embeddings::list_for_model(pool, cid, embedder.model_name()).await?;
+
+    if embeddings.is_empty() || k == 0 {
+        return Ok(Vec::new());
+    }
+
+    let query_embedding = embedder
+        .embed_sentences(vec![query])?
+        .remove(query)
+        .context("Failed to embed query")?;
+
+    // Page score is the score of its most relevant chunk.
+    let mut scores: HashMap<Uuid, f32> = HashMap::new();
+
+    for embedding in embeddings {
+        let score = cosine_similarity(&embedding.embedding, &query_embedding);
+        let best = scores.entry(embedding.page_id).or_insert(score);
+        *best = best.max(score);
+    }
+
+    let mut scores = scores.into_iter().collect::<Vec<_>>();
+    scores.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    let mut pages = Vec::with_capacity(k.min(scores.len()));
+
+    for (page_id, _) in scores.into_iter().take(k) {
+        pages.push(repo::pages::get(pool, cid, page_id).await?);
+    }
+
+    Ok(pages)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::embeddings::KeywordEmbedder;
+
+    use super::*;
+
+    const COMPANY_ID: Uuid = Uuid::from_u128(1);
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(path = "../db/fixtures", scripts("companies"))
+    )]
+    async fn test_reindex_with_model_replaces_embeddings(pool: Pool<Postgres>) {
+        for (title, text) in [
+            ("Weather", "Rain is in the forecast for the weekend."),
+            ("Scripts", "A python script to rename photos."),
+        ] {
+            repo::pages::create(
+                &pool,
+                COMPANY_ID,
+                repo::pages::CreateParams {
+                    title: title.to_string(),
+                    text: text.to_string(),
+                },
+            )
+            .await
+            .unwrap();
+        }
+
+        let old_model = KeywordEmbedder::new("old-model");
+        let new_model = KeywordEmbedder::new("new-model");
+
+        reindex_with_model(&pool, &old_model, COMPANY_ID)
+            .await
+            .unwrap();
+        reindex_with_model(&pool, &new_model, COMPANY_ID)
+            .await
+            .unwrap();
+
+        assert!(
+            repo::page_embeddings::list_for_model(&pool, COMPANY_ID, "old-model")
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        assert!(search(&pool, &old_model, COMPANY_ID, "rain", 1)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let pages = search(&pool, &new_model, COMPANY_ID, "Will it rain?", 1)
+            .await
+            .unwrap();
+
+        assert_eq!(pages.len(), 1);
+        assert_eq!(pages[0].title, "Weather");
+    }
+}
@@ -100,6 +100,55 @@ where
         .await?)
 }
 
+/// Create multiple abilities in a single query, returning them in the order of `params`.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while accessing database.
+pub async fn create_many<'a, E>(
+    executor: E,
+    company_id: Uuid,
+    params: Vec<CreateParams>,
+) -> Result<Vec<Ability>>
+where
+    E: Executor<'a, Database = Postgres>,
+{
+    let ids = params.iter().map(|_| Uuid::new_v4()).collect::<Vec<_>>();
+    let mut names = Vec::with_capacity(params.len());
+    let mut descriptions = Vec::with_capacity(params.len());
+    let mut codes = Vec::with_capacity(params.len());
+    let mut parameters = Vec::with_capacity(params.len());
+
+    for params in params {
+        names.push(params.name);
+        descriptions.push(params.description);
+        codes.push(params.code);
+        parameters.push(json!(params.parameters_json));
+    }
+
+    let abilities = query_as!(
+        Ability,
+        r#"
+        INSERT INTO abilities (id, company_id, name, description, code, parameters_json, created_at, updated_at)
+        SELECT id, $1, name, description, code, parameters_json, $2, $2
+        FROM UNNEST($3::uuid[], $4::text[], $5::text[], $6::text[], $7::jsonb[])
+            AS t(id, name, description, code, parameters_json)
+        RETURNING *
+        "#,
+        company_id,
+        Utc::now(),
+        &ids,
+        &names,
+        &descriptions,
+        &codes,
+        &parameters,
+    )
+    .fetch_all(executor)
+    .await?;
+
+    super::in_order_of(&ids, abilities, |ability| ability.id)
+}
+
 /// Update ability.
 ///
 /// # Errors
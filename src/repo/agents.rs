@@ -2,17 +2,25 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use chrono::Utc;
+use serde_json::json;
 use sqlx::{query, query_as, Executor, Postgres};
 use uuid::Uuid;
 
-use crate::types::{agents::Agent, Result};
+use crate::types::{
+    agents::{Agent, Example},
+    Result,
+};
 
 pub struct CreateParams {
     pub name: String,
     pub description: String,
     pub system_message: String,
+    pub is_enabled: bool,
     pub is_code_interpreter_enabled: bool,
     pub is_web_browser_enabled: bool,
+    pub execution_steps_limit: Option<i32>,
+    pub forbidden_tools: Vec<String>,
+    pub examples: Vec<Example>,
 }
 
 pub struct UpdateParams {
@@ -22,6 +30,8 @@ pub struct UpdateParams {
     pub system_message: String,
     pub is_code_interpreter_enabled: bool,
     pub is_web_browser_enabled: bool,
+    pub forbidden_tools: Vec<String>,
+    pub examples: Vec<Example>,
 }
 
 /// List all agents.
@@ -42,6 +52,59 @@ where
     .await?)
 }
 
+/// List all agents along with the number of their abilities.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while accessing database.
+pub async fn list_with_ability_counts<'a, E>(
+    executor: E,
+    company_id: Uuid,
+) -> Result<Vec<(Agent, i64)>>
+where
+    E: Executor<'a, Database = Postgres>,
+{
+    let rows = query!(
+        r#"
+        SELECT agents.*, COUNT(agent_abilities.ability_id) AS "ability_count!"
+        FROM agents
+        LEFT JOIN agent_abilities
+            ON agent_abilities.agent_id = agents.id AND agent_abilities.company_id = agents.company_id
+        WHERE agents.company_id = $1
+        GROUP BY agents.id
+        ORDER BY agents.id ASC
+        "#,
+        company_id
+    )
+    .fetch_all(executor)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| {
+            (
+                Agent {
+                    id: row.id,
+                    id_int: row.id_int,
+                    company_id: row.company_id,
+                    name: row.name,
+                    description: row.description,
+                    system_message: row.system_message,
+                    is_enabled: row.is_enabled,
+                    is_code_interpreter_enabled: row.is_code_interpreter_enabled,
+                    is_web_browser_enabled: row.is_web_browser_enabled,
+                    execution_steps_limit: row.execution_steps_limit,
+                    created_at: row.created_at,
+                    updated_at: row.updated_at,
+                    forbidden_tools: row.forbidden_tools,
+                    examples: row.examples,
+                },
+                row.ability_count,
+            )
+        })
+        .collect())
+}
+
 /// List enabled agents.
 ///
 /// # Errors
@@ -98,12 +161,16 @@ where
     .await?)
 }
 
-/// Get agent by chat id.
+/// Get agent by chat id. Returns `None` if no agent is associated with the chat.
 ///
 /// # Errors
 ///
 /// Returns error if there was a problem while accessing database.
-pub async fn get_for_chat<'a, E>(executor: E, company_id: Uuid, chat_id: Uuid) -> Result<Agent>
+pub async fn get_for_chat<'a, E>(
+    executor: E,
+    company_id: Uuid,
+    chat_id: Uuid,
+) -> Result<Option<Agent>>
 where
     E: Executor<'a, Database = Postgres>,
 {
@@ -119,11 +186,11 @@ where
         company_id,
         chat_id
     )
-    .fetch_one(executor)
+    .fetch_optional(executor)
     .await?)
 }
 
-/// Get agent.
+/// Create agent.
 ///
 /// # Errors
 ///
@@ -139,9 +206,10 @@ where
         r#"
         INSERT INTO agents (
             company_id, name, description, system_message,
-            created_at, updated_at, is_code_interpreter_enabled, is_web_browser_enabled
+            created_at, updated_at, is_code_interpreter_enabled, is_web_browser_enabled,
+            is_enabled, execution_steps_limit, forbidden_tools, examples
         )
-        VALUES ($1, $2, $3, $4, $5, $5, $6, $7)
+        VALUES ($1, $2, $3, $4, $5, $5, $6, $7, $8, $9, $10, $11)
         RETURNING *
         "#,
         company_id,
@@ -151,11 +219,103 @@ where
         now,
         params.is_code_interpreter_enabled,
         params.is_web_browser_enabled,
+        params.is_enabled,
+        params.execution_steps_limit,
+        &params.forbidden_tools,
+        json!(params.examples),
     )
     .fetch_one(executor)
     .await?)
 }
 
+/// Create multiple agents in a single query.
+///
+/// Agents are returned in the same order as `params`, matched back by their IDs, which are
+/// generated upfront.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while accessing database.
+pub async fn create_many<'a, E>(
+    executor: E,
+    company_id: Uuid,
+    params: Vec<CreateParams>,
+) -> Result<Vec<Agent>>
+where
+    E: Executor<'a, Database = Postgres>,
+{
+    let now = Utc::now();
+
+    let ids = params.iter().map(|_| Uuid::new_v4()).collect::<Vec<_>>();
+    let mut names = Vec::with_capacity(params.len());
+    let mut descriptions = Vec::with_capacity(params.len());
+    let mut system_messages = Vec::with_capacity(params.len());
+    let mut code_interpreter_flags = Vec::with_capacity(params.len());
+    let mut web_browser_flags = Vec::with_capacity(params.len());
+    let mut enabled_flags = Vec::with_capacity(params.len());
+    let mut execution_steps_limits = Vec::with_capacity(params.len());
+    // Lists differ in length, so they can't be passed as a two-dimensional array.
+    let mut forbidden_tools = Vec::with_capacity(params.len());
+    let mut examples = Vec::with_capacity(params.len());
+
+    for params in params {
+        names.push(params.name);
+        descriptions.push(params.description);
+        system_messages.push(params.system_message);
+        code_interpreter_flags.push(params.is_code_interpreter_enabled);
+        web_browser_flags.push(params.is_web_browser_enabled);
+        enabled_flags.push(params.is_enabled);
+        execution_steps_limits.push(params.execution_steps_limit);
+        forbidden_tools.push(serde_json::Value::from(params.forbidden_tools));
+        examples.push(json!(params.examples));
+    }
+
+    let agents = query_as!(
+        Agent,
+        r#"
+        INSERT INTO agents (
+            id, company_id, name, description, system_message,
+            created_at, updated_at, is_code_interpreter_enabled, is_web_browser_enabled,
+            is_enabled, execution_steps_limit, forbidden_tools, examples
+        )
+        SELECT
+            id, $1, name, description, system_message, $2, $2, is_code_interpreter_enabled, is_web_browser_enabled,
+            is_enabled, execution_steps_limit,
+            ARRAY(
+                SELECT tool
+                FROM jsonb_array_elements_text(forbidden_tools) WITH ORDINALITY AS f(tool, position)
+                ORDER BY position
+            ),
+            examples
+        FROM UNNEST(
+            $3::uuid[], $4::text[], $5::text[], $6::text[], $7::bool[], $8::bool[], $9::bool[], $10::integer[],
+            $11::jsonb[], $12::jsonb[]
+        )
+            AS t(
+                id, name, description, system_message, is_code_interpreter_enabled, is_web_browser_enabled,
+                is_enabled, execution_steps_limit, forbidden_tools, examples
+            )
+        RETURNING *
+        "#,
+        company_id,
+        now,
+        &ids,
+        &names,
+        &descriptions,
+        &system_messages,
+        &code_interpreter_flags,
+        &web_browser_flags,
+        &enabled_flags,
+        &execution_steps_limits as &[Option<i32>],
+        &forbidden_tools,
+        &examples,
+    )
+    .fetch_all(executor)
+    .await?;
+
+    super::in_order_of(&ids, agents, |agent| agent.id)
+}
+
 /// Update agent.
 ///
 /// # Errors
@@ -173,7 +333,8 @@ where
         UPDATE agents
         SET
             name = $3, description = $4, system_message = $5, updated_at = $6,
-            is_code_interpreter_enabled = $7, is_web_browser_enabled = $8
+            is_code_interpreter_enabled = $7, is_web_browser_enabled = $8, forbidden_tools = $9,
+            examples = $10
         WHERE company_id = $1 AND id = $2
         RETURNING *
         "#,
@@ -185,6 +346,8 @@ where
         now,
         params.is_code_interpreter_enabled,
         params.is_web_browser_enabled,
+        &params.forbidden_tools,
+        json!(params.examples),
     )
     .fetch_one(executor)
     .await?)
@@ -236,3 +399,98 @@ where
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use sqlx::Pool;
+
+    use super::*;
+
+    const COMPANY_ID: Uuid = Uuid::from_u128(1);
+    const CHAT_ID: Uuid = Uuid::from_u128(0x0006_0000_0000_0001);
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(path = "../../db/fixtures", scripts("companies", "models", "chats"))
+    )]
+    async fn test_get_for_chat_without_agent(pool: Pool<Postgres>) {
+        let agent = get_for_chat(&pool, COMPANY_ID, CHAT_ID).await.unwrap();
+
+        assert!(agent.is_none());
+    }
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(path = "../../db/fixtures", scripts("companies"))
+    )]
+    async fn test_create_many_keeps_forbidden_tools_order(pool: Pool<Postgres>) {
+        let forbidden_tools = ["sfai_wait_for_user", "sfai_done", "sfai_fail"]
+            .map(ToString::to_string)
+            .to_vec();
+        let params = |name: &str, forbidden_tools: Vec<String>| CreateParams {
+            name: name.to_string(),
+            description: String::new(),
+            system_message: String::new(),
+            is_enabled: true,
+            is_code_interpreter_enabled: false,
+            is_web_browser_enabled: false,
+            execution_steps_limit: None,
+            forbidden_tools,
+            examples: Vec::new(),
+        };
+
+        let agents = create_many(
+            &pool,
+            COMPANY_ID,
+            vec![
+                params("Researcher", forbidden_tools.clone()),
+                params("Writer", Vec::new()),
+            ],
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(agents[0].forbidden_tools, forbidden_tools);
+        assert!(agents[1].forbidden_tools.is_empty());
+    }
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(
+            path = "../../db/fixtures",
+            scripts("companies", "abilities", "agents")
+        )
+    )]
+    async fn test_list_with_ability_counts(pool: Pool<Postgres>) {
+        for (agent, ability) in [(2, 1), (3, 1), (3, 2), (3, 3)] {
+            crate::repo::agent_abilities::create(
+                &pool,
+                COMPANY_ID,
+                Uuid::from_u128(0x0003_0000_0000_0000 + agent),
+                Uuid::from_u128(0x0001_0000_0000_0000 + ability),
+            )
+            .await
+            .unwrap();
+        }
+
+        let counts = list_with_ability_counts(&pool, COMPANY_ID)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(agent, count)| (agent.name, count))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            counts,
+            [
+                ("Researcher".to_string(), 0),
+                ("Archivist".to_string(), 1),
+                ("Coder".to_string(), 3)
+            ]
+        );
+        assert!(list_with_ability_counts(&pool, Uuid::from_u128(2))
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
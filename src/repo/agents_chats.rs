@@ -4,7 +4,7 @@
 use std::collections::HashMap;
 
 use anyhow::Context;
-use sqlx::{query, query_as, Executor, Postgres};
+use sqlx::{query, query_as, query_scalar, Executor, Postgres};
 use uuid::Uuid;
 
 use crate::types::{agents_chats::AgentsChat, Result};
@@ -41,6 +41,25 @@ where
     Ok(chat_agents)
 }
 
+/// List agents of the chat.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while accessing database.
+pub async fn list_for_chat<'a, E>(executor: E, company_id: Uuid, chat_id: Uuid) -> Result<Vec<Uuid>>
+where
+    E: Executor<'a, Database = Postgres>,
+{
+    Ok(query_scalar!(
+        "SELECT agent_id FROM agents_chats WHERE company_id = $1 AND chat_id = $2",
+        company_id,
+        chat_id
+    )
+    .fetch_all(executor)
+    .await
+    .with_context(|| "Failed to fetch agents for chat")?)
+}
+
 /// Add agent to chat.
 ///
 /// # Errors
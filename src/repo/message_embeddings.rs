@@ -0,0 +1,74 @@
+// Copyright 2024 StarfleetAI
+// SPDX-License-Identifier: Apache-2.0
+
+use chrono::Utc;
+use sqlx::{query_as, Executor, Postgres};
+use uuid::Uuid;
+
+use crate::types::{message_embeddings::MessageEmbedding, Result};
+
+/// List message embeddings computed with the given model for the chat.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while accessing database.
+pub async fn list_for_chat<'a, E>(
+    executor: E,
+    company_id: Uuid,
+    chat_id: Uuid,
+    model: &str,
+) -> Result<Vec<MessageEmbedding>>
+where
+    E: Executor<'a, Database = Postgres>,
+{
+    Ok(query_as!(
+        MessageEmbedding,
+        r#"
+        SELECT message_embeddings.*
+        FROM message_embeddings
+        INNER JOIN messages ON messages.id = message_embeddings.message_id
+        WHERE message_embeddings.company_id = $1 AND messages.chat_id = $2 AND message_embeddings.model = $3
+        "#,
+        company_id,
+        chat_id,
+        model
+    )
+    .fetch_all(executor)
+    .await?)
+}
+
+/// Create message embedding. Replaces the existing one for the same message and model.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while accessing database.
+pub async fn create<'a, E>(
+    executor: E,
+    company_id: Uuid,
+    message_id: Uuid,
+    model: &str,
+    embedding: &[f32],
+) -> Result<MessageEmbedding>
+where
+    E: Executor<'a, Database = Postgres>,
+{
+    let now = Utc::now();
+
+    Ok(query_as!(
+        MessageEmbedding,
+        r#"
+        INSERT INTO message_embeddings (company_id, message_id, model, embedding, created_at, updated_at)
+        VALUES ($1, $2, $3, $4, $5, $5)
+        ON CONFLICT (company_id, message_id, model)
+        DO UPDATE SET embedding = EXCLUDED.embedding, updated_at = EXCLUDED.updated_at
+        RETURNING *
+        "#,
+        company_id,
+        message_id,
+        model,
+        embedding,
+        now,
+    )
+    .fetch_one(executor)
+    .await?)
+}
@@ -2,7 +2,7 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use anyhow::Context;
-use chrono::Utc;
+use chrono::{Duration, Utc};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use sqlx::{query, query_as, query_scalar, Executor, Postgres};
@@ -60,10 +60,10 @@ where
     let messages = query_as!(
         Message,
         r#"
-        SELECT *
+        SELECT *, ARRAY(SELECT tag FROM message_tags WHERE message_tags.message_id = messages.id ORDER BY tag) AS "tags!"
         FROM messages
         WHERE company_id = $1 AND chat_id = $2
-        ORDER BY id ASC
+        ORDER BY created_at ASC, id ASC
         "#,
         company_id,
         params.chat_id,
@@ -97,7 +97,7 @@ where
             $5, $6, $7, $8,
             $9, $10, $11, $11,
             $12, $13
-        ) RETURNING *
+        ) RETURNING *, '{}'::TEXT[] AS "tags!"
         "#,
         company_id,
         params.chat_id,
@@ -140,15 +140,16 @@ where
     let mut completion_tokens = Vec::with_capacity(params.len());
     let mut tool_calls = Vec::with_capacity(params.len());
     let mut tool_call_ids = Vec::with_capacity(params.len());
+    let mut created_at = Vec::with_capacity(params.len());
+    let mut is_self_reflection = Vec::with_capacity(params.len());
+    let mut is_internal_tool_output = Vec::with_capacity(params.len());
 
     let now = Utc::now();
 
-    let created_at = vec![now; params.len()];
-    let updated_at = vec![now; params.len()];
-    let is_self_reflection = vec![false; params.len()];
-    let is_internal_tool_output = vec![false; params.len()];
+    for (i, param) in params.into_iter().enumerate() {
+        // Keep the messages order, as the timestamps are used to sort them.
+        created_at.push(now + Duration::microseconds(i64::try_from(i).unwrap_or(i64::MAX)));
 
-    for param in params {
         company_ids.push(company_id);
         chat_ids.push(param.chat_id);
         agent_ids.push(param.agent_id);
@@ -159,6 +160,8 @@ where
         completion_tokens.push(param.completion_tokens);
         tool_calls.push(param.tool_calls);
         tool_call_ids.push(param.tool_call_id);
+        is_self_reflection.push(param.is_self_reflection);
+        is_internal_tool_output.push(param.is_internal_tool_output);
     }
 
     Ok(query_as!(
@@ -175,7 +178,7 @@ where
             $5::TEXT[], $6::TEXT[], $7::INTEGER[], $8::INTEGER[],
             $9::JSONB[], $10::TEXT[], $11::TIMESTAMPTZ[], $12::TIMESTAMPTZ[],
             $13::BOOLEAN[], $14::BOOLEAN[]
-        ) RETURNING *
+        ) RETURNING *, '{}'::TEXT[] AS "tags!"
         "#,
         &company_ids,
         &chat_ids,
@@ -188,7 +191,7 @@ where
         &tool_calls as &[Option<Value>],
         &tool_call_ids as &[Option<String>],
         &created_at,
-        &updated_at,
+        &created_at,
         &is_self_reflection,
         &is_internal_tool_output,
     )
@@ -207,7 +210,11 @@ where
 {
     Ok(query_as!(
         Message,
-        "SELECT * FROM messages WHERE company_id = $1 AND id = $2",
+        r#"
+        SELECT *, ARRAY(SELECT tag FROM message_tags WHERE message_tags.message_id = messages.id ORDER BY tag) AS "tags!"
+        FROM messages
+        WHERE company_id = $1 AND id = $2
+        "#,
         company_id,
         id
     )
@@ -254,10 +261,10 @@ where
     Ok(query_as!(
         Message,
         r#"
-        SELECT *
+        SELECT *, ARRAY(SELECT tag FROM message_tags WHERE message_tags.message_id = messages.id ORDER BY tag) AS "tags!"
         FROM messages
         WHERE company_id = $1 AND chat_id = $2
-        ORDER BY id DESC
+        ORDER BY created_at DESC, id DESC
         LIMIT 1
         "#,
         company_id,
@@ -313,13 +320,14 @@ where
     Ok(query_as!(
         Message,
         r#"
-        SELECT * FROM messages
+        SELECT *, ARRAY(SELECT tag FROM message_tags WHERE message_tags.message_id = messages.id ORDER BY tag) AS "tags!"
+        FROM messages
         WHERE
             company_id = $1 AND
             chat_id = $2 AND
             is_self_reflection = FALSE AND
             role = $3
-        ORDER BY id DESC LIMIT 1
+        ORDER BY created_at DESC, id DESC LIMIT 1
         "#,
         company_id,
         chat_id,
@@ -329,6 +337,40 @@ where
     .await?)
 }
 
+/// Get contents of the last non self-reflection agent messages for chat, newest first.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while accessing database.
+pub async fn list_last_agent_contents<'a, E>(
+    executor: E,
+    company_id: Uuid,
+    chat_id: Uuid,
+    limit: i64,
+) -> Result<Vec<Option<String>>>
+where
+    E: Executor<'a, Database = Postgres>,
+{
+    Ok(query_scalar!(
+        r#"
+        SELECT content
+        FROM messages
+        WHERE
+            company_id = $1 AND
+            chat_id = $2 AND
+            is_self_reflection = FALSE AND
+            role = $3
+        ORDER BY created_at DESC, id DESC LIMIT $4
+        "#,
+        company_id,
+        chat_id,
+        Role::Assistant.to_string(),
+        limit,
+    )
+    .fetch_all(executor)
+    .await?)
+}
+
 /// Update message status.
 ///
 /// # Errors
@@ -377,7 +419,7 @@ where
         UPDATE messages
         SET tool_call_id = $3, updated_at = $4
         WHERE company_id = $1 AND id = $2
-        RETURNING *
+        RETURNING *, ARRAY(SELECT tag FROM message_tags WHERE message_tags.message_id = messages.id ORDER BY tag) AS "tags!"
         "#,
         company_id,
         id,
@@ -415,7 +457,7 @@ where
             tool_calls = $7,
             updated_at = $8
         WHERE company_id = $1 AND id = $2
-        RETURNING *
+        RETURNING *, ARRAY(SELECT tag FROM message_tags WHERE message_tags.message_id = messages.id ORDER BY tag) AS "tags!"
         "#,
         company_id,
         params.id,
@@ -472,7 +514,7 @@ where
         UPDATE messages
         SET content = $3, updated_at = $4
         WHERE company_id = $1 AND id = $2
-        RETURNING *
+        RETURNING *, ARRAY(SELECT tag FROM message_tags WHERE message_tags.message_id = messages.id ORDER BY tag) AS "tags!"
         "#,
         company_id,
         id,
@@ -483,6 +525,117 @@ where
     .await?)
 }
 
+/// Pin or unpin the message, depending on its current state.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while updating message.
+pub async fn toggle_pin<'a, E>(executor: E, company_id: Uuid, id: Uuid) -> Result<Message>
+where
+    E: Executor<'a, Database = Postgres>,
+{
+    let now = Utc::now();
+
+    Ok(query_as!(
+        Message,
+        r#"
+        UPDATE messages
+        SET is_pinned = NOT is_pinned, updated_at = $3
+        WHERE company_id = $1 AND id = $2
+        RETURNING *, ARRAY(SELECT tag FROM message_tags WHERE message_tags.message_id = messages.id ORDER BY tag) AS "tags!"
+        "#,
+        company_id,
+        id,
+        now
+    )
+    .fetch_one(executor)
+    .await?)
+}
+
+/// Add tag to the message. Adding an already present tag is a no-op.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while accessing database.
+pub async fn add_tag<'a, E>(executor: E, company_id: Uuid, id: Uuid, tag: &str) -> Result<()>
+where
+    E: Executor<'a, Database = Postgres>,
+{
+    let now = Utc::now();
+
+    query!(
+        r#"
+        INSERT INTO message_tags (company_id, message_id, tag, created_at)
+        SELECT company_id, id, $3, $4
+        FROM messages
+        WHERE company_id = $1 AND id = $2
+        ON CONFLICT (message_id, tag) DO NOTHING
+        "#,
+        company_id,
+        id,
+        tag,
+        now
+    )
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+/// Remove tag from the message.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while accessing database.
+pub async fn remove_tag<'a, E>(executor: E, company_id: Uuid, id: Uuid, tag: &str) -> Result<()>
+where
+    E: Executor<'a, Database = Postgres>,
+{
+    query!(
+        "DELETE FROM message_tags WHERE company_id = $1 AND message_id = $2 AND tag = $3",
+        company_id,
+        id,
+        tag
+    )
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+/// List chat messages having the given tag.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while accessing database.
+pub async fn list_by_tag<'a, E>(
+    executor: E,
+    company_id: Uuid,
+    chat_id: Uuid,
+    tag: &str,
+) -> Result<Vec<Message>>
+where
+    E: Executor<'a, Database = Postgres>,
+{
+    Ok(query_as!(
+        Message,
+        r#"
+        SELECT
+            messages.*,
+            ARRAY(SELECT mt.tag FROM message_tags AS mt WHERE mt.message_id = messages.id ORDER BY mt.tag) AS "tags!"
+        FROM messages
+        INNER JOIN message_tags ON message_tags.message_id = messages.id
+        WHERE messages.company_id = $1 AND messages.chat_id = $2 AND message_tags.tag = $3
+        ORDER BY messages.created_at ASC, messages.id ASC
+        "#,
+        company_id,
+        chat_id,
+        tag
+    )
+    .fetch_all(executor)
+    .await?)
+}
+
 /// Transitions messages from one status to another.
 ///
 /// # Errors
@@ -562,3 +715,94 @@ where
 
     create_multiple(executor, company_id, messages).await
 }
+
+#[cfg(test)]
+mod tests {
+    use sqlx::Pool;
+
+    use super::*;
+
+    const COMPANY_ID: Uuid = Uuid::from_u128(1);
+    const CHAT_ID: Uuid = Uuid::from_u128(0x0006_0000_0000_0001);
+
+    async fn create_message(pool: &Pool<Postgres>, content: &str) -> Message {
+        create(
+            pool,
+            COMPANY_ID,
+            CreateParams {
+                chat_id: CHAT_ID,
+                role: Role::User,
+                status: Status::Completed,
+                content: Some(content.to_string()),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap()
+    }
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(path = "../../db/fixtures", scripts("companies", "models", "chats"))
+    )]
+    async fn test_add_and_remove_tags(pool: Pool<Postgres>) {
+        let message = create_message(&pool, "Let's use Postgres").await;
+        assert!(message.tags.is_empty());
+
+        add_tag(&pool, COMPANY_ID, message.id, "decision")
+            .await
+            .unwrap();
+        add_tag(&pool, COMPANY_ID, message.id, "bug").await.unwrap();
+        add_tag(&pool, COMPANY_ID, message.id, "decision")
+            .await
+            .unwrap();
+
+        let message = get(&pool, COMPANY_ID, message.id).await.unwrap();
+        assert_eq!(message.tags, vec!["bug", "decision"]);
+
+        let serialized = serde_json::to_value(&message).unwrap();
+        assert_eq!(serialized["tags"], serde_json::json!(["bug", "decision"]));
+
+        remove_tag(&pool, COMPANY_ID, message.id, "bug")
+            .await
+            .unwrap();
+
+        let message = get(&pool, COMPANY_ID, message.id).await.unwrap();
+        assert_eq!(message.tags, vec!["decision"]);
+    }
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(path = "../../db/fixtures", scripts("companies", "models", "chats"))
+    )]
+    async fn test_list_by_tag(pool: Pool<Postgres>) {
+        let first = create_message(&pool, "Let's use Postgres").await;
+        let second = create_message(&pool, "Sounds good").await;
+        let third = create_message(&pool, "Migrations are broken").await;
+
+        add_tag(&pool, COMPANY_ID, first.id, "decision")
+            .await
+            .unwrap();
+        add_tag(&pool, COMPANY_ID, third.id, "decision")
+            .await
+            .unwrap();
+        add_tag(&pool, COMPANY_ID, third.id, "bug").await.unwrap();
+
+        // Tagging a message of another company is ignored.
+        add_tag(&pool, Uuid::from_u128(2), second.id, "decision")
+            .await
+            .unwrap();
+
+        let messages = list_by_tag(&pool, COMPANY_ID, CHAT_ID, "decision")
+            .await
+            .unwrap();
+        let ids: Vec<Uuid> = messages.iter().map(|message| message.id).collect();
+        assert_eq!(ids, vec![first.id, third.id]);
+        assert_eq!(messages[1].tags, vec!["bug", "decision"]);
+
+        assert!(list_by_tag(&pool, COMPANY_ID, CHAT_ID, "todo")
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
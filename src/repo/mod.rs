@@ -1,14 +1,100 @@
 // Copyright 2024 StarfleetAI
 // SPDX-License-Identifier: Apache-2.0
 
+use std::collections::HashMap;
+
+use anyhow::anyhow;
+use uuid::Uuid;
+
+use crate::types::Result;
+
 pub mod abilities;
 pub mod agent_abilities;
 pub mod agents;
 pub mod agents_chats;
 pub mod chats;
+pub mod message_embeddings;
 pub mod messages;
 pub mod models;
+pub mod page_embeddings;
 pub mod pages;
 pub mod settings;
+pub mod task_plans;
 pub mod task_results;
+pub mod task_secrets;
 pub mod tasks;
+
+/// Puts the rows in the order of their `ids`. Postgres doesn't guarantee the order of the
+/// `RETURNING` rows, so the rows inserted in bulk are matched back to the inputs by ID.
+fn in_order_of<T>(ids: &[Uuid], rows: Vec<T>, id: impl Fn(&T) -> Uuid) -> Result<Vec<T>> {
+    let mut rows = rows
+        .into_iter()
+        .map(|row| (id(&row), row))
+        .collect::<HashMap<_, _>>();
+
+    ids.iter()
+        .map(|row_id| {
+            rows.remove(row_id)
+                .ok_or_else(|| anyhow!("Row `{row_id}` is missing").into())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::{Pool, Postgres};
+
+    use super::*;
+
+    /// Every repo takes the company ID as `Uuid`, so callers pass it straight through without
+    /// conversions. Changing the type of any of them fails the compilation of this test.
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(path = "../../db/fixtures", scripts("companies"))
+    )]
+    async fn test_repos_take_uuid_company_ids(pool: Pool<Postgres>) {
+        let cid: Uuid = Uuid::from_u128(1);
+
+        assert!(tasks::list_roots_in_progress(&pool, cid)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(messages::list(
+            &pool,
+            cid,
+            messages::ListParams {
+                chat_id: Uuid::nil()
+            }
+        )
+        .await
+        .unwrap()
+        .is_empty());
+        assert!(chats::list(&pool, cid, None).await.unwrap().is_empty());
+        assert!(models::list(&pool, cid).await.unwrap().is_empty());
+        assert!(abilities::list(&pool, cid).await.unwrap().is_empty());
+        assert!(agents::list(&pool, cid).await.unwrap().is_empty());
+        assert!(pages::list_full(&pool, cid).await.unwrap().is_empty());
+        assert!(task_results::list(&pool, cid, Uuid::nil())
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_in_order_of() {
+        let ids = [Uuid::from_u128(3), Uuid::from_u128(1), Uuid::from_u128(2)];
+        let rows = vec![
+            (Uuid::from_u128(1), "a"),
+            (Uuid::from_u128(2), "b"),
+            (Uuid::from_u128(3), "c"),
+        ];
+
+        let ordered = in_order_of(&ids, rows.clone(), |row| row.0).unwrap();
+        assert_eq!(
+            ordered.iter().map(|row| row.1).collect::<Vec<_>>(),
+            ["c", "a", "b"]
+        );
+
+        assert!(in_order_of(&[Uuid::from_u128(4)], rows, |row| row.0).is_err());
+    }
+}
@@ -2,11 +2,35 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use anyhow::Context;
+use chrono::Utc;
 use sqlx::{query_as, Executor, Postgres};
 use tracing::{instrument, trace};
 use uuid::Uuid;
 
-use crate::types::{models::Model, Result};
+use crate::types::{
+    models::{Model, Provider},
+    Result,
+};
+
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Debug, Default)]
+pub struct CreateParams {
+    pub provider: Provider,
+    pub name: String,
+    pub context_length: i32,
+    pub max_tokens: i64,
+    pub text_in: bool,
+    pub text_out: bool,
+    pub image_in: bool,
+    pub image_out: bool,
+    pub audio_in: bool,
+    pub audio_out: bool,
+    pub function_calling: bool,
+    pub api_url: Option<String>,
+    pub api_key: Option<String>,
+    pub deployment: Option<String>,
+    pub api_version: Option<String>,
+}
 
 /// Get model by ID.
 ///
@@ -78,3 +102,64 @@ where
     .fetch_all(executor)
     .await?)
 }
+
+/// Create model. Replaces the existing model with the same provider and name, keeping its API key
+/// unless a new one is given.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while accessing database.
+#[instrument(skip(executor, params))]
+pub async fn create<'a, E>(executor: E, company_id: Uuid, params: CreateParams) -> Result<Model>
+where
+    E: Executor<'a, Database = Postgres>,
+{
+    let now = Utc::now();
+
+    Ok(query_as!(
+        Model,
+        r#"
+        INSERT INTO models (
+            company_id, provider, name, context_length, max_tokens,
+            text_in, text_out, image_in, image_out, audio_in, audio_out,
+            function_calling, api_url, api_key, deployment, api_version, created_at, updated_at
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $17)
+        ON CONFLICT (company_id, provider, name) DO UPDATE SET
+            context_length = EXCLUDED.context_length,
+            max_tokens = EXCLUDED.max_tokens,
+            text_in = EXCLUDED.text_in,
+            text_out = EXCLUDED.text_out,
+            image_in = EXCLUDED.image_in,
+            image_out = EXCLUDED.image_out,
+            audio_in = EXCLUDED.audio_in,
+            audio_out = EXCLUDED.audio_out,
+            function_calling = EXCLUDED.function_calling,
+            api_url = EXCLUDED.api_url,
+            api_key = COALESCE(EXCLUDED.api_key, models.api_key),
+            deployment = EXCLUDED.deployment,
+            api_version = EXCLUDED.api_version,
+            updated_at = EXCLUDED.updated_at
+        RETURNING *
+        "#,
+        company_id,
+        params.provider.to_string(),
+        params.name,
+        params.context_length,
+        i32::try_from(params.max_tokens).context("Max tokens is out of range")?,
+        params.text_in,
+        params.text_out,
+        params.image_in,
+        params.image_out,
+        params.audio_in,
+        params.audio_out,
+        params.function_calling,
+        params.api_url,
+        params.api_key,
+        params.deployment,
+        params.api_version,
+        now,
+    )
+    .fetch_one(executor)
+    .await?)
+}
@@ -0,0 +1,213 @@
+// Copyright 2024 StarfleetAI
+// SPDX-License-Identifier: Apache-2.0
+
+use chrono::Utc;
+use serde_json::json;
+use sqlx::{query, query_as, Executor, Postgres};
+use uuid::Uuid;
+
+use crate::embeddings::check_dimension;
+use crate::types::{page_embeddings::PageEmbedding, Result};
+
+pub struct CreateParams<'a> {
+    pub page_id: Uuid,
+    pub chunk: &'a str,
+    pub embedding: &'a [f32],
+}
+
+/// List page embeddings computed with the given model.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while accessing database.
+pub async fn list_for_model<'a, E>(
+    executor: E,
+    company_id: Uuid,
+    model: &str,
+) -> Result<Vec<PageEmbedding>>
+where
+    E: Executor<'a, Database = Postgres>,
+{
+    Ok(query_as!(
+        PageEmbedding,
+        "SELECT * FROM page_embeddings WHERE company_id = $1 AND model = $2",
+        company_id,
+        model
+    )
+    .fetch_all(executor)
+    .await?)
+}
+
+/// Create page embeddings in a single query.
+///
+/// Every embedding is checked to have `dimension` dimensions before hitting the database.
+///
+/// # Errors
+///
+/// Returns error if any of the embeddings has unexpected dimension.
+/// Returns error if there was a problem while accessing database.
+pub async fn create_many<'a, E>(
+    executor: E,
+    company_id: Uuid,
+    model: &str,
+    dimension: usize,
+    params: Vec<CreateParams<'_>>,
+) -> Result<Vec<PageEmbedding>>
+where
+    E: Executor<'a, Database = Postgres>,
+{
+    let now = Utc::now();
+
+    let mut page_ids = Vec::with_capacity(params.len());
+    let mut chunks = Vec::with_capacity(params.len());
+    let mut embeddings = Vec::with_capacity(params.len());
+
+    for params in params {
+        check_dimension(params.embedding, dimension)?;
+
+        page_ids.push(params.page_id);
+        chunks.push(params.chunk.to_string());
+        // Postgres arrays can't be nested, so vectors are passed as JSON.
+        embeddings.push(json!(params.embedding));
+    }
+
+    Ok(query_as!(
+        PageEmbedding,
+        r#"
+        INSERT INTO page_embeddings (company_id, page_id, model, chunk, embedding, created_at, updated_at)
+        SELECT
+            $1, page_id, $2, chunk,
+            ARRAY(
+                SELECT value::real
+                FROM jsonb_array_elements_text(embedding) WITH ORDINALITY AS e(value, position)
+                ORDER BY position
+            ),
+            $3, $3
+        FROM UNNEST($4::uuid[], $5::text[], $6::jsonb[]) AS t(page_id, chunk, embedding)
+        RETURNING *
+        "#,
+        company_id,
+        model,
+        now,
+        &page_ids,
+        &chunks,
+        &embeddings,
+    )
+    .fetch_all(executor)
+    .await?)
+}
+
+/// Delete all page embeddings of the company.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while accessing database.
+pub async fn delete_all<'a, E>(executor: E, company_id: Uuid) -> Result<()>
+where
+    E: Executor<'a, Database = Postgres>,
+{
+    query!(
+        "DELETE FROM page_embeddings WHERE company_id = $1",
+        company_id
+    )
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::Pool;
+
+    use crate::{embeddings, errors, repo};
+
+    use super::*;
+
+    const COMPANY_ID: Uuid = Uuid::from_u128(1);
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(path = "../../db/fixtures", scripts("companies"))
+    )]
+    async fn test_create_many_rejects_wrong_dimension(pool: Pool<Postgres>) {
+        let page = repo::pages::create(
+            &pool,
+            COMPANY_ID,
+            repo::pages::CreateParams {
+                title: "Weather".to_string(),
+                text: "Rain is in the forecast.".to_string(),
+            },
+        )
+        .await
+        .unwrap();
+
+        let result = create_many(
+            &pool,
+            COMPANY_ID,
+            "model",
+            4,
+            vec![
+                CreateParams {
+                    page_id: page.id,
+                    chunk: "Rain",
+                    embedding: &[0.1, 0.2, 0.3, 0.4],
+                },
+                CreateParams {
+                    page_id: page.id,
+                    chunk: "is in the forecast.",
+                    embedding: &[0.1, 0.2, 0.3],
+                },
+            ],
+        )
+        .await;
+
+        match result {
+            Err(errors::Error::Embeddings(embeddings::Error::DimensionMismatch {
+                expected,
+                got,
+            })) => {
+                assert_eq!((expected, got), (4, 3));
+            }
+            other => panic!("unexpected result: {other:?}"),
+        }
+        assert!(list_for_model(&pool, COMPANY_ID, "model")
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(path = "../../db/fixtures", scripts("companies"))
+    )]
+    async fn test_create_many_keeps_embedding_order(pool: Pool<Postgres>) {
+        let page = repo::pages::create(
+            &pool,
+            COMPANY_ID,
+            repo::pages::CreateParams {
+                title: "Weather".to_string(),
+                text: "Rain is in the forecast.".to_string(),
+            },
+        )
+        .await
+        .unwrap();
+        let embedding = (0..64).map(|i| i as f32 / 64.0).rev().collect::<Vec<_>>();
+
+        let created = create_many(
+            &pool,
+            COMPANY_ID,
+            "model",
+            embedding.len(),
+            vec![CreateParams {
+                page_id: page.id,
+                chunk: "Rain is in the forecast.",
+                embedding: &embedding,
+            }],
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(created[0].embedding, embedding);
+    }
+}
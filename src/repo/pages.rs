@@ -8,6 +8,7 @@ use uuid::Uuid;
 
 use crate::types::{
     pages::{Page, ShortPage},
+    pagination::{Pagination, MAX_PER_PAGE},
     Result,
 };
 
@@ -50,18 +51,51 @@ where
     .await?)
 }
 
-/// List all pages.
+/// List pages.
 ///
 /// # Errors
 ///
+/// Returns error if pagination is invalid.
 /// Returns error if there was a problem while accessing database.
-pub async fn list<'a, E>(executor: E, company_id: Uuid) -> Result<Vec<ShortPage>>
+pub async fn list<'a, E>(
+    executor: E,
+    company_id: Uuid,
+    pagination: Pagination,
+) -> Result<Vec<ShortPage>>
 where
     E: Executor<'a, Database = Postgres>,
 {
+    let pagination = pagination.validate(MAX_PER_PAGE)?;
+
     Ok(query_as!(
         ShortPage,
-        "SELECT id, title, created_at, updated_at FROM pages WHERE company_id = $1",
+        r#"
+        SELECT id, title, created_at, updated_at
+        FROM pages
+        WHERE company_id = $1
+        ORDER BY updated_at DESC
+        LIMIT $2 OFFSET $3
+        "#,
+        company_id,
+        pagination.per_page,
+        pagination.offset(),
+    )
+    .fetch_all(executor)
+    .await?)
+}
+
+/// List all pages with their text.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while accessing database.
+pub async fn list_full<'a, E>(executor: E, company_id: Uuid) -> Result<Vec<Page>>
+where
+    E: Executor<'a, Database = Postgres>,
+{
+    Ok(query_as!(
+        Page,
+        "SELECT * FROM pages WHERE company_id = $1",
         company_id
     )
     .fetch_all(executor)
@@ -28,10 +28,29 @@ where
     {
         Some(row) => Ok(Settings::try_from(row.value)?),
         None => {
-            let settings = Settings::default();
-            insert(executor, company_id, &settings).await?;
+            // Defaults may be inserted concurrently, e.g. while executing sibling tasks at once.
+            query!(
+                r#"
+                INSERT INTO settings (company_id, value, created_at, updated_at)
+                VALUES ($1, $2, $3, $3)
+                ON CONFLICT (company_id) DO NOTHING
+                "#,
+                company_id,
+                serde_json::to_value(Settings::default())?,
+                Utc::now(),
+            )
+            .execute(executor)
+            .await?;
 
-            Ok(settings)
+            let row = query_as!(
+                SettingsRow,
+                "SELECT value FROM settings WHERE company_id = $1 LIMIT 1",
+                company_id,
+            )
+            .fetch_one(executor)
+            .await?;
+
+            Ok(Settings::try_from(row.value)?)
         }
     }
 }
@@ -78,3 +97,30 @@ where
 
     Ok(())
 }
+
+/// Insert or replace settings for a company.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while saving settings.
+pub async fn upsert<'a, E>(executor: E, company_id: Uuid, settings: &Settings) -> Result<()>
+where
+    E: Executor<'a, Database = Postgres>,
+{
+    let now = Utc::now();
+
+    query!(
+        r#"
+        INSERT INTO settings (company_id, value, created_at, updated_at)
+        VALUES ($1, $2, $3, $3)
+        ON CONFLICT (company_id) DO UPDATE SET value = EXCLUDED.value, updated_at = EXCLUDED.updated_at
+        "#,
+        company_id,
+        serde_json::to_value(settings)?,
+        now,
+    )
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
@@ -0,0 +1,93 @@
+// Copyright 2024 StarfleetAI
+// SPDX-License-Identifier: Apache-2.0
+
+use chrono::Utc;
+use sqlx::{query_as, Executor, Postgres};
+use uuid::Uuid;
+
+use crate::task_planner::ExecutionPlan;
+use crate::types::{task_plans::TaskPlan, Result};
+
+/// Create task plan.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while accessing database.
+pub async fn create<'a, E>(
+    executor: E,
+    company_id: Uuid,
+    task_id: Uuid,
+    plan: &ExecutionPlan,
+) -> Result<TaskPlan>
+where
+    E: Executor<'a, Database = Postgres>,
+{
+    let now = Utc::now();
+
+    Ok(query_as!(
+        TaskPlan,
+        r#"
+        INSERT INTO task_plans (company_id, task_id, plan, created_at, updated_at)
+        VALUES ($1, $2, $3, $4, $4)
+        RETURNING *
+        "#,
+        company_id,
+        task_id,
+        serde_json::to_value(plan)?,
+        now,
+    )
+    .fetch_one(executor)
+    .await?)
+}
+
+/// Get the latest plan for the task.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while accessing database.
+pub async fn get_latest_for_task<'a, E>(
+    executor: E,
+    company_id: Uuid,
+    task_id: Uuid,
+) -> Result<Option<TaskPlan>>
+where
+    E: Executor<'a, Database = Postgres>,
+{
+    Ok(query_as!(
+        TaskPlan,
+        r#"
+        SELECT *
+        FROM task_plans
+        WHERE company_id = $1 AND task_id = $2
+        ORDER BY created_at DESC
+        LIMIT 1
+        "#,
+        company_id,
+        task_id,
+    )
+    .fetch_optional(executor)
+    .await?)
+}
+
+/// List all plans for the task, from oldest to newest.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while accessing database.
+pub async fn list_for_task<'a, E>(
+    executor: E,
+    company_id: Uuid,
+    task_id: Uuid,
+) -> Result<Vec<TaskPlan>>
+where
+    E: Executor<'a, Database = Postgres>,
+{
+    Ok(query_as!(
+        TaskPlan,
+        "SELECT * FROM task_plans WHERE company_id = $1 AND task_id = $2 ORDER BY created_at ASC",
+        company_id,
+        task_id,
+    )
+    .fetch_all(executor)
+    .await?)
+}
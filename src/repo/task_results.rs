@@ -1,10 +1,13 @@
 // Copyright 2024 StarfleetAI
 // SPDX-License-Identifier: Apache-2.0
 
+use askama::{Html, MarkupDisplay};
 use chrono::Utc;
 use markdown::to_html;
+use reqwest::Url;
 use serde::{Deserialize, Serialize};
-use sqlx::{query, query_as, Executor, Postgres};
+use serde_json::Value;
+use sqlx::{query, query_as, query_scalar, Executor, Postgres};
 use uuid::Uuid;
 
 use crate::types::{
@@ -45,7 +48,7 @@ where
         company_id,
         params.agent_id,
         params.task_id,
-        params.kind as Kind,
+        params.kind.to_string(),
         params.data,
         now,
     )
@@ -72,7 +75,7 @@ where
     .await?)
 }
 
-/// Get text data by task result id
+/// Get task result data rendered to HTML according to its kind.
 ///
 /// # Errors
 ///
@@ -82,16 +85,79 @@ where
     E: Executor<'a, Database = Postgres>,
 {
     let task_result = query!(
-        "SELECT data FROM task_results WHERE company_id = $1 AND id = $2",
+        "SELECT kind, data FROM task_results WHERE company_id = $1 AND id = $2",
         company_id,
         id
     )
     .fetch_one(executor)
     .await?;
 
-    // Safely render markdown in a result as an untrusted input.
-    let serialized_data = to_html(task_result.data.as_str());
-    Ok(serialized_data)
+    Ok(get_rendered(
+        Kind::from(task_result.kind),
+        &task_result.data,
+    ))
+}
+
+/// Get task result data as it was stored.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while accessing database.
+pub async fn get_raw<'a, E>(executor: E, company_id: Uuid, id: Uuid) -> Result<String>
+where
+    E: Executor<'a, Database = Postgres>,
+{
+    Ok(query_scalar!(
+        "SELECT data FROM task_results WHERE company_id = $1 AND id = $2",
+        company_id,
+        id
+    )
+    .fetch_one(executor)
+    .await?)
+}
+
+/// Renders task result data to HTML. The data is treated as an untrusted input.
+#[must_use]
+pub fn get_rendered(kind: Kind, data: &str) -> String {
+    match kind {
+        // JSON would be mangled by markdown, e.g. `__init__` turns into bold text.
+        Kind::Text if is_json(data) => to_html(&format!("```json\n{}\n```", data.trim())),
+        Kind::Text => to_html(data),
+        Kind::Url => match web_url(data) {
+            Some(url) => format!(
+                r#"<a href="{0}" target="_blank" rel="noopener noreferrer">{0}</a>"#,
+                escape(url.as_str())
+            ),
+            None => format!("<p>{}</p>", escape(data)),
+        },
+        Kind::Image => match web_url(data) {
+            Some(url) => format!(r#"<img src="{}" alt="">"#, escape(url.as_str())),
+            None => render_file(data),
+        },
+        Kind::File => render_file(data),
+    }
+}
+
+fn render_file(path: &str) -> String {
+    format!("<p><code>{}</code></p>", escape(path.trim()))
+}
+
+fn escape(value: &str) -> String {
+    MarkupDisplay::new_unsafe(value, Html).to_string()
+}
+
+fn is_json(data: &str) -> bool {
+    matches!(
+        serde_json::from_str::<Value>(data),
+        Ok(Value::Object(_) | Value::Array(_))
+    )
+}
+
+/// Parses the URL, allowing only the web schemes.
+fn web_url(data: &str) -> Option<Url> {
+    Url::parse(data.trim())
+        .ok()
+        .filter(|url| matches!(url.scheme(), "http" | "https"))
 }
 
 /// Delete task results by task id
@@ -113,3 +179,40 @@ where
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_url_result_renders_as_link() {
+        assert_eq!(
+            get_rendered(Kind::Url, "https://example.com/report?a=1&b=2\n"),
+            r#"<a href="https://example.com/report?a=1&amp;b=2" target="_blank" rel="noopener noreferrer">https://example.com/report?a=1&amp;b=2</a>"#
+        );
+        assert_eq!(
+            get_rendered(Kind::Url, "javascript:alert(1)"),
+            "<p>javascript:alert(1)</p>"
+        );
+    }
+
+    #[test]
+    fn test_json_text_result_is_not_mangled() {
+        let data = r#"{"name": "__init__", "items": [1, 2]}"#;
+
+        let rendered = get_rendered(Kind::Text, data);
+
+        assert_eq!(
+            rendered,
+            format!(
+                r#"<pre><code class="language-json">{}
+</code></pre>"#,
+                escape(data)
+            )
+        );
+        assert_eq!(
+            get_rendered(Kind::Text, "Hello, **world**"),
+            "<p>Hello, <strong>world</strong></p>"
+        );
+    }
+}
@@ -0,0 +1,99 @@
+// Copyright 2024 StarfleetAI
+// SPDX-License-Identifier: Apache-2.0
+
+use chrono::Utc;
+use sqlx::{query, query_as, Executor, Postgres};
+use uuid::Uuid;
+
+use crate::types::{task_secrets::TaskSecret, Result};
+
+/// List secrets available to the agent: the company-wide ones and the agent's own.
+/// Company-wide secrets go first, so that the agent's ones can override them.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while accessing database.
+pub async fn list_for_agent<'a, E>(
+    executor: E,
+    company_id: Uuid,
+    agent_id: Option<Uuid>,
+) -> Result<Vec<TaskSecret>>
+where
+    E: Executor<'a, Database = Postgres>,
+{
+    Ok(query_as!(
+        TaskSecret,
+        r#"
+        SELECT *
+        FROM task_secrets
+        WHERE company_id = $1 AND (agent_id IS NULL OR agent_id = $2)
+        ORDER BY agent_id NULLS FIRST, name
+        "#,
+        company_id,
+        agent_id
+    )
+    .fetch_all(executor)
+    .await?)
+}
+
+/// Create secret, or replace the value of the existing one with the same name and scope.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while accessing database.
+pub async fn upsert<'a, E>(
+    executor: E,
+    company_id: Uuid,
+    agent_id: Option<Uuid>,
+    name: &str,
+    encrypted_value: &[u8],
+) -> Result<TaskSecret>
+where
+    E: Executor<'a, Database = Postgres>,
+{
+    let now = Utc::now();
+
+    Ok(query_as!(
+        TaskSecret,
+        r#"
+        INSERT INTO task_secrets (company_id, agent_id, name, encrypted_value, created_at, updated_at)
+        VALUES ($1, $2, $3, $4, $5, $5)
+        ON CONFLICT (company_id, COALESCE(agent_id, '00000000-0000-0000-0000-000000000000'), name)
+        DO UPDATE SET encrypted_value = EXCLUDED.encrypted_value, updated_at = EXCLUDED.updated_at
+        RETURNING *
+        "#,
+        company_id,
+        agent_id,
+        name,
+        encrypted_value,
+        now,
+    )
+    .fetch_one(executor)
+    .await?)
+}
+
+/// Delete secret.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while accessing database.
+pub async fn delete<'a, E>(
+    executor: E,
+    company_id: Uuid,
+    agent_id: Option<Uuid>,
+    name: &str,
+) -> Result<()>
+where
+    E: Executor<'a, Database = Postgres>,
+{
+    query!(
+        "DELETE FROM task_secrets WHERE company_id = $1 AND agent_id IS NOT DISTINCT FROM $2 AND name = $3",
+        company_id,
+        agent_id,
+        name
+    )
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
@@ -2,12 +2,12 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use anyhow::{anyhow, Context};
-use chrono::Utc;
-use sqlx::{query, query_as, query_scalar, Executor, Postgres};
+use chrono::{DateTime, Utc};
+use sqlx::{query, query_as, query_scalar, Connection, Executor, PgConnection, Postgres};
 use uuid::Uuid;
 
 use crate::types::{
-    pagination::Pagination,
+    pagination::{Page, Pagination, MAX_PER_PAGE},
     tasks::{Status, Task},
     Result,
 };
@@ -85,6 +85,190 @@ pub async fn get_child_for_execution<'a, E: Executor<'a, Database = Postgres>>(
     .await?)
 }
 
+/// Gets the next sub-task of the root task, which can be executed: its sub-tasks and its
+/// dependencies are done.
+///
+/// When none of the siblings have explicit dependencies, tasks depend on their siblings created
+/// before them, so plans without dependencies are executed sequentially, depth-first.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while accessing database.
+pub async fn next_runnable<'a, E: Executor<'a, Database = Postgres>>(
+    executor: E,
+    company_id: Uuid,
+    root: &Task,
+) -> Result<Option<Task>> {
+    let ancestry = root.children_ancestry();
+    let like_ancestry = format!("{ancestry}/%");
+
+    Ok(query_as!(
+        Task,
+        r#"
+        SELECT *
+        FROM tasks
+        WHERE company_id = $1 AND (ancestry = $2 OR ancestry LIKE $3)
+        AND status != $4 AND status != $5
+        AND NOT EXISTS (
+            SELECT 1 FROM tasks AS children
+            WHERE children.company_id = tasks.company_id
+            AND children.ancestry = tasks.ancestry || '/' || tasks.id
+            AND children.status != $5
+        )
+        AND CASE
+            WHEN EXISTS (
+                SELECT 1 FROM task_dependencies
+                INNER JOIN tasks AS siblings ON siblings.id = task_dependencies.task_id
+                WHERE siblings.company_id = tasks.company_id AND siblings.ancestry = tasks.ancestry
+            ) THEN NOT EXISTS (
+                SELECT 1 FROM task_dependencies
+                INNER JOIN tasks AS dependencies ON dependencies.id = task_dependencies.depends_on_id
+                WHERE task_dependencies.task_id = tasks.id AND dependencies.status != $5
+            )
+            ELSE NOT EXISTS (
+                SELECT 1 FROM tasks AS siblings
+                WHERE siblings.company_id = tasks.company_id
+                AND siblings.ancestry = tasks.ancestry
+                AND siblings.created_at < tasks.created_at
+                AND siblings.status != $5
+            )
+        END
+        ORDER BY created_at ASC
+        LIMIT 1
+        "#,
+        company_id,
+        ancestry,
+        like_ancestry,
+        Status::InProgress.to_string(),
+        Status::Done.to_string(),
+    )
+    .fetch_optional(executor)
+    .await?)
+}
+
+/// Returns true if the task's dependencies are done. When none of the siblings have explicit
+/// dependencies, the task depends on its siblings created before it.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while accessing database.
+pub async fn are_dependencies_done<'a, E: Executor<'a, Database = Postgres>>(
+    executor: E,
+    company_id: Uuid,
+    task: &Task,
+) -> Result<bool> {
+    Ok(query_scalar!(
+        r#"
+        SELECT CASE
+            WHEN EXISTS (
+                SELECT 1 FROM task_dependencies
+                INNER JOIN tasks AS siblings ON siblings.id = task_dependencies.task_id
+                WHERE siblings.company_id = tasks.company_id AND siblings.ancestry = tasks.ancestry
+            ) THEN NOT EXISTS (
+                SELECT 1 FROM task_dependencies
+                INNER JOIN tasks AS dependencies ON dependencies.id = task_dependencies.depends_on_id
+                WHERE task_dependencies.task_id = tasks.id AND dependencies.status != $3
+            )
+            ELSE NOT EXISTS (
+                SELECT 1 FROM tasks AS siblings
+                WHERE siblings.company_id = tasks.company_id
+                AND siblings.ancestry = tasks.ancestry
+                AND siblings.created_at < tasks.created_at
+                AND siblings.status != $3
+            )
+        END AS "is_done!"
+        FROM tasks
+        WHERE company_id = $1 AND id = $2
+        "#,
+        company_id,
+        task.id,
+        Status::Done.to_string(),
+    )
+    .fetch_one(executor)
+    .await?)
+}
+
+/// Makes the task depend on the given tasks. The dependencies are not validated, see
+/// [`crate::tasks::add_dependencies`].
+///
+/// # Errors
+///
+/// Returns error if there was a problem while accessing database.
+pub async fn add_dependencies<'a, E: Executor<'a, Database = Postgres>>(
+    executor: E,
+    company_id: Uuid,
+    task_id: Uuid,
+    depends_on: &[Uuid],
+) -> Result<()> {
+    query!(
+        r#"
+        INSERT INTO task_dependencies (company_id, task_id, depends_on_id, created_at)
+        SELECT $1, $2, depends_on_id, $4
+        FROM UNNEST($3::uuid[]) AS t(depends_on_id)
+        ON CONFLICT DO NOTHING
+        "#,
+        company_id,
+        task_id,
+        depends_on,
+        Utc::now(),
+    )
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+/// List ids of the tasks the task explicitly depends on.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while accessing database.
+pub async fn list_dependencies<'a, E: Executor<'a, Database = Postgres>>(
+    executor: E,
+    company_id: Uuid,
+    task_id: Uuid,
+) -> Result<Vec<Uuid>> {
+    Ok(query_scalar!(
+        r#"
+        SELECT depends_on_id
+        FROM task_dependencies
+        WHERE company_id = $1 AND task_id = $2
+        ORDER BY created_at ASC, depends_on_id ASC
+        "#,
+        company_id,
+        task_id,
+    )
+    .fetch_all(executor)
+    .await?)
+}
+
+/// List explicit dependencies among the tasks as `(task_id, depends_on_id)` pairs.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while accessing database.
+pub async fn list_dependencies_among<'a, E: Executor<'a, Database = Postgres>>(
+    executor: E,
+    company_id: Uuid,
+    task_ids: &[Uuid],
+) -> Result<Vec<(Uuid, Uuid)>> {
+    Ok(query!(
+        r#"
+        SELECT task_id, depends_on_id
+        FROM task_dependencies
+        WHERE company_id = $1 AND task_id = ANY($2) AND depends_on_id = ANY($2)
+        ORDER BY created_at ASC, task_id ASC, depends_on_id ASC
+        "#,
+        company_id,
+        task_ids,
+    )
+    .fetch_all(executor)
+    .await?
+    .into_iter()
+    .map(|row| (row.task_id, row.depends_on_id))
+    .collect())
+}
+
 /// List all tasks.
 ///
 /// # Errors
@@ -95,15 +279,7 @@ pub async fn list_roots<'a, E: Executor<'a, Database = Postgres>>(
     company_id: Uuid,
     pagination: Pagination,
 ) -> Result<Vec<Task>> {
-    if pagination.page < 1 {
-        return Err(anyhow!("`page` number must be greater than 0").into());
-    }
-
-    if pagination.per_page < 1 {
-        return Err(anyhow!("`per_page` number must be greater than 0").into());
-    }
-
-    let offset = (pagination.page - 1) * pagination.per_page;
+    let pagination = pagination.validate(MAX_PER_PAGE)?;
 
     Ok(query_as!(
         Task,
@@ -116,7 +292,7 @@ pub async fn list_roots<'a, E: Executor<'a, Database = Postgres>>(
         "#,
         company_id,
         pagination.per_page,
-        offset,
+        pagination.offset(),
     )
     .fetch_all(executor)
     .await?)
@@ -133,15 +309,7 @@ pub async fn list_roots_by_status<'a, E: Executor<'a, Database = Postgres>>(
     status: Status,
     pagination: Pagination,
 ) -> Result<Vec<Task>> {
-    if pagination.page < 1 {
-        return Err(anyhow!("`page` number must be greater than 0").into());
-    }
-
-    if pagination.per_page < 1 {
-        return Err(anyhow!("`per_page` number must be greater than 0").into());
-    }
-
-    let offset = (pagination.page - 1) * pagination.per_page;
+    let pagination = pagination.validate(MAX_PER_PAGE)?;
 
     Ok(query_as!(
         Task,
@@ -155,7 +323,117 @@ pub async fn list_roots_by_status<'a, E: Executor<'a, Database = Postgres>>(
         company_id,
         status.to_string(),
         pagination.per_page,
-        offset,
+        pagination.offset(),
+    )
+    .fetch_all(executor)
+    .await?)
+}
+
+/// List root tasks in progress, oldest first.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while accessing database.
+pub async fn list_roots_in_progress<'a, E: Executor<'a, Database = Postgres>>(
+    executor: E,
+    company_id: Uuid,
+) -> Result<Vec<Task>> {
+    Ok(query_as!(
+        Task,
+        r#"
+        SELECT *
+        FROM tasks
+        WHERE company_id = $1 AND ancestry IS NULL AND status = $2
+        ORDER BY created_at ASC
+        "#,
+        company_id,
+        Status::InProgress.to_string(),
+    )
+    .fetch_all(executor)
+    .await?)
+}
+
+/// List root tasks, optionally by status, along with their total number. The tasks and the total
+/// are read from the same snapshot, in a transaction of their own.
+///
+/// # Errors
+///
+/// Returns error if the connection is already in a transaction which has run queries.
+/// Returns error if there was a problem while accessing database.
+pub async fn list_roots_paged(
+    conn: &mut PgConnection,
+    company_id: Uuid,
+    status: Option<Status>,
+    pagination: Pagination,
+) -> Result<Page<Task>> {
+    let pagination = pagination.validate(MAX_PER_PAGE)?;
+    let status = status.map(|status| status.to_string());
+
+    let mut tx = conn.begin().await.context("Failed to begin transaction")?;
+    query!("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ")
+        .execute(&mut *tx)
+        .await?;
+
+    let items = query_as!(
+        Task,
+        r#"
+        SELECT *
+        FROM tasks
+        WHERE company_id = $1 AND ancestry IS NULL AND ($2::TEXT IS NULL OR status = $2)
+        ORDER BY created_at DESC, id DESC
+        LIMIT $3 OFFSET $4
+        "#,
+        company_id,
+        status,
+        pagination.per_page,
+        pagination.offset(),
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    // Counted separately, so that pages past the last one still report the total.
+    let total = query_scalar!(
+        r#"
+        SELECT COUNT(*) AS "total!"
+        FROM tasks
+        WHERE company_id = $1 AND ancestry IS NULL AND ($2::TEXT IS NULL OR status = $2)
+        "#,
+        company_id,
+        status,
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    tx.commit().await.context("Failed to commit transaction")?;
+
+    Ok(Page {
+        items,
+        total,
+        page: pagination.page,
+        per_page: pagination.per_page,
+    })
+}
+
+/// List tasks updated after the given timestamp, oldest changes first.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while accessing database.
+pub async fn list_changed_since<'a, E: Executor<'a, Database = Postgres>>(
+    executor: E,
+    company_id: Uuid,
+    since: DateTime<Utc>,
+) -> Result<Vec<Task>> {
+    Ok(query_as!(
+        Task,
+        r#"
+        SELECT *
+        FROM tasks
+        WHERE company_id = $1 AND updated_at > $2
+        ORDER BY updated_at ASC
+        "#,
+        company_id,
+        since,
     )
     .fetch_all(executor)
     .await?)
@@ -178,7 +456,7 @@ pub async fn list_all_children<'a, E: Executor<'a, Database = Postgres>>(
         r#"
         SELECT *
         FROM tasks
-        WHERE company_id = $1 AND ancestry = $2 OR ancestry LIKE $3
+        WHERE company_id = $1 AND (ancestry = $2 OR ancestry LIKE $3)
         ORDER BY created_at ASC
         "#,
         company_id,
@@ -206,7 +484,7 @@ pub async fn get_all_children_count<'a, E: Executor<'a, Database = Postgres>>(
         r#"
         SELECT COUNT(*)
         FROM tasks
-        WHERE company_id = $1 AND ancestry = $2 OR ancestry LIKE $3
+        WHERE company_id = $1 AND (ancestry = $2 OR ancestry LIKE $3)
         "#,
         company_id,
         ancestry,
@@ -245,6 +523,31 @@ pub async fn is_all_siblings_done<'a, E: Executor<'a, Database = Postgres>>(
     Ok(count == 0)
 }
 
+/// List tasks with the same parent as the given task, including the task itself.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while accessing database.
+pub async fn list_siblings<'a, E: Executor<'a, Database = Postgres>>(
+    executor: E,
+    company_id: Uuid,
+    task: &Task,
+) -> Result<Vec<Task>> {
+    Ok(query_as!(
+        Task,
+        r#"
+        SELECT *
+        FROM tasks
+        WHERE company_id = $1 AND ancestry IS NOT DISTINCT FROM $2
+        ORDER BY created_at ASC
+        "#,
+        company_id,
+        task.ancestry,
+    )
+    .fetch_all(executor)
+    .await?)
+}
+
 /// List direct children tasks for given task.
 ///
 /// # Errors
@@ -477,6 +780,41 @@ pub async fn start_progress<'a, E: Executor<'a, Database = Postgres>>(
     update_status(executor, company_id, id, Status::InProgress).await
 }
 
+/// Start task by id, unless it has already left one of the `statuses`. The check and the update
+/// are done at once, so a task is never started twice.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while starting task.
+pub async fn start_progress_from<'a, E: Executor<'a, Database = Postgres>>(
+    executor: E,
+    company_id: Uuid,
+    id: Uuid,
+    statuses: &[Status],
+) -> Result<Option<Task>> {
+    let now = Utc::now();
+    let statuses = statuses.iter().map(ToString::to_string).collect::<Vec<_>>();
+
+    Ok(query_as!(
+        Task,
+        r#"
+        UPDATE tasks
+        SET
+            status = $3,
+            updated_at = $4
+        WHERE company_id = $1 AND id = $2 AND status = ANY($5)
+        RETURNING *
+        "#,
+        company_id,
+        id,
+        Status::InProgress.to_string(),
+        now,
+        &statuses,
+    )
+    .fetch_optional(executor)
+    .await?)
+}
+
 /// Marks task as waiting for user input by id.
 ///
 /// # Errors
@@ -569,15 +907,17 @@ pub async fn delete_children<'a, E: Executor<'a, Database = Postgres>>(
     ancestry: Option<&'a str>,
 ) -> Result<()> {
     let children_ancestry = if let Some(ancestry) = ancestry {
-        format!("{ancestry}/{id}/%")
+        format!("{ancestry}/{id}")
     } else {
-        format!("{id}/%")
+        id.to_string()
     };
+    let like_ancestry = format!("{children_ancestry}/%");
 
     query!(
-        "DELETE FROM tasks WHERE company_id = $1 AND ancestry LIKE $2",
+        "DELETE FROM tasks WHERE company_id = $1 AND (ancestry = $2 OR ancestry LIKE $3)",
         company_id,
-        children_ancestry
+        children_ancestry,
+        like_ancestry
     )
     .execute(executor)
     .await?;
@@ -585,6 +925,70 @@ pub async fn delete_children<'a, E: Executor<'a, Database = Postgres>>(
     Ok(())
 }
 
+/// Re-point tasks referencing one chat to another chat.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while updating tasks.
+pub async fn reassign_chat<'a, E: Executor<'a, Database = Postgres>>(
+    executor: E,
+    company_id: Uuid,
+    from_chat_id: Uuid,
+    to_chat_id: Uuid,
+) -> Result<Vec<Task>> {
+    let now = Utc::now();
+
+    Ok(query_as!(
+        Task,
+        r#"
+        UPDATE tasks
+        SET
+            origin_chat_id = CASE WHEN origin_chat_id = $2 THEN $3 ELSE origin_chat_id END,
+            control_chat_id = CASE WHEN control_chat_id = $2 THEN $3 ELSE control_chat_id END,
+            execution_chat_id = CASE WHEN execution_chat_id = $2 THEN $3 ELSE execution_chat_id END,
+            updated_at = $4
+        WHERE company_id = $1 AND (origin_chat_id = $2 OR control_chat_id = $2 OR execution_chat_id = $2)
+        RETURNING *
+        "#,
+        company_id,
+        from_chat_id,
+        to_chat_id,
+        now,
+    )
+    .fetch_all(executor)
+    .await?)
+}
+
+/// List ids of all origin, control and execution chats referenced by the task and its subtree.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while accessing database.
+pub async fn list_related_chat_ids<'a, E: Executor<'a, Database = Postgres>>(
+    executor: E,
+    company_id: Uuid,
+    root: &Task,
+) -> Result<Vec<Uuid>> {
+    let ancestry = root.children_ancestry();
+    let like_ancestry = format!("{ancestry}/%");
+
+    Ok(query_scalar!(
+        r#"
+        SELECT DISTINCT chat_id AS "chat_id!"
+        FROM tasks, unnest(ARRAY[origin_chat_id, control_chat_id, execution_chat_id]) AS chat_id
+        WHERE company_id = $1
+        AND (id = $2 OR ancestry = $3 OR ancestry LIKE $4)
+        AND chat_id IS NOT NULL
+        "#,
+        company_id,
+        root.id,
+        ancestry,
+        like_ancestry,
+    )
+    .fetch_all(executor)
+    .await?)
+}
+
 /// Delete tasks from chat.
 ///
 /// # Errors
@@ -626,6 +1030,24 @@ where
     Ok(())
 }
 
+/// Lists tasks of all companies with the given status, oldest first.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while accessing database.
+pub async fn list_all_by_status<'a, E>(executor: E, status: Status) -> Result<Vec<Task>>
+where
+    E: Executor<'a, Database = Postgres>,
+{
+    Ok(query_as!(
+        Task,
+        "SELECT * FROM tasks WHERE status = $1 ORDER BY created_at ASC",
+        status.to_string(),
+    )
+    .fetch_all(executor)
+    .await?)
+}
+
 /// Assigns tasks to agent by id.
 ///
 /// # Errors
@@ -670,3 +1092,247 @@ pub async fn get_total_number_by_status<'a, E: Executor<'a, Database = Postgres>
 
     Ok(i32::try_from(count).context("Failed to convert tasks count to Uuid")?)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use chrono::TimeZone;
+    use sqlx::{Pool, Postgres};
+
+    use super::*;
+
+    const COMPANY_ID: Uuid = Uuid::from_u128(1);
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(
+            path = "../../db/fixtures",
+            scripts("companies", "users", "agents", "changed_tasks")
+        )
+    )]
+    async fn test_list_changed_since(pool: Pool<Postgres>) {
+        let since = Utc.with_ymd_and_hms(2024, 4, 21, 0, 0, 0).unwrap();
+
+        let titles = list_changed_since(&pool, COMPANY_ID, since)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|task| task.title)
+            .collect::<Vec<_>>();
+
+        assert_eq!(titles, vec!["Updated yesterday", "Updated today"]);
+    }
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(
+            path = "../../db/fixtures",
+            scripts("companies", "users", "agents", "changed_tasks")
+        )
+    )]
+    async fn test_list_roots_paged_total(pool: Pool<Postgres>) {
+        let mut conn = pool.acquire().await.unwrap();
+
+        for per_page in 1..=4 {
+            let page = list_roots_paged(
+                &mut conn,
+                COMPANY_ID,
+                None,
+                Pagination { page: 1, per_page },
+            )
+            .await
+            .unwrap();
+
+            assert_eq!(page.total, 3);
+            assert_eq!(page.items.len(), usize::try_from(per_page.min(3)).unwrap());
+        }
+
+        let page = list_roots_paged(
+            &mut conn,
+            COMPANY_ID,
+            Some(Status::Draft),
+            Pagination {
+                page: 2,
+                per_page: 2,
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(page.total, 3);
+        assert_eq!(page.items.len(), 1);
+        assert_eq!(page.items[0].title, "Updated today");
+
+        let page = list_roots_paged(
+            &mut conn,
+            COMPANY_ID,
+            Some(Status::Done),
+            Pagination {
+                page: 1,
+                per_page: 2,
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(page.total, 0);
+        assert!(page.items.is_empty());
+
+        let page = list_roots_paged(
+            &mut conn,
+            COMPANY_ID,
+            None,
+            Pagination {
+                page: 5,
+                per_page: 2,
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(page.total, 3);
+        assert!(page.items.is_empty());
+    }
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(
+            path = "../../db/fixtures",
+            scripts("companies", "users", "agents", "tasks")
+        )
+    )]
+    async fn test_start_progress_from_starts_task_once(pool: Pool<Postgres>) {
+        const TASK_ID: Uuid = Uuid::from_u128(0x0005_0000_0000_0001);
+
+        update_status(&pool, COMPANY_ID, TASK_ID, Status::ToDo)
+            .await
+            .unwrap();
+
+        let started = [Status::ToDo, Status::Failed];
+        let task = start_progress_from(&pool, COMPANY_ID, TASK_ID, &started)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(task.status, Status::InProgress);
+
+        let task = start_progress_from(&pool, COMPANY_ID, TASK_ID, &started)
+            .await
+            .unwrap();
+        assert!(task.is_none());
+    }
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(
+            path = "../../db/fixtures",
+            scripts("companies", "users", "agents", "tasks")
+        )
+    )]
+    async fn test_next_runnable_follows_diamond_dependencies(pool: Pool<Postgres>) {
+        let root = get(&pool, COMPANY_ID, Uuid::from_u128(0x0005_0000_0000_0001))
+            .await
+            .unwrap();
+
+        // Created in an order which differs from the execution one.
+        let mut ids = HashMap::new();
+        for title in ["D", "B", "C", "A"] {
+            let id = Uuid::new_v4();
+            sqlx::query(
+                "INSERT INTO tasks (id, company_id, user_id, agent_id, title, status, ancestry, \
+                 ancestry_level, created_at, updated_at) \
+                 VALUES ($1, $2, $3, $4, $5, 'ToDo', $6, 1, clock_timestamp(), NOW())",
+            )
+            .bind(id)
+            .bind(COMPANY_ID)
+            .bind(Uuid::from_u128(0x0002_0000_0000_0001))
+            .bind(Uuid::from_u128(0x0003_0000_0000_0001))
+            .bind(title)
+            .bind(root.children_ancestry())
+            .execute(&pool)
+            .await
+            .unwrap();
+
+            ids.insert(title, id);
+        }
+        for (task, depends_on) in [("B", vec!["A"]), ("C", vec!["A"]), ("D", vec!["B", "C"])] {
+            let depends_on = depends_on
+                .iter()
+                .map(|title| ids[title])
+                .collect::<Vec<_>>();
+            add_dependencies(&pool, COMPANY_ID, ids[task], &depends_on)
+                .await
+                .unwrap();
+        }
+
+        let mut order = Vec::new();
+        while let Some(task) = next_runnable(&pool, COMPANY_ID, &root).await.unwrap() {
+            order.push(task.title.clone());
+            complete(&pool, COMPANY_ID, task.id).await.unwrap();
+        }
+
+        assert_eq!(order, vec!["A", "B", "C", "D"]);
+        assert_eq!(
+            list_dependencies(&pool, COMPANY_ID, ids["D"])
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(
+            path = "../../db/fixtures",
+            scripts("companies", "users", "agents", "tasks")
+        )
+    )]
+    async fn test_all_children_are_scoped_to_company(pool: Pool<Postgres>) {
+        let other_company_id = Uuid::from_u128(2);
+        sqlx::query(
+            "INSERT INTO companies (id, name, slug, created_at, updated_at) \
+             VALUES ($1, 'Borg', 'borg', NOW(), NOW())",
+        )
+        .bind(other_company_id)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let root = get(&pool, COMPANY_ID, Uuid::from_u128(0x0005_0000_0000_0001))
+            .await
+            .unwrap();
+        let child_ancestry = root.children_ancestry();
+        let grandchild_ancestry = format!("{child_ancestry}/{}", Uuid::new_v4());
+
+        // Both companies have a child and a grandchild under the same ancestry.
+        for company_id in [COMPANY_ID, other_company_id] {
+            for (ancestry, level) in [(&child_ancestry, 1), (&grandchild_ancestry, 2)] {
+                sqlx::query(
+                    "INSERT INTO tasks (id, company_id, user_id, agent_id, title, status, \
+                     ancestry, ancestry_level, created_at, updated_at) \
+                     VALUES ($1, $2, $3, $4, 'Child', 'ToDo', $5, $6, NOW(), NOW())",
+                )
+                .bind(Uuid::new_v4())
+                .bind(company_id)
+                .bind(Uuid::from_u128(0x0002_0000_0000_0001))
+                .bind(Uuid::from_u128(0x0003_0000_0000_0001))
+                .bind(ancestry)
+                .bind(level)
+                .execute(&pool)
+                .await
+                .unwrap();
+            }
+        }
+
+        for company_id in [COMPANY_ID, other_company_id] {
+            let children = list_all_children(&pool, company_id, &child_ancestry)
+                .await
+                .unwrap();
+            assert_eq!(children.len(), 2);
+            assert!(children.iter().all(|task| task.company_id == company_id));
+
+            let count = get_all_children_count(&pool, company_id, &root)
+                .await
+                .unwrap();
+            assert_eq!(count, 2);
+        }
+    }
+}
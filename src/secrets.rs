@@ -0,0 +1,305 @@
+// Copyright 2024 StarfleetAI
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use sqlx::{Pool, Postgres};
+use tracing::instrument;
+use uuid::Uuid;
+
+use crate::{repo, types::Result};
+
+/// Replacement for the secret values found in the code output.
+const REDACTED: &str = "[REDACTED]";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("invalid secrets encryption key")]
+    InvalidKey,
+    #[error("secret name `{0}` is not a valid environment variable name")]
+    InvalidName(String),
+    #[error("failed to encrypt secret `{0}`")]
+    Encryption(String),
+    #[error("failed to decrypt secret `{0}`, was the encryption key changed?")]
+    Decryption(String),
+}
+
+/// Encrypts the secret values at rest with AES-256-GCM.
+///
+/// Values are bound to the secret names, so they can't be swapped between secrets.
+#[derive(Clone)]
+pub struct SecretsCipher {
+    key: Arc<LessSafeKey>,
+    rng: SystemRandom,
+}
+
+impl SecretsCipher {
+    /// Creates a cipher with the 256-bit key.
+    ///
+    /// # Errors
+    ///
+    /// Returns error if the key is rejected.
+    pub fn new(key: &[u8; 32]) -> Result<Self> {
+        let key = UnboundKey::new(&AES_256_GCM, key).map_err(|_| Error::InvalidKey)?;
+
+        Ok(Self {
+            key: Arc::new(LessSafeKey::new(key)),
+            rng: SystemRandom::new(),
+        })
+    }
+
+    /// Returns the random nonce followed by the sealed value.
+    fn encrypt(&self, name: &str, value: &str) -> std::result::Result<Vec<u8>, Error> {
+        let error = || Error::Encryption(name.to_string());
+
+        let mut nonce = [0; NONCE_LEN];
+        self.rng.fill(&mut nonce).map_err(|_| error())?;
+
+        let mut sealed = value.as_bytes().to_vec();
+        self.key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(name.as_bytes()),
+                &mut sealed,
+            )
+            .map_err(|_| error())?;
+
+        Ok([nonce.as_slice(), &sealed].concat())
+    }
+
+    fn decrypt(&self, name: &str, encrypted: &[u8]) -> std::result::Result<String, Error> {
+        let error = || Error::Decryption(name.to_string());
+
+        if encrypted.len() < NONCE_LEN {
+            return Err(error());
+        }
+        let (nonce, sealed) = encrypted.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| error())?;
+
+        let mut sealed = sealed.to_vec();
+        let value = self
+            .key
+            .open_in_place(nonce, Aad::from(name.as_bytes()), &mut sealed)
+            .map_err(|_| error())?;
+
+        String::from_utf8(value.to_vec()).map_err(|_| error())
+    }
+}
+
+impl Debug for SecretsCipher {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SecretsCipher").finish_non_exhaustive()
+    }
+}
+
+/// Decrypted secrets, passed to the executed code as environment variables.
+///
+/// Values are never printed, `Debug` only shows the names.
+#[derive(Default, Clone)]
+pub struct Secrets(Vec<(String, String)>);
+
+impl Secrets {
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().map(|(name, _)| name.as_str())
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// `NAME=value` pairs for the container environment.
+    pub(crate) fn env(&self) -> Vec<String> {
+        self.0
+            .iter()
+            .map(|(name, value)| format!("{name}={value}"))
+            .collect()
+    }
+
+    /// Replaces the secret values in the text, e.g. when the code prints them.
+    #[must_use]
+    pub fn redact(&self, text: &str) -> String {
+        let mut values = self
+            .0
+            .iter()
+            .map(|(_, value)| value.as_str())
+            .filter(|value| !value.is_empty())
+            .collect::<Vec<_>>();
+        // Longer values first, in case one contains another.
+        values.sort_by_key(|value| std::cmp::Reverse(value.len()));
+
+        values.into_iter().fold(text.to_string(), |text, value| {
+            text.replace(value, REDACTED)
+        })
+    }
+}
+
+impl Debug for Secrets {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Secrets")
+            .field(&self.names().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+/// Stores the secret for the agent, or for all the company's agents if `agent_id` is `None`.
+/// The value of the existing secret with the same name is replaced.
+///
+/// # Errors
+///
+/// Returns error if the name is not a valid environment variable name.
+/// Returns error if there was a problem while encrypting the value or accessing database.
+#[instrument(skip(pool, cipher, value))]
+pub async fn set(
+    pool: &Pool<Postgres>,
+    cipher: &SecretsCipher,
+    cid: Uuid,
+    agent_id: Option<Uuid>,
+    name: &str,
+    value: &str,
+) -> Result<()> {
+    let is_valid_name = name
+        .chars()
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && name
+            .chars()
+            .all(|char| char.is_ascii_alphanumeric() || char == '_');
+    if !is_valid_name {
+        return Err(Error::InvalidName(name.to_string()).into());
+    }
+
+    let encrypted = cipher.encrypt(name, value)?;
+    repo::task_secrets::upsert(pool, cid, agent_id, name, &encrypted).await?;
+
+    Ok(())
+}
+
+/// Deletes the secret of the agent, or the company-wide one if `agent_id` is `None`.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while accessing database.
+#[instrument(skip(pool))]
+pub async fn delete(
+    pool: &Pool<Postgres>,
+    cid: Uuid,
+    agent_id: Option<Uuid>,
+    name: &str,
+) -> Result<()> {
+    repo::task_secrets::delete(pool, cid, agent_id, name).await
+}
+
+/// Decrypts the secrets available to the agent. The agent's own secrets take precedence over
+/// the company-wide ones with the same name.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while accessing database or decrypting the values.
+#[instrument(skip(pool, cipher))]
+pub async fn for_agent(
+    pool: &Pool<Postgres>,
+    cipher: &SecretsCipher,
+    cid: Uuid,
+    agent_id: Option<Uuid>,
+) -> Result<Secrets> {
+    let mut secrets: Vec<(String, String)> = Vec::new();
+
+    for secret in repo::task_secrets::list_for_agent(pool, cid, agent_id).await? {
+        let value = cipher.decrypt(&secret.name, &secret.encrypted_value)?;
+
+        match secrets.iter_mut().find(|(name, _)| *name == secret.name) {
+            Some(existing) => existing.1 = value,
+            None => secrets.push((secret.name, value)),
+        }
+    }
+
+    Ok(Secrets(secrets))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMPANY_ID: Uuid = Uuid::from_u128(1);
+    const RESEARCHER_ID: Uuid = Uuid::from_u128(0x0003_0000_0000_0001);
+    const CODER_ID: Uuid = Uuid::from_u128(0x0003_0000_0000_0003);
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(path = "../db/fixtures", scripts("companies", "agents"))
+    )]
+    async fn test_agent_secrets_override_company_ones(pool: Pool<Postgres>) {
+        let cipher = SecretsCipher::new(&[7; 32]).unwrap();
+
+        set(
+            &pool,
+            &cipher,
+            COMPANY_ID,
+            None,
+            "API_TOKEN",
+            "company-token",
+        )
+        .await
+        .unwrap();
+        set(&pool, &cipher, COMPANY_ID, None, "REGION", "eu-west-1")
+            .await
+            .unwrap();
+        set(
+            &pool,
+            &cipher,
+            COMPANY_ID,
+            Some(CODER_ID),
+            "API_TOKEN",
+            "coder-token",
+        )
+        .await
+        .unwrap();
+
+        let stored = repo::task_secrets::list_for_agent(&pool, COMPANY_ID, Some(CODER_ID))
+            .await
+            .unwrap();
+        assert_eq!(stored.len(), 3);
+        assert!(stored.iter().all(|secret| !secret
+            .encrypted_value
+            .windows(5)
+            .any(|window| window == b"token")));
+
+        let secrets = for_agent(&pool, &cipher, COMPANY_ID, Some(CODER_ID))
+            .await
+            .unwrap();
+        assert_eq!(
+            secrets.env(),
+            vec!["API_TOKEN=coder-token", "REGION=eu-west-1"]
+        );
+        assert_eq!(
+            format!("{secrets:?}"),
+            r#"Secrets(["API_TOKEN", "REGION"])"#
+        );
+        assert_eq!(
+            secrets.redact("token: coder-token"),
+            "token: [REDACTED]".to_string()
+        );
+
+        let secrets = for_agent(&pool, &cipher, COMPANY_ID, Some(RESEARCHER_ID))
+            .await
+            .unwrap();
+        assert_eq!(
+            secrets.env(),
+            vec!["API_TOKEN=company-token", "REGION=eu-west-1"]
+        );
+
+        let other_cipher = SecretsCipher::new(&[8; 32]).unwrap();
+        assert!(matches!(
+            for_agent(&pool, &other_cipher, COMPANY_ID, None).await,
+            Err(crate::errors::Error::Secrets(Error::Decryption(_)))
+        ));
+        assert!(matches!(
+            set(&pool, &cipher, COMPANY_ID, None, "API-TOKEN", "value").await,
+            Err(crate::errors::Error::Secrets(Error::InvalidName(_)))
+        ));
+    }
+}
@@ -1,41 +1,121 @@
 // Copyright 2024 StarfleetAI
 // SPDX-License-Identifier: Apache-2.0
 
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, num::NonZeroUsize, time::Duration};
 
 use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::Value;
 
-use crate::types::models::Provider;
+use crate::{
+    embeddings::{DevicePref, Pooling},
+    types::{models::Provider, tasks::WorkdirIsolation},
+};
 
 const DEFAULT_EMBEDDINGS_MODEL: &str = "sentence-transformers/all-MiniLM-L6-v2";
+const DEFAULT_EMBEDDINGS_CACHE_SIZE: usize = 10_000;
+const DEFAULT_EMBEDDINGS_BATCH_SIZE: NonZeroUsize = NonZeroUsize::new(24).unwrap();
 const DEFAULT_MODEL: &str = "OpenAI/gpt-4-turbo";
 const DEFAULT_EXECUTION_STEPS_LIMIT: i64 = 12;
 const DEFAULT_PLANNING_DEPTH_LIMIT: u8 = 5;
+const DEFAULT_PLANNING_RETRIES_LIMIT: u8 = 2;
+const DEFAULT_COMPLETION_TIMEOUT_SECS: u64 = 300;
+const DEFAULT_NO_PROGRESS_WINDOW: u8 = 3;
+const DEFAULT_MAX_SUBTASKS_PER_PLAN: u16 = 20;
+const DEFAULT_MAX_TOOL_CALL_ARGUMENTS_LEN: usize = 256 * 1024;
+const DEFAULT_STREAMING_HEARTBEAT_INTERVAL_MS: u64 = 5_000;
+const DEFAULT_SANDBOX_MEMORY_MB: u64 = 512;
+const DEFAULT_SANDBOX_CPUS: f64 = 1.0;
+const DEFAULT_SANDBOX_PIDS_LIMIT: i64 = 512;
+const DEFAULT_SANDBOX_TIMEOUT_SECS: u64 = 60;
+const DEFAULT_PYTHON_IMAGE: &str = "python:slim";
+const DEFAULT_NODE_IMAGE: &str = "node:slim";
+const DEFAULT_CHROMEDRIVER_IMAGE: &str = "zenika/alpine-chrome:with-chromedriver";
+const DEFAULT_GECKODRIVER_IMAGE: &str = "instrumentisto/geckodriver";
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Embeddings {
     #[serde(default = "default_embeddings_model")]
     pub model: String,
+    /// Branch, tag or commit SHA of the model repo, `main` if not set.
+    #[serde(default)]
+    pub revision: Option<String>,
+    /// Number of computed embeddings kept in memory, so that identical texts are not re-embedded.
+    #[serde(default = "default_embeddings_cache_size")]
+    pub cache_size: usize,
+    /// Number of sentences embedded at once. Larger batches are faster on GPUs, smaller ones
+    /// need less memory.
+    #[serde(default = "default_embeddings_batch_size")]
+    pub batch_size: NonZeroUsize,
+    /// How the token embeddings are pooled, should match the one the model was trained with.
+    #[serde(default)]
+    pub pooling: Pooling,
+    /// Device to compute the embeddings on, e.g. `{ "cuda": 1 }` for the second CUDA GPU.
+    #[serde(default)]
+    pub device: DevicePref,
 }
 
 fn default_embeddings_model() -> String {
     DEFAULT_EMBEDDINGS_MODEL.to_string()
 }
 
+fn default_embeddings_cache_size() -> usize {
+    DEFAULT_EMBEDDINGS_CACHE_SIZE
+}
+
+fn default_embeddings_batch_size() -> NonZeroUsize {
+    DEFAULT_EMBEDDINGS_BATCH_SIZE
+}
+
 impl Default for Embeddings {
     fn default() -> Self {
         Self {
             model: DEFAULT_EMBEDDINGS_MODEL.to_string(),
+            revision: None,
+            cache_size: DEFAULT_EMBEDDINGS_CACHE_SIZE,
+            batch_size: DEFAULT_EMBEDDINGS_BATCH_SIZE,
+            pooling: Pooling::default(),
+            device: DevicePref::default(),
         }
     }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Tasks {
+    /// Maximum number of independent sibling sub-tasks executed at once.
     pub execution_concurrency: u16,
     #[serde(default = "default_planning_depth_limit")]
     pub planning_depth_limit: u8,
+    /// How many times the planner re-prompts the LLM when a plan assigns sub-tasks to invalid agents.
+    #[serde(default = "default_planning_retries_limit")]
+    pub planning_retries_limit: u8,
+    /// How long a single chat completion during task execution may take before the task fails.
+    /// `0` disables the limit.
+    #[serde(default = "default_completion_timeout_secs")]
+    pub completion_timeout_secs: u64,
+    #[serde(default)]
+    pub workdir_isolation: WorkdirIsolation,
+    /// Number of identical agent replies in a row after which the task is considered stuck.
+    /// The agent is urged to finish the task first, and the task fails on the next repeat.
+    /// Values below 2 disable the check.
+    #[serde(default = "default_no_progress_window")]
+    pub no_progress_window: u8,
+    /// Maximum number of sub-tasks in a single plan. Larger plans are sent back to the LLM to be
+    /// made smaller. Single-task plans are always allowed.
+    #[serde(default = "default_max_subtasks_per_plan")]
+    pub max_subtasks_per_plan: u16,
+    /// How long a single task may be executed, including the code interpretation, before it
+    /// fails. `0` disables the limit.
+    #[serde(default)]
+    pub task_timeout_secs: u64,
+    /// How many times a task is retried after a transient error, e.g. a rate limit or a network
+    /// failure, before it fails.
+    #[serde(default)]
+    pub max_retries: u8,
+    /// Act on the fenced code blocks without the `> execute` or `> save` directive: execute the
+    /// shell, Python and JavaScript ones, and save the ones with a filename in the info string.
+    /// Off by default, so that only the code explicitly marked for execution is run.
+    #[serde(default)]
+    pub implicit_code_block_actions: bool,
 }
 
 impl Default for Tasks {
@@ -43,6 +123,14 @@ impl Default for Tasks {
         Self {
             execution_concurrency: 1,
             planning_depth_limit: DEFAULT_PLANNING_DEPTH_LIMIT,
+            planning_retries_limit: DEFAULT_PLANNING_RETRIES_LIMIT,
+            completion_timeout_secs: DEFAULT_COMPLETION_TIMEOUT_SECS,
+            workdir_isolation: WorkdirIsolation::default(),
+            no_progress_window: DEFAULT_NO_PROGRESS_WINDOW,
+            max_subtasks_per_plan: DEFAULT_MAX_SUBTASKS_PER_PLAN,
+            task_timeout_secs: 0,
+            max_retries: 0,
+            implicit_code_block_actions: false,
         }
     }
 }
@@ -51,6 +139,22 @@ fn default_planning_depth_limit() -> u8 {
     DEFAULT_PLANNING_DEPTH_LIMIT
 }
 
+fn default_planning_retries_limit() -> u8 {
+    DEFAULT_PLANNING_RETRIES_LIMIT
+}
+
+fn default_completion_timeout_secs() -> u64 {
+    DEFAULT_COMPLETION_TIMEOUT_SECS
+}
+
+fn default_no_progress_window() -> u8 {
+    DEFAULT_NO_PROGRESS_WINDOW
+}
+
+fn default_max_subtasks_per_plan() -> u16 {
+    DEFAULT_MAX_SUBTASKS_PER_PLAN
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Agents {
     #[serde(default = "default_execution_steps_limit")]
@@ -69,6 +173,166 @@ impl Default for Agents {
     }
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Chats {
+    /// Maximum size of a single tool call's arguments in a streamed completion, in bytes.
+    /// The completion is aborted and the message fails once it's exceeded.
+    #[serde(default = "default_max_tool_call_arguments_len")]
+    pub max_tool_call_arguments_len: usize,
+    /// Interval of the `MessageStreaming` heartbeats, sent while no chunks arrive. `0` disables
+    /// the heartbeats.
+    #[serde(default = "default_streaming_heartbeat_interval_ms")]
+    pub streaming_heartbeat_interval_ms: u64,
+}
+
+fn default_max_tool_call_arguments_len() -> usize {
+    DEFAULT_MAX_TOOL_CALL_ARGUMENTS_LEN
+}
+
+fn default_streaming_heartbeat_interval_ms() -> u64 {
+    DEFAULT_STREAMING_HEARTBEAT_INTERVAL_MS
+}
+
+impl Default for Chats {
+    fn default() -> Self {
+        Self {
+            max_tool_call_arguments_len: DEFAULT_MAX_TOOL_CALL_ARGUMENTS_LEN,
+            streaming_heartbeat_interval_ms: DEFAULT_STREAMING_HEARTBEAT_INTERVAL_MS,
+        }
+    }
+}
+
+/// Resource limits of the containers the code is executed in. `0` disables a limit.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Sandbox {
+    /// Memory available to a container, in megabytes. The code is killed once it's exceeded.
+    #[serde(default = "default_sandbox_memory_mb")]
+    pub memory_mb: u64,
+    /// Number of CPUs available to a container, may be fractional.
+    #[serde(
+        default = "default_sandbox_cpus",
+        deserialize_with = "deserialize_cpus"
+    )]
+    pub cpus: f64,
+    /// Maximum number of processes and threads in a container.
+    #[serde(default = "default_sandbox_pids_limit")]
+    pub pids_limit: i64,
+    /// How long a single code execution may take before its container is removed.
+    #[serde(default = "default_sandbox_timeout_secs")]
+    pub timeout_secs: u64,
+    /// Whether the executed code is cut off from the network. Doesn't apply to the `WebDriver`
+    /// containers, which need the network to browse.
+    #[serde(default = "default_sandbox_network_disabled")]
+    pub network_disabled: bool,
+    /// Image the Python code and the shell commands are executed in.
+    #[serde(
+        default = "default_python_image",
+        deserialize_with = "deserialize_image"
+    )]
+    pub python_image: String,
+    /// Image the JavaScript code is executed in.
+    #[serde(default = "default_node_image", deserialize_with = "deserialize_image")]
+    pub node_image: String,
+    /// Image of the `WebDriver` container for Chrome.
+    #[serde(
+        default = "default_chromedriver_image",
+        deserialize_with = "deserialize_image"
+    )]
+    pub chromedriver_image: String,
+    /// Image of the `WebDriver` container for Firefox.
+    #[serde(
+        default = "default_geckodriver_image",
+        deserialize_with = "deserialize_image"
+    )]
+    pub geckodriver_image: String,
+}
+
+impl Sandbox {
+    /// Code execution timeout, `None` if it's disabled.
+    #[must_use]
+    pub fn timeout(&self) -> Option<Duration> {
+        (self.timeout_secs > 0).then(|| Duration::from_secs(self.timeout_secs))
+    }
+}
+
+fn default_sandbox_memory_mb() -> u64 {
+    DEFAULT_SANDBOX_MEMORY_MB
+}
+
+fn default_sandbox_cpus() -> f64 {
+    DEFAULT_SANDBOX_CPUS
+}
+
+fn default_sandbox_pids_limit() -> i64 {
+    DEFAULT_SANDBOX_PIDS_LIMIT
+}
+
+fn default_sandbox_timeout_secs() -> u64 {
+    DEFAULT_SANDBOX_TIMEOUT_SECS
+}
+
+fn default_sandbox_network_disabled() -> bool {
+    true
+}
+
+fn default_python_image() -> String {
+    DEFAULT_PYTHON_IMAGE.to_string()
+}
+
+fn default_node_image() -> String {
+    DEFAULT_NODE_IMAGE.to_string()
+}
+
+fn default_chromedriver_image() -> String {
+    DEFAULT_CHROMEDRIVER_IMAGE.to_string()
+}
+
+fn default_geckodriver_image() -> String {
+    DEFAULT_GECKODRIVER_IMAGE.to_string()
+}
+
+fn deserialize_image<'de, D>(deserializer: D) -> std::result::Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let image = String::deserialize(deserializer)?;
+    if image.trim().is_empty() {
+        return Err(serde::de::Error::custom("image name must not be empty"));
+    }
+
+    Ok(image)
+}
+
+fn deserialize_cpus<'de, D>(deserializer: D) -> std::result::Result<f64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let cpus = f64::deserialize(deserializer)?;
+    if !cpus.is_finite() || cpus < 0.0 {
+        return Err(serde::de::Error::custom(
+            "number of CPUs must be a finite non-negative number",
+        ));
+    }
+
+    Ok(cpus)
+}
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        Self {
+            memory_mb: DEFAULT_SANDBOX_MEMORY_MB,
+            cpus: DEFAULT_SANDBOX_CPUS,
+            pids_limit: DEFAULT_SANDBOX_PIDS_LIMIT,
+            timeout_secs: DEFAULT_SANDBOX_TIMEOUT_SECS,
+            network_disabled: true,
+            python_image: DEFAULT_PYTHON_IMAGE.to_string(),
+            node_image: DEFAULT_NODE_IMAGE.to_string(),
+            chromedriver_image: DEFAULT_CHROMEDRIVER_IMAGE.to_string(),
+            geckodriver_image: DEFAULT_GECKODRIVER_IMAGE.to_string(),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Settings {
     #[serde(default = "default_model")]
@@ -81,6 +345,10 @@ pub struct Settings {
     pub embeddings: Embeddings,
     #[serde(default)]
     pub tasks: Tasks,
+    #[serde(default)]
+    pub chats: Chats,
+    #[serde(default)]
+    pub sandbox: Sandbox,
 }
 
 fn deserialize_null_default<'de, D, T>(deserializer: D) -> std::result::Result<T, D::Error>
@@ -104,6 +372,8 @@ impl Default for Settings {
             agents: Agents::default(),
             embeddings: Embeddings::default(),
             tasks: Tasks::default(),
+            chats: Chats::default(),
+            sandbox: Sandbox::default(),
         }
     }
 }
@@ -121,3 +391,67 @@ impl TryFrom<Value> for Settings {
         serde_json::from_value(value).map_err(Self::Error::JsonDeserialization)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_sandbox_images() {
+        let settings = Settings::try_from(json!({
+            "sandbox": { "python_image": "registry.local/python:3.12-pandas" }
+        }))
+        .unwrap();
+        assert_eq!(
+            settings.sandbox.python_image,
+            "registry.local/python:3.12-pandas"
+        );
+        assert_eq!(settings.sandbox.node_image, DEFAULT_NODE_IMAGE);
+        assert_eq!(
+            settings.sandbox.chromedriver_image,
+            DEFAULT_CHROMEDRIVER_IMAGE
+        );
+
+        let result = Settings::try_from(json!({ "sandbox": { "python_image": " " } }));
+        assert!(matches!(result, Err(Error::JsonDeserialization(_))));
+    }
+
+    #[test]
+    fn test_sandbox_cpus() {
+        let settings = Settings::try_from(json!({ "sandbox": { "cpus": 0.5 } })).unwrap();
+        assert!((settings.sandbox.cpus - 0.5).abs() < f64::EPSILON);
+
+        let settings = Settings::try_from(json!({ "sandbox": { "cpus": 0 } })).unwrap();
+        assert!(settings.sandbox.cpus.abs() < f64::EPSILON);
+
+        let result = Settings::try_from(json!({ "sandbox": { "cpus": -1 } }));
+        assert!(matches!(result, Err(Error::JsonDeserialization(_))));
+    }
+
+    #[test]
+    fn test_embeddings_batch_size() {
+        let settings = Settings::try_from(json!({})).unwrap();
+        assert_eq!(settings.embeddings.batch_size.get(), 24);
+
+        let settings = Settings::try_from(json!({ "embeddings": { "batch_size": 128 } })).unwrap();
+        assert_eq!(settings.embeddings.batch_size.get(), 128);
+
+        let result = Settings::try_from(json!({ "embeddings": { "batch_size": 0 } }));
+        assert!(matches!(result, Err(Error::JsonDeserialization(_))));
+    }
+
+    #[test]
+    fn test_embeddings_device() {
+        let settings = Settings::try_from(json!({})).unwrap();
+        assert_eq!(settings.embeddings.device, DevicePref::Auto);
+
+        let settings = Settings::try_from(json!({ "embeddings": { "device": "cpu" } })).unwrap();
+        assert_eq!(settings.embeddings.device, DevicePref::Cpu);
+
+        let settings =
+            Settings::try_from(json!({ "embeddings": { "device": { "cuda": 1 } } })).unwrap();
+        assert_eq!(settings.embeddings.device, DevicePref::Cuda(1));
+    }
+}
@@ -1,24 +1,34 @@
 // Copyright 2024 StarfleetAI
 // SPDX-License-Identifier: Apache-2.0
 
-use std::path::PathBuf;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write,
+    hash::{DefaultHasher, Hash, Hasher},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::Duration,
+};
 
 use anyhow::{anyhow, Context};
 use askama::Template;
+use futures_util::{stream::FuturesUnordered, StreamExt};
+use reqwest::StatusCode;
 use serde::Deserialize;
-use serde_json::json;
 use sqlx::{Pool, Postgres};
-use tokio::fs;
-use tracing::{debug, error, info, instrument};
+use tokio::{fs, io::AsyncReadExt};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, field, info, instrument, warn, Span};
 use uuid::Uuid;
 
 use crate::channel::{self, Channel};
-use crate::clients::openai::{ToolCall, ToolCalls};
+use crate::clients::openai::{self, Function, Tool, ToolCall, ToolCalls};
 use crate::repo::{self, messages::CreateParams};
+use crate::secrets::{self, Secrets, SecretsCipher};
 use crate::settings::Settings;
+use crate::transcript::TranscriptSink;
 use crate::types::Result;
 use crate::types::{
-    abilities::Ability,
     agents::Agent,
     chats::{Chat, Kind},
     messages::{Message, Role},
@@ -28,7 +38,7 @@ use crate::{
     chats::{self, CreateCompletionParams},
     docker,
 };
-use crate::{models, types};
+use crate::{errors, models, types};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -38,6 +48,20 @@ pub enum Error {
     NotAnExecutionChat(Uuid),
     #[error("failed to render template: {0}")]
     TemplateRender(#[from] askama::Error),
+    #[error("agent made no progress in the last {0} replies")]
+    NoProgress(u8),
+    #[error("task `{0}` can't be executed in `{1}` status")]
+    NotRunnable(Uuid, Status),
+    #[error("task `{0}` has sub-tasks which are not ready for execution")]
+    ChildrenNotReady(Uuid),
+    #[error("task `{0}` can't be executed before its dependencies are done")]
+    DependenciesNotReady(Uuid),
+    #[error("agent's response `{0}` was stopped by the content filter")]
+    ContentFiltered(Uuid),
+    #[error("task execution was cancelled")]
+    Cancelled,
+    #[error("task execution timed out after {0:?}")]
+    TimedOut(Duration),
 }
 
 pub struct TaskExecutor<'a> {
@@ -46,11 +70,22 @@ pub struct TaskExecutor<'a> {
     pub settings: &'a Settings,
     pub workdir_root: PathBuf,
     pub user_agent: String,
+    /// Audit log of the execution messages and tool calls.
+    pub transcript: Option<TranscriptSink>,
+    /// Decrypts the secrets passed to the interpreted code. No secrets are passed if not set.
+    pub secrets_cipher: Option<SecretsCipher>,
 }
 
 impl TaskExecutor<'_> {
+    /// Executes the next root task. Once `cancel` is cancelled, the execution stops before the
+    /// next step and the task is marked as `Cancelled`.
+    ///
+    /// # Errors
+    ///
+    /// Returns error if there are no root tasks to execute.
+    /// Returns error if there was a problem while executing the task.
     #[instrument(skip_all)]
-    pub async fn execute_root_task(&self, cid: Uuid) -> Result<()> {
+    pub async fn execute_root_task(&self, cid: Uuid, cancel: &CancellationToken) -> Result<()> {
         let mut task = match self.get_root_task_for_execution(cid).await {
             Ok(Some(task)) => task,
             Ok(None) => return Err(Error::NoRootTasks.into()),
@@ -62,21 +97,74 @@ impl TaskExecutor<'_> {
         self.channel
             .emit(uid, &channel::Event::TaskUpdated(&task))
             .await?;
+        self.emit_tree(cid, uid, &task).await?;
 
         info!("Root task for execution: #{}. {}", task.id, task.title);
 
-        let children_count = repo::tasks::get_all_children_count(self.pool, cid, &task).await?;
+        self.execute_root(cid, uid, &mut task, cancel).await
+    }
+
+    /// Continues the root tasks left in progress by the previous process, from the state of their
+    /// execution chats. Tasks which can't be resumed are reset by [`crate::database::prepare`].
+    ///
+    /// # Errors
+    ///
+    /// Returns error if there was a problem while executing a task.
+    #[instrument(skip(self, cancel))]
+    pub async fn resume_root_tasks(&self, cid: Uuid, cancel: &CancellationToken) -> Result<()> {
+        for mut task in repo::tasks::list_roots_in_progress(self.pool, cid).await? {
+            // TODO: get the uid from task author
+            let uid = Uuid::new_v4();
+            self.channel
+                .emit(uid, &channel::Event::TaskUpdated(&task))
+                .await?;
+            self.emit_tree(cid, uid, &task).await?;
+
+            info!("Resuming root task #{}: {}", task.id, task.title);
+
+            // Sub-tasks in progress are not picked as runnable, so they are continued first.
+            let children =
+                repo::tasks::list_all_children(self.pool, cid, &task.children_ancestry()).await?;
+            for child in &children {
+                let ancestry = child.children_ancestry();
+                let is_leaf = !children
+                    .iter()
+                    .any(|other| other.ancestry.as_deref() == Some(ancestry.as_str()));
+
+                if child.status == Status::InProgress && is_leaf {
+                    info!("Resuming child task #{}: {}", child.id, child.title);
+                    self.execute_child(cid, uid, &task, child.clone(), cancel)
+                        .await?;
+                }
+            }
+
+            self.execute_root(cid, uid, &mut task, cancel).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Executes the root task in progress, along with its sub-tasks if it has any.
+    async fn execute_root(
+        &self,
+        cid: Uuid,
+        uid: Uuid,
+        task: &mut Task,
+        cancel: &CancellationToken,
+    ) -> Result<()> {
+        let children_count = repo::tasks::get_all_children_count(self.pool, cid, task).await?;
 
         if children_count > 0 {
             info!("Executing children tasks for root task #{}.", task.id);
-            self.execute_children_task_tree(cid, uid, &mut task).await?;
+            self.execute_children_task_tree(cid, uid, task, cancel)
+                .await?;
 
             return Ok(());
         }
 
         info!("Executing root task #{}", task.id);
 
-        match self.execute_task(cid, uid, &mut task).await {
+        match self.execute_task(cid, uid, task, cancel).await {
             Ok(status) => {
                 debug!(
                     "No errors. Transitioning root task #{} to status: {:?}",
@@ -87,6 +175,84 @@ impl TaskExecutor<'_> {
                 self.channel
                     .emit(uid, &channel::Event::TaskUpdated(&task))
                     .await?;
+                self.emit_tree(cid, uid, &task).await?;
+
+                Ok(())
+            }
+            Err(err) => {
+                let task = repo::tasks::fail(self.pool, cid, task.id).await?;
+                self.channel
+                    .emit(uid, &channel::Event::TaskUpdated(&task))
+                    .await?;
+                self.emit_tree(cid, uid, &task).await?;
+
+                Err(err)
+            }
+        }
+    }
+
+    /// Executes the given task right away instead of waiting for its turn. Tasks with sub-tasks
+    /// are executed along with them.
+    ///
+    /// # Errors
+    ///
+    /// Returns error if the task is in progress, done or is a draft.
+    /// Returns error if any of the sub-tasks is a draft.
+    /// Returns error if any of the task's dependencies is not done.
+    /// Returns error if there was a problem while executing the task.
+    #[instrument(skip(self, cancel))]
+    pub async fn execute_task_by_id(
+        &self,
+        cid: Uuid,
+        task_id: Uuid,
+        cancel: &CancellationToken,
+    ) -> Result<()> {
+        let task = repo::tasks::get(self.pool, cid, task_id).await?;
+        self.ensure_runnable(cid, &task).await?;
+
+        let root = match task.parent_ids()? {
+            Some(parent_ids) => {
+                let root_id = parent_ids.first().context("ancestry is empty")?;
+                repo::tasks::get(self.pool, cid, *root_id).await?
+            }
+            None => task.clone(),
+        };
+
+        // TODO: get the uid from task author
+        let uid = Uuid::new_v4();
+        // The task may have been started since the check.
+        let Some(mut task) =
+            repo::tasks::start_progress_from(self.pool, cid, task.id, &RUNNABLE_STATUSES).await?
+        else {
+            let task = repo::tasks::get(self.pool, cid, task.id).await?;
+
+            return Err(Error::NotRunnable(task.id, task.status).into());
+        };
+        self.channel
+            .emit(uid, &channel::Event::TaskUpdated(&task))
+            .await?;
+        self.emit_tree(cid, uid, &root).await?;
+
+        if repo::tasks::get_all_children_count(self.pool, cid, &task).await? > 0 {
+            info!("Executing children tasks for task #{}.", task.id);
+
+            return self
+                .execute_children_task_tree(cid, uid, &task, cancel)
+                .await;
+        }
+
+        match self.execute_task(cid, uid, &mut task, cancel).await {
+            Ok(status) => {
+                let task = repo::tasks::update_status(self.pool, cid, task.id, status).await?;
+                self.channel
+                    .emit(uid, &channel::Event::TaskUpdated(&task))
+                    .await?;
+
+                if status == Status::Done && task.ancestry.is_some() {
+                    self.complete_parent_if_siblings_done(cid, uid, &task)
+                        .await?;
+                }
+                self.emit_tree(cid, uid, &root).await?;
 
                 Ok(())
             }
@@ -95,12 +261,83 @@ impl TaskExecutor<'_> {
                 self.channel
                     .emit(uid, &channel::Event::TaskUpdated(&task))
                     .await?;
+                self.fail_parent_tasks(cid, uid, &task).await?;
+                self.emit_tree(cid, uid, &root).await?;
 
                 Err(err)
             }
         }
     }
 
+    /// Checks that the task can be executed on demand.
+    async fn ensure_runnable(&self, cid: Uuid, task: &Task) -> Result<()> {
+        if !RUNNABLE_STATUSES.contains(&task.status) {
+            return Err(Error::NotRunnable(task.id, task.status).into());
+        }
+
+        // Sub-tasks are executed along with the task, so they must be approved.
+        let children =
+            repo::tasks::list_all_children(self.pool, cid, &task.children_ancestry()).await?;
+        if children.iter().any(|child| child.status == Status::Draft) {
+            return Err(Error::ChildrenNotReady(task.id).into());
+        }
+
+        if !repo::tasks::are_dependencies_done(self.pool, cid, task).await? {
+            return Err(Error::DependenciesNotReady(task.id).into());
+        }
+
+        Ok(())
+    }
+
+    /// Emits the current state of the whole task subtree as a single event, so the client
+    /// doesn't have to assemble it from the individual task updates.
+    ///
+    /// # Errors
+    ///
+    /// Returns error if there was a problem while accessing database or emitting the event.
+    #[instrument(skip(self, root), fields(root_id = %root.id))]
+    pub async fn emit_tree(&self, cid: Uuid, uid: Uuid, root: &Task) -> Result<()> {
+        let tasks = self.load_tree(cid, root.id).await?;
+
+        self.channel
+            .emit(
+                uid,
+                &channel::Event::TaskTreeUpdated {
+                    root_id: root.id,
+                    tasks: &tasks,
+                },
+            )
+            .await
+    }
+
+    /// Renders the task subtree as a Mermaid diagram, see [`render_tree`].
+    ///
+    /// # Errors
+    ///
+    /// Returns error if there was a problem while accessing database.
+    #[instrument(skip(self))]
+    pub async fn render_subtree(&self, cid: Uuid, root_id: Uuid) -> Result<String> {
+        let tasks = self.load_tree(cid, root_id).await?;
+        let ids = tasks.iter().map(|task| task.id).collect::<Vec<_>>();
+        let dependencies = repo::tasks::list_dependencies_among(self.pool, cid, &ids).await?;
+
+        render_tree(&tasks, &dependencies)
+    }
+
+    /// Current state of the task subtree, root first.
+    async fn load_tree(&self, cid: Uuid, root_id: Uuid) -> Result<Vec<Task>> {
+        let root = repo::tasks::get(self.pool, cid, root_id).await?;
+        let mut children =
+            repo::tasks::list_all_children(self.pool, cid, &root.children_ancestry()).await?;
+        sort_task_tree(&mut children);
+
+        let mut tasks = Vec::with_capacity(children.len() + 1);
+        tasks.push(root);
+        tasks.append(&mut children);
+
+        Ok(tasks)
+    }
+
     #[instrument(skip_all)]
     async fn get_task_execution_chat(&self, cid: Uuid, task: &Task) -> Result<Chat> {
         if let Some(chat_id) = task.execution_chat_id {
@@ -148,87 +385,146 @@ impl TaskExecutor<'_> {
 
     #[instrument(skip_all)]
     async fn get_child_task_for_execution(&self, cid: Uuid, parent: &Task) -> Result<Option<Task>> {
-        let mut children_tasks =
-            repo::tasks::list_all_children(self.pool, cid, &parent.children_ancestry())
-                .await
-                .context("failed to list children")?;
-
-        let mut tree = TaskTree {
-            root: (*parent).clone(),
-            children: Vec::new(),
-        };
-
-        sort_task_tree(&mut children_tasks);
-        collect_children(&mut tree, &mut children_tasks)?;
-
-        if let Some(task) = find_execution_candidate(&tree) {
-            return Ok(Some(
+        match repo::tasks::next_runnable(self.pool, cid, parent)
+            .await
+            .context("failed to get next runnable task")?
+        {
+            Some(task) => Ok(Some(
                 repo::tasks::start_progress(self.pool, cid, task.id).await?,
-            ));
+            )),
+            None => Ok(None),
         }
-
-        Ok(None)
     }
 
+    /// Executes the runnable sub-tasks, up to `execution_concurrency` of them at once. Once a
+    /// sub-task fails or the execution is cancelled, no more sub-tasks are started, and the ones
+    /// running are waited for.
     async fn execute_children_task_tree(
         &self,
         cid: Uuid,
         uid: Uuid,
-        parent: &mut Task,
+        parent: &Task,
+        cancel: &CancellationToken,
     ) -> Result<()> {
         info!("Executing children tasks tree for task #{}", parent.id);
 
-        while let Some(mut child) = match self.get_child_task_for_execution(cid, parent).await {
-            Ok(task) => task,
-            Err(err) => {
-                repo::tasks::fail(self.pool, cid, parent.id).await?;
-                self.fail_parent_tasks(cid, uid, parent).await?;
+        let concurrency = usize::from(self.settings.tasks.execution_concurrency.max(1));
+        let mut running = FuturesUnordered::new();
+        let mut result = Ok(());
+
+        loop {
+            while result.is_ok() && !cancel.is_cancelled() && running.len() < concurrency {
+                let child = match self.get_child_task_for_execution(cid, parent).await {
+                    Ok(Some(child)) => child,
+                    Ok(None) => break,
+                    Err(err) => {
+                        repo::tasks::fail(self.pool, cid, parent.id).await?;
+                        self.fail_parent_tasks(cid, uid, parent).await?;
+                        self.emit_tree(cid, uid, parent).await?;
+
+                        result = Err(err);
+                        break;
+                    }
+                };
+
+                info!("Executing child task #{}: {}", child.id, child.title);
+
+                // TODO: seems counterintuitive to emit the task update here, since it was updated in the
+                //       `get_child_task_for_execution` function. Consider code reorganization.
+                self.channel
+                    .emit(uid, &channel::Event::TaskUpdated(&child))
+                    .await?;
+                self.emit_tree(cid, uid, parent).await?;
+
+                running.push(self.execute_child(cid, uid, parent, child, cancel));
+            }
 
-                return Err(err);
+            // Finished sub-tasks may unblock their siblings, so runnable ones are looked up again.
+            match running.next().await {
+                Some(Err(err)) if result.is_ok() => result = Err(err),
+                Some(_) => {}
+                None => break,
             }
-        } {
-            info!("Executing child task #{}: {}", child.id, child.title);
+        }
+
+        if result.is_ok() && cancel.is_cancelled() {
+            info!("Execution of task #{} is cancelled", parent.id);
 
-            // TODO: seems counterintuitive to emit the task update here, since it was updated in the
-            //       `get_child_task_for_execution` function. Consider code reorganization.
+            let parent =
+                repo::tasks::update_status(self.pool, cid, parent.id, Status::Cancelled).await?;
             self.channel
-                .emit(uid, &channel::Event::TaskUpdated(&child))
+                .emit(uid, &channel::Event::TaskUpdated(&parent))
                 .await?;
+            self.emit_tree(cid, uid, &parent).await?;
+        }
 
-            match self.execute_task(cid, uid, &mut child).await {
-                Ok(_) => {
-                    info!("Child task #{} is done", child.id);
-                    repo::tasks::complete(self.pool, cid, child.id).await?;
+        result
+    }
 
-                    // Complete parent task if all siblings are done
-                    if repo::tasks::is_all_siblings_done(self.pool, cid, &child).await? {
-                        info!(
-                        "All siblings are done for the parent task #{}, marking it as `Done` as well",
-                        parent.id
-                    );
+    /// Executes the child task in progress, completing or failing its parents accordingly.
+    async fn execute_child(
+        &self,
+        cid: Uuid,
+        uid: Uuid,
+        parent: &Task,
+        mut child: Task,
+        cancel: &CancellationToken,
+    ) -> Result<()> {
+        match self.execute_task(cid, uid, &mut child, cancel).await {
+            Ok(Status::Cancelled) => {
+                let child =
+                    repo::tasks::update_status(self.pool, cid, child.id, Status::Cancelled).await?;
+                self.channel
+                    .emit(uid, &channel::Event::TaskUpdated(&child))
+                    .await?;
+                self.emit_tree(cid, uid, parent).await?;
 
-                        let task = repo::tasks::complete(
-                            self.pool,
-                            cid,
-                            child
-                                .parent_id()?
-                                .context("parent_id is not set for the child task")?,
-                        )
-                        .await?;
+                Ok(())
+            }
+            Ok(_) => {
+                info!("Child task #{} is done", child.id);
+                repo::tasks::complete(self.pool, cid, child.id).await?;
 
-                        self.channel
-                            .emit(uid, &channel::Event::TaskUpdated(&task))
-                            .await?;
-                    }
-                }
-                Err(err) => {
-                    repo::tasks::fail(self.pool, cid, child.id).await?;
-                    self.fail_parent_tasks(cid, uid, &child).await?;
+                self.complete_parent_if_siblings_done(cid, uid, &child)
+                    .await?;
 
-                    return Err(err);
-                }
+                self.emit_tree(cid, uid, parent).await?;
+
+                Ok(())
+            }
+            Err(err) => {
+                repo::tasks::fail(self.pool, cid, child.id).await?;
+                self.fail_parent_tasks(cid, uid, &child).await?;
+                self.emit_tree(cid, uid, parent).await?;
+
+                Err(err)
             }
         }
+    }
+
+    /// Completes the parent task if all the child's siblings are done.
+    async fn complete_parent_if_siblings_done(
+        &self,
+        cid: Uuid,
+        uid: Uuid,
+        child: &Task,
+    ) -> Result<()> {
+        if !repo::tasks::is_all_siblings_done(self.pool, cid, child).await? {
+            return Ok(());
+        }
+
+        let parent_id = child
+            .parent_id()?
+            .context("parent_id is not set for the child task")?;
+        info!(
+            "All siblings are done for the parent task #{}, marking it as `Done` as well",
+            parent_id
+        );
+
+        let task = repo::tasks::complete(self.pool, cid, parent_id).await?;
+        self.channel
+            .emit(uid, &channel::Event::TaskUpdated(&task))
+            .await?;
 
         Ok(())
     }
@@ -246,53 +542,253 @@ impl TaskExecutor<'_> {
         Ok(())
     }
 
-    #[instrument(skip_all)]
-    async fn execute_task(&self, cid: Uuid, uid: Uuid, task: &mut Task) -> Result<Status> {
+    #[instrument(
+        skip_all,
+        fields(company_id = %cid, task_id = %task.id, chat_id = field::Empty)
+    )]
+    async fn execute_task(
+        &self,
+        cid: Uuid,
+        uid: Uuid,
+        task: &mut Task,
+        cancel: &CancellationToken,
+    ) -> Result<Status> {
+        let steps = self.execute_task_with_retries(cid, uid, task, cancel);
+        let result = match self.task_timeout() {
+            // On expiry the steps are dropped, along with the in-flight completion or code.
+            Some(timeout) => match tokio::time::timeout(timeout, steps).await {
+                Ok(result) => result,
+                Err(_) => {
+                    warn!("Task #{} timed out after {:?}", task.id, timeout);
+                    self.fail_timed_out_step(cid, uid, task, timeout).await?;
+
+                    Err(Error::TimedOut(timeout).into())
+                }
+            },
+            None => steps.await,
+        };
+
+        match result {
+            Err(errors::Error::Executor(Error::Cancelled)) => {
+                info!("Execution of task #{} is cancelled", task.id);
+
+                Ok(Status::Cancelled)
+            }
+            result => result,
+        }
+    }
+
+    /// Executes the task, retrying it up to `max_retries` times after the transient errors. The
+    /// execution is continued from the last completed message, so nothing is repeated.
+    async fn execute_task_with_retries(
+        &self,
+        cid: Uuid,
+        uid: Uuid,
+        task: &mut Task,
+        cancel: &CancellationToken,
+    ) -> Result<Status> {
+        let max_retries = self.settings.tasks.max_retries;
+        let mut attempt = 0;
+
+        loop {
+            match self.execute_task_steps(cid, uid, task, cancel).await {
+                Err(err) if attempt < max_retries && is_transient(&err) => {
+                    attempt += 1;
+                    warn!(
+                        "Task #{} failed with a transient error, retrying ({}/{}): {}",
+                        task.id, attempt, max_retries, err
+                    );
+
+                    self.discard_unfinished_reply(cid, task).await?;
+                    self.channel
+                        .emit(
+                            uid,
+                            &channel::Event::TaskRetrying {
+                                id: task.id,
+                                attempt,
+                                max_retries,
+                            },
+                        )
+                        .await?;
+
+                    tokio::select! {
+                        () = tokio::time::sleep(retry_backoff(attempt)) => {}
+                        () = cancel.cancelled() => {}
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Deletes the agent's reply which failed midway, so that it's written anew on the retry.
+    async fn discard_unfinished_reply(&self, cid: Uuid, task: &Task) -> Result<()> {
+        let Some(chat_id) = task.execution_chat_id else {
+            return Ok(());
+        };
+
+        match repo::messages::get_last_message(self.pool, cid, chat_id).await? {
+            Some(message)
+                if message.role == Role::Assistant
+                    && matches!(
+                        message.status,
+                        types::messages::Status::Writing | types::messages::Status::Failed
+                    ) =>
+            {
+                debug!("Discarding unfinished message #{}", message.id);
+                repo::messages::delete(self.pool, cid, message.id).await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Records the timeout in the execution chat, so that the chat stays valid and the agent knows
+    /// about the timeout if the task is run again. The tool calls of the agent's last reply which
+    /// are not answered yet are answered with the timeout, and the reply is failed if it was still
+    /// being written.
+    async fn fail_timed_out_step(
+        &self,
+        cid: Uuid,
+        uid: Uuid,
+        task: &Task,
+        timeout: Duration,
+    ) -> Result<()> {
+        let Some(chat_id) = task.execution_chat_id else {
+            return Ok(());
+        };
+
+        let messages =
+            repo::messages::list(self.pool, cid, repo::messages::ListParams { chat_id }).await?;
+        let text = format!("Timed out after {} seconds", timeout.as_secs());
+
+        if let Some(position) = messages
+            .iter()
+            .rposition(|message| message.role == Role::Assistant)
+        {
+            let message = &messages[position];
+            let answered = messages[position + 1..]
+                .iter()
+                .filter_map(|output| output.tool_call_id.as_deref())
+                .collect::<HashSet<_>>();
+
+            for tool_call in message.tool_calls().iter() {
+                if !answered.contains(tool_call.id.as_str()) {
+                    self.create_tool_output(cid, task, message, tool_call, &text)
+                        .await?;
+                }
+            }
+
+            if message.status == types::messages::Status::Writing {
+                self.fail_message(cid, uid, task, message).await?;
+            }
+        }
+
+        let note = repo::messages::create(
+            self.pool,
+            cid,
+            CreateParams {
+                chat_id,
+                status: types::messages::Status::Completed,
+                role: Role::User,
+                content: Some(format!("Task execution: {text}")),
+                ..Default::default()
+            },
+        )
+        .await?;
+
+        if let Some(transcript) = self.transcript(task) {
+            transcript.message_created(&note);
+        }
+
+        Ok(())
+    }
+
+    /// Runs the agent in the task's execution chat until the task status is decided.
+    async fn execute_task_steps(
+        &self,
+        cid: Uuid,
+        uid: Uuid,
+        task: &mut Task,
+        cancel: &CancellationToken,
+    ) -> Result<Status> {
         info!("Executing task #{}: {}", task.id, task.title);
 
         let chat = self.get_task_execution_chat(cid, task).await?;
+        Span::current().record("chat_id", field::display(chat.id));
 
         task.execution_chat_id = Some(chat.id);
 
         self.channel
-            .emit(uid, &channel::Event::TaskUpdated(&task))
+            .emit(uid, &channel::Event::TaskUpdated(task))
             .await?;
 
+        // Whether the agent has already been urged to finish the task after repeating itself.
+        let mut is_nudged = false;
+
         loop {
+            ensure_not_cancelled(cancel)?;
+
             match repo::messages::get_last_message(self.pool, cid, chat.id).await? {
                 Some(message) => match message.role {
                     Role::CodeInterpreter | Role::Tool | Role::User => {
-                        self.send_to_agent(cid, uid, chat.id, task).await?;
+                        self.send_to_agent(cid, uid, chat.id, task, cancel).await?;
                     }
                     Role::Assistant => {
+                        // The response may be cut off anywhere, so neither its content nor its
+                        // tool calls can be acted upon.
+                        if message.status == types::messages::Status::ContentFiltered {
+                            return Err(Error::ContentFiltered(message.id).into());
+                        }
+
                         let tc = message.tool_calls();
 
                         match tc.len() {
                             0 if message.is_self_reflection => {
-                                self.send_to_agent(cid, uid, chat.id, task).await?;
+                                self.send_to_agent(cid, uid, chat.id, task, cancel).await?;
+                            }
+                            0 if self.is_making_no_progress(cid, chat.id).await? => {
+                                if is_nudged {
+                                    return Err(Error::NoProgress(
+                                        self.settings.tasks.no_progress_window,
+                                    )
+                                    .into());
+                                }
+
+                                warn!("Agent is repeating itself, urging to finish the task");
+                                is_nudged = true;
+                                self.self_reflect(cid, uid, chat.id, task, true, cancel)
+                                    .await?;
                             }
                             0 => {
                                 let content = message.content.clone().unwrap_or_default();
-                                match parse_code_blocks(&content) {
+                                match parse_code_blocks(
+                                    &content,
+                                    self.settings.tasks.implicit_code_block_actions,
+                                ) {
                                     Ok(code_blocks) if !code_blocks.is_empty() => {
-                                        self.sfai_code_interpreter(cid, uid, &message, task)
-                                            .await?;
+                                        self.sfai_code_interpreter(
+                                            cid, uid, &message, task, cancel,
+                                        )
+                                        .await?;
+                                    }
+                                    _ => {
+                                        self.self_reflect(cid, uid, chat.id, task, false, cancel)
+                                            .await?
                                     }
-                                    _ => self.self_reflect(cid, uid, chat.id, task).await?,
                                 }
                             }
                             _ => {
                                 // I acknowledge, that this is weird to pass `tool_calls` alongside the `message`, but why not since it's already unpacked from `Option`?
-                                match self.call_tools(cid, uid, &message, tc, task).await {
+                                match self.call_tools(cid, uid, &message, tc, task, cancel).await {
                                     Ok(maybe_new_status) => {
-                                        self.complete_message(cid, uid, &message).await?;
+                                        self.complete_message(cid, uid, task, &message).await?;
 
                                         if let Some(new_status) = maybe_new_status {
                                             return Ok(new_status);
                                         }
                                     }
                                     Err(err) => {
-                                        self.fail_message(cid, uid, &message).await?;
+                                        self.fail_message(cid, uid, task, &message).await?;
                                         return Err(err);
                                     }
                                 }
@@ -305,7 +801,7 @@ impl TaskExecutor<'_> {
                         );
                     }
                 },
-                None => self.send_to_agent(cid, uid, chat.id, task).await?,
+                None => self.send_to_agent(cid, uid, chat.id, task, cancel).await?,
             }
         }
     }
@@ -322,20 +818,33 @@ impl TaskExecutor<'_> {
         message: &Message,
         tool_calls: ToolCalls,
         task: &Task,
+        cancel: &CancellationToken,
     ) -> Result<Option<Status>> {
         let mut new_status = None;
+        let agent = self.agent_for_chat(cid, message.chat_id, task).await?;
 
         // Call task management tools
         for tool_call in tool_calls.iter() {
+            if let Some(transcript) = self.transcript(task) {
+                transcript.tool_call(message, tool_call);
+            }
+
+            if agent.is_tool_forbidden(&tool_call.function.name) {
+                self.reject_forbidden_tool(cid, task, message, tool_call)
+                    .await?;
+                continue;
+            }
+
             if let Some(status) = match tool_call.function.name.as_str() {
-                "sfai_done" => {
-                    self.sfai_done(cid, uid, message, task.id, &tool_call)
+                "sfai_done" => self.sfai_done(cid, uid, message, task, tool_call).await?,
+                "sfai_fail" => self.sfai_fail(cid, task, message, tool_call).await?,
+                "sfai_wait_for_user" => {
+                    self.sfai_wait_for_user(cid, task, message, tool_call)
                         .await?
                 }
-                "sfai_fail" => self.sfai_fail(cid, message, &tool_call).await?,
-                "sfai_wait_for_user" => self.sfai_wait_for_user(cid, message, &tool_call).await?,
                 "sfai_code_interpreter" => {
-                    self.sfai_code_interpreter(cid, uid, message, task).await?
+                    self.sfai_code_interpreter(cid, uid, message, task, cancel)
+                        .await?
                 }
                 _ => None,
             } {
@@ -350,41 +859,48 @@ impl TaskExecutor<'_> {
         Ok(new_status)
     }
 
-    async fn sfai_wait_for_user(
+    /// Answers the call of a tool the agent is not allowed to use, without calling it.
+    async fn reject_forbidden_tool(
         &self,
         cid: Uuid,
+        task: &Task,
         message: &Message,
         tool_call: &ToolCall,
-    ) -> Result<Option<Status>> {
-        repo::messages::create(
-            self.pool,
+    ) -> Result<()> {
+        warn!(
+            "Agent tried to call forbidden tool `{}`",
+            tool_call.function.name
+        );
+
+        self.create_tool_output(
             cid,
-            CreateParams {
-                content: Some("```\nWaiting for user input\n```".to_string()),
-                chat_id: message.chat_id,
-                status: types::messages::Status::Completed,
-                role: Role::Tool,
-                tool_call_id: Some(tool_call.id.clone()),
-                is_internal_tool_output: true,
-                ..Default::default()
-            },
+            task,
+            message,
+            tool_call,
+            &format!(
+                "Tool `{}` is not allowed for this agent, use one of the available tools instead",
+                tool_call.function.name
+            ),
         )
         .await?;
 
-        Ok(Some(Status::WaitingForUser))
+        Ok(())
     }
 
-    async fn sfai_fail(
+    /// Answers the tool call with an internal tool output.
+    async fn create_tool_output(
         &self,
         cid: Uuid,
+        task: &Task,
         message: &Message,
         tool_call: &ToolCall,
-    ) -> Result<Option<Status>> {
-        repo::messages::create(
+        text: &str,
+    ) -> Result<()> {
+        let output = repo::messages::create(
             self.pool,
             cid,
             CreateParams {
-                content: Some("```\nTask has been marked as failed\n```".to_string()),
+                content: Some(format!("```\n{text}\n```")),
                 chat_id: message.chat_id,
                 status: types::messages::Status::Completed,
                 role: Role::Tool,
@@ -395,6 +911,42 @@ impl TaskExecutor<'_> {
         )
         .await?;
 
+        if let Some(transcript) = self.transcript(task) {
+            transcript.message_created(&output);
+        }
+
+        Ok(())
+    }
+
+    async fn sfai_wait_for_user(
+        &self,
+        cid: Uuid,
+        task: &Task,
+        message: &Message,
+        tool_call: &ToolCall,
+    ) -> Result<Option<Status>> {
+        self.create_tool_output(cid, task, message, tool_call, "Waiting for user input")
+            .await?;
+
+        Ok(Some(Status::WaitingForUser))
+    }
+
+    async fn sfai_fail(
+        &self,
+        cid: Uuid,
+        task: &Task,
+        message: &Message,
+        tool_call: &ToolCall,
+    ) -> Result<Option<Status>> {
+        self.create_tool_output(
+            cid,
+            task,
+            message,
+            tool_call,
+            "Task has been marked as failed",
+        )
+        .await?;
+
         Ok(Some(Status::Failed))
     }
 
@@ -404,15 +956,20 @@ impl TaskExecutor<'_> {
         uid: Uuid,
         message: &Message,
         task: &Task,
+        cancel: &CancellationToken,
     ) -> Result<Option<Status>> {
         if let Some(result_message) =
             repo::messages::get_last_non_self_reflection_message(self.pool, cid, message.chat_id)
                 .await?
         {
-            let content = Some(match self.interpret_code(&result_message, task).await {
-                Ok(out_lines) => out_lines.join("\n\n"),
-                Err(err) => format!("Failed to interpret code: {err}"),
-            });
+            let content = Some(
+                match self.interpret_code(&result_message, task, cancel).await {
+                    Ok(out_lines) => out_lines.join("\n\n"),
+                    // The code was aborted, so there is no output to report.
+                    Err(_) if cancel.is_cancelled() => return Err(Error::Cancelled.into()),
+                    Err(err) => format!("Failed to interpret code: {err}"),
+                },
+            );
 
             let out_message = repo::messages::create(
                 self.pool,
@@ -430,43 +987,98 @@ impl TaskExecutor<'_> {
             self.channel
                 .emit(uid, &channel::Event::MessageCreated(&out_message))
                 .await?;
+
+            if let Some(transcript) = self.transcript(task) {
+                transcript.message_created(&out_message);
+            }
         }
 
         Ok(None)
     }
 
-    async fn interpret_code(&self, message: &Message, task: &Task) -> Result<Vec<String>> {
-        let code_blocks = match parse_code_blocks(match &message.content.as_ref() {
-            Some(content) => content,
-            None => return Ok(vec!["No content in the message to interpret".to_string()]),
-        }) {
-            Ok(code_blocks) => code_blocks,
-            Err(err) => {
-                return Ok(vec![format!(
-                    "Failed to parse code blocks in the message: {err}"
-                )])
-            }
+    async fn interpret_code(
+        &self,
+        message: &Message,
+        task: &Task,
+        cancel: &CancellationToken,
+    ) -> Result<Vec<String>> {
+        let Some(content) = message.content.as_ref() else {
+            return Ok(vec!["No content in the message to interpret".to_string()]);
         };
+        let code_blocks =
+            match parse_code_blocks(content, self.settings.tasks.implicit_code_block_actions) {
+                Ok(code_blocks) => code_blocks,
+                Err(err) => {
+                    return Ok(vec![format!(
+                        "Failed to parse code blocks in the message: {err}"
+                    )])
+                }
+            };
 
         let mut lines = Vec::with_capacity(code_blocks.len());
 
-        let workdir = task.workdir(&self.workdir_root).await?;
+        let workdir = self.task_workdir(task).await?;
+        let secrets = match &self.secrets_cipher {
+            Some(cipher) => {
+                secrets::for_agent(self.pool, cipher, task.company_id, message.agent_id).await?
+            }
+            None => Secrets::default(),
+        };
+        let env = task_env(task);
 
         for code_block in code_blocks {
             if code_block.filename.is_none() {
                 let result = match code_block.language {
-                    Language::Shell => docker::run_cmd(&code_block.code, Some(&workdir)).await?,
+                    Language::Shell => {
+                        let output = docker::run_cmd(
+                            &code_block.code,
+                            Some(&workdir),
+                            &secrets,
+                            Some(&env),
+                            &self.settings.sandbox,
+                            self.settings.sandbox.timeout(),
+                            Some(cancel),
+                        )
+                        .await?;
+
+                        format_exec_output(&output)
+                    }
                     Language::Python => {
-                        docker::run_python_code(&code_block.code, Some(&workdir)).await?
+                        let output = docker::run_python_code(
+                            &code_block.code,
+                            Some(&workdir),
+                            &secrets,
+                            Some(&env),
+                            &self.settings.sandbox,
+                            self.settings.sandbox.timeout(),
+                            Some(cancel),
+                        )
+                        .await?;
+
+                        format_exec_output(&output)
                     }
-                    lang => {
-                        format!("Error: language `{lang:?}` is not supported for code execution")
+                    Language::JavaScript => {
+                        let output = docker::run_node_code(
+                            &code_block.code,
+                            Some(&workdir),
+                            &secrets,
+                            Some(&env),
+                            &self.settings.sandbox,
+                            self.settings.sandbox.timeout(),
+                            Some(cancel),
+                        )
+                        .await?;
+
+                        format_exec_output(&output)
                     }
+                    lang => format!(
+                        "```\nError: language `{lang:?}` is not supported for code execution\n```"
+                    ),
                 };
 
-                lines.push(format!("```\n{result}\n```"));
+                lines.push(result);
             } else if let Some(filename) = &code_block.filename {
-                let mut workdir = match task.workdir(&self.workdir_root).await {
+                let mut workdir = match self.task_workdir(task).await {
                     Ok(workdir) => workdir,
                     Err(err) => {
                         lines.push(format!("```\nFailed to get task workdir: {err}\n```"));
@@ -476,6 +1088,13 @@ impl TaskExecutor<'_> {
 
                 workdir.push(filename);
 
+                if let Some(dir) = workdir.parent() {
+                    if let Err(err) = fs::create_dir_all(dir).await {
+                        lines.push(format!("```\nFailed to create task workdir: {err}\n```"));
+                        continue;
+                    }
+                }
+
                 match fs::write(&workdir, code_block.code).await {
                     Ok(()) => {
                         lines.push(format!("```\nFile `{filename}` has been saved\n```"));
@@ -495,21 +1114,15 @@ impl TaskExecutor<'_> {
         cid: Uuid,
         uid: Uuid,
         message: &Message,
-        task_id: Uuid,
+        task: &Task,
         tool_call: &ToolCall,
     ) -> Result<Option<Status>> {
-        repo::messages::create(
-            self.pool,
+        self.create_tool_output(
             cid,
-            CreateParams {
-                content: Some("```\nTask has been marked as done\n```".to_string()),
-                chat_id: message.chat_id,
-                status: types::messages::Status::Completed,
-                role: Role::Tool,
-                tool_call_id: Some(tool_call.id.clone()),
-                is_internal_tool_output: true,
-                ..Default::default()
-            },
+            task,
+            message,
+            tool_call,
+            "Task has been marked as done",
         )
         .await?;
 
@@ -523,7 +1136,7 @@ impl TaskExecutor<'_> {
                 cid,
                 uid,
                 &result_message,
-                task_id,
+                task.id,
                 ProvideTextResultArgs {
                     text,
                     ..Default::default()
@@ -575,13 +1188,19 @@ impl TaskExecutor<'_> {
         }
 
         self.channel
-            .emit(uid, &channel::Event::MessageCreated(&message))
+            .emit(uid, &channel::Event::MessageCreated(message))
             .await?;
 
         Ok(new_status)
     }
 
-    async fn complete_message(&self, cid: Uuid, uid: Uuid, message: &Message) -> Result<()> {
+    async fn complete_message(
+        &self,
+        cid: Uuid,
+        uid: Uuid,
+        task: &Task,
+        message: &Message,
+    ) -> Result<()> {
         repo::messages::update_status(
             self.pool,
             cid,
@@ -597,10 +1216,20 @@ impl TaskExecutor<'_> {
             .emit(uid, &channel::Event::MessageUpdated(&message))
             .await?;
 
+        if let Some(transcript) = self.transcript(task) {
+            transcript.message_updated(&message);
+        }
+
         Ok(())
     }
 
-    async fn fail_message(&self, cid: Uuid, uid: Uuid, message: &Message) -> Result<()> {
+    async fn fail_message(
+        &self,
+        cid: Uuid,
+        uid: Uuid,
+        task: &Task,
+        message: &Message,
+    ) -> Result<()> {
         repo::messages::update_status(self.pool, cid, message.id, types::messages::Status::Failed)
             .await?;
 
@@ -611,12 +1240,79 @@ impl TaskExecutor<'_> {
             .emit(uid, &channel::Event::MessageUpdated(&message))
             .await?;
 
+        if let Some(transcript) = self.transcript(task) {
+            transcript.message_updated(&message);
+        }
+
         Ok(())
     }
 
-    #[instrument(skip_all)]
-    async fn send_to_agent(&self, cid: Uuid, uid: Uuid, chat_id: Uuid, task: &Task) -> Result<()> {
-        let agent = repo::agents::get_for_chat(self.pool, cid, chat_id).await?;
+    async fn task_workdir(&self, task: &Task) -> Result<PathBuf> {
+        task.workdir(&self.workdir_root, self.settings.tasks.workdir_isolation)
+            .await
+    }
+
+    fn transcript(&self, task: &Task) -> Option<TranscriptSink> {
+        self.transcript
+            .as_ref()
+            .map(|transcript| transcript.for_task(task.id))
+    }
+
+    /// Whether the last agent replies within the no-progress window are all the same.
+    async fn is_making_no_progress(&self, cid: Uuid, chat_id: Uuid) -> Result<bool> {
+        let window = self.settings.tasks.no_progress_window;
+        if window < 2 {
+            return Ok(false);
+        }
+
+        let contents =
+            repo::messages::list_last_agent_contents(self.pool, cid, chat_id, window.into())
+                .await?;
+
+        Ok(contents.len() == usize::from(window) && is_repeating(&contents))
+    }
+
+    fn completion_timeout(&self) -> Option<Duration> {
+        let secs = self.settings.tasks.completion_timeout_secs;
+
+        (secs > 0).then(|| Duration::from_secs(secs))
+    }
+
+    fn task_timeout(&self) -> Option<Duration> {
+        let secs = self.settings.tasks.task_timeout_secs;
+
+        (secs > 0).then(|| Duration::from_secs(secs))
+    }
+
+    /// Gets the execution chat agent. If the chat has lost its agent, the task's agent is
+    /// associated with the chat again.
+    async fn agent_for_chat(&self, cid: Uuid, chat_id: Uuid, task: &Task) -> Result<Agent> {
+        match repo::agents::get_for_chat(self.pool, cid, chat_id).await? {
+            Some(agent) => Ok(agent),
+            None => {
+                warn!(
+                    "Chat `{}` has no agent, falling back to the task's agent `{}`",
+                    chat_id, task.agent_id
+                );
+
+                let agent = repo::agents::get(self.pool, cid, task.agent_id).await?;
+                repo::agents_chats::create(self.pool, cid, agent.id, chat_id).await?;
+
+                Ok(agent)
+            }
+        }
+    }
+
+    #[instrument(skip_all, fields(company_id = %cid, chat_id = %chat_id, task_id = %task.id))]
+    async fn send_to_agent(
+        &self,
+        cid: Uuid,
+        uid: Uuid,
+        chat_id: Uuid,
+        task: &Task,
+        cancel: &CancellationToken,
+    ) -> Result<()> {
+        let agent = self.agent_for_chat(cid, chat_id, task).await?;
 
         let model =
             match repo::models::get_by_full_name(self.pool, cid, &self.settings.default_model)
@@ -632,9 +1328,11 @@ impl TaskExecutor<'_> {
                 }
             };
 
-        // TODO: get the api key
-        let api_key = "";
+        let api_key = models::api_key(self.settings, &model)?;
+
+        let files = workdir_manifest(&self.task_workdir(task).await?).await?;
 
+        ensure_not_cancelled(cancel)?;
         chats::create_completion(
             self.pool,
             self.channel,
@@ -642,7 +1340,10 @@ impl TaskExecutor<'_> {
             uid,
             chat_id,
             CreateCompletionParams {
-                messages_pre: Some(execution_prelude(chat_id, task, &agent, false)?),
+                messages_pre: Some(execution_prelude(chat_id, task, &agent, &files, false)?),
+                metadata: Some(task_metadata(task)),
+                timeout: self.completion_timeout(),
+                transcript: self.transcript(task),
                 ..Default::default()
             },
             &model,
@@ -654,11 +1355,24 @@ impl TaskExecutor<'_> {
         Ok(())
     }
 
-    #[instrument(skip_all)]
-    async fn self_reflect(&self, cid: Uuid, uid: Uuid, chat_id: Uuid, task: &Task) -> Result<()> {
-        let agent = repo::agents::get_for_chat(self.pool, cid, chat_id).await?;
+    /// Asks the agent to evaluate its last message and report the task status. With `is_stuck`,
+    /// the agent is told to finish the task right away.
+    #[instrument(skip_all, fields(company_id = %cid, chat_id = %chat_id, task_id = %task.id))]
+    async fn self_reflect(
+        &self,
+        cid: Uuid,
+        uid: Uuid,
+        chat_id: Uuid,
+        task: &Task,
+        is_stuck: bool,
+        cancel: &CancellationToken,
+    ) -> Result<()> {
+        let agent = self.agent_for_chat(cid, chat_id, task).await?;
 
-        let message = SelfReflectionMessageTemplate {};
+        let message = SelfReflectionMessageTemplate {
+            agent: &agent,
+            is_stuck,
+        };
         let content = Some(message.render().map_err(Error::TemplateRender)?);
 
         let messages_post = vec![Message {
@@ -682,9 +1396,17 @@ impl TaskExecutor<'_> {
                 }
             };
 
-        // TODO: get the api key
-        let api_key = "";
+        let api_key = models::api_key(self.settings, &model)?;
+
+        let files = workdir_manifest(&self.task_workdir(task).await?).await?;
 
+        let tools = internal_task_tools()
+            .iter()
+            .filter(|tool| !agent.is_tool_forbidden(&tool.function.name))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        ensure_not_cancelled(cancel)?;
         chats::create_completion(
             self.pool,
             self.channel,
@@ -692,10 +1414,15 @@ impl TaskExecutor<'_> {
             uid,
             chat_id,
             CreateCompletionParams {
-                messages_pre: Some(execution_prelude(chat_id, task, &agent, true)?),
+                messages_pre: Some(execution_prelude(chat_id, task, &agent, &files, true)?),
                 messages_post: Some(messages_post),
-                abilities: Some(internal_task_abilities()),
                 is_self_reflection: true,
+                metadata: Some(task_metadata(task)),
+                // Providers reject an empty list of tools.
+                tools: (!tools.is_empty()).then_some(tools),
+                timeout: self.completion_timeout(),
+                transcript: self.transcript(task),
+                ..Default::default()
             },
             &model,
             api_key,
@@ -707,36 +1434,95 @@ impl TaskExecutor<'_> {
     }
 }
 
+/// Environment variables of the code interpreted for the task.
+fn task_env(task: &Task) -> HashMap<String, String> {
+    HashMap::from([("BRIDGE_TASK_ID".to_string(), task.id.to_string())])
+}
+
+/// Formats the code execution output for the agent, with the exit code and the non-empty
+/// streams.
+fn format_exec_output(output: &docker::ExecOutput) -> String {
+    let mut text = format!("Exit code: {}", output.exit_code);
+
+    for (name, stream) in [("stdout", &output.stdout), ("stderr", &output.stderr)] {
+        if !stream.is_empty() {
+            let _ = write!(text, "\n\n{name}:\n```\n{stream}\n```");
+        }
+    }
+
+    text
+}
+
+/// Whether the error may go away on its own, e.g. the provider is overloaded or the network is
+/// down, so the task execution can be retried.
+fn is_transient(err: &errors::Error) -> bool {
+    match err {
+        errors::Error::OpenAI(openai::Error::Timeout(_)) => true,
+        errors::Error::OpenAI(openai::Error::Api(err)) => {
+            err.status == StatusCode::TOO_MANY_REQUESTS || err.status.is_server_error()
+        }
+        errors::Error::Chats(
+            chats::Error::CompletionTimedOut(_) | chats::Error::FailedToGetCompletion,
+        ) => true,
+        errors::Error::Application(err) => err
+            .chain()
+            .any(|cause| cause.downcast_ref::<reqwest::Error>().is_some()),
+        _ => false,
+    }
+}
+
+/// Delay before the given retry of a task, starting from 1.
+fn retry_backoff(attempt: u8) -> Duration {
+    let factor = 2_u32.saturating_pow(u32::from(attempt.saturating_sub(1)));
+
+    RETRY_BACKOFF_BASE
+        .saturating_mul(factor)
+        .min(MAX_RETRY_BACKOFF)
+}
+
+/// Returns [`Error::Cancelled`] once the execution is cancelled.
+fn ensure_not_cancelled(cancel: &CancellationToken) -> Result<()> {
+    if cancel.is_cancelled() {
+        return Err(Error::Cancelled.into());
+    }
+
+    Ok(())
+}
+
 #[derive(Deserialize, Debug, Default)]
 pub struct ProvideTextResultArgs {
     pub text: String,
     pub is_done: bool,
 }
 
-fn internal_task_abilities() -> Vec<Ability> {
-    // TODO: it's inefficient to use `Ability` here, since we're serializing parameters to JSON
-    //       only to deserialize them back in `chats::create_completion`. Consider using [`Tool`]
-    //       instead.
-    //
-    // TODO: It's also slightly inefficient to create these abilities on every iteration.
-    //       Consider caching them or something.
-    vec![
-        Ability::for_fn("Mark current task as done", &json!({ "name": "sfai_done" })),
-        Ability::for_fn(
-            "Mark current task as failed",
-            &json!({ "name": "sfai_fail" }),
-        ),
-        Ability::for_fn(
-            "Wait for additional user input",
-            &json!({ "name": "sfai_wait_for_user" }),
-        ),
-    ]
+static INTERNAL_TASK_TOOLS: OnceLock<Vec<Tool>> = OnceLock::new();
+
+/// Tools used by the agent to report task status during self-reflection. Built once and reused.
+fn internal_task_tools() -> &'static [Tool] {
+    INTERNAL_TASK_TOOLS.get_or_init(|| {
+        [
+            ("sfai_done", "Mark current task as done"),
+            ("sfai_fail", "Mark current task as failed"),
+            ("sfai_wait_for_user", "Wait for additional user input"),
+        ]
+        .into_iter()
+        .map(|(name, description)| Tool {
+            type_: "function".to_string(),
+            function: Function {
+                name: name.to_string(),
+                description: Some(description.to_string()),
+                parameters: None,
+            },
+        })
+        .collect()
+    })
 }
 
 #[derive(Template)]
 #[template(path = "task_executor/task_message.md", escape = "none")]
 struct TaskMessageTemplate<'a> {
     task: &'a Task,
+    files: &'a WorkdirManifest,
 }
 
 #[derive(Template)]
@@ -748,19 +1534,81 @@ struct SystemMessageTemplate<'a> {
 
 #[derive(Template)]
 #[template(path = "task_executor/self_reflection_message.md", escape = "none")]
-struct SelfReflectionMessageTemplate {}
+struct SelfReflectionMessageTemplate<'a> {
+    agent: &'a Agent,
+    is_stuck: bool,
+}
+
+impl SelfReflectionMessageTemplate<'_> {
+    fn is_allowed(&self, tool: &str) -> bool {
+        !self.agent.is_tool_forbidden(tool)
+    }
+
+    /// Allowed ways for a stuck agent to finish the task. Waiting for the user is offered only
+    /// when the task can't be finished otherwise.
+    fn finish_options(&self) -> Vec<&'static str> {
+        let mut options = [
+            (
+                "sfai_done",
+                "the `sfai_done` function if the task is complete",
+            ),
+            (
+                "sfai_fail",
+                "the `sfai_fail` function if it can't be completed",
+            ),
+        ]
+        .into_iter()
+        .filter(|(tool, _)| self.is_allowed(tool))
+        .map(|(_, option)| option)
+        .collect::<Vec<_>>();
+
+        if options.is_empty() && self.is_allowed("sfai_wait_for_user") {
+            options.push("the `sfai_wait_for_user` function to hand the task over to the user");
+        }
+
+        options
+    }
+}
+
+/// Whether all the contents are the same, ignoring case and whitespace.
+fn is_repeating(contents: &[Option<String>]) -> bool {
+    let mut hashes = contents.iter().map(|content| {
+        content
+            .as_deref()
+            .filter(|content| !content.trim().is_empty())
+            .map(|content| {
+                let mut hasher = DefaultHasher::new();
+                for word in content.split_whitespace() {
+                    word.to_lowercase().hash(&mut hasher);
+                }
+
+                hasher.finish()
+            })
+    });
+
+    match hashes.next() {
+        Some(Some(first)) => hashes.all(|hash| hash == Some(first)),
+        _ => false,
+    }
+}
+
+/// Request metadata used to correlate provider-side logs with the task being executed.
+fn task_metadata(task: &Task) -> HashMap<String, String> {
+    HashMap::from([("task_id".to_string(), task.id.to_string())])
+}
 
 fn execution_prelude(
     chat_id: Uuid,
     task: &Task,
     agent: &Agent,
+    files: &WorkdirManifest,
     is_self_reflection: bool,
 ) -> Result<Vec<Message>> {
     let system_message = SystemMessageTemplate {
         agent,
         is_self_reflection,
     };
-    let task_message = TaskMessageTemplate { task };
+    let task_message = TaskMessageTemplate { task, files };
 
     Ok(vec![
         Message {
@@ -778,63 +1626,227 @@ fn execution_prelude(
     ])
 }
 
-struct TaskTree {
-    pub root: Task,
-    pub children: Vec<TaskTree>,
+/// Statuses of the tasks which can be executed on demand.
+const RUNNABLE_STATUSES: [Status; 4] = [
+    Status::ToDo,
+    Status::WaitingForUser,
+    Status::Failed,
+    Status::Cancelled,
+];
+
+/// Delay before the first retry of a task, doubled on every next one.
+const RETRY_BACKOFF_BASE: Duration = Duration::from_millis(500);
+/// Upper bound for the delay between the task retries.
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(30);
+const WORKDIR_MANIFEST_MAX_FILES: usize = 50;
+const WORKDIR_EXCERPT_MAX_BYTES: u64 = 512;
+
+/// Files available in the task workdir, shown to the agent in the task message.
+#[derive(Debug, Default)]
+struct WorkdirManifest {
+    files: Vec<WorkdirFile>,
+    /// Whether there were more files than the manifest can hold.
+    is_truncated: bool,
+}
+
+#[derive(Debug)]
+struct WorkdirFile {
+    /// Path relative to the workdir.
+    path: String,
+    size: u64,
+    /// Beginning of the file, for text files only.
+    excerpt: Option<String>,
 }
 
-fn find_execution_candidate(tree: &TaskTree) -> Option<&Task> {
-    if !tree.children.is_empty() {
-        for child in &tree.children {
-            if let Some(task) = find_execution_candidate(child) {
-                return Some(task);
+/// Lists files in the task workdir, along with the excerpts of the text ones. Missing workdir
+/// results in an empty manifest. Entries that can't be read, as well as anything but regular
+/// files and directories, are skipped with a warning.
+async fn workdir_manifest(workdir: &Path) -> Result<WorkdirManifest> {
+    let mut files = Vec::new();
+    let mut dirs = vec![workdir.to_path_buf()];
+
+    while let Some(dir) = dirs.pop() {
+        let mut entries = match fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(err) if dir == workdir => {
+                return Err(anyhow!(err).context("failed to read task workdir").into())
+            }
+            Err(err) => {
+                warn!("Skipping unreadable directory `{}`: {err}", dir.display());
+                continue;
+            }
+        };
+
+        loop {
+            let entry = match entries.next_entry().await {
+                Ok(Some(entry)) => entry,
+                Ok(None) => break,
+                Err(err) => {
+                    warn!("Failed to read entries of `{}`: {err}", dir.display());
+                    break;
+                }
+            };
+
+            let path = entry.path();
+            // Follows symlinks, so that broken ones are skipped.
+            let metadata = match fs::metadata(&path).await {
+                Ok(metadata) => metadata,
+                Err(err) => {
+                    warn!("Skipping unreadable file `{}`: {err}", path.display());
+                    continue;
+                }
+            };
+            if metadata.is_dir() {
+                dirs.push(path);
+            } else if metadata.is_file() {
+                files.push((path, metadata.len()));
+            } else {
+                // Opening FIFOs and the like could block the step.
+                warn!("Skipping special file `{}`", path.display());
             }
         }
     }
 
-    match tree.root.status {
-        Status::InProgress | Status::Done => None,
-        Status::Draft | Status::ToDo | Status::WaitingForUser | Status::Failed => Some(&tree.root),
+    files.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let mut manifest = WorkdirManifest {
+        is_truncated: files.len() > WORKDIR_MANIFEST_MAX_FILES,
+        ..Default::default()
+    };
+    for (path, size) in files.into_iter().take(WORKDIR_MANIFEST_MAX_FILES) {
+        let excerpt = match file_excerpt(&path).await {
+            Ok(excerpt) => excerpt,
+            Err(err) => {
+                warn!("Skipping unreadable file `{}`: {err}", path.display());
+                continue;
+            }
+        };
+
+        manifest.files.push(WorkdirFile {
+            path: path
+                .strip_prefix(workdir)
+                .unwrap_or(&path)
+                .display()
+                .to_string(),
+            size,
+            excerpt,
+        });
+    }
+
+    Ok(manifest)
+}
+
+/// Reads the beginning of the file, if it looks like a text one.
+async fn file_excerpt(path: &Path) -> Result<Option<String>> {
+    let mut bytes = Vec::new();
+    fs::File::open(path)
+        .await
+        .context("failed to open file")?
+        .take(WORKDIR_EXCERPT_MAX_BYTES)
+        .read_to_end(&mut bytes)
+        .await
+        .context("failed to read file")?;
+
+    if bytes.contains(&0) {
+        return Ok(None);
     }
+
+    Ok(match std::str::from_utf8(&bytes) {
+        Ok(text) => Some(text.to_string()),
+        // The excerpt may end in the middle of a character.
+        Err(err) if err.error_len().is_none() => {
+            Some(String::from_utf8_lossy(&bytes[..err.valid_up_to()]).into_owned())
+        }
+        Err(_) => None,
+    })
 }
 
-fn collect_children(tree: &mut TaskTree, tasks: &mut Vec<Task>) -> Result<()> {
-    for task in tasks.clone() {
-        if task.parent_id()? == Some(tree.root.id) {
-            tree.children.push(TaskTree {
-                root: task.clone(),
-                children: Vec::new(),
-            });
+fn sort_task_tree(tasks: &mut [Task]) {
+    tasks.sort_by_key(|task| task.created_at);
+}
 
-            tasks.retain(|t| t.id != task.id);
+/// Node colors of the task statuses in the rendered tree.
+const TREE_STATUS_COLORS: [(Status, &str); 7] = [
+    (Status::Draft, "#e5e7eb"),
+    (Status::ToDo, "#bfdbfe"),
+    (Status::InProgress, "#fde68a"),
+    (Status::WaitingForUser, "#fbcfe8"),
+    (Status::Done, "#bbf7d0"),
+    (Status::Failed, "#fecaca"),
+    (Status::Cancelled, "#d1d5db"),
+];
+
+/// Renders the task tree as a Mermaid `graph TD` diagram, with nodes labeled by title and
+/// colored by status. Solid edges go from parents to children, dotted ones from dependencies to
+/// the dependent tasks. Tasks outside of `tasks` are not rendered, nor are the edges to them.
+///
+/// # Errors
+///
+/// Returns error if the ancestry of any of the tasks is malformed.
+pub fn render_tree(tasks: &[Task], dependencies: &[(Uuid, Uuid)]) -> Result<String> {
+    let node_ids = tasks
+        .iter()
+        .enumerate()
+        .map(|(i, task)| (task.id, format!("t{i}")))
+        .collect::<HashMap<_, _>>();
+
+    let mut diagram = "graph TD\n".to_string();
+
+    for task in tasks {
+        let label = task
+            .title
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .replace('"', "#quot;");
+        let _ = writeln!(
+            diagram,
+            "    {}[\"{label}\"]:::{}",
+            node_ids[&task.id], task.status
+        );
+    }
 
-            collect_children(tree.children.last_mut().unwrap(), tasks)?;
+    for task in tasks {
+        if let Some(parent_id) = task.parent_id()? {
+            if let Some(parent) = node_ids.get(&parent_id) {
+                let _ = writeln!(diagram, "    {parent} --> {}", node_ids[&task.id]);
+            }
         }
     }
 
-    Ok(())
-}
+    for (task_id, depends_on_id) in dependencies {
+        if let (Some(task), Some(depends_on)) = (node_ids.get(task_id), node_ids.get(depends_on_id))
+        {
+            let _ = writeln!(diagram, "    {depends_on} -.-> {task}");
+        }
+    }
 
-fn sort_task_tree(tasks: &mut [Task]) {
-    tasks.sort_by(|a, b| a.created_at.cmp(&b.created_at));
+    for (status, color) in TREE_STATUS_COLORS {
+        let _ = writeln!(diagram, "    classDef {status} fill:{color}");
+    }
+
+    Ok(diagram)
 }
 
-#[derive(Default, Debug)]
+#[derive(Default, Debug, PartialEq)]
 enum Language {
     #[default]
     Unknown,
     Shell,
     Markdown,
     Python,
+    JavaScript,
     Other,
 }
 
 impl From<String> for Language {
     fn from(s: String) -> Self {
         match s.to_lowercase().as_str() {
-            "sh" | "shell" => Language::Shell,
+            "sh" | "shell" | "bash" | "zsh" | "console" => Language::Shell,
             "markdown" | "md" => Language::Markdown,
-            "python" => Language::Python,
+            "python" | "py" | "python3" => Language::Python,
+            "js" | "javascript" | "node" => Language::JavaScript,
             "" => Language::Unknown,
             _ => Language::Other,
         }
@@ -857,7 +1869,42 @@ struct CodeBlock {
     pub action: CodeBlockAction,
 }
 
-fn parse_code_blocks(text: &str) -> Result<Vec<CodeBlock>> {
+impl Language {
+    fn is_executable(&self) -> bool {
+        matches!(
+            self,
+            Language::Shell | Language::Python | Language::JavaScript
+        )
+    }
+}
+
+/// Filename in the info string of a fenced code block, e.g. ` ```python main.py ` or
+/// ` ```main.py `. Other metadata, e.g. ` ```python {1,3} `, is not a filename.
+fn info_string_filename(code: &markdown::mdast::Code) -> Option<String> {
+    let name = match (&code.lang, &code.meta) {
+        (_, Some(meta)) => meta.split_whitespace().next()?,
+        (Some(lang), None) if lang.contains('.') => lang,
+        _ => return None,
+    };
+
+    is_relative_filename(name).then(|| name.to_string())
+}
+
+/// Whether the name is a relative path of a file in the workdir, e.g. `src/main.py`.
+fn is_relative_filename(name: &str) -> bool {
+    let is_allowed_char = |c: char| c.is_alphanumeric() || matches!(c, '.' | '_' | '-' | '/');
+
+    name.chars().all(is_allowed_char)
+        && name
+            .split('/')
+            .all(|part| !part.is_empty() && part != "." && part != "..")
+}
+
+/// Parses the code blocks to act on. A code block is executed or saved if it follows the
+/// `> execute` or ``> save: `filename` `` directive. With `implicit_actions`, the blocks
+/// without a directive are saved if their info string has a filename, or executed if their
+/// language is executable.
+fn parse_code_blocks(text: &str, implicit_actions: bool) -> Result<Vec<CodeBlock>> {
     let ast = markdown::to_mdast(text, &markdown::ParseOptions::default())
         .map_err(|err| anyhow!("Failed to parse markdown AST: {}", err))?;
 
@@ -911,9 +1958,1745 @@ fn parse_code_blocks(text: &str) -> Result<Vec<CodeBlock>> {
                 code_blocks.push(code_block);
                 code_block = CodeBlock::default();
             }
+            markdown::mdast::Node::Code(code) if implicit_actions => {
+                let filename = info_string_filename(code);
+                let language: Language = match &filename {
+                    Some(_) if code.meta.is_none() => Language::Unknown,
+                    _ => code.lang.clone().unwrap_or_default().into(),
+                };
+
+                let action = if filename.is_some() {
+                    CodeBlockAction::Save
+                } else if language.is_executable() {
+                    CodeBlockAction::Execute
+                } else {
+                    continue;
+                };
+
+                code_blocks.push(CodeBlock {
+                    code: code.value.clone(),
+                    language,
+                    filename,
+                    action,
+                });
+            }
             _ => {}
         }
     }
 
     Ok(code_blocks)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        fmt::Debug,
+        sync::{Arc, Mutex},
+    };
+
+    use tracing::{
+        field::{Field, Visit},
+        span::{Attributes, Id},
+        Subscriber,
+    };
+    use tracing_subscriber::{layer::Context, prelude::*, Layer};
+    use wiremock::{
+        matchers::{body_string_contains, header, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use chrono::Utc;
+
+    use crate::{channel::NoopEmitter, types::tasks::WorkdirIsolation};
+
+    use super::*;
+
+    const COMPANY_ID: Uuid = Uuid::from_u128(1);
+    const CHAT_ID: Uuid = Uuid::from_u128(0x0006_0000_0000_0001);
+    const TASK_ID: Uuid = Uuid::from_u128(0x0005_0000_0000_0001);
+
+    fn test_settings() -> Settings {
+        let mut settings = Settings::default();
+        settings
+            .api_keys
+            .insert(types::models::Provider::OpenAI, "sk-test".to_string());
+
+        settings
+    }
+
+    /// Executor with the workdirs in a fresh temporary directory.
+    fn executor<'a>(
+        pool: &'a Pool<Postgres>,
+        channel: &'a Channel,
+        settings: &'a Settings,
+    ) -> TaskExecutor<'a> {
+        TaskExecutor {
+            pool,
+            channel,
+            settings,
+            workdir_root: std::env::temp_dir().join(format!("bridge-test-{}", Uuid::new_v4())),
+            user_agent: String::new(),
+            transcript: None,
+            secrets_cipher: None,
+        }
+    }
+
+    /// Points the fixture model to the mock server.
+    async fn use_mock(pool: &Pool<Postgres>, server: &MockServer) {
+        sqlx::query("UPDATE models SET api_url = $1")
+            .bind(format!("{}/", server.uri()))
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+
+    /// Points the fixture model to the mock server and makes the fixture task ready to be
+    /// executed in the fixture chat.
+    async fn prepare_execution(pool: &Pool<Postgres>, server: &MockServer) {
+        use_mock(pool, server).await;
+        sqlx::query("UPDATE chats SET kind = 'Execution'")
+            .execute(pool)
+            .await
+            .unwrap();
+        sqlx::query("UPDATE tasks SET status = 'ToDo', execution_chat_id = $1")
+            .bind(CHAT_ID)
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+
+    type SpanFields = Arc<Mutex<HashMap<String, HashMap<String, String>>>>;
+
+    /// Records the fields of every created span by span name.
+    struct SpanFieldsLayer(SpanFields);
+
+    struct FieldsVisitor<'a>(&'a mut HashMap<String, String>);
+
+    impl Visit for FieldsVisitor<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+            self.0
+                .insert(field.name().to_string(), format!("{value:?}"));
+        }
+    }
+
+    impl<S: Subscriber> Layer<S> for SpanFieldsLayer {
+        fn on_new_span(&self, attrs: &Attributes<'_>, _id: &Id, _ctx: Context<'_, S>) {
+            let mut fields = HashMap::new();
+            attrs.record(&mut FieldsVisitor(&mut fields));
+
+            self.0
+                .lock()
+                .unwrap()
+                .insert(attrs.metadata().name().to_string(), fields);
+        }
+    }
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(path = "../db/fixtures", scripts("companies", "models", "chats"))
+    )]
+    async fn test_execute_task_span_has_ids(pool: Pool<Postgres>) {
+        let spans = SpanFields::default();
+        let _guard = tracing::subscriber::set_default(
+            tracing_subscriber::registry().with(SpanFieldsLayer(spans.clone())),
+        );
+
+        let channel: Channel = Box::new(NoopEmitter);
+        let settings = test_settings();
+        let executor = executor(&pool, &channel, &settings);
+        // Fixture chat is not an execution one, so execution stops right after the span is entered.
+        let mut task = Task {
+            id: TASK_ID,
+            company_id: COMPANY_ID,
+            execution_chat_id: Some(CHAT_ID),
+            ..Default::default()
+        };
+
+        assert!(executor
+            .execute_task(
+                COMPANY_ID,
+                Uuid::nil(),
+                &mut task,
+                &CancellationToken::new()
+            )
+            .await
+            .is_err());
+
+        let spans = spans.lock().unwrap();
+        let fields = spans.get("execute_task").expect("span is not recorded");
+
+        assert_eq!(fields.get("company_id"), Some(&COMPANY_ID.to_string()));
+        assert_eq!(fields.get("task_id"), Some(&TASK_ID.to_string()));
+    }
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(
+            path = "../db/fixtures",
+            scripts("companies", "users", "agents", "models", "chats", "tasks")
+        )
+    )]
+    async fn test_agent_for_chat_falls_back_to_task_agent(pool: Pool<Postgres>) {
+        let channel: Channel = Box::new(NoopEmitter);
+        let settings = test_settings();
+        let executor = executor(&pool, &channel, &settings);
+        let task = repo::tasks::get(&pool, COMPANY_ID, TASK_ID).await.unwrap();
+
+        let agent = executor
+            .agent_for_chat(COMPANY_ID, CHAT_ID, &task)
+            .await
+            .unwrap();
+
+        assert_eq!(agent.id, task.agent_id);
+        // The association is restored, so the completion can find the agent as well.
+        assert_eq!(
+            repo::agents::get_for_chat(&pool, COMPANY_ID, CHAT_ID)
+                .await
+                .unwrap()
+                .map(|agent| agent.id),
+            Some(task.agent_id)
+        );
+    }
+
+    /// Records every emitted event as JSON.
+    struct RecordingEmitter(Arc<Mutex<Vec<serde_json::Value>>>);
+
+    #[async_trait::async_trait]
+    impl channel::Emitter for RecordingEmitter {
+        async fn emit(&self, _user_id: Uuid, event: &channel::Event) -> Result<()> {
+            self.0.lock().unwrap().push(serde_json::to_value(event)?);
+
+            Ok(())
+        }
+    }
+
+    async fn insert_task(pool: &Pool<Postgres>, cid: Uuid, ancestry: &str) -> Uuid {
+        let id = Uuid::new_v4();
+        sqlx::query(
+            "INSERT INTO tasks (id, company_id, user_id, agent_id, title, status, ancestry, \
+             ancestry_level, created_at, updated_at) \
+             VALUES ($1, $2, $3, $4, 'Subtask', 'ToDo', $5, $6, NOW(), NOW())",
+        )
+        .bind(id)
+        .bind(cid)
+        .bind(Uuid::from_u128(0x0002_0000_0000_0001))
+        .bind(Uuid::from_u128(0x0003_0000_0000_0001))
+        .bind(ancestry)
+        .bind(i32::try_from(ancestry.split('/').count()).unwrap())
+        .execute(pool)
+        .await
+        .unwrap();
+
+        id
+    }
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(
+            path = "../db/fixtures",
+            scripts("companies", "users", "agents", "tasks")
+        )
+    )]
+    async fn test_emit_tree_contains_whole_subtree(pool: Pool<Postgres>) {
+        const OTHER_COMPANY_ID: Uuid = Uuid::from_u128(2);
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let channel: Channel = Box::new(RecordingEmitter(events.clone()));
+        let settings = test_settings();
+        let executor = executor(&pool, &channel, &settings);
+
+        let child = insert_task(&pool, COMPANY_ID, &TASK_ID.to_string()).await;
+        let grandchild = insert_task(&pool, COMPANY_ID, &format!("{TASK_ID}/{child}")).await;
+        let sibling = insert_task(&pool, COMPANY_ID, &TASK_ID.to_string()).await;
+        sqlx::query(
+            "INSERT INTO companies (id, name, slug, created_at, updated_at) \
+             VALUES ($1, 'Enterprise', 'enterprise', NOW(), NOW())",
+        )
+        .bind(OTHER_COMPANY_ID)
+        .execute(&pool)
+        .await
+        .unwrap();
+        // Matches the ancestry, but belongs to another company.
+        insert_task(&pool, OTHER_COMPANY_ID, &TASK_ID.to_string()).await;
+        repo::tasks::complete(&pool, COMPANY_ID, grandchild)
+            .await
+            .unwrap();
+
+        let root = repo::tasks::get(&pool, COMPANY_ID, TASK_ID).await.unwrap();
+        executor
+            .emit_tree(COMPANY_ID, Uuid::nil(), &root)
+            .await
+            .unwrap();
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["event"], "TaskTreeUpdated");
+        assert_eq!(events[0]["data"]["root_id"], TASK_ID.to_string());
+
+        let tasks = events[0]["data"]["tasks"].as_array().unwrap();
+        let ids = tasks
+            .iter()
+            .map(|task| task["id"].as_str().unwrap().parse::<Uuid>().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![TASK_ID, child, grandchild, sibling]);
+        assert_eq!(tasks[2]["status"], "Done");
+    }
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(
+            path = "../db/fixtures",
+            scripts("companies", "users", "agents", "models", "chats", "tasks")
+        )
+    )]
+    async fn test_completion_timeout_fails_task(pool: Pool<Postgres>) {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(60)))
+            .mount(&server)
+            .await;
+
+        prepare_execution(&pool, &server).await;
+
+        let channel: Channel = Box::new(NoopEmitter);
+        let mut settings = test_settings();
+        settings.tasks.completion_timeout_secs = 1;
+        let executor = executor(&pool, &channel, &settings);
+
+        let result = executor
+            .execute_root_task(COMPANY_ID, &CancellationToken::new())
+            .await;
+
+        assert!(matches!(
+            result,
+            Err(errors::Error::Chats(chats::Error::CompletionTimedOut(_)))
+        ));
+        assert_eq!(
+            repo::tasks::get(&pool, COMPANY_ID, TASK_ID)
+                .await
+                .unwrap()
+                .status,
+            Status::Failed
+        );
+        let message = repo::messages::get_last_message(&pool, COMPANY_ID, CHAT_ID)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(message.status, types::messages::Status::Failed);
+        assert_eq!(
+            message.content.as_deref(),
+            Some("_The response timed out after 1s._")
+        );
+    }
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(
+            path = "../db/fixtures",
+            scripts("companies", "users", "agents", "models", "chats", "tasks")
+        )
+    )]
+    async fn test_content_filtered_response_fails_task(pool: Pool<Postgres>) {
+        let server = MockServer::start().await;
+        // The filter cuts the response off in the middle of the tool call arguments.
+        let chunks = [
+            serde_json::json!({ "choices": [{ "index": 0, "delta": {
+                "role": "assistant",
+                "content": "",
+                "tool_calls": [{
+                    "index": 0,
+                    "id": "call_1",
+                    "type": "function",
+                    "function": { "name": "sfai_done", "arguments": "{\"result\": \"Par" }
+                }]
+            } }] }),
+            serde_json::json!({ "choices": [{ "index": 0, "delta": {}, "finish_reason": "content_filter" }] }),
+        ];
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(
+                format!(
+                    "data: {}\n\ndata: {}\n\ndata: [DONE]\n\n",
+                    chunks[0], chunks[1]
+                ),
+                "text/event-stream",
+            ))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        prepare_execution(&pool, &server).await;
+
+        let channel: Channel = Box::new(NoopEmitter);
+        let settings = test_settings();
+        let executor = executor(&pool, &channel, &settings);
+
+        let result = executor
+            .execute_root_task(COMPANY_ID, &CancellationToken::new())
+            .await;
+
+        let message = repo::messages::get_last_message(&pool, COMPANY_ID, CHAT_ID)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            result,
+            Err(errors::Error::Executor(Error::ContentFiltered(id))) if id == message.id
+        ));
+        assert_eq!(message.status, types::messages::Status::ContentFiltered);
+        assert_eq!(
+            message.content.as_deref(),
+            Some(chats::CONTENT_FILTERED_NOTE)
+        );
+        assert_eq!(
+            repo::tasks::get(&pool, COMPANY_ID, TASK_ID)
+                .await
+                .unwrap()
+                .status,
+            Status::Failed
+        );
+    }
+
+    /// Streamed completion with a single chunk.
+    fn completion_response(delta: serde_json::Value) -> ResponseTemplate {
+        let chunk = serde_json::json!({ "choices": [{ "index": 0, "delta": delta }] });
+
+        ResponseTemplate::new(200).set_body_raw(
+            format!("data: {chunk}\n\ndata: [DONE]\n\n"),
+            "text/event-stream",
+        )
+    }
+
+    fn tool_call_response(id: &str, name: &str) -> ResponseTemplate {
+        completion_response(serde_json::json!({
+            "tool_calls": [{
+                "index": 0,
+                "id": id,
+                "type": "function",
+                "function": { "name": name, "arguments": "{}" }
+            }]
+        }))
+    }
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(
+            path = "../db/fixtures",
+            scripts("companies", "users", "agents", "models", "chats", "tasks")
+        )
+    )]
+    async fn test_forbidden_done_routes_to_wait_for_user(pool: Pool<Postgres>) {
+        let server = MockServer::start().await;
+        // Model ignores the available tools and tries to complete the task anyway.
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .respond_with(tool_call_response("call_1", "sfai_done"))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .respond_with(completion_response(
+                serde_json::json!({ "content": "Please review the report" }),
+            ))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .respond_with(tool_call_response("call_2", "sfai_wait_for_user"))
+            .mount(&server)
+            .await;
+
+        prepare_execution(&pool, &server).await;
+        sqlx::query("UPDATE agents SET forbidden_tools = '{sfai_done}'")
+            .execute(&pool)
+            .await
+            .unwrap();
+        repo::messages::create(
+            &pool,
+            COMPANY_ID,
+            CreateParams {
+                chat_id: CHAT_ID,
+                status: types::messages::Status::Completed,
+                role: Role::Assistant,
+                content: Some("The report is ready".to_string()),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        let channel: Channel = Box::new(NoopEmitter);
+        let settings = test_settings();
+        let executor = executor(&pool, &channel, &settings);
+
+        executor
+            .execute_root_task(COMPANY_ID, &CancellationToken::new())
+            .await
+            .unwrap();
+
+        let task = repo::tasks::get(&pool, COMPANY_ID, TASK_ID).await.unwrap();
+        assert_eq!(task.status, Status::WaitingForUser);
+
+        let requests = server.received_requests().await.unwrap();
+        assert_eq!(requests.len(), 3);
+        // Both self-reflections are offered the allowed tools only.
+        for request in [&requests[0], &requests[2]] {
+            let body = request.body_json::<serde_json::Value>().unwrap();
+            let tools = body["tools"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|tool| tool["function"]["name"].as_str().unwrap())
+                .collect::<Vec<_>>();
+            assert_eq!(tools, vec!["sfai_fail", "sfai_wait_for_user"]);
+        }
+
+        let messages = repo::messages::list(
+            &pool,
+            COMPANY_ID,
+            repo::messages::ListParams { chat_id: CHAT_ID },
+        )
+        .await
+        .unwrap();
+        let correction = messages
+            .iter()
+            .find(|message| message.tool_call_id.as_deref() == Some("call_1"))
+            .unwrap();
+        assert!(correction
+            .content
+            .as_ref()
+            .unwrap()
+            .contains("`sfai_done` is not allowed"));
+        assert!(repo::task_results::list(&pool, COMPANY_ID, TASK_ID)
+            .await
+            .unwrap()
+            .is_empty());
+
+        fs::remove_dir_all(&executor.workdir_root).await.ok();
+    }
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(
+            path = "../db/fixtures",
+            scripts("companies", "users", "agents", "models", "chats", "tasks")
+        )
+    )]
+    async fn test_completed_task_writes_transcript(pool: Pool<Postgres>) {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .respond_with(tool_call_response("call_1", "sfai_done"))
+            .mount(&server)
+            .await;
+
+        prepare_execution(&pool, &server).await;
+        repo::messages::create(
+            &pool,
+            COMPANY_ID,
+            CreateParams {
+                chat_id: CHAT_ID,
+                agent_id: Some(Uuid::from_u128(0x0003_0000_0000_0001)),
+                status: types::messages::Status::Completed,
+                role: Role::Assistant,
+                content: Some("It's sunny".to_string()),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        let path = std::env::temp_dir().join(format!("bridge-transcript-{}.jsonl", Uuid::new_v4()));
+        let transcript = TranscriptSink::open(&path).await.unwrap();
+        let channel: Channel = Box::new(NoopEmitter);
+        let settings = test_settings();
+        let executor = TaskExecutor {
+            transcript: Some(transcript.clone()),
+            ..executor(&pool, &channel, &settings)
+        };
+
+        executor
+            .execute_root_task(COMPANY_ID, &CancellationToken::new())
+            .await
+            .unwrap();
+        transcript.flush().await.unwrap();
+
+        let records = fs::read_to_string(&path)
+            .await
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        fs::remove_file(&path).await.unwrap();
+        fs::remove_dir_all(&executor.workdir_root).await.ok();
+
+        assert_eq!(
+            records
+                .iter()
+                .map(|record| record["kind"].as_str().unwrap())
+                .collect::<Vec<_>>(),
+            vec![
+                // Self-reflection
+                "MessageCreated",
+                "MessageUpdated",
+                // `sfai_done` call and its output
+                "ToolCall",
+                "MessageCreated",
+                "MessageUpdated",
+            ]
+        );
+        for record in &records {
+            assert_eq!(record["company_id"], COMPANY_ID.to_string());
+            assert_eq!(record["task_id"], TASK_ID.to_string());
+            assert_eq!(record["chat_id"], CHAT_ID.to_string());
+            assert!(record["timestamp"].is_string());
+        }
+        assert_eq!(records[1]["data"]["status"], "WaitingForToolCall");
+        assert_eq!(
+            records[2]["data"]["tool_call"]["function"]["name"],
+            "sfai_done"
+        );
+        assert_eq!(records[2]["data"]["message_id"], records[1]["data"]["id"]);
+        assert_eq!(records[3]["data"]["role"], "Tool");
+        assert_eq!(records[4]["data"]["id"], records[1]["data"]["id"]);
+        assert_eq!(records[4]["data"]["status"], "Completed");
+        assert_eq!(
+            repo::tasks::get(&pool, COMPANY_ID, TASK_ID)
+                .await
+                .unwrap()
+                .status,
+            Status::Done
+        );
+    }
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(
+            path = "../db/fixtures",
+            scripts("companies", "users", "agents", "models", "chats", "tasks")
+        )
+    )]
+    async fn test_stuck_agent_is_nudged_and_then_fails(pool: Pool<Postgres>) {
+        let server = MockServer::start().await;
+        // Self-reflection never calls a tool, and the agent always replies the same.
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .and(body_string_contains("sfai_done"))
+            .respond_with(completion_response(
+                serde_json::json!({ "content": "I should try again" }),
+            ))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .respond_with(completion_response(
+                serde_json::json!({ "content": "I'm working on it" }),
+            ))
+            .mount(&server)
+            .await;
+
+        prepare_execution(&pool, &server).await;
+
+        let channel: Channel = Box::new(NoopEmitter);
+        let settings = test_settings();
+        let executor = executor(&pool, &channel, &settings);
+
+        let result = executor
+            .execute_root_task(COMPANY_ID, &CancellationToken::new())
+            .await;
+        fs::remove_dir_all(&executor.workdir_root).await.ok();
+
+        assert!(matches!(
+            result,
+            Err(errors::Error::Executor(Error::NoProgress(3)))
+        ));
+        assert_eq!(
+            repo::tasks::get(&pool, COMPANY_ID, TASK_ID)
+                .await
+                .unwrap()
+                .status,
+            Status::Failed
+        );
+
+        // Three repeated replies trigger the nudge, the fourth one fails the task.
+        let steps = server
+            .received_requests()
+            .await
+            .unwrap()
+            .iter()
+            .map(|request| {
+                let body = String::from_utf8_lossy(&request.body).to_string();
+
+                if !body.contains("sfai_done") {
+                    "reply"
+                } else if body.contains("You must now call") {
+                    "nudge"
+                } else {
+                    "reflection"
+                }
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            steps,
+            vec![
+                "reply",
+                "reflection",
+                "reply",
+                "reflection",
+                "reply",
+                "nudge",
+                "reply"
+            ]
+        );
+    }
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(
+            path = "../db/fixtures",
+            scripts("companies", "users", "agents", "models", "chats", "tasks")
+        )
+    )]
+    async fn test_execute_leaf_task_by_id(pool: Pool<Postgres>) {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .and(body_string_contains("sfai_done"))
+            .respond_with(tool_call_response("call_1", "sfai_done"))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .respond_with(completion_response(
+                serde_json::json!({ "content": "It's sunny" }),
+            ))
+            .mount(&server)
+            .await;
+
+        use_mock(&pool, &server).await;
+        sqlx::query("UPDATE chats SET kind = 'Execution'")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("UPDATE tasks SET status = 'ToDo'")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let first = insert_task(&pool, COMPANY_ID, &TASK_ID.to_string()).await;
+        let second = insert_task(&pool, COMPANY_ID, &TASK_ID.to_string()).await;
+        sqlx::query("UPDATE tasks SET execution_chat_id = $1 WHERE id = $2")
+            .bind(CHAT_ID)
+            .bind(second)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let channel: Channel = Box::new(NoopEmitter);
+        let settings = test_settings();
+        let executor = executor(&pool, &channel, &settings);
+
+        // The preceding sibling is not done yet.
+        let result = executor
+            .execute_task_by_id(COMPANY_ID, second, &CancellationToken::new())
+            .await;
+        assert!(matches!(
+            result,
+            Err(errors::Error::Executor(Error::DependenciesNotReady(id))) if id == second
+        ));
+
+        repo::tasks::complete(&pool, COMPANY_ID, first)
+            .await
+            .unwrap();
+        executor
+            .execute_task_by_id(COMPANY_ID, second, &CancellationToken::new())
+            .await
+            .unwrap();
+        fs::remove_dir_all(&executor.workdir_root).await.ok();
+
+        let status = |id| {
+            let pool = pool.clone();
+            async move {
+                repo::tasks::get(&pool, COMPANY_ID, id)
+                    .await
+                    .unwrap()
+                    .status
+            }
+        };
+        assert_eq!(status(second).await, Status::Done);
+        // All the sub-tasks are done, so is the root.
+        assert_eq!(status(TASK_ID).await, Status::Done);
+
+        let result = executor
+            .execute_task_by_id(COMPANY_ID, second, &CancellationToken::new())
+            .await;
+        assert!(matches!(
+            result,
+            Err(errors::Error::Executor(Error::NotRunnable(_, Status::Done)))
+        ));
+    }
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(
+            path = "../db/fixtures",
+            scripts("companies", "users", "agents", "models", "chats", "tasks")
+        )
+    )]
+    async fn test_cancelled_execution_stops_before_next_step(pool: Pool<Postgres>) {
+        let server = MockServer::start().await;
+        // The agent keeps working, so only the cancellation stops the execution.
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .respond_with(
+                completion_response(serde_json::json!({ "content": "Still working on it" }))
+                    .set_delay(Duration::from_millis(300)),
+            )
+            .mount(&server)
+            .await;
+
+        prepare_execution(&pool, &server).await;
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let channel: Channel = Box::new(RecordingEmitter(events.clone()));
+        let settings = test_settings();
+        let executor = executor(&pool, &channel, &settings);
+
+        let cancel = CancellationToken::new();
+        // Cancels while the first completion is in flight.
+        let cancel_during_completion = async {
+            while server.received_requests().await.unwrap().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            cancel.cancel();
+        };
+        let (result, ()) = tokio::join!(
+            executor.execute_root_task(COMPANY_ID, &cancel),
+            cancel_during_completion
+        );
+        fs::remove_dir_all(&executor.workdir_root).await.ok();
+
+        result.unwrap();
+        assert_eq!(server.received_requests().await.unwrap().len(), 1);
+
+        let task = repo::tasks::get(&pool, COMPANY_ID, TASK_ID).await.unwrap();
+        assert_eq!(task.status, Status::Cancelled);
+        assert!(events.lock().unwrap().iter().any(|event| {
+            event["event"] == "TaskUpdated" && event["data"]["status"] == "Cancelled"
+        }));
+        // The completion in flight is finished, and is kept for the next run.
+        let messages = repo::messages::list(
+            &pool,
+            COMPANY_ID,
+            repo::messages::ListParams { chat_id: CHAT_ID },
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            messages.last().unwrap().content.as_deref(),
+            Some("Still working on it")
+        );
+    }
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(
+            path = "../db/fixtures",
+            scripts("companies", "users", "agents", "models", "chats", "tasks")
+        )
+    )]
+    async fn test_task_timeout_fails_task(pool: Pool<Postgres>) {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .respond_with(
+                tool_call_response("call_1", "sfai_done").set_delay(Duration::from_secs(5)),
+            )
+            .mount(&server)
+            .await;
+
+        prepare_execution(&pool, &server).await;
+
+        let channel: Channel = Box::new(NoopEmitter);
+        let mut settings = test_settings();
+        settings.tasks.task_timeout_secs = 1;
+        let executor = executor(&pool, &channel, &settings);
+
+        let result = executor
+            .execute_root_task(COMPANY_ID, &CancellationToken::new())
+            .await;
+        fs::remove_dir_all(&executor.workdir_root).await.ok();
+
+        assert!(matches!(
+            result,
+            Err(errors::Error::Executor(Error::TimedOut(t))) if t == Duration::from_secs(1)
+        ));
+        let task = repo::tasks::get(&pool, COMPANY_ID, TASK_ID).await.unwrap();
+        assert_eq!(task.status, Status::Failed);
+
+        // The completion in flight is failed instead of being left as written, and the timeout
+        // is noted for the next run.
+        let messages = repo::messages::list(
+            &pool,
+            COMPANY_ID,
+            repo::messages::ListParams { chat_id: CHAT_ID },
+        )
+        .await
+        .unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].role, Role::Assistant);
+        assert_eq!(messages[0].status, types::messages::Status::Failed);
+        assert_eq!(messages[1].role, Role::User);
+        assert_eq!(
+            messages[1].content.as_deref(),
+            Some("Task execution: Timed out after 1 seconds")
+        );
+    }
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(
+            path = "../db/fixtures",
+            scripts("companies", "users", "agents", "models", "chats", "tasks")
+        )
+    )]
+    async fn test_timed_out_step_answers_remaining_tool_calls(pool: Pool<Postgres>) {
+        // Timed out after the first of the two tool calls was answered.
+        for params in [
+            CreateParams {
+                chat_id: CHAT_ID,
+                status: types::messages::Status::WaitingForToolCall,
+                role: Role::Assistant,
+                tool_calls: Some(serde_json::json!([
+                    {
+                        "id": "call_1",
+                        "type": "function",
+                        "function": { "name": "sfai_web_search", "arguments": "{}" }
+                    },
+                    {
+                        "id": "call_2",
+                        "type": "function",
+                        "function": { "name": "sfai_web_search", "arguments": "{}" }
+                    }
+                ])),
+                ..Default::default()
+            },
+            CreateParams {
+                chat_id: CHAT_ID,
+                status: types::messages::Status::Completed,
+                role: Role::Tool,
+                content: Some("Found it".to_string()),
+                tool_call_id: Some("call_1".to_string()),
+                ..Default::default()
+            },
+        ] {
+            repo::messages::create(&pool, COMPANY_ID, params)
+                .await
+                .unwrap();
+        }
+
+        let channel: Channel = Box::new(NoopEmitter);
+        let settings = test_settings();
+        let executor = executor(&pool, &channel, &settings);
+        let task = Task {
+            execution_chat_id: Some(CHAT_ID),
+            ..repo::tasks::get(&pool, COMPANY_ID, TASK_ID).await.unwrap()
+        };
+
+        executor
+            .fail_timed_out_step(COMPANY_ID, Uuid::nil(), &task, Duration::from_secs(60))
+            .await
+            .unwrap();
+
+        let messages = repo::messages::list(
+            &pool,
+            COMPANY_ID,
+            repo::messages::ListParams { chat_id: CHAT_ID },
+        )
+        .await
+        .unwrap();
+        let summary = messages
+            .iter()
+            .map(|message| (message.role, message.tool_call_id.as_deref()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                (Role::Assistant, None),
+                (Role::Tool, Some("call_1")),
+                (Role::Tool, Some("call_2")),
+                (Role::User, None),
+            ]
+        );
+        assert_eq!(
+            messages[0].status,
+            types::messages::Status::WaitingForToolCall
+        );
+        assert_eq!(
+            messages[2].content.as_deref(),
+            Some("```\nTimed out after 60 seconds\n```")
+        );
+    }
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(
+            path = "../db/fixtures",
+            scripts("companies", "users", "agents", "models", "chats", "tasks")
+        )
+    )]
+    async fn test_transient_failure_is_retried(pool: Pool<Postgres>) {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .respond_with(
+                tool_call_response("call_1", "sfai_done").set_delay(Duration::from_secs(3)),
+            )
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .respond_with(tool_call_response("call_2", "sfai_done"))
+            .mount(&server)
+            .await;
+
+        prepare_execution(&pool, &server).await;
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let channel: Channel = Box::new(RecordingEmitter(events.clone()));
+        let mut settings = test_settings();
+        settings.tasks.completion_timeout_secs = 1;
+        settings.tasks.max_retries = 1;
+        let executor = executor(&pool, &channel, &settings);
+
+        executor
+            .execute_root_task(COMPANY_ID, &CancellationToken::new())
+            .await
+            .unwrap();
+        fs::remove_dir_all(&executor.workdir_root).await.ok();
+
+        let task = repo::tasks::get(&pool, COMPANY_ID, TASK_ID).await.unwrap();
+        assert_eq!(task.status, Status::Done);
+
+        let retrying = events
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|event| event["event"] == "TaskRetrying")
+            .cloned()
+            .unwrap();
+        assert_eq!(retrying["data"]["attempt"], 1);
+        assert_eq!(retrying["data"]["max_retries"], 1);
+
+        // The timed out reply is discarded, only the retried one is kept.
+        let messages = repo::messages::list(
+            &pool,
+            COMPANY_ID,
+            repo::messages::ListParams { chat_id: CHAT_ID },
+        )
+        .await
+        .unwrap();
+        let replies: Vec<_> = messages
+            .iter()
+            .filter(|message| message.role == Role::Assistant)
+            .collect();
+        assert_eq!(replies.len(), 1);
+        assert_ne!(replies[0].status, types::messages::Status::Failed);
+    }
+
+    /// Inserts the sub-tasks `first` and `second` of the root task, and the `last` one depending
+    /// on both of them.
+    async fn insert_diamond(pool: &Pool<Postgres>) -> [Uuid; 3] {
+        sqlx::query("UPDATE tasks SET status = 'ToDo'")
+            .execute(pool)
+            .await
+            .unwrap();
+        let first = insert_task(pool, COMPANY_ID, &TASK_ID.to_string()).await;
+        let second = insert_task(pool, COMPANY_ID, &TASK_ID.to_string()).await;
+        let last = insert_task(pool, COMPANY_ID, &TASK_ID.to_string()).await;
+        repo::tasks::add_dependencies(pool, COMPANY_ID, last, &[first, second])
+            .await
+            .unwrap();
+        sqlx::query(
+            "WITH chats AS ( \
+                 INSERT INTO chats (id, company_id, model_id, kind, created_at, updated_at) \
+                 SELECT id, company_id, $1, 'Execution', NOW(), NOW() \
+                 FROM tasks WHERE ancestry IS NOT NULL RETURNING id \
+             ) \
+             UPDATE tasks SET execution_chat_id = tasks.id FROM chats WHERE chats.id = tasks.id",
+        )
+        .bind(Uuid::from_u128(0x0004_0000_0000_0001))
+        .execute(pool)
+        .await
+        .unwrap();
+
+        [first, second, last]
+    }
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(
+            path = "../db/fixtures",
+            scripts("companies", "users", "agents", "models", "chats", "tasks")
+        )
+    )]
+    async fn test_independent_siblings_run_concurrently(pool: Pool<Postgres>) {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .respond_with(tool_call_response("call_1", "sfai_done"))
+            .mount(&server)
+            .await;
+        use_mock(&pool, &server).await;
+        let [first, second, last] = insert_diamond(&pool).await;
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let channel: Channel = Box::new(RecordingEmitter(events.clone()));
+        let mut settings = test_settings();
+        settings.tasks.execution_concurrency = 2;
+        let executor = executor(&pool, &channel, &settings);
+
+        executor
+            .execute_root_task(COMPANY_ID, &CancellationToken::new())
+            .await
+            .unwrap();
+        fs::remove_dir_all(&executor.workdir_root).await.ok();
+
+        let in_progress = events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|event| event["event"] == "TaskTreeUpdated")
+            .map(|event| {
+                event["data"]["tasks"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .filter(|task| {
+                        task["status"] == "InProgress" && task["id"] != TASK_ID.to_string()
+                    })
+                    .map(|task| task["id"].as_str().unwrap().parse::<Uuid>().unwrap())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        // Both independent sub-tasks run at once, the dependent one runs alone after them.
+        assert!(in_progress.contains(&vec![first, second]));
+        assert!(in_progress
+            .iter()
+            .all(|ids| !ids.contains(&last) || ids == &vec![last]));
+
+        for id in [first, second, last, TASK_ID] {
+            let task = repo::tasks::get(&pool, COMPANY_ID, id).await.unwrap();
+            assert_eq!(task.status, Status::Done);
+        }
+    }
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(
+            path = "../db/fixtures",
+            scripts("companies", "users", "agents", "models", "chats", "tasks")
+        )
+    )]
+    async fn test_failed_concurrent_sibling_fails_parent(pool: Pool<Postgres>) {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .and(body_string_contains("Broken subtask"))
+            .respond_with(ResponseTemplate::new(400))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .respond_with(tool_call_response("call_1", "sfai_done"))
+            .mount(&server)
+            .await;
+        use_mock(&pool, &server).await;
+        let [first, second, last] = insert_diamond(&pool).await;
+        sqlx::query("UPDATE tasks SET title = 'Broken subtask' WHERE id = $1")
+            .bind(second)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let channel: Channel = Box::new(NoopEmitter);
+        let mut settings = test_settings();
+        settings.tasks.execution_concurrency = 2;
+        let executor = executor(&pool, &channel, &settings);
+
+        assert!(executor
+            .execute_root_task(COMPANY_ID, &CancellationToken::new())
+            .await
+            .is_err());
+        fs::remove_dir_all(&executor.workdir_root).await.ok();
+
+        let status = |id| {
+            let pool = pool.clone();
+            async move {
+                repo::tasks::get(&pool, COMPANY_ID, id)
+                    .await
+                    .unwrap()
+                    .status
+            }
+        };
+        // The running sibling is finished, but the dependent one is not started.
+        assert_eq!(status(first).await, Status::Done);
+        assert_eq!(status(second).await, Status::Failed);
+        assert_eq!(status(last).await, Status::ToDo);
+        assert_eq!(status(TASK_ID).await, Status::Failed);
+    }
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(
+            path = "../db/fixtures",
+            scripts("companies", "users", "agents", "models", "chats", "tasks")
+        )
+    )]
+    async fn test_execution_uses_model_api_key(pool: Pool<Postgres>) {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .and(header("authorization", "Bearer sk-model"))
+            .respond_with(tool_call_response("call_1", "sfai_done"))
+            .mount(&server)
+            .await;
+
+        prepare_execution(&pool, &server).await;
+
+        let channel: Channel = Box::new(NoopEmitter);
+        // No API key for the provider.
+        let settings = Settings::default();
+        let executor = executor(&pool, &channel, &settings);
+        let task = repo::tasks::get(&pool, COMPANY_ID, TASK_ID).await.unwrap();
+
+        let result = executor
+            .send_to_agent(
+                COMPANY_ID,
+                Uuid::new_v4(),
+                CHAT_ID,
+                &task,
+                &CancellationToken::new(),
+            )
+            .await;
+        assert!(matches!(
+            result,
+            Err(errors::Error::Models(models::Error::ApiKeyNotFound(
+                types::models::Provider::OpenAI
+            )))
+        ));
+        assert!(server.received_requests().await.unwrap().is_empty());
+
+        sqlx::query("UPDATE models SET api_key = 'sk-model'")
+            .execute(&pool)
+            .await
+            .unwrap();
+        executor
+            .execute_root_task(COMPANY_ID, &CancellationToken::new())
+            .await
+            .unwrap();
+        fs::remove_dir_all(&executor.workdir_root).await.ok();
+
+        let task = repo::tasks::get(&pool, COMPANY_ID, TASK_ID).await.unwrap();
+        assert_eq!(task.status, Status::Done);
+    }
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(
+            path = "../db/fixtures",
+            scripts("companies", "users", "agents", "models", "chats", "tasks")
+        )
+    )]
+    async fn test_resume_in_progress_task_after_restart(pool: Pool<Postgres>) {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .respond_with(tool_call_response("call_2", "sfai_done"))
+            .mount(&server)
+            .await;
+
+        use_mock(&pool, &server).await;
+        sqlx::query("UPDATE chats SET kind = 'Execution'")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("UPDATE tasks SET status = 'InProgress', execution_chat_id = $1")
+            .bind(CHAT_ID)
+            .execute(&pool)
+            .await
+            .unwrap();
+        // Interrupted before the execution chat was created.
+        let stuck = Uuid::new_v4();
+        sqlx::query(
+            "INSERT INTO tasks (id, company_id, user_id, agent_id, title, status, created_at, \
+             updated_at) VALUES ($1, $2, $3, $4, 'Stuck', 'InProgress', NOW(), NOW())",
+        )
+        .bind(stuck)
+        .bind(COMPANY_ID)
+        .bind(Uuid::from_u128(0x0002_0000_0000_0001))
+        .bind(Uuid::from_u128(0x0003_0000_0000_0001))
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        // The process was terminated after the tool call was answered.
+        for params in [
+            CreateParams {
+                chat_id: CHAT_ID,
+                status: types::messages::Status::Completed,
+                role: Role::User,
+                content: Some("Fetch the weather".to_string()),
+                ..Default::default()
+            },
+            CreateParams {
+                chat_id: CHAT_ID,
+                status: types::messages::Status::Completed,
+                role: Role::Assistant,
+                tool_calls: Some(serde_json::json!([{
+                    "id": "call_1",
+                    "type": "function",
+                    "function": { "name": "sfai_done", "arguments": "{}" }
+                }])),
+                ..Default::default()
+            },
+            CreateParams {
+                chat_id: CHAT_ID,
+                status: types::messages::Status::Completed,
+                role: Role::Tool,
+                content: Some("It's sunny".to_string()),
+                tool_call_id: Some("call_1".to_string()),
+                ..Default::default()
+            },
+        ] {
+            repo::messages::create(&pool, COMPANY_ID, params)
+                .await
+                .unwrap();
+        }
+
+        crate::database::prepare(&pool).await.unwrap();
+
+        let status = |id| {
+            let pool = pool.clone();
+            async move {
+                repo::tasks::get(&pool, COMPANY_ID, id)
+                    .await
+                    .unwrap()
+                    .status
+            }
+        };
+        assert_eq!(status(TASK_ID).await, Status::InProgress);
+        assert_eq!(status(stuck).await, Status::ToDo);
+
+        let channel: Channel = Box::new(NoopEmitter);
+        let settings = test_settings();
+        let executor = executor(&pool, &channel, &settings);
+
+        executor
+            .resume_root_tasks(COMPANY_ID, &CancellationToken::new())
+            .await
+            .unwrap();
+        fs::remove_dir_all(&executor.workdir_root).await.ok();
+
+        assert_eq!(status(TASK_ID).await, Status::Done);
+        assert_eq!(status(stuck).await, Status::ToDo);
+
+        // The execution is continued with the existing messages.
+        let requests = server.received_requests().await.unwrap();
+        assert_eq!(requests.len(), 1);
+        let messages = repo::messages::list(
+            &pool,
+            COMPANY_ID,
+            repo::messages::ListParams { chat_id: CHAT_ID },
+        )
+        .await
+        .unwrap();
+        assert_eq!(messages[0].content.as_deref(), Some("Fetch the weather"));
+        assert!(messages
+            .iter()
+            .any(|message| message.tool_call_id.as_deref() == Some("call_2")));
+    }
+
+    #[tokio::test]
+    async fn test_workdir_files_appear_in_next_step_prelude() {
+        let workdir_root = std::env::temp_dir().join(format!("bridge-test-{}", Uuid::new_v4()));
+        let root = Task {
+            id: TASK_ID,
+            ..Default::default()
+        };
+        let child = Task {
+            id: Uuid::new_v4(),
+            title: "Summarize the report".to_string(),
+            ancestry: Some(TASK_ID.to_string()),
+            ..Default::default()
+        };
+
+        // Previous step saves files to the shared workdir.
+        let workdir = root
+            .workdir(&workdir_root, WorkdirIsolation::Shared)
+            .await
+            .unwrap();
+        fs::create_dir_all(workdir.join("data")).await.unwrap();
+        fs::write(workdir.join("report.md"), "# Weather report")
+            .await
+            .unwrap();
+        fs::write(workdir.join("data/raw.bin"), [0_u8, 159, 146, 150])
+            .await
+            .unwrap();
+
+        let files = workdir_manifest(
+            &child
+                .workdir(&workdir_root, WorkdirIsolation::Shared)
+                .await
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+        let agent = Agent {
+            id: Uuid::new_v4(),
+            id_int: 1001,
+            company_id: COMPANY_ID,
+            name: "Researcher".to_string(),
+            description: String::new(),
+            system_message: String::new(),
+            is_enabled: true,
+            is_code_interpreter_enabled: true,
+            is_web_browser_enabled: false,
+            execution_steps_limit: None,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            forbidden_tools: Vec::new(),
+            examples: serde_json::Value::Null,
+        };
+        let prelude = execution_prelude(CHAT_ID, &child, &agent, &files, false).unwrap();
+        fs::remove_dir_all(&workdir_root).await.unwrap();
+
+        let task_message = prelude[1].content.as_ref().unwrap();
+        assert!(task_message.contains("`report.md` (16 bytes)"));
+        assert!(task_message.contains("# Weather report"));
+        assert!(task_message.contains("`data/raw.bin` (4 bytes)"));
+        assert!(!files.is_truncated);
+        assert!(files.files[0].excerpt.is_none());
+    }
+
+    #[test]
+    fn test_stuck_nudge_offers_only_allowed_tools() {
+        let agent = |forbidden_tools: &[&str]| Agent {
+            id: Uuid::new_v4(),
+            id_int: 1001,
+            company_id: COMPANY_ID,
+            name: "Researcher".to_string(),
+            description: String::new(),
+            system_message: String::new(),
+            is_enabled: true,
+            is_code_interpreter_enabled: false,
+            is_web_browser_enabled: false,
+            execution_steps_limit: None,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            forbidden_tools: forbidden_tools.iter().map(ToString::to_string).collect(),
+            examples: serde_json::Value::Null,
+        };
+        let render = |agent: &Agent| {
+            SelfReflectionMessageTemplate {
+                agent,
+                is_stuck: true,
+            }
+            .render()
+            .unwrap()
+        };
+
+        let message = render(&agent(&[]));
+        assert!(message.ends_with(
+            "You must now call the `sfai_done` function if the task is complete, or the \
+             `sfai_fail` function if it can't be completed."
+        ));
+
+        let message = render(&agent(&["sfai_done"]));
+        assert!(!message.contains("sfai_done"));
+        assert!(message
+            .ends_with("You must now call the `sfai_fail` function if it can't be completed."));
+
+        let message = render(&agent(&["sfai_done", "sfai_fail"]));
+        assert!(!message.contains("sfai_done"));
+        assert!(!message.contains("sfai_fail"));
+        assert!(message.ends_with(
+            "You must now call the `sfai_wait_for_user` function to hand the task over to the user."
+        ));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_workdir_manifest_skips_broken_entries_and_keeps_first_files() {
+        let workdir = std::env::temp_dir().join(format!("bridge-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&workdir).await.unwrap();
+        for i in (0..WORKDIR_MANIFEST_MAX_FILES + 5).rev() {
+            fs::write(workdir.join(format!("{i:03}.txt")), "data")
+                .await
+                .unwrap();
+        }
+        fs::symlink(workdir.join("missing.txt"), workdir.join("broken.txt"))
+            .await
+            .unwrap();
+
+        let manifest = workdir_manifest(&workdir).await.unwrap();
+        fs::remove_dir_all(&workdir).await.unwrap();
+
+        assert!(manifest.is_truncated);
+        assert_eq!(manifest.files.len(), WORKDIR_MANIFEST_MAX_FILES);
+        assert_eq!(manifest.files[0].path, "000.txt");
+        assert_eq!(manifest.files[49].path, "049.txt");
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_siblings_write_to_isolated_workdirs() {
+        let workdir_root = std::env::temp_dir().join(format!("bridge-test-{}", Uuid::new_v4()));
+        let siblings = [Uuid::new_v4(), Uuid::new_v4()].map(|id| Task {
+            id,
+            ancestry: Some(TASK_ID.to_string()),
+            ..Default::default()
+        });
+
+        let write = |task: &Task, isolation| {
+            let workdir_root = workdir_root.clone();
+            let task = task.clone();
+
+            async move {
+                let workdir = task.workdir(&workdir_root, isolation).await.unwrap();
+                fs::create_dir_all(&workdir).await.unwrap();
+                fs::write(workdir.join("result.txt"), task.id.to_string())
+                    .await
+                    .unwrap();
+
+                workdir
+            }
+        };
+
+        let (first, second) = tokio::join!(
+            write(&siblings[0], WorkdirIsolation::PerTask),
+            write(&siblings[1], WorkdirIsolation::PerTask)
+        );
+        let shared = write(&siblings[0], WorkdirIsolation::Shared).await;
+
+        let first_result = fs::read_to_string(first.join("result.txt")).await.unwrap();
+        let second_result = fs::read_to_string(second.join("result.txt")).await.unwrap();
+        fs::remove_dir_all(&workdir_root).await.unwrap();
+
+        assert_ne!(first, second);
+        assert!(first.starts_with(&shared) && second.starts_with(&shared));
+        assert_eq!(first_result, siblings[0].id.to_string());
+        assert_eq!(second_result, siblings[1].id.to_string());
+    }
+
+    #[test]
+    fn test_internal_task_tools_are_built_once() {
+        let first = internal_task_tools();
+
+        // Each self-reflection cycle must reuse the same tools instead of rebuilding them.
+        for _ in 0..3 {
+            assert!(std::ptr::eq(first, internal_task_tools()));
+        }
+
+        assert_eq!(
+            first
+                .iter()
+                .map(|tool| tool.function.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["sfai_done", "sfai_fail", "sfai_wait_for_user"]
+        );
+    }
+
+    #[test]
+    fn test_format_exec_output() {
+        let output = docker::ExecOutput {
+            stdout: "42".to_string(),
+            stderr: String::new(),
+            exit_code: 0,
+        };
+        assert_eq!(
+            format_exec_output(&output),
+            "Exit code: 0\n\nstdout:\n```\n42\n```"
+        );
+
+        let output = docker::ExecOutput {
+            stdout: "partial".to_string(),
+            stderr: "Traceback".to_string(),
+            exit_code: 1,
+        };
+        assert_eq!(
+            format_exec_output(&output),
+            "Exit code: 1\n\nstdout:\n```\npartial\n```\n\nstderr:\n```\nTraceback\n```"
+        );
+    }
+
+    #[test]
+    fn test_parse_javascript_code_blocks() {
+        let text = "> Execute\n\n```js\nconsole.log(1)\n```\n\n\
+                    > Save: `index.mjs`\n\n```node\nexport {}\n```\n";
+
+        let code_blocks = parse_code_blocks(text, false).unwrap();
+
+        assert_eq!(code_blocks.len(), 2);
+        assert!(matches!(code_blocks[0].language, Language::JavaScript));
+        assert_eq!(code_blocks[0].action, CodeBlockAction::Execute);
+        assert_eq!(code_blocks[0].code, "console.log(1)");
+        assert!(matches!(code_blocks[1].language, Language::JavaScript));
+        assert_eq!(code_blocks[1].filename.as_deref(), Some("index.mjs"));
+    }
+
+    #[test]
+    fn test_language_aliases() {
+        let aliases = [
+            ("sh", Language::Shell),
+            ("shell", Language::Shell),
+            ("bash", Language::Shell),
+            ("zsh", Language::Shell),
+            ("console", Language::Shell),
+            ("python", Language::Python),
+            ("py", Language::Python),
+            ("python3", Language::Python),
+            ("js", Language::JavaScript),
+            ("javascript", Language::JavaScript),
+            ("node", Language::JavaScript),
+            ("md", Language::Markdown),
+            ("", Language::Unknown),
+            ("rust", Language::Other),
+        ];
+
+        for (alias, language) in aliases {
+            assert_eq!(Language::from(alias.to_string()), language, "`{alias}`");
+            assert_eq!(
+                Language::from(alias.to_uppercase()),
+                language,
+                "`{}`",
+                alias.to_uppercase()
+            );
+        }
+    }
+
+    #[test]
+    fn test_retry_backoff_is_capped() {
+        assert_eq!(retry_backoff(1), Duration::from_millis(500));
+        assert_eq!(retry_backoff(3), Duration::from_secs(2));
+        assert_eq!(retry_backoff(7), MAX_RETRY_BACKOFF);
+        assert_eq!(retry_backoff(u8::MAX), MAX_RETRY_BACKOFF);
+    }
+
+    #[test]
+    fn test_parse_code_blocks_without_directives() {
+        let text = "Listing the files:\n\n```sh\nls -la\n```\n\n\
+                    ```python main.py\nprint(1)\n```\n\n\
+                    ```requirements.txt\npandas\n```\n\n\
+                    ```json\n{}\n```\n\n\
+                    > Execute\n\n```python\nprint(2)\n```\n";
+
+        // Strict mode acts on the directives only.
+        let code_blocks = parse_code_blocks(text, false).unwrap();
+        assert_eq!(code_blocks.len(), 1);
+        assert_eq!(code_blocks[0].code, "print(2)");
+
+        let code_blocks = parse_code_blocks(text, true).unwrap();
+        let actions = code_blocks
+            .iter()
+            .map(|block| {
+                (
+                    &block.action,
+                    block.filename.as_deref(),
+                    block.code.as_str(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            actions,
+            [
+                (&CodeBlockAction::Execute, None, "ls -la"),
+                (&CodeBlockAction::Save, Some("main.py"), "print(1)"),
+                (&CodeBlockAction::Save, Some("requirements.txt"), "pandas"),
+                (&CodeBlockAction::Execute, None, "print(2)"),
+            ]
+        );
+        assert!(matches!(code_blocks[0].language, Language::Shell));
+        assert!(matches!(code_blocks[1].language, Language::Python));
+
+        // Only the relative paths within the workdir are taken as filenames.
+        let text = "```python src/main.py\nprint(1)\n```\n\n\
+                    ```python {1,3}\nprint(2)\n```\n\n\
+                    ```python title=\"x\"\nprint(3)\n```\n\n\
+                    ```python ../main.py\nprint(4)\n```\n\n\
+                    ```text /etc/passwd\nroot\n```\n";
+        let actions = parse_code_blocks(text, true)
+            .unwrap()
+            .into_iter()
+            .map(|block| (block.action, block.filename))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            actions,
+            [
+                (CodeBlockAction::Save, Some("src/main.py".to_string())),
+                (CodeBlockAction::Execute, None),
+                (CodeBlockAction::Execute, None),
+                (CodeBlockAction::Execute, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_render_tree_as_mermaid() {
+        let task = |id: u128, title: &str, status, ancestry: Option<String>| Task {
+            id: Uuid::from_u128(id),
+            title: title.to_string(),
+            status,
+            ancestry,
+            ..Default::default()
+        };
+        let root = task(1, "Plan the \"big\"\n trip", Status::InProgress, None);
+        let flights = task(
+            2,
+            "Book flights",
+            Status::Done,
+            Some(root.children_ancestry()),
+        );
+        let hotel = task(
+            3,
+            "Book hotel",
+            Status::ToDo,
+            Some(root.children_ancestry()),
+        );
+        let payment = task(4, "Pay", Status::Failed, Some(hotel.children_ancestry()));
+        let tasks = [root, flights, hotel, payment];
+
+        let diagram = render_tree(
+            &tasks,
+            &[
+                (tasks[2].id, tasks[1].id),
+                // Dependency on a task outside of the tree is skipped.
+                (tasks[3].id, Uuid::from_u128(5)),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            diagram,
+            "graph TD\n\
+             \x20   t0[\"Plan the #quot;big#quot; trip\"]:::InProgress\n\
+             \x20   t1[\"Book flights\"]:::Done\n\
+             \x20   t2[\"Book hotel\"]:::ToDo\n\
+             \x20   t3[\"Pay\"]:::Failed\n\
+             \x20   t0 --> t1\n\
+             \x20   t0 --> t2\n\
+             \x20   t2 --> t3\n\
+             \x20   t1 -.-> t2\n\
+             \x20   classDef Draft fill:#e5e7eb\n\
+             \x20   classDef ToDo fill:#bfdbfe\n\
+             \x20   classDef InProgress fill:#fde68a\n\
+             \x20   classDef WaitingForUser fill:#fbcfe8\n\
+             \x20   classDef Done fill:#bbf7d0\n\
+             \x20   classDef Failed fill:#fecaca\n\
+             \x20   classDef Cancelled fill:#d1d5db\n"
+        );
+    }
+}
@@ -1,24 +1,27 @@
 // Copyright 2024 StarfleetAI
 // SPDX-License-Identifier: Apache-2.0
 
+use std::collections::HashMap;
+
 use anyhow::Context;
 use async_recursion::async_recursion;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 use sqlx::{Pool, Postgres};
-use tracing::info;
+use tracing::{info, instrument, warn};
 use uuid::Uuid;
 
 use crate::channel::{self, Channel};
 use crate::chats::construct_tools;
 use crate::clients::openai::{
-    ChatCompletion, Client, CreateChatCompletionRequest, Message, ToolCalls,
+    ChatCompletion, Client, CreateChatCompletionRequest, Message, ToolCalls, ToolChoice,
 };
-use crate::repo;
+use crate::{models, repo};
 
 use crate::repo::tasks::CreateParams;
 use crate::settings::Settings;
 use crate::types::abilities::Ability;
+use crate::types::agents::Agent;
 use crate::types::tasks::Task;
 use crate::types::Result;
 
@@ -63,18 +66,40 @@ pub struct TaskPlanner<'a> {
     user_agent: &'a str,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct ExecutionPlanTask {
     pub title: String,
     pub summary: String,
     pub agent_id: i32,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct ExecutionPlan {
     pub tasks: Vec<ExecutionPlanTask>,
 }
 
+/// Sub-task which is present in both plans, but differs in summary or assigned agent.
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct ChangedPlanTask {
+    pub old: ExecutionPlanTask,
+    pub new: ExecutionPlanTask,
+}
+
+/// Difference between two execution plans. Sub-tasks are matched by their titles.
+#[derive(Debug, Serialize, Default, Clone, PartialEq)]
+pub struct PlanDiff {
+    pub added: Vec<ExecutionPlanTask>,
+    pub removed: Vec<ExecutionPlanTask>,
+    pub changed: Vec<ChangedPlanTask>,
+}
+
+impl PlanDiff {
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
 #[derive(Debug, Deserialize)]
 struct SfaiAssignToAgentArgs {
     agent_id: i32,
@@ -92,6 +117,10 @@ pub enum Error {
     CannotLoadModel(String),
     #[error("Empty plan received from LLM")]
     EmptyPlan,
+    #[error("invalid plan received from LLM: {0}")]
+    InvalidPlan(String),
+    #[error("plan received from LLM has {count} sub-tasks, at most {max} are allowed")]
+    TooManySubtasks { count: usize, max: usize },
 }
 
 impl<'a> TaskPlanner<'a> {
@@ -118,6 +147,7 @@ impl<'a> TaskPlanner<'a> {
     ///
     /// Returns error if planning is unavailable for the task status, or if there was a problem while planning the task execution.
     #[async_recursion]
+    #[instrument(skip_all, fields(company_id = %task.company_id, task_id = %task.id))]
     pub async fn plan(&self, task: &mut Task) -> Result<()> {
         match task.status {
             crate::types::tasks::Status::ToDo | crate::types::tasks::Status::InProgress => {
@@ -128,8 +158,11 @@ impl<'a> TaskPlanner<'a> {
 
         info!("Planning task: {}", task.id);
 
-        let messages = self.messages(task).await?;
-        let tools = construct_tools(Self::abilities()).await?;
+        let agents = repo::agents::list(self.pool, task.company_id)
+            .await
+            .context("Failed to list agents")?;
+        let mut messages = Self::messages(task, &agents);
+        let tools = construct_tools(Self::abilities(), Vec::new()).await?;
 
         let model = match repo::models::get_by_full_name(
             self.pool,
@@ -143,43 +176,87 @@ impl<'a> TaskPlanner<'a> {
             None => return Err(Error::CannotLoadModel(self.settings.default_model.clone()).into()),
         };
 
-        let api_key = self
-            .settings
-            .api_keys
-            .get(&model.provider)
-            .with_context(|| format!("Failed to get api key for provider: {:?}", model.provider))?;
+        let api_key = models::api_key(self.settings, &model)?;
 
         // Send request to LLM
-        let client = Client::new(api_key, model.api_url_or_default(), self.user_agent);
-        let response = client
-            .create_chat_completion(CreateChatCompletionRequest {
-                model: &model.name,
-                messages,
-                stream: false,
-                tools,
-            })
-            .await
-            .context("Failed to create chat completion")?;
+        let client = Client::for_model(&model, api_key, self.user_agent);
+        let mut retries = 0;
+
+        let plan = loop {
+            let response = client
+                .create_chat_completion(CreateChatCompletionRequest {
+                    model: &model.name,
+                    messages: messages.clone(),
+                    tools: tools.clone(),
+                    // Either of the planning tools must be called, plain text can't be parsed.
+                    tool_choice: Some(ToolChoice::Required),
+                    metadata: model
+                        .provider
+                        .supports_request_metadata()
+                        .then(|| HashMap::from([("task_id".to_string(), task.id.to_string())])),
+                    ..Default::default()
+                })
+                .await
+                .context("Failed to create chat completion")?;
+
+            let plan = Self::plan_from_response(&response, task)
+                .context("Failed to plan a task execution")?
+                .context("Empty plan received")?;
+
+            let max_subtasks = usize::from(self.settings.tasks.max_subtasks_per_plan).max(1);
+            let (problem, error) = if plan.tasks.is_empty() {
+                (
+                    "The plan has no tasks. Make a plan with at least one task.".to_string(),
+                    Error::EmptyPlan,
+                )
+            } else if plan.tasks.len() > max_subtasks {
+                (
+                    format!(
+                        "The plan has {} tasks, while at most {} are allowed. \
+                        Make a smaller plan by merging the related tasks.",
+                        plan.tasks.len(),
+                        max_subtasks
+                    ),
+                    Error::TooManySubtasks {
+                        count: plan.tasks.len(),
+                        max: max_subtasks,
+                    },
+                )
+            } else if let Some(problem) = Self::validate(&plan, &agents) {
+                (problem.clone(), Error::InvalidPlan(problem))
+            } else {
+                break plan;
+            };
+
+            if retries >= self.settings.tasks.planning_retries_limit {
+                return Err(error.into());
+            }
 
-        let plan = Self::plan_from_response(&response, task)
-            .context("Failed to plan a task execution")?
-            .context("Empty plan received")?;
+            retries += 1;
+            warn!(
+                "Invalid plan received for task {} (retry {}): {}",
+                task.id, retries, problem
+            );
 
-        if plan.tasks.is_empty() {
-            // TODO: retry planning
-            return Err(Error::EmptyPlan.into());
-        }
+            // Reply to every tool call with the problem, so the LLM can fix the plan.
+            let assistant_message = response.first_choice()?.message.clone();
+            let tool_calls = assistant_message.tool_calls();
 
-        if plan.tasks.len() == 1 {
-            let agent =
-                repo::agents::get_by_id_int(self.pool, task.company_id, plan.tasks[0].agent_id)
-                    .await?;
+            messages.push(assistant_message);
+            messages.extend(tool_calls.iter().map(|tool_call| Message::Tool {
+                content: problem.clone(),
+                tool_call_id: tool_call.id.clone(),
+            }));
+        };
+
+        repo::task_plans::create(self.pool, task.company_id, task.id, &plan).await?;
 
-            task.agent_id = agent.id;
+        if plan.tasks.len() == 1 {
+            task.agent_id = Self::agent_by_id_int(&agents, plan.tasks[0].agent_id)?.id;
             repo::tasks::assign(self.pool, task.company_id, task.id, task.agent_id).await?;
 
             self.channel
-                .emit(self.user_id, &channel::Event::TaskUpdated(&task))
+                .emit(self.user_id, &channel::Event::TaskUpdated(task))
                 .await?;
 
             return Ok(());
@@ -198,8 +275,7 @@ impl<'a> TaskPlanner<'a> {
         }
 
         for sub_task in plan.tasks {
-            let agent =
-                repo::agents::get_by_id_int(self.pool, task.company_id, sub_task.agent_id).await?;
+            let agent = Self::agent_by_id_int(&agents, sub_task.agent_id)?;
 
             let mut task = repo::tasks::create(
                 self.pool,
@@ -225,8 +301,70 @@ impl<'a> TaskPlanner<'a> {
         Ok(())
     }
 
+    /// Checks that every sub-task is assigned to an existing and enabled agent.
+    ///
+    /// Returns the description of the problems found, listing the agents to choose from.
+    fn validate(plan: &ExecutionPlan, agents: &[Agent]) -> Option<String> {
+        let problems = plan
+            .tasks
+            .iter()
+            .filter_map(|sub_task| {
+                match agents
+                    .iter()
+                    .find(|agent| agent.id_int == sub_task.agent_id)
+                {
+                    Some(agent) if agent.is_enabled => None,
+                    Some(agent) => Some(format!(
+                        "Task `{}`: agent {} ({}) is disabled.",
+                        sub_task.title, agent.id_int, agent.name
+                    )),
+                    None => Some(format!(
+                        "Task `{}`: agent {} does not exist.",
+                        sub_task.title, sub_task.agent_id
+                    )),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        if problems.is_empty() {
+            return None;
+        }
+
+        Some(format!(
+            "{}\n\nChoose from:\n{}",
+            problems.join("\n"),
+            Self::agents_list(agents)
+        ))
+    }
+
+    fn agent_by_id_int(agents: &[Agent], id_int: i32) -> Result<&Agent> {
+        Ok(agents
+            .iter()
+            .find(|agent| agent.id_int == id_int)
+            .with_context(|| format!("Agent {id_int} is not found"))?)
+    }
+
+    fn agents_list(agents: &[Agent]) -> String {
+        let agents = agents
+            .iter()
+            .filter(|agent| agent.is_enabled)
+            .map(|agent| {
+                format!(
+                    "- ID: {}. {}: {}",
+                    agent.id_int, agent.name, agent.description
+                )
+            })
+            .collect::<Vec<String>>();
+
+        if agents.is_empty() {
+            "No agents available".to_string()
+        } else {
+            agents.join("\n")
+        }
+    }
+
     fn assistant_message_tool_calls(response: &ChatCompletion) -> Result<ToolCalls> {
-        let message = &response.choices[0].message;
+        let message = &response.first_choice()?.message;
 
         match &message {
             Message::Assistant { .. } => Ok(message.tool_calls()),
@@ -266,19 +404,8 @@ impl<'a> TaskPlanner<'a> {
         Ok(plan)
     }
 
-    async fn messages(&self, task: &Task) -> Result<Vec<Message>> {
-        let agents = repo::agents::list_enabled(self.pool, task.company_id)
-            .await
-            .context("Failed to list agents")?
-            .into_iter()
-            .map(|agent| format!("- ID: {}. {}: {}", agent.id, agent.name, agent.description))
-            .collect::<Vec<String>>();
-
-        let agents = if agents.is_empty() {
-            "No agents available".to_string()
-        } else {
-            agents.join("\n")
-        };
+    fn messages(task: &Task, agents: &[Agent]) -> Vec<Message> {
+        let agents = Self::agents_list(agents);
 
         let summary = if task.summary.is_empty() {
             String::new()
@@ -286,7 +413,7 @@ impl<'a> TaskPlanner<'a> {
             format!("\n\n{}", task.summary)
         };
 
-        Ok(vec![
+        vec![
             Message::System {
                 content: PROMPT.to_string(),
                 name: None,
@@ -297,10 +424,11 @@ impl<'a> TaskPlanner<'a> {
                     agents,
                     task.title,
                     summary
-                ),
+                )
+                .into(),
                 name: None,
             },
-        ])
+        ]
     }
 
     fn abilities() -> Vec<Ability> {
@@ -316,7 +444,8 @@ impl<'a> TaskPlanner<'a> {
                                 "type": "integer",
                                 "description": "ID of the agent to assign the task to"
                             }
-                        }
+                        },
+                        "required": ["agent_id"]
                     }
                 }),
             ),
@@ -345,13 +474,350 @@ impl<'a> TaskPlanner<'a> {
                                             "type": "integer",
                                             "description": "ID of the agent to assign the task to"
                                         }
-                                    }
+                                    },
+                                    "required": ["title", "summary", "agent_id"]
                                 }
                             }
-                        }
+                        },
+                        "required": ["tasks"]
                     }
                 }),
             ),
         ]
     }
 }
+
+/// Compares two execution plans and reports added, removed and changed sub-tasks.
+///
+/// Sub-tasks are matched by their titles. A matched sub-task is considered changed if its summary
+/// or assigned agent differs.
+#[must_use]
+pub fn diff(old: &ExecutionPlan, new: &ExecutionPlan) -> PlanDiff {
+    let mut diff = PlanDiff::default();
+
+    for new_task in &new.tasks {
+        match old.tasks.iter().find(|task| task.title == new_task.title) {
+            Some(old_task) if old_task != new_task => diff.changed.push(ChangedPlanTask {
+                old: old_task.clone(),
+                new: new_task.clone(),
+            }),
+            Some(_) => {}
+            None => diff.added.push(new_task.clone()),
+        }
+    }
+
+    diff.removed = old
+        .tasks
+        .iter()
+        .filter(|old_task| !new.tasks.iter().any(|task| task.title == old_task.title))
+        .cloned()
+        .collect();
+
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::{
+        matchers::{body_string_contains, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use crate::channel::NoopEmitter;
+
+    use super::*;
+
+    const COMPANY_ID: Uuid = Uuid::from_u128(1);
+    const TASK_ID: Uuid = Uuid::from_u128(0x0005_0000_0000_0001);
+    const CODER_ID: Uuid = Uuid::from_u128(0x0003_0000_0000_0003);
+
+    fn assign_to_agent_response(agent_id: i32) -> ResponseTemplate {
+        tool_call_response("sfai_assign_to_agent", &json!({ "agent_id": agent_id }))
+    }
+
+    fn plan_response(tasks_count: usize) -> ResponseTemplate {
+        let tasks = (1..=tasks_count)
+            .map(|i| json!({ "title": format!("Step {i}"), "summary": "", "agent_id": 1001 }))
+            .collect::<Vec<_>>();
+
+        tool_call_response("sfai_plan_task_execution", &json!({ "tasks": tasks }))
+    }
+
+    fn tool_call_response(name: &str, arguments: &serde_json::Value) -> ResponseTemplate {
+        ResponseTemplate::new(200).set_body_json(json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 1_713_657_600,
+            "model": "gpt-4-turbo",
+            "choices": [{
+                "index": 0,
+                "finish_reason": "tool_calls",
+                "logprobs": null,
+                "message": {
+                    "role": "assistant",
+                    "tool_calls": [{
+                        "id": "call_1",
+                        "type": "function",
+                        "function": {
+                            "name": name,
+                            "arguments": arguments.to_string()
+                        }
+                    }]
+                }
+            }],
+            "usage": { "completion_tokens": 1, "prompt_tokens": 1, "total_tokens": 2 }
+        }))
+    }
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(
+            path = "../db/fixtures",
+            scripts("companies", "users", "agents", "models", "tasks")
+        )
+    )]
+    async fn test_plan_retries_when_agent_is_disabled(pool: Pool<Postgres>) {
+        let server = MockServer::start().await;
+
+        // Retry request carries the validation problem, so it is matched first.
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .and(body_string_contains("is disabled"))
+            .respond_with(assign_to_agent_response(1003))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .and(body_string_contains(r#""tool_choice":"required""#))
+            .respond_with(assign_to_agent_response(1002))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        sqlx::query("UPDATE models SET api_url = $1")
+            .bind(format!("{}/", server.uri()))
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let channel: Channel = Box::new(NoopEmitter);
+        let mut settings = Settings::default();
+        settings.api_keys.insert(
+            crate::types::models::Provider::OpenAI,
+            "sk-test".to_string(),
+        );
+
+        let mut task = repo::tasks::get(&pool, COMPANY_ID, TASK_ID).await.unwrap();
+
+        TaskPlanner::new(&pool, &channel, &settings, task.user_id, "bridge-test")
+            .plan(&mut task)
+            .await
+            .unwrap();
+
+        assert_eq!(task.agent_id, CODER_ID);
+        assert_eq!(
+            repo::tasks::get(&pool, COMPANY_ID, TASK_ID)
+                .await
+                .unwrap()
+                .agent_id,
+            CODER_ID
+        );
+    }
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(
+            path = "../db/fixtures",
+            scripts("companies", "users", "agents", "models", "tasks")
+        )
+    )]
+    async fn test_plan_exceeding_subtasks_cap_is_rejected(pool: Pool<Postgres>) {
+        let server = MockServer::start().await;
+
+        // LLM keeps making the plan larger than allowed, even when asked for a smaller one.
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .and(body_string_contains("at most 2 are allowed"))
+            .respond_with(plan_response(3))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .respond_with(plan_response(4))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        sqlx::query("UPDATE models SET api_url = $1")
+            .bind(format!("{}/", server.uri()))
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let channel: Channel = Box::new(NoopEmitter);
+        let mut settings = Settings::default();
+        settings.api_keys.insert(
+            crate::types::models::Provider::OpenAI,
+            "sk-test".to_string(),
+        );
+        settings.tasks.planning_retries_limit = 1;
+        settings.tasks.max_subtasks_per_plan = 2;
+
+        let mut task = repo::tasks::get(&pool, COMPANY_ID, TASK_ID).await.unwrap();
+
+        let result = TaskPlanner::new(&pool, &channel, &settings, task.user_id, "bridge-test")
+            .plan(&mut task)
+            .await;
+
+        assert!(matches!(
+            result,
+            Err(crate::errors::Error::Planner(Error::TooManySubtasks {
+                count: 3,
+                max: 2
+            }))
+        ));
+        assert!(repo::tasks::list_direct_children(&pool, COMPANY_ID, &task)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(
+            path = "../db/fixtures",
+            scripts("companies", "users", "agents", "models", "tasks")
+        )
+    )]
+    async fn test_plan_retries_empty_plan(pool: Pool<Postgres>) {
+        let server = MockServer::start().await;
+
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .and(body_string_contains("The plan has no tasks"))
+            .respond_with(assign_to_agent_response(1003))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .respond_with(plan_response(0))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        sqlx::query("UPDATE models SET api_url = $1")
+            .bind(format!("{}/", server.uri()))
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let channel: Channel = Box::new(NoopEmitter);
+        let mut settings = Settings::default();
+        settings.api_keys.insert(
+            crate::types::models::Provider::OpenAI,
+            "sk-test".to_string(),
+        );
+
+        let mut task = repo::tasks::get(&pool, COMPANY_ID, TASK_ID).await.unwrap();
+
+        TaskPlanner::new(&pool, &channel, &settings, task.user_id, "bridge-test")
+            .plan(&mut task)
+            .await
+            .unwrap();
+
+        assert_eq!(task.agent_id, CODER_ID);
+    }
+
+    #[test]
+    fn test_plan_schema_round_trips_through_typed_structs() {
+        let abilities = TaskPlanner::abilities();
+        let ability = abilities
+            .iter()
+            .find(|ability| ability.parameters_json["name"] == "sfai_plan_task_execution")
+            .unwrap();
+
+        let function = ability.function();
+        let tasks = &function.parameters.as_ref().unwrap().properties["tasks"];
+        let items = tasks.items.as_ref().unwrap();
+
+        assert_eq!(items.properties.len(), 3);
+        assert_eq!(
+            items.required,
+            Some(vec![
+                "title".to_string(),
+                "summary".to_string(),
+                "agent_id".to_string()
+            ])
+        );
+        assert_eq!(
+            serde_json::to_value(&function).unwrap(),
+            ability.parameters_json
+        );
+    }
+
+    #[test]
+    fn test_empty_choices_is_an_error() {
+        let response: ChatCompletion = serde_json::from_value(json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 1_713_657_600,
+            "model": "gpt-4-turbo",
+            "choices": [],
+            "usage": { "completion_tokens": 0, "prompt_tokens": 1, "total_tokens": 1 }
+        }))
+        .unwrap();
+
+        assert!(matches!(
+            TaskPlanner::assistant_message_tool_calls(&response),
+            Err(crate::errors::Error::OpenAI(
+                crate::clients::openai::Error::EmptyChoices
+            ))
+        ));
+    }
+
+    fn plan_task(title: &str, agent_id: i32) -> ExecutionPlanTask {
+        ExecutionPlanTask {
+            title: title.to_string(),
+            summary: format!("{title} summary"),
+            agent_id,
+        }
+    }
+
+    #[test]
+    fn test_diff_added_and_reassigned() {
+        let old = ExecutionPlan {
+            tasks: vec![plan_task("Gather data", 1), plan_task("Write script", 2)],
+        };
+        let new = ExecutionPlan {
+            tasks: vec![
+                plan_task("Gather data", 1),
+                plan_task("Write script", 3),
+                plan_task("Build chart", 2),
+            ],
+        };
+
+        let diff = diff(&old, &new);
+
+        assert_eq!(diff.added, vec![plan_task("Build chart", 2)]);
+        assert!(diff.removed.is_empty());
+        assert_eq!(
+            diff.changed,
+            vec![ChangedPlanTask {
+                old: plan_task("Write script", 2),
+                new: plan_task("Write script", 3),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_diff_identical_plans() {
+        let plan = ExecutionPlan {
+            tasks: vec![plan_task("Gather data", 1)],
+        };
+
+        assert!(diff(&plan, &plan).is_empty());
+    }
+}
@@ -0,0 +1,345 @@
+// Copyright 2024 StarfleetAI
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Context;
+use sqlx::{Pool, Postgres};
+use tracing::{debug, instrument};
+use uuid::Uuid;
+
+use crate::{
+    repo,
+    types::{chats::Kind, Result},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("task `{task_id}` can only depend on its siblings, `{depends_on_id}` is not one")]
+    NotSibling { task_id: Uuid, depends_on_id: Uuid },
+    #[error("task `{task_id}` can't depend on `{depends_on_id}`, since it would make a cycle")]
+    DependencyCycle { task_id: Uuid, depends_on_id: Uuid },
+}
+
+/// Deletes the task along with its subtree, their results and the control and execution chats
+/// with their messages, in a single transaction.
+///
+/// Direct chats the tasks were created from are kept, since they belong to the user's
+/// conversation.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while accessing database.
+#[instrument(skip(pool))]
+pub async fn delete_tree(pool: &Pool<Postgres>, cid: Uuid, root_id: Uuid) -> Result<()> {
+    let mut tx = pool.begin().await.context("Failed to begin transaction")?;
+
+    let root = repo::tasks::get(&mut *tx, cid, root_id).await?;
+    let chat_ids = repo::tasks::list_related_chat_ids(&mut *tx, cid, &root).await?;
+    let children = repo::tasks::list_all_children(&mut *tx, cid, &root.children_ancestry()).await?;
+
+    debug!(
+        "Deleting {} children tasks and up to {} chats",
+        children.len(),
+        chat_ids.len()
+    );
+
+    for task in children.iter().chain([&root]) {
+        repo::task_results::delete_for_task(&mut *tx, cid, task.id).await?;
+    }
+
+    repo::tasks::delete_children(&mut *tx, cid, root.id, root.ancestry.as_deref()).await?;
+    repo::tasks::delete(&mut *tx, cid, root.id).await?;
+
+    for chat_id in chat_ids {
+        let chat = repo::chats::get(&mut *tx, cid, chat_id).await?;
+        if chat.kind == Kind::Direct {
+            continue;
+        }
+
+        repo::agents_chats::delete_for_chat(&mut *tx, cid, chat_id).await?;
+        repo::messages::delete_for_chat(&mut *tx, cid, chat_id).await?;
+        repo::chats::delete(&mut *tx, cid, chat_id).await?;
+    }
+
+    tx.commit().await.context("Failed to commit transaction")?;
+
+    Ok(())
+}
+
+/// Makes the task depend on the given tasks, which must be its siblings.
+///
+/// # Errors
+///
+/// Returns error if any of the tasks is not a sibling of the task, e.g. belongs to another
+/// company.
+/// Returns error if any of the dependencies would make a cycle.
+/// Returns error if there was a problem while accessing database.
+#[instrument(skip(pool))]
+pub async fn add_dependencies(
+    pool: &Pool<Postgres>,
+    cid: Uuid,
+    task_id: Uuid,
+    depends_on: &[Uuid],
+) -> Result<()> {
+    let mut tx = pool.begin().await.context("Failed to begin transaction")?;
+
+    let task = repo::tasks::get(&mut *tx, cid, task_id).await?;
+    let sibling_ids = repo::tasks::list_siblings(&mut *tx, cid, &task)
+        .await?
+        .into_iter()
+        .map(|sibling| sibling.id)
+        .collect::<Vec<_>>();
+
+    let mut dependencies: HashMap<Uuid, Vec<Uuid>> = HashMap::new();
+    for (id, depends_on_id) in
+        repo::tasks::list_dependencies_among(&mut *tx, cid, &sibling_ids).await?
+    {
+        dependencies.entry(id).or_default().push(depends_on_id);
+    }
+
+    for &depends_on_id in depends_on {
+        if !sibling_ids.contains(&depends_on_id) {
+            return Err(Error::NotSibling {
+                task_id,
+                depends_on_id,
+            }
+            .into());
+        }
+
+        if depends_on_id == task_id || depends_on_task(&dependencies, depends_on_id, task_id) {
+            return Err(Error::DependencyCycle {
+                task_id,
+                depends_on_id,
+            }
+            .into());
+        }
+        dependencies.entry(task_id).or_default().push(depends_on_id);
+    }
+
+    repo::tasks::add_dependencies(&mut *tx, cid, task_id, depends_on).await?;
+
+    tx.commit().await.context("Failed to commit transaction")?;
+
+    Ok(())
+}
+
+/// Whether the `task_id` depends on the `depends_on_id`, directly or through other tasks.
+fn depends_on_task(
+    dependencies: &HashMap<Uuid, Vec<Uuid>>,
+    task_id: Uuid,
+    depends_on_id: Uuid,
+) -> bool {
+    let mut visited = HashSet::new();
+    let mut pending = vec![task_id];
+
+    while let Some(id) = pending.pop() {
+        if id == depends_on_id {
+            return true;
+        }
+        if visited.insert(id) {
+            pending.extend(dependencies.get(&id).into_iter().flatten());
+        }
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::errors;
+
+    use super::*;
+
+    const COMPANY_ID: Uuid = Uuid::from_u128(1);
+    const CHAT_ID: Uuid = Uuid::from_u128(0x0006_0000_0000_0001);
+    const TASK_ID: Uuid = Uuid::from_u128(0x0005_0000_0000_0001);
+    const AGENT_ID: Uuid = Uuid::from_u128(0x0003_0000_0000_0001);
+
+    async fn insert_chat(pool: &Pool<Postgres>, kind: Kind) -> Uuid {
+        let id = Uuid::new_v4();
+        sqlx::query(
+            "INSERT INTO chats (id, company_id, model_id, kind, created_at, updated_at) \
+             VALUES ($1, $2, '00000000-0000-0000-0004-000000000001', $3, NOW(), NOW())",
+        )
+        .bind(id)
+        .bind(COMPANY_ID)
+        .bind(kind.to_string())
+        .execute(pool)
+        .await
+        .unwrap();
+        sqlx::query(
+            "INSERT INTO messages (company_id, chat_id, role, status, content, created_at, updated_at) \
+             VALUES ($1, $2, 'User', 'Completed', 'Hello', NOW(), NOW())",
+        )
+        .bind(COMPANY_ID)
+        .bind(id)
+        .execute(pool)
+        .await
+        .unwrap();
+
+        id
+    }
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(
+            path = "../db/fixtures",
+            scripts("companies", "users", "agents", "models", "chats", "tasks")
+        )
+    )]
+    async fn test_delete_tree_removes_chats_and_messages(pool: Pool<Postgres>) {
+        let control_chat_id = insert_chat(&pool, Kind::Control).await;
+        let execution_chat_id = insert_chat(&pool, Kind::Execution).await;
+        let child_chat_id = insert_chat(&pool, Kind::Execution).await;
+        let unrelated_chat_id = insert_chat(&pool, Kind::Execution).await;
+
+        sqlx::query(
+            "UPDATE tasks SET origin_chat_id = $1, control_chat_id = $2, execution_chat_id = $3",
+        )
+        .bind(CHAT_ID)
+        .bind(control_chat_id)
+        .bind(execution_chat_id)
+        .execute(&pool)
+        .await
+        .unwrap();
+        let child_id = Uuid::new_v4();
+        sqlx::query(
+            "INSERT INTO tasks (id, company_id, user_id, agent_id, execution_chat_id, title, \
+             status, ancestry, ancestry_level, created_at, updated_at) \
+             VALUES ($1, $2, '00000000-0000-0000-0002-000000000001', $3, $4, 'Subtask', 'Done', \
+             $5, 1, NOW(), NOW())",
+        )
+        .bind(child_id)
+        .bind(COMPANY_ID)
+        .bind(AGENT_ID)
+        .bind(child_chat_id)
+        .bind(TASK_ID.to_string())
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query(
+            "INSERT INTO task_results (company_id, agent_id, task_id, data, created_at, updated_at) \
+             VALUES ($1, $2, $3, 'Result', NOW(), NOW())",
+        )
+        .bind(COMPANY_ID)
+        .bind(AGENT_ID)
+        .bind(child_id)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let root = repo::tasks::get(&pool, COMPANY_ID, TASK_ID).await.unwrap();
+        let mut chat_ids = repo::tasks::list_related_chat_ids(&pool, COMPANY_ID, &root)
+            .await
+            .unwrap();
+        chat_ids.sort();
+        let mut expected = vec![CHAT_ID, control_chat_id, execution_chat_id, child_chat_id];
+        expected.sort();
+        assert_eq!(chat_ids, expected);
+
+        delete_tree(&pool, COMPANY_ID, TASK_ID).await.unwrap();
+
+        let tasks: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM tasks")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(tasks, 0);
+        let task_results: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM task_results")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(task_results, 0);
+
+        let mut remaining_chats: Vec<Uuid> = sqlx::query_scalar("SELECT id FROM chats")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        remaining_chats.sort();
+        let mut expected = vec![CHAT_ID, unrelated_chat_id];
+        expected.sort();
+        assert_eq!(remaining_chats, expected);
+
+        let orphans: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM messages WHERE chat_id NOT IN (SELECT id FROM chats)",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(orphans, 0);
+        let messages: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM messages")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(messages, 1);
+    }
+
+    async fn insert_subtask(pool: &Pool<Postgres>, ancestry: &str) -> Uuid {
+        let id = Uuid::new_v4();
+        sqlx::query(
+            "INSERT INTO tasks (id, company_id, user_id, agent_id, title, status, ancestry, \
+             ancestry_level, created_at, updated_at) \
+             VALUES ($1, $2, '00000000-0000-0000-0002-000000000001', $3, 'Subtask', 'ToDo', $4, \
+             $5, NOW(), NOW())",
+        )
+        .bind(id)
+        .bind(COMPANY_ID)
+        .bind(AGENT_ID)
+        .bind(ancestry)
+        .bind(i32::try_from(ancestry.split('/').count()).unwrap())
+        .execute(pool)
+        .await
+        .unwrap();
+
+        id
+    }
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(
+            path = "../db/fixtures",
+            scripts("companies", "users", "agents", "tasks")
+        )
+    )]
+    async fn test_add_dependencies_rejects_cycles_and_non_siblings(pool: Pool<Postgres>) {
+        let ancestry = TASK_ID.to_string();
+        let [first, second, third] = [
+            insert_subtask(&pool, &ancestry).await,
+            insert_subtask(&pool, &ancestry).await,
+            insert_subtask(&pool, &ancestry).await,
+        ];
+        let nested = insert_subtask(&pool, &format!("{ancestry}/{first}")).await;
+
+        add_dependencies(&pool, COMPANY_ID, second, &[first])
+            .await
+            .unwrap();
+        add_dependencies(&pool, COMPANY_ID, third, &[second])
+            .await
+            .unwrap();
+
+        for (task_id, depends_on_id) in [(first, first), (first, second), (first, third)] {
+            assert!(matches!(
+                add_dependencies(&pool, COMPANY_ID, task_id, &[depends_on_id]).await,
+                Err(errors::Error::Tasks(Error::DependencyCycle { .. }))
+            ));
+        }
+        for depends_on_id in [nested, TASK_ID, Uuid::new_v4()] {
+            assert!(matches!(
+                add_dependencies(&pool, COMPANY_ID, second, &[first, depends_on_id]).await,
+                Err(errors::Error::Tasks(Error::NotSibling { depends_on_id: id, .. }))
+                    if id == depends_on_id
+            ));
+        }
+
+        assert!(repo::tasks::list_dependencies(&pool, COMPANY_ID, first)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            repo::tasks::list_dependencies(&pool, COMPANY_ID, second)
+                .await
+                .unwrap(),
+            vec![first]
+        );
+    }
+}
@@ -0,0 +1,248 @@
+// Copyright 2024 StarfleetAI
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, OnceLock, PoisonError},
+    time::{Duration, Instant},
+};
+
+use futures_util::future::BoxFuture;
+use tokenizers::Tokenizer;
+use tokio::sync::OnceCell;
+use tracing::{debug, warn};
+
+use crate::{embeddings::hub_tokenizer, types::Result};
+
+/// Rough number of characters per token, used when there is no tokenizer for the model.
+const CHARS_PER_TOKEN: usize = 4;
+
+/// How long the estimate is used after a failed tokenizer load, before the load is retried.
+const LOAD_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Hugging Face Hub repos with tokenizers of the model families, matched by model name prefix.
+/// More specific prefixes go first.
+const TOKENIZER_REPOS: [(&str, &str); 3] = [
+    ("gpt-4o", "Xenova/gpt-4o"),
+    ("gpt-4", "Xenova/gpt-4"),
+    ("gpt-3.5", "Xenova/gpt-4"),
+];
+
+static SHARED: OnceLock<TokenCounter> = OnceLock::new();
+
+type Loader = Box<dyn Fn(&'static str) -> BoxFuture<'static, Result<Tokenizer>> + Send + Sync>;
+
+/// Every tokenizer repo gets its own entry, so that tokenizers are loaded once even when requested
+/// concurrently, without blocking the counting with other tokenizers.
+type Tokenizers = Mutex<HashMap<&'static str, Arc<TokenizerEntry>>>;
+
+#[derive(Default)]
+struct TokenizerEntry {
+    tokenizer: OnceCell<Arc<Tokenizer>>,
+    /// Set after a failed load, so that the load is not retried on every count.
+    retry_after: Mutex<Option<Instant>>,
+}
+
+/// Counts tokens with the model family's tokenizer, falling back to the character-based
+/// estimate for unknown models.
+///
+/// Tokenizers are loaded on the first use and cached. Failed loads are retried on the first use
+/// after [`LOAD_RETRY_DELAY`].
+pub struct TokenCounter {
+    loader: Loader,
+    tokenizers: Tokenizers,
+    retry_delay: Duration,
+}
+
+impl Default for TokenCounter {
+    fn default() -> Self {
+        Self::with_loader(|repo_id| Box::pin(hub_tokenizer(repo_id)))
+    }
+}
+
+impl TokenCounter {
+    /// Token counter sharing the tokenizers across the whole process.
+    #[must_use]
+    pub fn shared() -> &'static Self {
+        SHARED.get_or_init(Self::default)
+    }
+
+    fn with_loader<F>(loader: F) -> Self
+    where
+        F: Fn(&'static str) -> BoxFuture<'static, Result<Tokenizer>> + Send + Sync + 'static,
+    {
+        Self {
+            loader: Box::new(loader),
+            tokenizers: Mutex::default(),
+            retry_delay: LOAD_RETRY_DELAY,
+        }
+    }
+
+    /// Counts tokens in the text for the given model.
+    pub async fn count(&self, text: &str, model_name: &str) -> usize {
+        let Some(tokenizer) = self.tokenizer(model_name).await else {
+            return estimate(text);
+        };
+
+        match tokenizer.encode(text, false) {
+            Ok(encoding) => encoding.len(),
+            Err(err) => {
+                warn!("Failed to encode text for `{model_name}`: {err}");
+                estimate(text)
+            }
+        }
+    }
+
+    async fn tokenizer(&self, model_name: &str) -> Option<Arc<Tokenizer>> {
+        let (_, repo_id) = TOKENIZER_REPOS
+            .iter()
+            .find(|(prefix, _)| model_name.starts_with(prefix))?;
+
+        let entry = self
+            .tokenizers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(repo_id)
+            .or_default()
+            .clone();
+
+        if let Some(tokenizer) = entry.tokenizer.get() {
+            return Some(tokenizer.clone());
+        }
+
+        let retry_after = *entry
+            .retry_after
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if retry_after.is_some_and(|retry_after| Instant::now() < retry_after) {
+            return None;
+        }
+
+        let tokenizer = entry
+            .tokenizer
+            .get_or_try_init(|| async {
+                debug!("Loading tokenizer `{repo_id}` for `{model_name}`");
+                (self.loader)(repo_id).await.map(Arc::new)
+            })
+            .await;
+
+        match tokenizer {
+            Ok(tokenizer) => Some(tokenizer.clone()),
+            Err(err) => {
+                warn!(
+                    "Failed to load tokenizer `{repo_id}`, falling back to estimate for {:?}: {err}",
+                    self.retry_delay
+                );
+                *entry
+                    .retry_after
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner) =
+                    Some(Instant::now() + self.retry_delay);
+                None
+            }
+        }
+    }
+}
+
+fn estimate(text: &str) -> usize {
+    text.chars().count().div_ceil(CHARS_PER_TOKEN)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokenizers::{models::wordlevel::WordLevel, pre_tokenizers::whitespace::Whitespace};
+
+    use super::*;
+
+    fn word_tokenizer() -> Tokenizer {
+        let vocab = ["[UNK]", "hello", "world"]
+            .into_iter()
+            .zip(0..)
+            .map(|(word, id)| (word.to_string(), id))
+            .collect();
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("[UNK]".to_string())
+            .build()
+            .unwrap();
+
+        let mut tokenizer = Tokenizer::new(model);
+        tokenizer.with_pre_tokenizer(Whitespace {});
+
+        tokenizer
+    }
+
+    #[tokio::test]
+    async fn test_count_reuses_cached_tokenizer() {
+        let loads = Arc::new(AtomicUsize::new(0));
+        let counter = TokenCounter::with_loader({
+            let loads = loads.clone();
+            move |_| {
+                loads.fetch_add(1, Ordering::SeqCst);
+                Box::pin(async { Ok(word_tokenizer()) })
+            }
+        });
+
+        let text = "hello brave new world";
+        let first = counter.count(text, "gpt-4-turbo").await;
+        let second = counter.count(text, "gpt-4").await;
+
+        assert_eq!(first, 4);
+        assert_eq!(second, first);
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_count_falls_back_to_estimate() {
+        let counter = TokenCounter::with_loader(|repo_id| {
+            Box::pin(async move { Err(anyhow::anyhow!("`{repo_id}` is unavailable").into()) })
+        });
+
+        assert_eq!(counter.count("hello world", "llama3-70b-8192").await, 3);
+        assert_eq!(counter.count("hello world", "gpt-4").await, 3);
+    }
+
+    #[tokio::test]
+    async fn test_count_remembers_failed_load() {
+        let loads = Arc::new(AtomicUsize::new(0));
+        let counter = TokenCounter::with_loader({
+            let loads = loads.clone();
+            move |repo_id| {
+                loads.fetch_add(1, Ordering::SeqCst);
+                Box::pin(async move { Err(anyhow::anyhow!("`{repo_id}` is unavailable").into()) })
+            }
+        });
+
+        for _ in 0..3 {
+            assert_eq!(counter.count("hello world", "gpt-4").await, 3);
+        }
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_count_retries_failed_load() {
+        let loads = Arc::new(AtomicUsize::new(0));
+        let mut counter = TokenCounter::with_loader({
+            let loads = loads.clone();
+            move |repo_id| {
+                let is_first = loads.fetch_add(1, Ordering::SeqCst) == 0;
+                Box::pin(async move {
+                    if is_first {
+                        Err(anyhow::anyhow!("`{repo_id}` is unavailable").into())
+                    } else {
+                        Ok(word_tokenizer())
+                    }
+                })
+            }
+        });
+        counter.retry_delay = Duration::ZERO;
+
+        let text = "hello brave new world";
+        assert_eq!(counter.count(text, "gpt-4").await, 6);
+        assert_eq!(counter.count(text, "gpt-4").await, 4);
+        assert_eq!(counter.count(text, "gpt-4").await, 4);
+        assert_eq!(loads.load(Ordering::SeqCst), 2);
+    }
+}
@@ -1,17 +1,32 @@
 // Copyright 2024 StarfleetAI
 // SPDX-License-Identifier: Apache-2.0
 
+use std::collections::VecDeque;
+use std::time::Duration;
+
 use anyhow::{anyhow, Context};
 use askama::Template;
-use serde::Deserialize;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
 use serde_json::json;
-use tracing::{debug, error, instrument, trace};
-
-use crate::browser::{Browser, BrowserBuilder};
-use crate::chats::construct_tools;
-use crate::clients::openai::{Client, CreateChatCompletionRequest, Message, ToolCalls};
+use sqlx::{Executor, Pool, Postgres};
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{debug, error, instrument, trace, warn};
 
-use crate::types::{abilities::Ability, models::Model, Result};
+use crate::browser::{self, Browser, BrowserBuilder};
+use crate::chats::{construct_tools, CompletionStream, StreamUpdate};
+use crate::clients::openai::{
+    Client, Content, ContentPart, CreateChatCompletionRequest, Message, ToolCalls,
+};
+use crate::types::{
+    abilities::Ability,
+    messages::{self, Role, Status},
+    models::Model,
+    task_results::{Kind, TaskResult},
+    tasks::Task,
+    Result,
+};
+use crate::{errors, repo};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -26,19 +41,134 @@ pub enum WebBrowsingResult {
     Text(String),
 }
 
+/// Browsing progress, reported while LLM responses are being streamed.
+#[derive(Debug, Clone)]
+pub enum Progress {
+    /// Partial response for the next browsing action.
+    Action(Message),
+    /// Partial self-reflection response.
+    SelfReflection(Message),
+}
+
+const DEFAULT_NOTEBOOK_SEPARATOR: &str = "\n\n---\n\n";
+/// How long to wait for the element the LLM asked to wait for after navigation.
+const SELECTOR_WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How many of the last screenshots are kept in the workdir during a browsing session.
+const MAX_KEPT_SCREENSHOTS: usize = 20;
+
+/// Piece of text saved by the LLM while browsing.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NotebookEntry {
+    /// Page the text was taken from.
+    pub url: String,
+    pub timestamp: DateTime<Utc>,
+    pub text: String,
+}
+
+/// Browsing notebook. Kept as structured entries and rendered to markdown for the LLM and the
+/// task result.
+#[derive(Serialize, Debug, Clone)]
+pub struct Notebook {
+    /// Condensed content of the entries appended before the last summarization.
+    summary: Option<String>,
+    entries: Vec<NotebookEntry>,
+    summarizations: u32,
+    #[serde(skip)]
+    separator: String,
+}
+
+impl Default for Notebook {
+    fn default() -> Self {
+        Self::new(DEFAULT_NOTEBOOK_SEPARATOR)
+    }
+}
+
+impl Notebook {
+    #[must_use]
+    pub fn new(separator: &str) -> Self {
+        Self {
+            summary: None,
+            entries: Vec::new(),
+            summarizations: 0,
+            separator: separator.to_string(),
+        }
+    }
+
+    pub fn append(&mut self, url: &str, text: &str) {
+        self.entries.push(NotebookEntry {
+            url: url.to_string(),
+            timestamp: Utc::now(),
+            text: text.to_string(),
+        });
+    }
+
+    pub fn clear(&mut self) {
+        self.summary = None;
+        self.entries.clear();
+    }
+
+    /// Replaces the whole content with its condensed version.
+    pub fn replace_with_summary(&mut self, summary: &str) {
+        self.summary = Some(summary.to_string());
+        self.entries.clear();
+        self.summarizations += 1;
+    }
+
+    /// Entries appended since the last summarization.
+    #[must_use]
+    pub fn entries(&self) -> &[NotebookEntry] {
+        &self.entries
+    }
+
+    #[must_use]
+    pub fn summary(&self) -> Option<&str> {
+        self.summary.as_deref()
+    }
+
+    /// How many times the notebook was summarized.
+    #[must_use]
+    pub fn summarizations(&self) -> u32 {
+        self.summarizations
+    }
+
+    /// Renders the summary and entries to markdown, each one preceded by the separator. Entries
+    /// are preceded by their URL as well.
+    #[must_use]
+    pub fn render(&self) -> String {
+        let summary = self
+            .summary
+            .iter()
+            .map(|summary| format!("{}{}", self.separator, summary));
+        let entries = self
+            .entries
+            .iter()
+            .map(|entry| format!("{}{}\n\n{}", self.separator, entry.url, entry.text));
+
+        summary.chain(entries).collect()
+    }
+}
+
 #[derive(Debug)]
 pub struct Builder<'a> {
     app_local_data_dir: &'a str,
     objective: String,
+    notebook_separator: String,
+    max_notebook_chars: Option<usize>,
+    max_text_chars: Option<usize>,
+    dismiss_consent: bool,
     model: Option<&'a Model>,
     api_key: String,
     user_agent: String,
+    pool: Option<&'a Pool<Postgres>>,
+    task: Option<&'a Task>,
+    progress: Option<UnboundedSender<Progress>>,
 }
 
 #[derive(Debug)]
 pub struct WebBrowsing<'a> {
     browser: Browser,
-    notebook: String,
+    notebook: Notebook,
+    max_notebook_chars: Option<usize>,
     objective: String,
     model: &'a Model,
     api_key: String,
@@ -46,11 +176,22 @@ pub struct WebBrowsing<'a> {
     messages: Vec<Message>,
     is_active: bool,
     history: Vec<String>,
+    /// Number of the saved screenshots.
+    screenshots: usize,
+    /// Paths of the screenshots kept in the workdir, the oldest first.
+    kept_screenshots: VecDeque<String>,
+    failure: Option<String>,
+    pool: Option<&'a Pool<Postgres>>,
+    task: Option<&'a Task>,
+    progress: Option<UnboundedSender<Progress>>,
 }
 
 #[derive(Deserialize)]
 pub struct GotoArgs {
     pub url: String,
+    /// CSS selector of an element to wait for after the navigation.
+    #[serde(default)]
+    pub wait_for_selector: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -64,6 +205,23 @@ pub struct ClickArgs {
     pub id: i64,
 }
 
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TableFormat {
+    #[default]
+    Csv,
+    Json,
+}
+
+#[derive(Deserialize)]
+pub struct ExtractTableArgs {
+    /// Index of the table on the page, starting from 0.
+    #[serde(default)]
+    pub index: usize,
+    #[serde(default)]
+    pub format: TableFormat,
+}
+
 #[derive(Deserialize)]
 pub struct AppendNotebookArgs {
     pub text: String,
@@ -99,6 +257,14 @@ struct ViewportMessageTemplate<'a> {
 #[template(path = "web_browsing/self_reflection_message.md", escape = "none")]
 struct SelfReflectionMessageTemplate {}
 
+#[derive(Template)]
+#[template(path = "web_browsing/summarize_notebook_message.md", escape = "none")]
+struct SummarizeNotebookMessageTemplate<'a> {
+    objective: &'a str,
+    notebook: &'a str,
+    max_chars: usize,
+}
+
 impl<'a> Default for Builder<'a> {
     fn default() -> Self {
         Self::new()
@@ -111,9 +277,16 @@ impl<'a> Builder<'a> {
         Self {
             app_local_data_dir: "",
             objective: String::new(),
+            notebook_separator: DEFAULT_NOTEBOOK_SEPARATOR.to_string(),
+            max_notebook_chars: None,
+            max_text_chars: None,
+            dismiss_consent: false,
             model: None,
             api_key: String::new(),
             user_agent: String::new(),
+            pool: None,
+            task: None,
+            progress: None,
         }
     }
 
@@ -129,6 +302,36 @@ impl<'a> Builder<'a> {
         self
     }
 
+    /// Separator placed before every notebook entry in the rendered notebook.
+    #[must_use]
+    pub fn with_notebook_separator(mut self, separator: &str) -> Self {
+        self.notebook_separator = separator.to_string();
+        self
+    }
+
+    /// Summarize the notebook with the LLM once its rendered content exceeds `max_chars`, so
+    /// that long sessions don't overflow the context.
+    #[must_use]
+    pub fn with_max_notebook_chars(mut self, max_chars: usize) -> Self {
+        self.max_notebook_chars = Some(max_chars);
+        self
+    }
+
+    /// Length cap of the page text returned by the `extract_text` ability, in characters. The
+    /// browser's default cap is used if not set.
+    #[must_use]
+    pub fn with_max_text_chars(mut self, max_chars: usize) -> Self {
+        self.max_text_chars = Some(max_chars);
+        self
+    }
+
+    /// Dismiss cookie consent dialogs after navigation, saving browsing steps.
+    #[must_use]
+    pub fn with_dismiss_consent(mut self, dismiss_consent: bool) -> Self {
+        self.dismiss_consent = dismiss_consent;
+        self
+    }
+
     #[must_use]
     pub fn with_model(mut self, model: &'a Model) -> Self {
         self.model = Some(model);
@@ -147,20 +350,39 @@ impl<'a> Builder<'a> {
         self
     }
 
+    /// Run browsing as a part of the task. The final notebook will be saved as the task result.
+    #[must_use]
+    pub fn with_task(mut self, pool: &'a Pool<Postgres>, task: &'a Task) -> Self {
+        self.pool = Some(pool);
+        self.task = Some(task);
+        self
+    }
+
+    /// Report browsing progress to the `progress` channel.
+    #[must_use]
+    pub fn with_progress(mut self, progress: UnboundedSender<Progress>) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
     /// Build a new `WebBrowsing` instance.
     ///
     /// # Errors
     ///
     /// Returns an error if the browser fails to connect.
     pub async fn build(self) -> Result<WebBrowsing<'a>> {
-        let mut browser = BrowserBuilder::new(self.app_local_data_dir)
-            .connect()
-            .await?;
+        let mut builder =
+            BrowserBuilder::new(self.app_local_data_dir).with_dismiss_consent(self.dismiss_consent);
+        if let Some(max_text_chars) = self.max_text_chars {
+            builder = builder.with_max_text_chars(max_text_chars);
+        }
+        let mut browser = builder.connect().await?;
         browser.goto("https://google.com").await?;
 
         Ok(WebBrowsing {
             browser,
-            notebook: String::new(),
+            notebook: Notebook::new(&self.notebook_separator),
+            max_notebook_chars: self.max_notebook_chars,
             objective: self.objective,
             model: self.model.context("Model not provided")?,
             api_key: self.api_key,
@@ -168,6 +390,12 @@ impl<'a> Builder<'a> {
             messages: vec![],
             is_active: false,
             history: vec![],
+            screenshots: 0,
+            kept_screenshots: VecDeque::new(),
+            failure: None,
+            pool: self.pool,
+            task: self.task,
+            progress: self.progress,
         })
     }
 }
@@ -179,31 +407,40 @@ impl WebBrowsing<'_> {
         self.is_active = true;
 
         loop {
+            let client = Client::for_model(self.model, &self.api_key, &self.user_agent);
+
+            if let Some(max_chars) = self.max_notebook_chars {
+                summarize_notebook(
+                    &client,
+                    &self.model.name,
+                    &self.objective,
+                    &mut self.notebook,
+                    max_chars,
+                )
+                .await?;
+            }
+
             // Construct messages for the LLM
             let mut messages = self.messages().await?;
             trace!("Messages: {:?}", messages);
 
             // Send request to LLM
-            let client = Client::new(
-                &self.api_key,
-                self.model.api_url_or_default(),
-                &self.user_agent,
-            );
-            let response = client
-                .create_chat_completion(CreateChatCompletionRequest {
+            let response = stream_completion(
+                &client,
+                CreateChatCompletionRequest {
                     model: &self.model.name,
                     messages: messages.clone(),
-                    tools: construct_tools(Self::abilities()).await?,
+                    tools: construct_tools(Self::abilities(), Vec::new()).await?,
                     ..Default::default()
-                })
-                .await
-                .context("Failed to create chat completion")?;
+                },
+                |message| self.report(Progress::Action(message)),
+            )
+            .await?;
 
             let has_content;
 
             // Process LLM function calls
-            let choice = response.choices.first().context("No response from LLM")?;
-            match &choice.message {
+            match &response {
                 Message::Assistant { content, .. } => {
                     has_content = content.is_some();
 
@@ -211,10 +448,10 @@ impl WebBrowsing<'_> {
                         debug!("LLM Response: {}", content.as_ref().unwrap());
                     }
 
-                    messages.push(choice.message.clone());
-                    self.messages.push(choice.message.clone());
+                    messages.push(response.clone());
+                    self.messages.push(response.clone());
 
-                    self.call_tools(&choice.message.tool_calls()).await?;
+                    self.call_tools(&response.tool_calls()).await?;
                 }
                 _ => return Err(anyhow!("Unexpected response from LLM").into()),
             }
@@ -222,24 +459,26 @@ impl WebBrowsing<'_> {
             if has_content {
                 // Reflect on text response from LLM
                 messages.push(Self::self_reflection_message()?);
-                let response = client
-                    .create_chat_completion(CreateChatCompletionRequest {
+                let response = stream_completion(
+                    &client,
+                    CreateChatCompletionRequest {
                         model: &self.model.name,
                         messages,
-                        tools: construct_tools(Self::self_reflection_abilities()).await?,
+                        tools: construct_tools(Self::self_reflection_abilities(), Vec::new())
+                            .await?,
                         ..Default::default()
-                    })
-                    .await
-                    .context("Failed to create chat completion")?;
+                    },
+                    |message| self.report(Progress::SelfReflection(message)),
+                )
+                .await?;
 
                 // Process self-reflection function calls
-                let choice = response.choices.first().context("No response from LLM")?;
-                match &choice.message {
+                match &response {
                     Message::Assistant { content, .. } => {
                         debug!("Self-reflection: {:?}", content);
-                        self.messages.push(choice.message.clone());
+                        self.messages.push(response.clone());
 
-                        self.call_self_reflection_tools(&choice.message.tool_calls())?;
+                        self.call_self_reflection_tools(&response.tool_calls())?;
                     }
                     _ => return Err(anyhow!("Unexpected response from LLM").into()),
                 }
@@ -250,8 +489,51 @@ impl WebBrowsing<'_> {
             }
         }
 
-        // TODO: return result
-        Ok(WebBrowsingResult::Text(self.objective.clone()))
+        if let Some(reason) = self.failure.take() {
+            return Ok(WebBrowsingResult::Failure(reason));
+        }
+
+        if let (Some(pool), Some(task)) = (self.pool, self.task) {
+            save_notebook(pool, task, &self.notebook.render(), &self.history).await?;
+        }
+
+        Ok(WebBrowsingResult::Text(self.notebook.render()))
+    }
+
+    /// Structured notebook entries, e.g. to extract the sources of the result.
+    #[must_use]
+    pub fn notebook(&self) -> &Notebook {
+        &self.notebook
+    }
+
+    fn report(&self, progress: Progress) {
+        if let Some(sender) = &self.progress {
+            // Browsing goes on even if nobody listens to the progress anymore.
+            if sender.send(progress).is_err() {
+                trace!("Progress receiver is dropped");
+            }
+        }
+    }
+
+    /// Saves the screenshot of the current page, numbered so that the earlier ones are kept.
+    /// Only the last [`MAX_KEPT_SCREENSHOTS`] are kept, the older ones are removed.
+    async fn save_screenshot(&mut self) -> Result<()> {
+        self.screenshots += 1;
+        let path = self
+            .browser
+            .save_screenshot(&format!("screenshot-{:03}.png", self.screenshots))
+            .await?;
+        self.kept_screenshots.push_back(path);
+
+        while self.kept_screenshots.len() > MAX_KEPT_SCREENSHOTS {
+            if let Some(oldest) = self.kept_screenshots.pop_front() {
+                if let Err(err) = std::fs::remove_file(&oldest) {
+                    warn!("Failed to remove screenshot `{oldest}`: {err}");
+                }
+            }
+        }
+
+        Ok(())
     }
 
     fn push_tool_message(&mut self, content: &str, tool_call_id: &str) {
@@ -269,30 +551,63 @@ impl WebBrowsing<'_> {
 
                     self.messages.clear();
                     self.browser.scroll_down().await?;
-                    self.browser.save_screenshot().await?;
+                    self.save_screenshot().await?;
                     self.history.push("scroll_down".to_string());
                 }
-                // "scroll_up" => {
-                //     self.messages.clear();
-                //     self.browser.scroll_up().await?;
-                //     self.browser.save_screenshot().await?;
-                // }
+                "scroll_up" => {
+                    debug!("Scrolling up");
+
+                    self.messages.clear();
+                    self.browser.scroll_up().await?;
+                    self.save_screenshot().await?;
+                    self.history.push("scroll_up".to_string());
+                }
+                "go_back" => {
+                    debug!("Going back");
+
+                    self.messages.clear();
+                    self.browser.go_back().await?;
+                    self.save_screenshot().await?;
+                    self.history.push("go_back".to_string());
+                }
                 "goto" => {
                     self.messages.clear();
 
                     let args: GotoArgs = serde_json::from_str(&tool_call.function.arguments)?;
                     debug!("Navigating to: {}", args.url);
                     self.browser.goto(&args.url).await?;
-                    self.browser.save_screenshot().await?;
                     self.history.push(args.url.clone());
+
+                    if let Some(selector) = &args.wait_for_selector {
+                        match self
+                            .browser
+                            .wait_for_selector(selector, SELECTOR_WAIT_TIMEOUT)
+                            .await
+                        {
+                            Ok(()) => {}
+                            // The tool call is cleared with the messages, so the LLM learns
+                            // about the timeout or the invalid selector from the history, and
+                            // may wait again.
+                            Err(errors::Error::Browser(
+                                err @ (browser::Error::SelectorTimeout { .. }
+                                | browser::Error::InvalidSelector { .. }),
+                            )) => {
+                                debug!("{err}");
+                                self.history.push(err.to_string());
+                            }
+                            Err(err) => return Err(err),
+                        }
+                    }
+
+                    self.save_screenshot().await?;
                 }
                 "send_keys" => {
                     let args: SendKeysArgs = serde_json::from_str(&tool_call.function.arguments)?;
                     debug!("Sending keys: {}", args.text);
-                    self.browser.save_screenshot().await?;
+                    self.save_screenshot().await?;
                     self.browser.send_keys(args.id, &args.text).await?;
                     self.push_tool_message("Keys sent", &tool_call.id);
-                    self.browser.save_screenshot().await?;
+                    self.save_screenshot().await?;
                 }
                 "click" => {
                     let current_url = self.browser.get_current_url().await?;
@@ -300,7 +615,7 @@ impl WebBrowsing<'_> {
                     debug!("Clicking element: {}", args.id);
                     self.browser.click(args.id).await?;
                     self.push_tool_message("Clicked", &tool_call.id);
-                    self.browser.save_screenshot().await?;
+                    self.save_screenshot().await?;
 
                     if current_url != self.browser.get_current_url().await? {
                         debug!("Navigated to: {}", self.browser.get_current_url().await?);
@@ -308,15 +623,40 @@ impl WebBrowsing<'_> {
                         self.messages.clear();
                     }
                 }
+                "extract_table" => {
+                    let args: ExtractTableArgs =
+                        serde_json::from_str(&tool_call.function.arguments)?;
+                    debug!("Extracting table: {}", args.index);
+                    let tables = self.browser.extract_tables().await?;
+                    let content = match tables.get(args.index) {
+                        Some(table) => match args.format {
+                            TableFormat::Csv => table.to_csv(),
+                            TableFormat::Json => serde_json::to_string_pretty(table)?,
+                        },
+                        None => format!(
+                            "No table with index {}, the page has {} tables",
+                            args.index,
+                            tables.len()
+                        ),
+                    };
+                    self.push_tool_message(&content, &tool_call.id);
+                }
+                "extract_text" => {
+                    debug!("Extracting page text");
+                    let text = self.browser.extract_readable_text().await?;
+                    let content = if text.is_empty() {
+                        "No readable text on the page"
+                    } else {
+                        &text
+                    };
+                    self.push_tool_message(content, &tool_call.id);
+                }
                 "append_notebook" => {
                     let args: AppendNotebookArgs =
                         serde_json::from_str(&tool_call.function.arguments)?;
                     debug!("Appending to notebook: {}", args.text);
-                    self.notebook.push_str("\n\n---\n\n");
-                    self.notebook
-                        .push_str(self.browser.get_current_url().await?.as_str());
-                    self.notebook.push_str("\n\n");
-                    self.notebook.push_str(&args.text);
+                    let url = self.browser.get_current_url().await?;
+                    self.notebook.append(url.as_str(), &args.text);
                     self.push_tool_message("Appended to notebook", &tool_call.id);
                 }
                 "clear_notebook" => {
@@ -338,6 +678,7 @@ impl WebBrowsing<'_> {
                 "fail" => {
                     let args: FailArgs = serde_json::from_str(&tool_call.function.arguments)?;
                     error!("Objective failed: {}", args.reason);
+                    self.failure = Some(args.reason);
                     self.is_active = false;
                 }
                 _ => return Err(anyhow!("Unknown tool call: {}", tool_call.function.name).into()),
@@ -353,7 +694,7 @@ impl WebBrowsing<'_> {
             .map_err(Error::TemplateRender)?;
 
         Ok(Message::User {
-            content: self_reflection_message_content,
+            content: self_reflection_message_content.into(),
             name: None,
         })
     }
@@ -364,7 +705,7 @@ impl WebBrowsing<'_> {
 
         let system_message_content = SystemMessageTemplate {
             objective: &self.objective,
-            notebook: &self.notebook,
+            notebook: &self.notebook.render(),
         }
         .render()
         .map_err(Error::TemplateRender)?;
@@ -378,6 +719,18 @@ impl WebBrowsing<'_> {
         .render()
         .map_err(Error::TemplateRender)?;
 
+        // Let the models with vision see the page, not only its elements.
+        let viewport_message_content = if self.model.image_in {
+            Content::Parts(vec![
+                ContentPart::Text {
+                    text: viewport_message_content,
+                },
+                ContentPart::png(&self.browser.screenshot().await?),
+            ])
+        } else {
+            viewport_message_content.into()
+        };
+
         let mut messages = vec![
             Message::System {
                 content: system_message_content,
@@ -397,7 +750,11 @@ impl WebBrowsing<'_> {
     fn abilities() -> Vec<Ability> {
         vec![
             Ability::for_fn("Scroll one page down", &json!({ "name": "scroll_down" })),
-            // Ability::for_fn("Scroll one page up", &json!({ "name": "scroll_up" })),
+            Ability::for_fn("Scroll one page up", &json!({ "name": "scroll_up" })),
+            Ability::for_fn(
+                "Go back to the previous page",
+                &json!({ "name": "go_back" }),
+            ),
             Ability::for_fn(
                 "Go to URL",
                 &json!({
@@ -408,8 +765,14 @@ impl WebBrowsing<'_> {
                             "url": {
                                 "type": "string",
                                 "description": "URL to navigate to"
+                            },
+                            "wait_for_selector": {
+                                "type": "string",
+                                "description": "CSS selector of an element to wait for, \
+                                    if the page is rendered by scripts"
                             }
-                        }
+                        },
+                        "required": ["url"]
                     }
                 }),
             ),
@@ -428,7 +791,8 @@ impl WebBrowsing<'_> {
                                 "type": "string",
                                 "description": "Text to type"
                             }
-                        }
+                        },
+                        "required": ["id", "text"]
                     }
                 }),
             ),
@@ -443,10 +807,37 @@ impl WebBrowsing<'_> {
                                 "type": "integer",
                                 "description": "Element ID to click"
                             }
-                        }
+                        },
+                        "required": ["id"]
+                    }
+                }),
+            ),
+            Ability::for_fn(
+                "Extract a table from the page, to read tabular data by rows and columns",
+                &json!({
+                    "name": "extract_table",
+                    "parameters": {
+                        "type": "object",
+                        "properties": {
+                            "index": {
+                                "type": "integer",
+                                "description": "Index of the table on the page, in the document order, starting from 0"
+                            },
+                            "format": {
+                                "type": "string",
+                                "enum": ["csv", "json"],
+                                "description": "Format to return the table in, `csv` by default"
+                            }
+                        },
+                        "required": ["index"]
                     }
                 }),
             ),
+            Ability::for_fn(
+                "Extract the main text of the page, without navigation and ads, to read a whole \
+                    article and save the relevant parts to the notebook",
+                &json!({ "name": "extract_text" }),
+            ),
             Ability::for_fn(
                 "Append text to notebook",
                 &json!({
@@ -458,7 +849,8 @@ impl WebBrowsing<'_> {
                                 "type": "string",
                                 "description": "Text to append to notebook"
                             }
-                        }
+                        },
+                        "required": ["text"]
                     }
                 }),
             ),
@@ -498,10 +890,430 @@ impl WebBrowsing<'_> {
                                 "type": "string",
                                 "description": "Reason for failure"
                             }
-                        }
+                        },
+                        "required": ["reason"]
                     }
                 }),
             ),
         ]
     }
 }
+
+/// Streams a chat completion, calling `on_update` with the partial response after every chunk.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while streaming the completion.
+/// Returns error if the stream ended before the completion was done.
+async fn stream_completion<F>(
+    client: &Client,
+    request: CreateChatCompletionRequest<'_>,
+    mut on_update: F,
+) -> Result<Message>
+where
+    F: FnMut(Message),
+{
+    let response = client
+        .create_chat_completion_stream(request)
+        .await
+        .context("Failed to create chat completion")?;
+
+    let mut stream = CompletionStream::new(response).with_read_timeout(client.timeout());
+    let mut message = messages::Message {
+        role: Role::Assistant,
+        ..Default::default()
+    };
+
+    while let Some(update) = stream.next(&mut message).await? {
+        if update == StreamUpdate::Chunk {
+            on_update(message.clone().try_into()?);
+        }
+    }
+
+    if message.status == Status::Writing {
+        return Err(anyhow!("Incomplete response from LLM").into());
+    }
+
+    Ok(message.try_into()?)
+}
+
+/// Condenses the notebook with the LLM if its rendered content is longer than `max_chars`. The
+/// summary is truncated if it's still too long. Returns whether the notebook was summarized.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while creating the completion.
+/// Returns error if the LLM responded with no summary.
+async fn summarize_notebook(
+    client: &Client,
+    model_name: &str,
+    objective: &str,
+    notebook: &mut Notebook,
+    max_chars: usize,
+) -> Result<bool> {
+    let rendered = notebook.render();
+    let chars = rendered.chars().count();
+    if chars <= max_chars {
+        return Ok(false);
+    }
+
+    debug!("Summarizing notebook of {chars} characters");
+
+    let content = SummarizeNotebookMessageTemplate {
+        objective,
+        notebook: rendered.trim(),
+        max_chars,
+    }
+    .render()
+    .map_err(Error::TemplateRender)?;
+
+    let response = stream_completion(
+        client,
+        CreateChatCompletionRequest {
+            model: model_name,
+            messages: vec![Message::User {
+                content: content.into(),
+                name: None,
+            }],
+            ..Default::default()
+        },
+        |_| {},
+    )
+    .await?;
+
+    let summary = match response {
+        Message::Assistant {
+            content: Some(summary),
+            ..
+        } if !summary.trim().is_empty() => summary,
+        _ => return Err(anyhow!("LLM responded with no notebook summary").into()),
+    };
+
+    debug!(
+        "Notebook is summarized to {} characters",
+        summary.chars().count()
+    );
+
+    // Summarizing the summary again may never fit it in, so it's cut instead.
+    let summary = summary.trim();
+    let max_summary_chars = max_chars.saturating_sub(notebook.separator.chars().count());
+    let summary = match summary.char_indices().nth(max_summary_chars) {
+        Some((end, _)) => {
+            warn!("Notebook summary is over {max_chars} characters, truncating it");
+            &summary[..end]
+        }
+        None => summary,
+    };
+    notebook.replace_with_summary(summary);
+
+    Ok(true)
+}
+
+/// Saves the notebook along with the visited pages as the task result.
+///
+/// # Errors
+///
+/// Returns error if there was a problem while accessing database.
+pub async fn save_notebook<'a, E>(
+    executor: E,
+    task: &Task,
+    notebook: &str,
+    history: &[String],
+) -> Result<TaskResult>
+where
+    E: Executor<'a, Database = Postgres>,
+{
+    let visited_pages = history
+        .iter()
+        .filter(|entry| entry.starts_with("http"))
+        .map(|url| format!("- {url}"))
+        .collect::<Vec<_>>();
+
+    let mut data = notebook.trim().to_string();
+
+    if !visited_pages.is_empty() {
+        data.push_str("\n\n## Visited Pages\n\n");
+        data.push_str(&visited_pages.join("\n"));
+    }
+
+    repo::task_results::create(
+        executor,
+        task.company_id,
+        repo::task_results::CreateParams {
+            agent_id: task.agent_id,
+            task_id: task.id,
+            kind: Kind::Text,
+            data,
+        },
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::{
+        matchers::{body_partial_json, body_string_contains, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::*;
+
+    const COMPANY_ID: uuid::Uuid = uuid::Uuid::from_u128(1);
+    const TASK_ID: uuid::Uuid = uuid::Uuid::from_u128(0x0005_0000_0000_0001);
+
+    #[sqlx::test(
+        migrations = "db/migrations",
+        fixtures(
+            path = "../../db/fixtures",
+            scripts("companies", "users", "agents", "tasks")
+        )
+    )]
+    async fn test_save_notebook_as_task_result(pool: Pool<Postgres>) {
+        let task = repo::tasks::get(&pool, COMPANY_ID, TASK_ID).await.unwrap();
+        let notebook = "\n\n---\n\nhttps://weather.example.com\n\nSunny, 24°C";
+        let history = vec![
+            "https://google.com/search?q=weather".to_string(),
+            "scroll_down".to_string(),
+            "https://weather.example.com".to_string(),
+        ];
+
+        save_notebook(&pool, &task, notebook, &history)
+            .await
+            .unwrap();
+
+        let results = repo::task_results::list(&pool, COMPANY_ID, TASK_ID)
+            .await
+            .unwrap();
+
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].agent_id, task.agent_id);
+        assert_eq!(
+            results[0].data,
+            "---\n\nhttps://weather.example.com\n\nSunny, 24°C\n\n## Visited Pages\n\n\
+            - https://google.com/search?q=weather\n\
+            - https://weather.example.com"
+        );
+    }
+
+    #[test]
+    fn test_abilities_include_extraction() {
+        let names = WebBrowsing::abilities()
+            .iter()
+            .map(|ability| ability.function().name)
+            .collect::<Vec<_>>();
+
+        for name in ["extract_table", "extract_text"] {
+            assert!(names.iter().any(|n| n == name), "`{name}` is not offered");
+        }
+    }
+
+    #[test]
+    fn test_abilities_include_navigation() {
+        let names = WebBrowsing::abilities()
+            .iter()
+            .map(|ability| ability.function().name)
+            .collect::<Vec<_>>();
+
+        for name in ["scroll_down", "scroll_up", "go_back", "goto"] {
+            assert!(names.iter().any(|n| n == name), "`{name}` is not offered");
+        }
+    }
+
+    #[test]
+    fn test_notebook_keeps_structured_entries() {
+        let mut notebook = Notebook::default();
+
+        notebook.append("https://weather.example.com", "Sunny, 24°C");
+        notebook.append("https://news.example.com", "No storms expected");
+
+        let urls = notebook
+            .entries()
+            .iter()
+            .map(|entry| entry.url.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            urls,
+            ["https://weather.example.com", "https://news.example.com"]
+        );
+        assert_eq!(notebook.entries()[1].text, "No storms expected");
+        assert_eq!(
+            notebook.render(),
+            "\n\n---\n\nhttps://weather.example.com\n\nSunny, 24°C\
+             \n\n---\n\nhttps://news.example.com\n\nNo storms expected"
+        );
+
+        let json = serde_json::to_value(&notebook).unwrap();
+        assert_eq!(json["entries"][0]["url"], "https://weather.example.com");
+    }
+
+    fn tool_call_chunk(tool_call: &serde_json::Value) -> String {
+        format!(
+            "data: {}\n\n",
+            json!({ "choices": [{ "index": 0, "delta": { "tool_calls": [tool_call] } }] })
+        )
+    }
+
+    #[tokio::test]
+    async fn test_stream_completion_reconstructs_tool_calls() {
+        let server = MockServer::start().await;
+
+        let body = [
+            tool_call_chunk(&json!({
+                "index": 0,
+                "id": "call_1",
+                "type": "function",
+                "function": { "name": "goto", "arguments": "" }
+            })),
+            tool_call_chunk(&json!({ "index": 0, "function": { "arguments": "{\"url\": " } })),
+            tool_call_chunk(&json!({
+                "index": 0,
+                "function": { "arguments": "\"https://weather.example.com\"}" }
+            })),
+            tool_call_chunk(&json!({
+                "index": 1,
+                "id": "call_2",
+                "type": "function",
+                "function": { "name": "scroll_down", "arguments": "{}" }
+            })),
+            "data: [DONE]\n\n".to_string(),
+        ]
+        .concat();
+
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .and(body_partial_json(json!({ "stream": true })))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(body, "text/event-stream"))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = Client::new("sk-test", &format!("{}/", server.uri()), "bridge-test");
+        let mut updates = Vec::new();
+
+        let message = stream_completion(
+            &client,
+            CreateChatCompletionRequest {
+                model: "gpt-4-turbo",
+                messages: vec![Message::User {
+                    content: "What's the weather?".into(),
+                    name: None,
+                }],
+                ..Default::default()
+            },
+            |message| updates.push(message),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(updates.len(), 4);
+        assert_eq!(updates[1].tool_calls()[0].function.arguments, "{\"url\": ");
+
+        let tool_calls = message.tool_calls();
+        assert_eq!(tool_calls.len(), 2);
+        assert_eq!(tool_calls[0].id, "call_1");
+        assert_eq!(tool_calls[0].function.name, "goto");
+        assert_eq!(
+            serde_json::from_str::<GotoArgs>(&tool_calls[0].function.arguments)
+                .unwrap()
+                .url,
+            "https://weather.example.com"
+        );
+        assert_eq!(tool_calls[1].id, "call_2");
+        assert_eq!(tool_calls[1].function.name, "scroll_down");
+    }
+
+    #[tokio::test]
+    async fn test_oversized_notebook_is_summarized() {
+        let server = MockServer::start().await;
+        let summary = "Weather: sunny, 24°C, no storms (https://weather.example.com)";
+
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .and(body_string_contains("No storms expected"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_raw(
+                    [
+                        format!(
+                            "data: {}\n\n",
+                            json!({ "choices": [{ "index": 0, "delta": { "content": summary } }] })
+                        ),
+                        "data: [DONE]\n\n".to_string(),
+                    ]
+                    .concat(),
+                    "text/event-stream",
+                ),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = Client::new("sk-test", &format!("{}/", server.uri()), "bridge-test");
+        let mut notebook = Notebook::default();
+        notebook.append("https://weather.example.com", &"Sunny, 24°C. ".repeat(10));
+        notebook.append("https://news.example.com", "No storms expected");
+
+        let is_summarized =
+            summarize_notebook(&client, "gpt-4-turbo", "Weather", &mut notebook, 150)
+                .await
+                .unwrap();
+
+        assert!(is_summarized);
+        assert_eq!(notebook.summarizations(), 1);
+        assert_eq!(notebook.summary(), Some(summary));
+        assert!(notebook.entries().is_empty());
+
+        // Browsing goes on with the condensed notebook, which is within the limit.
+        notebook.append("https://news.example.com", "Windy");
+        assert_eq!(
+            notebook.render(),
+            format!("\n\n---\n\n{summary}\n\n---\n\nhttps://news.example.com\n\nWindy")
+        );
+
+        let is_summarized =
+            summarize_notebook(&client, "gpt-4-turbo", "Weather", &mut notebook, 150)
+                .await
+                .unwrap();
+
+        assert!(!is_summarized);
+        assert_eq!(notebook.summarizations(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_overlong_notebook_summary_is_truncated() {
+        let server = MockServer::start().await;
+        let summary = "Sunny. ".repeat(30);
+
+        Mock::given(method("POST"))
+            .and(path("/chat/completions"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_raw(
+                    [
+                        format!(
+                            "data: {}\n\n",
+                            json!({ "choices": [{ "index": 0, "delta": { "content": summary } }] })
+                        ),
+                        "data: [DONE]\n\n".to_string(),
+                    ]
+                    .concat(),
+                    "text/event-stream",
+                ),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = Client::new("sk-test", &format!("{}/", server.uri()), "bridge-test");
+        let mut notebook = Notebook::default();
+        notebook.append("https://weather.example.com", &"Sunny, 24°C. ".repeat(20));
+
+        let is_summarized =
+            summarize_notebook(&client, "gpt-4-turbo", "Weather", &mut notebook, 150)
+                .await
+                .unwrap();
+
+        assert!(is_summarized);
+        assert_eq!(notebook.summarizations(), 1);
+        assert_eq!(notebook.render().chars().count(), 150);
+        assert!(summary.starts_with(notebook.summary().unwrap()));
+    }
+}
@@ -0,0 +1,187 @@
+// Copyright 2024 StarfleetAI
+// SPDX-License-Identifier: Apache-2.0
+
+use std::path::Path;
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::{
+    fs::OpenOptions,
+    io::{AsyncWrite, AsyncWriteExt, BufWriter},
+    sync::{mpsc, oneshot},
+};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{clients::openai::ToolCall, types::messages::Message, types::Result};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("transcript writer is closed")]
+    Closed,
+}
+
+#[derive(Serialize, Debug)]
+struct Record<'a> {
+    timestamp: DateTime<Utc>,
+    company_id: Uuid,
+    task_id: Option<Uuid>,
+    chat_id: Uuid,
+    #[serde(flatten)]
+    entry: Entry<'a>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "kind", content = "data")]
+enum Entry<'a> {
+    MessageCreated(&'a Message),
+    MessageUpdated(&'a Message),
+    ToolCall {
+        message_id: Uuid,
+        tool_call: &'a ToolCall,
+    },
+}
+
+enum Command {
+    Write(String),
+    Flush(oneshot::Sender<()>),
+}
+
+/// Append-only audit log of messages and tool calls, written as JSON lines.
+///
+/// Records are queued and written by a background task, so recording never waits for I/O.
+/// Clones share the same writer.
+#[derive(Clone, Debug)]
+pub struct TranscriptSink {
+    sender: mpsc::UnboundedSender<Command>,
+    task_id: Option<Uuid>,
+}
+
+impl TranscriptSink {
+    /// Creates a sink writing to the `writer`. Must be called within a Tokio runtime.
+    #[must_use]
+    pub fn new<W>(writer: W) -> Self
+    where
+        W: AsyncWrite + Send + Unpin + 'static,
+    {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(write_lines(BufWriter::new(writer), receiver));
+
+        Self {
+            sender,
+            task_id: None,
+        }
+    }
+
+    /// Creates a sink appending to the file, which is created if it doesn't exist.
+    ///
+    /// # Errors
+    ///
+    /// Returns error if the file can't be opened.
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .with_context(|| format!("Failed to open transcript file {}", path.display()))?;
+
+        Ok(Self::new(file))
+    }
+
+    /// Sink attributing the records to the task.
+    #[must_use]
+    pub fn for_task(&self, task_id: Uuid) -> Self {
+        Self {
+            sender: self.sender.clone(),
+            task_id: Some(task_id),
+        }
+    }
+
+    pub fn message_created(&self, message: &Message) {
+        self.record(message, Entry::MessageCreated(message));
+    }
+
+    pub fn message_updated(&self, message: &Message) {
+        self.record(message, Entry::MessageUpdated(message));
+    }
+
+    pub fn tool_call(&self, message: &Message, tool_call: &ToolCall) {
+        self.record(
+            message,
+            Entry::ToolCall {
+                message_id: message.id,
+                tool_call,
+            },
+        );
+    }
+
+    /// Waits until all the records queued so far are written out.
+    ///
+    /// # Errors
+    ///
+    /// Returns error if the writer task has stopped.
+    pub async fn flush(&self) -> Result<()> {
+        let (done, flushed) = oneshot::channel();
+        self.sender
+            .send(Command::Flush(done))
+            .map_err(|_| Error::Closed)?;
+
+        Ok(flushed.await.map_err(|_| Error::Closed)?)
+    }
+
+    fn record(&self, message: &Message, entry: Entry) {
+        let record = Record {
+            timestamp: Utc::now(),
+            company_id: message.company_id,
+            task_id: self.task_id,
+            chat_id: message.chat_id,
+            entry,
+        };
+
+        match serde_json::to_string(&record) {
+            Ok(mut line) => {
+                line.push('\n');
+
+                if self.sender.send(Command::Write(line)).is_err() {
+                    warn!("Transcript writer is closed, record is dropped");
+                }
+            }
+            Err(err) => warn!("Failed to serialize transcript record: {err}"),
+        }
+    }
+}
+
+async fn write_lines<W>(mut writer: BufWriter<W>, mut receiver: mpsc::UnboundedReceiver<Command>)
+where
+    W: AsyncWrite + Unpin,
+{
+    while let Some(command) = receiver.recv().await {
+        match command {
+            Command::Write(line) => {
+                if let Err(err) = writer.write_all(line.as_bytes()).await {
+                    warn!("Failed to write transcript record: {err}");
+                }
+            }
+            Command::Flush(done) => {
+                flush(&mut writer).await;
+                let _ = done.send(());
+            }
+        }
+
+        // Don't keep records in the buffer while idle.
+        if receiver.is_empty() {
+            flush(&mut writer).await;
+        }
+    }
+
+    flush(&mut writer).await;
+}
+
+async fn flush<W: AsyncWrite + Unpin>(writer: &mut BufWriter<W>) {
+    if let Err(err) = writer.flush().await {
+        warn!("Failed to flush transcript: {err}");
+    }
+}
@@ -2,6 +2,8 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use uuid::Uuid;
 
 pub struct Agent {
@@ -17,4 +19,28 @@ pub struct Agent {
     pub execution_steps_limit: Option<i32>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    /// Internal task tools (e.g. `sfai_done`) the agent is not allowed to call.
+    pub forbidden_tools: Vec<String>,
+    /// Few-shot examples, see [`Agent::examples`].
+    pub examples: Value,
+}
+
+/// Example exchange showing the agent how to respond.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Example {
+    pub user: String,
+    pub assistant: String,
+}
+
+impl Agent {
+    #[must_use]
+    pub fn is_tool_forbidden(&self, name: &str) -> bool {
+        self.forbidden_tools.iter().any(|tool| tool == name)
+    }
+
+    /// Few-shot examples, in the order they are given to the LLM.
+    #[must_use]
+    pub fn examples(&self) -> Vec<Example> {
+        serde_json::from_value(self.examples.clone()).unwrap_or_default()
+    }
 }
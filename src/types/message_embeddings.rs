@@ -0,0 +1,18 @@
+// Copyright 2024 StarfleetAI
+// SPDX-License-Identifier: Apache-2.0
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MessageEmbedding {
+    pub id: Uuid,
+    pub company_id: Uuid,
+    pub message_id: Uuid,
+    /// Name of the embeddings model used to compute the embedding.
+    pub model: String,
+    pub embedding: Vec<f32>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
@@ -47,6 +47,8 @@ pub enum Status {
     Completed,
     Failed,
     ToolCallDenied,
+    /// Generation was stopped by the provider's content filter, the content may be partial.
+    ContentFiltered,
 }
 
 impl Display for Status {
@@ -62,6 +64,7 @@ impl From<String> for Status {
             "WaitingForToolCall" => Status::WaitingForToolCall,
             "Failed" => Status::Failed,
             "ToolCallDenied" => Status::ToolCallDenied,
+            "ContentFiltered" => Status::ContentFiltered,
             _ => Status::Completed,
         }
     }
@@ -84,18 +87,20 @@ pub struct Message {
     pub tool_call_id: Option<String>,
     pub is_self_reflection: bool,
     pub is_internal_tool_output: bool,
+    /// Pinned messages are never dropped when the chat history is trimmed to fit the context.
+    pub is_pinned: bool,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    /// Tags attached to the message, sorted alphabetically.
+    #[sqlx(default)]
+    pub tags: Vec<String>,
 }
 
 impl Message {
     #[must_use]
     pub fn tool_calls(&self) -> ToolCalls {
         match self.tool_calls {
-            Some(ref tool_calls) => match serde_json::from_value(tool_calls.clone()) {
-                Ok(tool_calls) => tool_calls,
-                Err(_) => ToolCalls::default(),
-            },
+            Some(ref tool_calls) => serde_json::from_value(tool_calls.clone()).unwrap_or_default(),
             None => ToolCalls::default(),
         }
     }
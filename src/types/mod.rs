@@ -6,11 +6,15 @@ pub mod agent_abilities;
 pub mod agents;
 pub mod agents_chats;
 pub mod chats;
+pub mod message_embeddings;
 pub mod messages;
 pub mod models;
+pub mod page_embeddings;
 pub mod pages;
 pub mod pagination;
+pub mod task_plans;
 pub mod task_results;
+pub mod task_secrets;
 pub mod tasks;
 
 pub type Result<T> = std::result::Result<T, crate::errors::Error>;
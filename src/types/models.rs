@@ -1,12 +1,17 @@
 // Copyright 2024 StarfleetAI
 // SPDX-License-Identifier: Apache-2.0
 
+use std::fmt::{Display, Formatter};
+
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 const OPENAI_API_URL: &str = "https://api.openai.com/v1/";
 const GROQ_API_URL: &str = "https://api.groq.com/openai/v1/";
+const ANTHROPIC_API_URL: &str = "https://api.anthropic.com/v1/";
+const OLLAMA_API_URL: &str = "http://localhost:11434/v1/";
+const DEFAULT_AZURE_API_VERSION: &str = "2024-02-01";
 
 #[derive(
     Serialize, Deserialize, Debug, sqlx::Type, Default, PartialEq, Eq, Clone, Ord, PartialOrd,
@@ -15,12 +20,46 @@ pub enum Provider {
     #[default]
     OpenAI,
     Groq,
+    Anthropic,
+    Ollama,
+    /// Azure OpenAI, `api_url` of the model must be set to the resource endpoint.
+    Azure,
+}
+
+impl Provider {
+    /// Whether the provider accepts `metadata` and `store` fields in chat completion requests.
+    #[must_use]
+    pub fn supports_request_metadata(&self) -> bool {
+        match self {
+            Provider::OpenAI => true,
+            Provider::Groq | Provider::Anthropic | Provider::Ollama | Provider::Azure => false,
+        }
+    }
+
+    /// Whether the provider only reports token usage in streamed completions when asked to with
+    /// `stream_options`.
+    #[must_use]
+    pub fn requires_stream_usage_option(&self) -> bool {
+        match self {
+            Provider::OpenAI | Provider::Ollama => true,
+            Provider::Groq | Provider::Anthropic | Provider::Azure => false,
+        }
+    }
+}
+
+impl Display for Provider {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self:?}")
+    }
 }
 
 impl From<String> for Provider {
     fn from(s: String) -> Self {
         match s.as_str() {
             "Groq" => Provider::Groq,
+            "Anthropic" => Provider::Anthropic,
+            "Ollama" => Provider::Ollama,
+            "Azure" => Provider::Azure,
             _ => Provider::OpenAI,
         }
     }
@@ -59,6 +98,10 @@ pub struct Model {
     pub api_url: Option<String>,
     // API key for the API. Leave empty to use provider's default
     pub api_key: Option<String>,
+    // Azure OpenAI deployment. Leave empty to use the model name
+    pub deployment: Option<String>,
+    // Azure OpenAI API version. Leave empty to use the default one
+    pub api_version: Option<String>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
@@ -71,7 +114,23 @@ impl Model {
             None => match self.provider {
                 Provider::OpenAI => OPENAI_API_URL,
                 Provider::Groq => GROQ_API_URL,
+                Provider::Anthropic => ANTHROPIC_API_URL,
+                Provider::Ollama => OLLAMA_API_URL,
+                // Every Azure resource has its own endpoint.
+                Provider::Azure => "",
             },
         }
     }
+
+    #[must_use]
+    pub fn deployment_or_default(&self) -> &str {
+        self.deployment.as_deref().unwrap_or(&self.name)
+    }
+
+    #[must_use]
+    pub fn api_version_or_default(&self) -> &str {
+        self.api_version
+            .as_deref()
+            .unwrap_or(DEFAULT_AZURE_API_VERSION)
+    }
 }
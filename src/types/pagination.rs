@@ -3,8 +3,133 @@
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+/// Upper bound for `per_page` used by list functions.
+pub const MAX_PER_PAGE: i64 = 100;
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum Error {
+    #[error("`page` number must be greater than 0")]
+    InvalidPage,
+    #[error("`per_page` number must be greater than 0")]
+    InvalidPerPage,
+    #[error("`page` number is too large")]
+    PageOutOfRange,
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
 pub struct Pagination {
     pub page: i64,
     pub per_page: i64,
 }
+
+impl Pagination {
+    /// Validates pagination, clamping `per_page` to `max_per_page`.
+    ///
+    /// # Errors
+    ///
+    /// Returns error if `page` or `per_page` is less than 1.
+    /// Returns error if `page` is so large that the offset overflows.
+    pub fn validate(self, max_per_page: i64) -> Result<Self, Error> {
+        if self.page < 1 {
+            return Err(Error::InvalidPage);
+        }
+
+        if self.per_page < 1 {
+            return Err(Error::InvalidPerPage);
+        }
+
+        let per_page = self.per_page.min(max_per_page);
+        if (self.page - 1).checked_mul(per_page).is_none() {
+            return Err(Error::PageOutOfRange);
+        }
+
+        Ok(Self {
+            page: self.page,
+            per_page,
+        })
+    }
+
+    /// Number of records to skip. Never overflows for the validated pagination.
+    #[must_use]
+    pub fn offset(&self) -> i64 {
+        (self.page - 1) * self.per_page
+    }
+}
+
+/// Single page of the list along with the total number of records.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub total: i64,
+    pub page: i64,
+    pub per_page: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_clamps_per_page() {
+        let pagination = Pagination {
+            page: 3,
+            per_page: 1_000_000,
+        }
+        .validate(MAX_PER_PAGE)
+        .unwrap();
+
+        assert_eq!(
+            pagination,
+            Pagination {
+                page: 3,
+                per_page: MAX_PER_PAGE
+            }
+        );
+        assert_eq!(pagination.offset(), 200);
+    }
+
+    #[test]
+    fn test_validate_rejects_non_positive_values() {
+        for value in [0, -1] {
+            assert_eq!(
+                Pagination {
+                    page: value,
+                    per_page: 10
+                }
+                .validate(MAX_PER_PAGE),
+                Err(Error::InvalidPage)
+            );
+            assert_eq!(
+                Pagination {
+                    page: 1,
+                    per_page: value
+                }
+                .validate(MAX_PER_PAGE),
+                Err(Error::InvalidPerPage)
+            );
+        }
+    }
+
+    #[test]
+    fn test_validate_rejects_overflowing_page() {
+        assert_eq!(
+            Pagination {
+                page: i64::MAX / 2,
+                per_page: 10
+            }
+            .validate(MAX_PER_PAGE),
+            Err(Error::PageOutOfRange)
+        );
+
+        let pagination = Pagination {
+            page: i64::MAX / MAX_PER_PAGE,
+            per_page: MAX_PER_PAGE,
+        }
+        .validate(MAX_PER_PAGE)
+        .unwrap();
+        assert_eq!(
+            pagination.offset(),
+            (i64::MAX / MAX_PER_PAGE - 1) * MAX_PER_PAGE
+        );
+    }
+}
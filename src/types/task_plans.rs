@@ -0,0 +1,32 @@
+// Copyright 2024 StarfleetAI
+// SPDX-License-Identifier: Apache-2.0
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::task_planner::ExecutionPlan;
+use crate::types::Result;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TaskPlan {
+    pub id: Uuid,
+    pub company_id: Uuid,
+    pub task_id: Uuid,
+    /// Raw execution plan as it was received from the LLM.
+    pub plan: Value,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl TaskPlan {
+    /// Returns the stored execution plan.
+    ///
+    /// # Errors
+    ///
+    /// Returns error if the stored plan cannot be deserialized.
+    pub fn execution_plan(&self) -> Result<ExecutionPlan> {
+        Ok(serde_json::from_value(self.plan.clone())?)
+    }
+}
@@ -1,6 +1,8 @@
 // Copyright 2024 StarfleetAI
 // SPDX-License-Identifier: Apache-2.0
 
+use std::fmt::{self, Display, Formatter};
+
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
@@ -12,12 +14,24 @@ pub enum Kind {
     #[default]
     Text,
     Url,
+    /// Path of a file in the task's working directory.
+    File,
+    /// Image URL.
+    Image,
+}
+
+impl Display for Kind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{self:?}")
+    }
 }
 
 impl From<String> for Kind {
     fn from(kind: String) -> Self {
         match kind.as_str() {
             "Url" => Kind::Url,
+            "File" => Kind::File,
+            "Image" => Kind::Image,
             _ => Kind::Text,
         }
     }
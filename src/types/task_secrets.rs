@@ -0,0 +1,20 @@
+// Copyright 2024 StarfleetAI
+// SPDX-License-Identifier: Apache-2.0
+
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+/// Secret injected into the containers executing the agent's code. The value is never
+/// serialized, it is only decrypted right before the execution.
+#[derive(Debug, Clone)]
+pub struct TaskSecret {
+    pub id: Uuid,
+    pub company_id: Uuid,
+    /// Agent the secret is scoped to, or `None` for all the company's agents.
+    pub agent_id: Option<Uuid>,
+    /// Name of the environment variable.
+    pub name: String,
+    pub encrypted_value: Vec<u8>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
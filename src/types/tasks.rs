@@ -3,7 +3,7 @@
 
 use std::{
     fmt::{self, Display, Formatter},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
 use anyhow::Context;
@@ -28,6 +28,8 @@ pub enum Status {
     Done,
     /// Task execution failed.
     Failed,
+    /// Task execution was cancelled by the user.
+    Cancelled,
 }
 
 impl Display for Status {
@@ -44,11 +46,23 @@ impl From<String> for Status {
             "WaitingForUser" => Status::WaitingForUser,
             "Done" => Status::Done,
             "Failed" => Status::Failed,
+            "Cancelled" => Status::Cancelled,
             _ => Status::Draft,
         }
     }
 }
 
+/// How the tasks of a single tree share the working directory.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum WorkdirIsolation {
+    /// All tasks work in the root task's workdir.
+    #[default]
+    Shared,
+    /// Each sub-task works in its own subdirectory of the root task's workdir, so that concurrent
+    /// siblings don't overwrite each other's files.
+    PerTask,
+}
+
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct Task {
     pub id: Uuid,
@@ -82,7 +96,7 @@ impl Task {
             Some(ref ancestry) => {
                 let segment = ancestry
                     .split('/')
-                    .last()
+                    .next_back()
                     .context("No segments found in ancestry")?;
 
                 Some(
@@ -129,13 +143,16 @@ impl Task {
     /// # Errors
     ///
     /// Returns error if there was a problem while building workdir path.
-    pub async fn workdir(&self, root: &PathBuf) -> Result<PathBuf> {
-        let dir = format!(
-            "wd-task-{}",
-            self.workdir_id().context("Failed to get workdir ID")?
-        );
+    pub async fn workdir(&self, root: &Path, isolation: WorkdirIsolation) -> Result<PathBuf> {
+        let workdir_id = self.workdir_id().context("Failed to get workdir ID")?;
+        let workdir = root.join(format!("wd-task-{workdir_id}"));
 
-        Ok(root.join(dir))
+        Ok(match isolation {
+            WorkdirIsolation::PerTask if workdir_id != self.id => {
+                workdir.join(format!("task-{}", self.id))
+            }
+            WorkdirIsolation::Shared | WorkdirIsolation::PerTask => workdir,
+        })
     }
 
     fn workdir_id(&self) -> Result<Uuid> {
@@ -0,0 +1,79 @@
+// Copyright 2024 StarfleetAI
+// SPDX-License-Identifier: Apache-2.0
+
+// Buttons of the well-known consent frameworks, rejecting ones go first
+const knownSelectors = [
+    // OneTrust
+    '#onetrust-reject-all-handler',
+    '#onetrust-accept-btn-handler',
+    // Cookiebot
+    '#CybotCookiebotDialogBodyButtonDecline',
+    '#CybotCookiebotDialogBodyLevelButtonLevelOptinAllowAll',
+    '#CybotCookiebotDialogBodyButtonAccept',
+    // Didomi
+    '#didomi-notice-disagree-button',
+    '#didomi-notice-agree-button',
+    // Google Funding Choices
+    '.fc-cta-do-not-consent',
+    '.fc-cta-consent',
+]
+
+const containerPattern = /cookie|consent|gdpr|privacy|cmp/i
+const buttonPatterns = [
+    /^(reject|decline|refuse|deny)( all)?( cookies)?$/i,
+    /^(accept|allow|agree|i agree|ok|okay|got it)( all)?( cookies)?$/i,
+]
+
+function isVisible(element) {
+    const style = window.getComputedStyle(element)
+
+    return (
+        style.display !== 'none' && style.visibility !== 'hidden' && element.offsetWidth > 0 && element.offsetHeight > 0
+    )
+}
+
+function isInConsentContainer(element) {
+    for (let node = element; node && node !== document.body; node = node.parentElement) {
+        const attributes = [node.id, node.className, node.getAttribute('aria-label'), node.getAttribute('role')]
+
+        if (attributes.some((value) => typeof value === 'string' && containerPattern.test(value))) {
+            return true
+        }
+    }
+
+    return false
+}
+
+function findButton() {
+    for (const selector of knownSelectors) {
+        const element = document.querySelector(selector)
+
+        if (element && isVisible(element)) {
+            return element
+        }
+    }
+
+    const candidates = Array.from(
+        document.querySelectorAll('button, a, [role="button"], input[type="button"], input[type="submit"]'),
+    ).filter((element) => isVisible(element) && isInConsentContainer(element))
+
+    for (const pattern of buttonPatterns) {
+        const element = candidates.find((candidate) =>
+            pattern.test((candidate.textContent || candidate.value || '').trim()),
+        )
+
+        if (element) {
+            return element
+        }
+    }
+
+    return null
+}
+
+const button = findButton()
+
+if (button) {
+    button.click()
+}
+
+return button !== null
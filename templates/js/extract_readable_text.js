@@ -0,0 +1,85 @@
+// Copyright 2024 StarfleetAI
+// SPDX-License-Identifier: Apache-2.0
+
+// Returns the main text of the page, one paragraph per block element
+const SKIPPED = [
+    'script', 'style', 'noscript', 'template', 'svg', 'canvas', 'iframe', 'form', 'button',
+    'nav', 'header', 'footer', 'aside',
+    '[role="navigation"]', '[role="banner"]', '[role="contentinfo"]', '[role="complementary"]',
+    '[aria-hidden="true"]', '[hidden]',
+].join(', ')
+const JUNK = /(^|[\s_-])(ads?|advert\w*|banner|sponsor\w*|promo\w*|cookies?|consent|comments?|share|social|related|newsletter|popup|subscribe)([\s_-]|$)/i
+const BLOCKS = new Set([
+    'ADDRESS', 'ARTICLE', 'BLOCKQUOTE', 'BR', 'DD', 'DIV', 'DL', 'DT', 'FIGCAPTION', 'FIGURE',
+    'H1', 'H2', 'H3', 'H4', 'H5', 'H6', 'HR', 'LI', 'MAIN', 'OL', 'P', 'PRE', 'SECTION',
+    'TABLE', 'TD', 'TH', 'TR', 'UL',
+])
+
+function isSkipped(element) {
+    const className = typeof element.className === 'string' ? element.className : ''
+
+    return element.matches(SKIPPED)
+        || JUNK.test(element.id)
+        || JUNK.test(className)
+        || getComputedStyle(element).display === 'none'
+}
+
+// The explicit main content, or the element with the most paragraph text
+function findContent() {
+    const main = document.querySelector('article, main, [role="main"]')
+    if (main) {
+        return main
+    }
+
+    const scores = new Map()
+    document.querySelectorAll('p').forEach((p) => {
+        const parent = p.parentElement
+        scores.set(parent, (scores.get(parent) || 0) + p.innerText.trim().length)
+    })
+
+    let content = document.body
+    let bestScore = 0
+    scores.forEach((score, element) => {
+        if (score > bestScore) {
+            content = element
+            bestScore = score
+        }
+    })
+
+    return content
+}
+
+const paragraphs = []
+let paragraph = ''
+
+function flush() {
+    const text = paragraph.replace(/\s+/g, ' ').trim()
+    if (text.length > 0) {
+        paragraphs.push(text)
+    }
+    paragraph = ''
+}
+
+function walk(node) {
+    if (node.nodeType === Node.TEXT_NODE) {
+        paragraph += node.textContent
+        return
+    }
+    if (node.nodeType !== Node.ELEMENT_NODE || isSkipped(node)) {
+        return
+    }
+
+    const isBlock = BLOCKS.has(node.tagName)
+    if (isBlock) {
+        flush()
+    }
+    node.childNodes.forEach(walk)
+    if (isBlock) {
+        flush()
+    }
+}
+
+walk(findContent())
+flush()
+
+return paragraphs.join('\n\n')
@@ -0,0 +1,31 @@
+// Copyright 2024 StarfleetAI
+// SPDX-License-Identifier: Apache-2.0
+
+// Cells are returned as is, spans are expanded to the grid on the Rust side
+function collapseWhitespace(text) {
+    return (text || '').replace(/\s+/g, ' ').trim()
+}
+
+const tables = []
+
+document.querySelectorAll('table').forEach((table) => {
+    // `table.rows` skips the rows of the nested tables
+    const rows = Array.from(table.rows).map((row) => ({
+        is_head: row.parentElement.tagName.toLowerCase() === 'thead',
+        cells: Array.from(row.cells).map((cell) => ({
+            text: collapseWhitespace(cell.innerText),
+            is_header: cell.tagName.toLowerCase() === 'th',
+            rowspan: Math.max(cell.rowSpan, 1),
+            colspan: Math.max(cell.colSpan, 1),
+        })),
+    }))
+
+    if (rows.some((row) => row.cells.some((cell) => cell.text.length > 0))) {
+        tables.push({
+            caption: table.caption ? collapseWhitespace(table.caption.innerText) || null : null,
+            rows,
+        })
+    }
+})
+
+return tables